@@ -0,0 +1,59 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Compare the implementations of the one's complement sum on MTU-sized buffers.
+//!
+//! Run with `cargo bench --bench checksum`.
+
+use relaylib::packet::checksum::{ones_complement_sum, ones_complement_sum_naive};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const BUFFER_LENGTH: usize = 1500;
+const ITERATIONS: u32 = 200_000;
+
+fn bench(name: &str, buffer: &[u8], sum: fn(&[u8]) -> u16) -> Duration {
+    // warm up
+    for _ in 0..ITERATIONS / 10 {
+        black_box(sum(black_box(buffer)));
+    }
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        black_box(sum(black_box(buffer)));
+    }
+    let per_iteration = start.elapsed() / ITERATIONS;
+    println!(
+        "{:>10}: {:?}/iter ({} bytes)",
+        name,
+        per_iteration,
+        buffer.len()
+    );
+    per_iteration
+}
+
+fn main() {
+    let buffer: Vec<u8> = (0..BUFFER_LENGTH).map(|i| (i * 7 + 3) as u8).collect();
+    assert_eq!(
+        ones_complement_sum_naive(&buffer),
+        ones_complement_sum(&buffer)
+    );
+    let naive = bench("naive", &buffer, ones_complement_sum_naive);
+    let optimized = bench("optimized", &buffer, ones_complement_sum);
+    println!(
+        "speedup: {:.1}x",
+        naive.as_secs_f64() / optimized.as_secs_f64()
+    );
+}
@@ -19,6 +19,7 @@ pub const PARAM_SERIAL: u8 = 1;
 pub const PARAM_DNS_SERVERS: u8 = 1 << 1;
 pub const PARAM_ROUTES: u8 = 1 << 2;
 pub const PARAM_PORT: u8 = 1 << 3;
+pub const PARAM_FILE: u8 = 1 << 4;
 
 pub const DEFAULT_PORT: u16 = 31416;
 
@@ -27,6 +28,7 @@ pub struct CommandLineArguments {
     dns_servers: Option<String>,
     routes: Option<String>,
     port: u16,
+    file: Option<String>,
 }
 
 impl CommandLineArguments {
@@ -36,6 +38,7 @@ impl CommandLineArguments {
         let mut dns_servers = None;
         let mut routes = None;
         let mut port = 0;
+        let mut file = None;
 
         let mut iter = args.into_iter();
         while let Some(arg) = iter.next() {
@@ -72,6 +75,8 @@ impl CommandLineArguments {
                 }
             } else if (accepted_parameters & PARAM_SERIAL) != 0 && serial.is_none() {
                 serial = Some(arg);
+            } else if (accepted_parameters & PARAM_FILE) != 0 && file.is_none() {
+                file = Some(arg);
             } else {
                 return Err(format!("Unexpected argument: \"{}\"", arg));
             }
@@ -84,6 +89,7 @@ impl CommandLineArguments {
             dns_servers,
             routes,
             port,
+            file,
         })
     }
 
@@ -102,6 +108,10 @@ impl CommandLineArguments {
     pub fn port(&self) -> u16 {
         self.port
     }
+
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
 }
 
 #[cfg(test)]
@@ -178,4 +188,15 @@ mod tests {
         let raw_args = vec!["-r"];
         assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
     }
+
+    #[test]
+    fn test_file_parameter() {
+        let raw_args = vec!["packet.bin"];
+        let args = CommandLineArguments::parse(PARAM_FILE, raw_args).unwrap();
+        assert_eq!("packet.bin", args.file.unwrap());
+        assert!(args.serial.is_none());
+
+        let raw_args = vec!["packet.bin", "other.bin"];
+        assert!(CommandLineArguments::parse(PARAM_FILE, raw_args).is_err());
+    }
 }
@@ -14,12 +14,24 @@
  * limitations under the License.
  */
 
+pub mod packet;
+#[cfg(feature = "relay")]
 mod relay;
-pub use crate::relay::byte_buffer;
 
-use crate::relay::Relay;
-use std::io;
+pub use crate::packet::ipv4_header::Protocol;
+#[cfg(feature = "relay")]
+pub use crate::relay::byte_buffer;
+#[cfg(feature = "relay")]
+pub use crate::relay::{
+    AuditRecord, ChecksumValidation, CloseReason, ConnectionId, ConnectionSummary, Counter,
+    Decision, DnsOverride, DropReason, DscpRemap, IcmpPolicy, Inspector, JsonLinesSink, Metrics,
+    Observer, OverflowPolicy, PauseSwitch, Relay, RelayConfig, RelayConfigBuilder, ShardedRelay,
+    UpstreamFactory, Verdict,
+};
+#[cfg(all(feature = "relay", unix))]
+pub use crate::relay::{Handoff, HandoffRequest};
 
-pub fn relay(port: u16) -> io::Result<()> {
+#[cfg(feature = "relay")]
+pub fn relay(port: u16) -> std::io::Result<()> {
     Relay::new(port).run()
 }
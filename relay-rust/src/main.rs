@@ -29,6 +29,8 @@ use crate::adb_monitor::AdbMonitor;
 use crate::cli_args::CommandLineArguments;
 use crate::execution_error::{Cmd, CommandExecutionError, ProcessIoError, ProcessStatusError};
 use std::env;
+use std::fs;
+use std::io;
 use std::process::{self, exit};
 use std::thread;
 use std::time::Duration;
@@ -66,6 +68,7 @@ const COMMANDS: &[&dyn Command] = &[
     &RestartCommand,
     &TunnelCommand,
     &RelayCommand,
+    &ValidateCommand,
 ];
 
 trait Command {
@@ -86,6 +89,7 @@ struct StopCommand;
 struct RestartCommand;
 struct TunnelCommand;
 struct RelayCommand;
+struct ValidateCommand;
 
 impl Command for InstallCommand {
     fn command(&self) -> &'static str {
@@ -341,6 +345,28 @@ impl Command for RelayCommand {
     }
 }
 
+impl Command for ValidateCommand {
+    fn command(&self) -> &'static str {
+        "validate"
+    }
+
+    fn accepted_parameters(&self) -> u8 {
+        cli_args::PARAM_FILE
+    }
+
+    fn description(&self) -> &'static str {
+        "Check a captured IPv4 packet (the raw bytes stored in FILE) the\n\
+         way the relay parses it, and report everything malformed."
+    }
+
+    fn execute(&self, args: &CommandLineArguments) -> Result<(), CommandExecutionError> {
+        match args.file() {
+            Some(file) => cmd_validate(file),
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "Missing packet file").into()),
+        }
+    }
+}
+
 fn cmd_install(serial: Option<&str>) -> Result<(), CommandExecutionError> {
     info!(target: TAG, "Installing gnirehtet client...");
     exec_adb(serial, vec!["install".into(), "-r".into(), get_apk_path()])
@@ -485,6 +511,13 @@ fn cmd_relay(port: u16) -> Result<(), CommandExecutionError> {
     Ok(())
 }
 
+fn cmd_validate(file: &str) -> Result<(), CommandExecutionError> {
+    let raw = fs::read(file)?;
+    let report = relaylib::packet::validation::validate_packet(&raw);
+    println!("{}", report);
+    Ok(())
+}
+
 fn async_start(serial: Option<&str>, dns_servers: Option<&str>, routes: Option<&str>, port: u16) {
     let start_serial = serial.map(String::from);
     let start_dns_servers = dns_servers.map(String::from);
@@ -605,6 +638,9 @@ fn append_command_usage(msg: &mut String, command: &dyn Command) {
     if (accepted_parameters & cli_args::PARAM_ROUTES) != 0 {
         msg.push_str(" [-r ROUTE[,ROUTE2,...]]");
     }
+    if (accepted_parameters & cli_args::PARAM_FILE) != 0 {
+        msg.push_str(" FILE");
+    }
     msg.push('\n');
     for desc_line in command.description().split('\n') {
         msg.push_str("      ");
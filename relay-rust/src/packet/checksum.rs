@@ -0,0 +1,167 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, ByteOrder};
+
+/// Compute the one's complement sum of `data` as big-endian 16-bit words (the last byte is padded
+/// if the length is odd), folded to 16 bits.
+///
+/// The checksum is the complement of the sum (of all the data it covers).
+///
+/// Checksum computation is the most CPU-intensive task in gnirehtet, so the data are summed 64 bits
+/// at a time: since 2^16 = 1 (mod 2^16 - 1), adding wider words with end-around carry and folding
+/// the result gives the same sum.
+pub fn ones_complement_sum(data: &[u8]) -> u16 {
+    let mut sum = 0u64;
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let (result, carry) = sum.overflowing_add(BigEndian::read_u64(chunk));
+        sum = result + u64::from(carry);
+    }
+    let remainder = chunks.remainder();
+    let mut tail = 0u64;
+    for (i, &byte) in remainder.iter().enumerate() {
+        // the remaining bytes are the beginning of a zero-padded 64-bit word
+        tail |= u64::from(byte) << (56 - 8 * i);
+    }
+    let (result, carry) = sum.overflowing_add(tail);
+    sum = result + u64::from(carry);
+    fold(sum)
+}
+
+/// Same as `ones_complement_sum()`, 16 bits at a time (the straightforward implementation, kept as
+/// a reference).
+pub fn ones_complement_sum_naive(data: &[u8]) -> u16 {
+    let sum = data
+        .chunks(2)
+        .map(|chunk| {
+            if chunk.len() == 2 {
+                u64::from(BigEndian::read_u16(chunk))
+            } else {
+                // pad the last byte
+                u64::from(chunk[0]) << 8
+            }
+        })
+        .sum::<u64>();
+    fold(sum)
+}
+
+/// Add two one's complement sums (of data whose first one has an even length).
+pub fn add(a: u16, b: u16) -> u16 {
+    fold(u64::from(a) + u64::from(b))
+}
+
+/// Update the checksum `checksum` after the data `old` it covers have been replaced by `new`,
+/// without summing the rest of the data again (RFC 1624).
+///
+/// Both must have the same even length, and start at an even offset.
+pub fn update_incremental(checksum: u16, old: &[u8], new: &[u8]) -> u16 {
+    debug_assert!(old.len() == new.len() && old.len().is_multiple_of(2));
+    // HC' = ~(~HC + ~m + m')
+    !add(
+        add(!checksum, !ones_complement_sum(old)),
+        ones_complement_sum(new),
+    )
+}
+
+fn fold(mut sum: u64) -> u16 {
+    while (sum & !0xffff) != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // xorshift, to generate reproducible buffers without depending on rand
+    fn random_bytes(seed: &mut u64, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                *seed ^= *seed << 13;
+                *seed ^= *seed >> 7;
+                *seed ^= *seed << 17;
+                *seed as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sum_rfc1071_example() {
+        // the example of RFC 1071 section 3
+        let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(0xddf2, ones_complement_sum(&data));
+        assert_eq!(0xddf2, ones_complement_sum_naive(&data));
+    }
+
+    #[test]
+    fn sum_odd_length() {
+        assert_eq!(0x1200, ones_complement_sum(&[0x12]));
+        assert_eq!(0x1234 + 0x5600, ones_complement_sum(&[0x12, 0x34, 0x56]));
+        assert_eq!(0, ones_complement_sum(&[]));
+    }
+
+    #[test]
+    fn implementations_agree_on_random_buffers() {
+        let mut seed = 0x1234_5678_9abc_def0;
+        for len in (0..64).chain(vec![1499, 1500, 65535]) {
+            for _ in 0..16 {
+                let data = random_bytes(&mut seed, len);
+                assert_eq!(
+                    ones_complement_sum_naive(&data),
+                    ones_complement_sum(&data),
+                    "Sums differ for {} bytes",
+                    len
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn implementations_agree_on_carries() {
+        // all ones maximize the carries
+        for len in &[7, 8, 9, 1500, 65535] {
+            let data = vec![0xff; *len];
+            assert_eq!(ones_complement_sum_naive(&data), ones_complement_sum(&data));
+        }
+    }
+
+    #[test]
+    fn update_checksum_incrementally() {
+        let mut seed = 1624;
+        let mut data = random_bytes(&mut seed, 1500);
+        let checksum = !ones_complement_sum(&data);
+        let old = data[12..20].to_vec();
+        let new = random_bytes(&mut seed, 8);
+        data[12..20].copy_from_slice(&new);
+        assert_eq!(
+            !ones_complement_sum(&data),
+            update_incremental(checksum, &old, &new)
+        );
+    }
+
+    #[test]
+    fn add_sums() {
+        let mut seed = 42;
+        let data = random_bytes(&mut seed, 1500);
+        let (head, tail) = data.split_at(40);
+        assert_eq!(
+            ones_complement_sum(&data),
+            add(ones_complement_sum(head), ones_complement_sum(tail))
+        );
+    }
+}
@@ -0,0 +1,257 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use super::ipv4_header::Ipv4HeaderData;
+use byteorder::{BigEndian, ByteOrder};
+
+const FLAG_DONT_FRAGMENT: u16 = 0x4000;
+const FLAG_MORE_FRAGMENTS: u16 = 0x2000;
+const FRAGMENT_OFFSET_MASK: u16 = 0x1fff;
+
+// the options having this bit set in their type must be copied into every fragment
+const OPTION_COPIED: u8 = 0x80;
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+
+/// Indicate whether the Don't Fragment flag of the IPv4 `packet` is set.
+pub fn is_dont_fragment(packet: &[u8]) -> bool {
+    BigEndian::read_u16(&packet[6..8]) & FLAG_DONT_FRAGMENT != 0
+}
+
+/// Split the (complete) IPv4 `packet` into fragments of at most `mtu` bytes (RFC 791 section 3.2).
+///
+/// A packet which already fits is returned unchanged, as a single fragment. The Don't Fragment
+/// flag is not checked, it is the responsibility of the caller.
+///
+/// The packet may itself be a fragment: the offsets of the resulting fragments are relative to its
+/// own offset, and the last one keeps its More Fragments flag.
+pub fn fragment_packet(packet: &[u8], mtu: u16) -> Vec<Vec<u8>> {
+    let data = Ipv4HeaderData::parse(packet);
+    let total_length = data.total_length() as usize;
+    if total_length <= mtu as usize {
+        return vec![packet[..total_length].to_vec()];
+    }
+
+    let header_length = data.header_length() as usize;
+    let first_header = &packet[..header_length];
+    let other_header = copied_options_header(first_header);
+    let payload = &packet[header_length..total_length];
+
+    let flags_fragment_offset = BigEndian::read_u16(&packet[6..8]);
+    let base_offset = flags_fragment_offset & FRAGMENT_OFFSET_MASK;
+    let last_more_fragments = flags_fragment_offset & FLAG_MORE_FRAGMENTS;
+    let flags = flags_fragment_offset & !(FRAGMENT_OFFSET_MASK | FLAG_MORE_FRAGMENTS);
+
+    let mut fragments = Vec::new();
+    let mut offset = 0;
+    while offset < payload.len() {
+        let header = if offset == 0 {
+            first_header
+        } else {
+            &other_header
+        };
+        // all the fragments but the last carry a multiple of 8 bytes
+        let max_length = (mtu as usize - header.len()) & !7;
+        assert!(max_length > 0, "MTU too small to fragment: {}", mtu);
+        let length = max_length.min(payload.len() - offset);
+        let last = offset + length == payload.len();
+
+        let mut fragment = Vec::with_capacity(header.len() + length);
+        fragment.extend_from_slice(header);
+        fragment.extend_from_slice(&payload[offset..offset + length]);
+
+        let more_fragments = if last {
+            last_more_fragments
+        } else {
+            FLAG_MORE_FRAGMENTS
+        };
+        let fragment_offset = base_offset + (offset / 8) as u16;
+        BigEndian::write_u16(
+            &mut fragment[6..8],
+            flags | more_fragments | fragment_offset,
+        );
+
+        let mut data = Ipv4HeaderData::parse(&fragment);
+        let mut header = data.bind_mut(&mut fragment);
+        let fragment_length = header.header_length() as usize + length;
+        header.set_total_length(fragment_length as u16);
+        header.update_checksum();
+
+        fragments.push(fragment);
+        offset += length;
+    }
+    fragments
+}
+
+// the header of the non-first fragments, keeping only the options to copy
+fn copied_options_header(header: &[u8]) -> Vec<u8> {
+    let mut result = header[..20].to_vec();
+    let options = &header[20..];
+    let mut i = 0;
+    while i < options.len() {
+        let option_type = options[i];
+        if option_type == OPTION_END {
+            break;
+        }
+        if option_type == OPTION_NOP {
+            i += 1;
+            continue;
+        }
+        if i + 1 >= options.len() {
+            break;
+        }
+        let length = options[i + 1] as usize;
+        if length < 2 || i + length > options.len() {
+            // malformed option, copy nothing more
+            break;
+        }
+        if option_type & OPTION_COPIED != 0 {
+            result.extend_from_slice(&options[i..i + length]);
+        }
+        i += length;
+    }
+    // pad the options with End of Option List to a multiple of 4 bytes
+    while !result.len().is_multiple_of(4) {
+        result.push(OPTION_END);
+    }
+    result[0] = result[0] & 0xf0 | (result.len() >> 2) as u8;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::checksum;
+    use crate::packet::ipv4_header::Protocol;
+
+    fn create_packet(payload_length: usize) -> Vec<u8> {
+        let mut raw = vec![0; 20 + payload_length];
+        let mut data = Ipv4HeaderData::init(
+            &mut raw,
+            Protocol::Udp,
+            0x12345678,
+            0x42424242,
+            payload_length as u16,
+        );
+        BigEndian::write_u16(&mut raw[4..6], 0x1234); // identification
+        for (i, byte) in raw[20..].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        data.bind_mut(&mut raw).update_checksum();
+        raw
+    }
+
+    fn fragment_offset(fragment: &[u8]) -> u16 {
+        BigEndian::read_u16(&fragment[6..8]) & FRAGMENT_OFFSET_MASK
+    }
+
+    fn more_fragments(fragment: &[u8]) -> bool {
+        BigEndian::read_u16(&fragment[6..8]) & FLAG_MORE_FRAGMENTS != 0
+    }
+
+    fn header_length_of(fragment: &[u8]) -> usize {
+        Ipv4HeaderData::parse(fragment).header_length() as usize
+    }
+
+    fn reassemble(fragments: &[Vec<u8>]) -> Vec<u8> {
+        let mut payload = Vec::new();
+        for fragment in fragments {
+            let header_length = header_length_of(fragment);
+            assert_eq!(payload.len(), fragment_offset(fragment) as usize * 8);
+            payload.extend_from_slice(&fragment[header_length..]);
+        }
+        payload
+    }
+
+    #[test]
+    fn keep_small_packet() {
+        let packet = create_packet(100);
+        assert_eq!(vec![packet.clone()], fragment_packet(&packet, 1500));
+    }
+
+    #[test]
+    fn fragment_large_packet() {
+        let packet = create_packet(2980);
+        assert_eq!(3000, packet.len());
+
+        let fragments = fragment_packet(&packet, 1500);
+        let lengths: Vec<usize> = fragments.iter().map(Vec::len).collect();
+        assert_eq!(vec![1500, 1500, 40], lengths);
+        let offsets: Vec<u16> = fragments.iter().map(|f| fragment_offset(f)).collect();
+        assert_eq!(vec![0, 185, 370], offsets);
+        let flags: Vec<bool> = fragments.iter().map(|f| more_fragments(f)).collect();
+        assert_eq!(vec![true, true, false], flags);
+
+        for fragment in &fragments {
+            let data = Ipv4HeaderData::parse(fragment);
+            assert_eq!(fragment.len(), data.total_length() as usize);
+            assert_eq!(0x1234, BigEndian::read_u16(&fragment[4..6]));
+            assert_eq!(0, !checksum::ones_complement_sum(&fragment[..20]));
+        }
+
+        assert_eq!(&packet[20..], &reassemble(&fragments)[..]);
+    }
+
+    #[test]
+    fn fragment_at_multiple_of_8() {
+        let packet = create_packet(1000);
+        // 100 - 20 = 80 bytes of payload per fragment
+        let fragments = fragment_packet(&packet, 103);
+        assert_eq!(13, fragments.len());
+        assert!(fragments[..12].iter().all(|f| f.len() == 100));
+        assert_eq!(&packet[20..], &reassemble(&fragments)[..]);
+    }
+
+    #[test]
+    fn fragment_fragment() {
+        let mut packet = create_packet(1000);
+        // the second fragment of a larger packet
+        BigEndian::write_u16(&mut packet[6..8], FLAG_MORE_FRAGMENTS | 100);
+        let fragments = fragment_packet(&packet, 520);
+        let offsets: Vec<u16> = fragments.iter().map(|f| fragment_offset(f)).collect();
+        assert_eq!(vec![100, 162, 224], offsets);
+        // more fragments follow the original one
+        assert!(fragments.iter().all(|f| more_fragments(f)));
+    }
+
+    #[test]
+    fn copy_only_copied_options() {
+        let payload_length = 2000;
+        let mut packet = vec![0; 32 + payload_length];
+        packet[0] = 4 << 4 | 8; // 32-byte header
+        BigEndian::write_u16(&mut packet[2..4], 32 + payload_length as u16);
+        // a copied option (security, type 130) and a non-copied one (record route, type 7)
+        packet[20..24].copy_from_slice(&[130, 4, 0xab, 0xcd]);
+        packet[24..31].copy_from_slice(&[7, 7, 4, 0, 0, 0, 0]);
+
+        let fragments = fragment_packet(&packet, 1000);
+        assert_eq!(32, header_length_of(&fragments[0]));
+        assert_eq!(&packet[20..32], &fragments[0][20..32]);
+        for fragment in &fragments[1..] {
+            assert_eq!(24, header_length_of(fragment));
+            assert_eq!(&[130, 4, 0xab, 0xcd], &fragment[20..24]);
+        }
+        assert_eq!(&packet[32..], &reassemble(&fragments)[..]);
+    }
+
+    #[test]
+    fn detect_dont_fragment() {
+        let mut packet = create_packet(10);
+        assert!(!is_dont_fragment(&packet));
+        packet[6] |= 0x40;
+        assert!(is_dont_fragment(&packet));
+    }
+}
@@ -0,0 +1,779 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use super::checksum;
+use byteorder::{BigEndian, ByteOrder};
+use std::cmp;
+use std::error;
+use std::fmt;
+use std::mem;
+use std::net::Ipv4Addr;
+use std::ops::Range;
+
+pub struct Ipv4Header<'a> {
+    raw: &'a [u8],
+    data: &'a Ipv4HeaderData,
+}
+
+pub struct Ipv4HeaderMut<'a> {
+    raw: &'a mut [u8],
+    data: &'a mut Ipv4HeaderData,
+}
+
+#[derive(Clone)]
+pub struct Ipv4HeaderData {
+    version: u8,
+    header_length: u8,
+    total_length: u16,
+    protocol: Protocol,
+    source: u32,
+    destination: u32,
+}
+
+/// The fields of an IPv4 header as plain values, to be sent across threads or channels without
+/// borrowing the packet buffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ipv4Fields {
+    pub version: u8,
+    pub header_length: u8,
+    pub total_length: u16,
+    pub protocol: Protocol,
+    pub source: Ipv4Addr,
+    pub destination: Ipv4Addr,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    /// Any other protocol, identified by its number in the IPv4 header
+    Other(u8),
+}
+
+impl Protocol {
+    /// The protocol identified by `number` in the IPv4 header.
+    pub fn from_number(number: u8) -> Self {
+        match number {
+            6 => Protocol::Tcp,
+            17 => Protocol::Udp,
+            n => Protocol::Other(n),
+        }
+    }
+
+    /// The number of the protocol in the IPv4 header.
+    pub fn number(self) -> u8 {
+        match self {
+            Protocol::Tcp => 6,
+            Protocol::Udp => 17,
+            Protocol::Other(n) => n,
+        }
+    }
+}
+
+/// Inconsistency detected in a parsed IPv4 header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// The version is not 4.
+    Version(u8),
+    /// The header length is not between 20 and 60 bytes.
+    HeaderLength(u8),
+    /// The total length is smaller than the header length.
+    TotalLength(u16),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::Version(version) => write!(f, "Invalid IP version: {}", version),
+            ParseError::HeaderLength(length) => write!(f, "Invalid header length: {}", length),
+            ParseError::TotalLength(length) => {
+                write!(f, "Total length smaller than the header: {}", length)
+            }
+        }
+    }
+}
+
+impl error::Error for ParseError {}
+
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_IGMP: u8 = 2;
+pub const PROTOCOL_GRE: u8 = 47;
+
+// the TTL of the headers initialized by the relay
+const DEFAULT_TTL: u8 = 64;
+
+const IPV6_HEADER_LENGTH: u32 = 40;
+
+#[allow(dead_code)]
+impl Ipv4HeaderData {
+    pub fn parse(raw: &[u8]) -> Self {
+        Self {
+            version: raw[0] >> 4,
+            header_length: (raw[0] & 0xf) << 2,
+            total_length: BigEndian::read_u16(&raw[2..4]),
+            protocol: Protocol::from_number(raw[9]),
+            source: BigEndian::read_u32(&raw[12..16]),
+            destination: BigEndian::read_u32(&raw[16..20]),
+        }
+    }
+
+    /// Write a 20-byte header (without options) at the start of `raw`, for a packet of `protocol`
+    /// carrying `payload_length` bytes, and return its data, to be bound to `raw`.
+    ///
+    /// The checksum is left to 0, it must be computed once the header is complete.
+    pub fn init(
+        raw: &mut [u8],
+        protocol: Protocol,
+        source: u32,
+        destination: u32,
+        payload_length: u16,
+    ) -> Self {
+        let total_length = payload_length
+            .checked_add(20)
+            .expect("Payload too long for an IPv4 packet");
+        let raw = &mut raw[..20];
+        raw[0] = 4 << 4 | 5; // version and IHL
+        raw[1] = 0; // ToS
+        BigEndian::write_u16(&mut raw[2..4], total_length);
+        BigEndian::write_u32(&mut raw[4..8], 0); // id, flags and fragment offset
+        raw[8] = DEFAULT_TTL;
+        raw[9] = protocol.number();
+        BigEndian::write_u16(&mut raw[10..12], 0); // checksum
+        BigEndian::write_u32(&mut raw[12..16], source);
+        BigEndian::write_u32(&mut raw[16..20], destination);
+        Self::parse(raw)
+    }
+
+    pub fn bind<'c, 'a: 'c, 'b: 'c>(&'a self, raw: &'b [u8]) -> Ipv4Header<'c> {
+        Ipv4Header::new(raw, self)
+    }
+
+    pub fn bind_mut<'c, 'a: 'c, 'b: 'c>(&'a mut self, raw: &'b mut [u8]) -> Ipv4HeaderMut<'c> {
+        Ipv4HeaderMut::new(raw, self)
+    }
+
+    pub fn header_length(&self) -> u8 {
+        self.header_length
+    }
+
+    /// The offset of the transport header in the packet, after the IPv4 options (if any).
+    pub fn transport_offset(&self) -> usize {
+        self.header_length as usize
+    }
+
+    pub fn total_length(&self) -> u16 {
+        self.total_length
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    pub fn source(&self) -> u32 {
+        self.source
+    }
+
+    pub fn destination(&self) -> u32 {
+        self.destination
+    }
+
+    /// Copy the fields of this header into plain values.
+    pub fn to_fields(&self) -> Ipv4Fields {
+        Ipv4Fields {
+            version: self.version,
+            header_length: self.header_length,
+            total_length: self.total_length,
+            protocol: self.protocol,
+            source: Ipv4Addr::from(self.source),
+            destination: Ipv4Addr::from(self.destination),
+        }
+    }
+
+    /// Check the consistency of the parsed fields, to detect a misparse (or garbage) early.
+    pub fn validate(&self) -> Result<(), ParseError> {
+        if self.version != 4 {
+            return Err(ParseError::Version(self.version));
+        }
+        if !(20..=60).contains(&self.header_length) {
+            return Err(ParseError::HeaderLength(self.header_length));
+        }
+        if self.total_length < u16::from(self.header_length) {
+            return Err(ParseError::TotalLength(self.total_length));
+        }
+        Ok(())
+    }
+
+    /// Indicate whether a buffer of `buffer_length` bytes starting with this header holds the whole
+    /// packet (it may also contain the following packets).
+    pub fn is_complete(&self, buffer_length: usize) -> bool {
+        self.total_length as usize <= buffer_length
+    }
+
+    /// The range of the transport header and payload (covered by the transport checksum) in a
+    /// packet buffer of `buffer_length` bytes starting with this header.
+    ///
+    /// The range starts after the IPv4 options and ends at the total length, clamped to the buffer
+    /// length (it is empty if the buffer is truncated before the transport).
+    pub fn transport_range(&self, buffer_length: usize) -> Range<usize> {
+        let end = cmp::min(self.total_length as usize, buffer_length);
+        let start = cmp::min(self.transport_offset(), end);
+        start..end
+    }
+}
+
+/// The kind of packet a raw buffer starts with, according to its version and length fields.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PacketClass {
+    /// An IPv4 packet of `length` bytes (its total length field, not validated).
+    Ipv4 { length: u16 },
+    /// An IPv6 packet of `length` bytes (the fixed header and the payload).
+    Ipv6 { length: u32 },
+    /// Not enough data to read the length.
+    TooShort,
+    /// Any other version.
+    Unknown(u8),
+}
+
+/// Classify the packet in front of `raw` (which may be followed by other data).
+pub fn classify_packet(raw: &[u8]) -> PacketClass {
+    let (version, length) = match peek_version_length(raw) {
+        Some(version_length) => version_length,
+        None => return PacketClass::TooShort,
+    };
+    match version {
+        4 => PacketClass::Ipv4 { length },
+        // the IPv6 header stores the length of the payload only
+        6 if raw.len() >= 6 => PacketClass::Ipv6 {
+            length: IPV6_HEADER_LENGTH + u32::from(BigEndian::read_u16(&raw[4..6])),
+        },
+        6 => PacketClass::TooShort,
+        _ => PacketClass::Unknown(version),
+    }
+}
+
+pub fn peek_version_length(raw: &[u8]) -> Option<(u8, u16)> {
+    if raw.len() >= 4 {
+        // version is stored in the 4 first bits
+        let version = raw[0] >> 4;
+        // packet length is 16 bits starting at offset 2
+        let length = BigEndian::read_u16(&raw[2..4]);
+        Some((version, length))
+    } else {
+        None
+    }
+}
+
+// shared definition for Ipv4Header and Ipv4HeaderMut
+macro_rules! ipv4_header_common {
+    ($name:ident, $raw_type:ty, $data_type:ty) => {
+        // for readability, declare structs manually outside the macro
+        #[allow(dead_code)]
+        impl<'a> $name<'a> {
+            pub fn new(raw: $raw_type, data: $data_type) -> Self {
+                Self {
+                    raw: raw,
+                    data: data,
+                }
+            }
+
+            pub fn raw(&self) -> &[u8] {
+                self.raw
+            }
+
+            pub fn data(&self) -> &Ipv4HeaderData {
+                self.data
+            }
+
+            pub fn header_length(&self) -> u8 {
+                self.data.header_length
+            }
+
+            pub fn transport_offset(&self) -> usize {
+                self.data.transport_offset()
+            }
+
+            pub fn total_length(&self) -> u16 {
+                self.data.total_length
+            }
+
+            pub fn protocol(&self) -> Protocol {
+                self.data.protocol
+            }
+
+            pub fn source(&self) -> u32 {
+                self.data.source
+            }
+
+            /// The Differentiated Services Code Point, the 6 upper bits of the former ToS field.
+            pub fn dscp(&self) -> u8 {
+                self.raw[1] >> 2
+            }
+
+            pub fn destination(&self) -> u32 {
+                self.data.destination
+            }
+
+            pub fn transport_range(&self, buffer_length: usize) -> Range<usize> {
+                self.data.transport_range(buffer_length)
+            }
+
+            /// The header checksum, as stored (it is not verified).
+            pub fn checksum(&self) -> u16 {
+                BigEndian::read_u16(&self.raw[10..12])
+            }
+
+            /// The IPv4 options (empty if the header is 20 bytes long).
+            pub fn options(&self) -> &[u8] {
+                let end = cmp::min(self.data.header_length as usize, self.raw.len());
+                &self.raw[cmp::min(20, end)..end]
+            }
+
+            pub fn ttl(&self) -> u8 {
+                self.raw[8]
+            }
+
+            /// A human-readable summary of all the fields, for troubleshooting.
+            pub fn describe(&self) -> String {
+                let flags_fragment_offset = BigEndian::read_u16(&self.raw[6..8]);
+                let mut flags = Vec::new();
+                if flags_fragment_offset & 0x4000 != 0 {
+                    flags.push("DF");
+                }
+                if flags_fragment_offset & 0x2000 != 0 {
+                    flags.push("MF");
+                }
+                let header_end = cmp::min(self.data.header_length as usize, self.raw.len());
+                let checksum_valid =
+                    checksum::ones_complement_sum(&self.raw[..header_end]) == 0xFFFF;
+                format!(
+                    "IPv4 version={} ihl={} total_length={} protocol={:?} source={} \
+                     destination={} ttl={} flags=[{}] fragment_offset={} checksum={:#06x} ({})",
+                    self.data.version,
+                    self.data.header_length,
+                    self.data.total_length,
+                    self.data.protocol,
+                    Ipv4Addr::from(self.data.source),
+                    Ipv4Addr::from(self.data.destination),
+                    self.ttl(),
+                    flags.join(","),
+                    (flags_fragment_offset & 0x1FFF) * 8,
+                    self.checksum(),
+                    if checksum_valid { "valid" } else { "invalid" }
+                )
+            }
+        }
+    };
+}
+
+ipv4_header_common!(Ipv4Header, &'a [u8], &'a Ipv4HeaderData);
+ipv4_header_common!(Ipv4HeaderMut, &'a mut [u8], &'a mut Ipv4HeaderData);
+
+// additional methods for the mutable version
+#[allow(dead_code)]
+impl<'a> Ipv4HeaderMut<'a> {
+    pub fn raw_mut(&mut self) -> &mut [u8] {
+        self.raw
+    }
+
+    pub fn data_mut(&mut self) -> &mut Ipv4HeaderData {
+        self.data
+    }
+
+    pub fn set_total_length(&mut self, total_length: u16) {
+        self.data.total_length = total_length;
+        BigEndian::write_u16(&mut self.raw[2..4], total_length);
+    }
+
+    /// Set the header length (a multiple of 4), without moving the options nor the payload.
+    pub fn set_header_length(&mut self, header_length: u8) {
+        assert!(
+            (20..=60).contains(&header_length) && header_length & 3 == 0,
+            "Invalid header length: {}",
+            header_length
+        );
+        self.data.header_length = header_length;
+        self.raw[0] = self.raw[0] & 0xf0 | header_length >> 2;
+    }
+
+    pub fn set_source(&mut self, source: u32) {
+        self.data.source = source;
+        BigEndian::write_u32(&mut self.raw[12..16], source);
+    }
+
+    pub fn set_destination(&mut self, destination: u32) {
+        self.data.destination = destination;
+        BigEndian::write_u32(&mut self.raw[16..20], destination);
+    }
+
+    pub fn swap_source_and_destination(&mut self) {
+        mem::swap(&mut self.data.source, &mut self.data.destination);
+        for i in 12..16 {
+            self.raw.swap(i, i + 4);
+        }
+    }
+
+    /// Set the DSCP (keeping the ECN bits), and update the checksum incrementally (RFC 1624).
+    pub fn set_dscp(&mut self, dscp: u8) {
+        assert!(dscp < 64, "Invalid DSCP: {}", dscp);
+        let old_word = [self.raw[0], self.raw[1]];
+        self.raw[1] = dscp << 2 | self.raw[1] & 0b11;
+        let new_word = [self.raw[0], self.raw[1]];
+        self.update_checksum_incremental(&old_word, &new_word);
+    }
+
+    /// Update the checksum after the header words `old` have been replaced by `new` (RFC 1624).
+    pub fn update_checksum_incremental(&mut self, old: &[u8], new: &[u8]) {
+        let checksum = checksum::update_incremental(self.checksum(), old, new);
+        self.set_checksum(checksum);
+    }
+
+    fn set_checksum(&mut self, checksum: u16) {
+        BigEndian::write_u16(&mut self.raw[10..12], checksum);
+    }
+
+    pub fn update_checksum(&mut self) {
+        // reset checksum field, so that it can be added with other bytes
+        self.set_checksum(0);
+        let header_length = self.data.header_length as usize;
+        let sum = checksum::ones_complement_sum(&self.raw[..header_length]);
+        self.set_checksum(!sum);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{BigEndian, WriteBytesExt};
+    use std::thread;
+
+    fn create_header() -> Vec<u8> {
+        let mut raw: Vec<u8> = Vec::new();
+        raw.reserve(20);
+        raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
+        raw.write_u8(0).unwrap(); //ToS
+        raw.write_u16::<BigEndian>(28).unwrap(); // total length
+        raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+        raw.write_u8(0).unwrap(); // TTL
+        raw.write_u8(17).unwrap(); // protocol (UDP)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x12345678).unwrap(); // source address
+        raw.write_u32::<BigEndian>(0x42424242).unwrap(); // destination address
+        raw
+    }
+
+    #[test]
+    fn parse_header() {
+        let raw = &create_header()[..];
+        let data = Ipv4HeaderData::parse(raw);
+        assert_eq!(4, data.version);
+        assert_eq!(20, data.header_length);
+        assert_eq!(28, data.total_length);
+        assert_eq!(Protocol::Udp, data.protocol);
+        assert_eq!(0x12345678, data.source);
+        assert_eq!(0x42424242, data.destination);
+    }
+
+    #[test]
+    fn describe_header() {
+        let mut raw = create_header();
+        raw[6] = 0x40; // don't fragment
+        raw[8] = 64; // TTL
+        let mut data = Ipv4HeaderData::parse(&raw);
+        data.bind_mut(&mut raw).update_checksum();
+        let description = data.bind(&raw).describe();
+        assert!(description.contains("version=4"), "{}", description);
+        assert!(description.contains("ihl=20"), "{}", description);
+        assert!(description.contains("total_length=28"), "{}", description);
+        assert!(description.contains("protocol=Udp"), "{}", description);
+        assert!(
+            description.contains("source=18.52.86.120"),
+            "{}",
+            description
+        );
+        assert!(
+            description.contains("destination=66.66.66.66"),
+            "{}",
+            description
+        );
+        assert!(description.contains("ttl=64"), "{}", description);
+        assert!(description.contains("flags=[DF]"), "{}", description);
+        assert!(description.contains("(valid)"), "{}", description);
+
+        raw[10] ^= 0xFF;
+        assert!(data.bind(&raw).describe().contains("(invalid)"));
+    }
+
+    #[test]
+    fn copy_fields() {
+        let raw = &create_header()[..];
+        let fields = Ipv4HeaderData::parse(raw).to_fields();
+        assert_eq!(
+            Ipv4Fields {
+                version: 4,
+                header_length: 20,
+                total_length: 28,
+                protocol: Protocol::Udp,
+                source: Ipv4Addr::new(0x12, 0x34, 0x56, 0x78),
+                destination: Ipv4Addr::new(0x42, 0x42, 0x42, 0x42),
+            },
+            fields
+        );
+
+        // the fields do not borrow the buffer
+        let sent = thread::spawn(move || fields).join().unwrap();
+        assert_eq!(fields, sent);
+    }
+
+    #[test]
+    fn init_header() {
+        let mut raw = [0xffu8; 24];
+        let mut header_data =
+            Ipv4HeaderData::init(&mut raw, Protocol::Tcp, 0x12345678, 0x42424242, 100);
+        assert_eq!(Ok(()), header_data.validate());
+        {
+            let mut header = header_data.bind_mut(&mut raw);
+            header.update_checksum();
+        }
+        // only the header has been written
+        assert_eq!([0xff; 4], raw[20..]);
+
+        let data = Ipv4HeaderData::parse(&raw);
+        assert_eq!(4, data.version);
+        assert_eq!(20, data.header_length());
+        assert_eq!(120, data.total_length());
+        assert_eq!(Protocol::Tcp, data.protocol());
+        assert_eq!(0x12345678, data.source());
+        assert_eq!(0x42424242, data.destination());
+        assert_eq!(DEFAULT_TTL, raw[8]);
+        assert_eq!(0, BigEndian::read_u16(&raw[4..6])); // identification
+
+        // the checksum is valid: the sum of all the 16-bit words is 0xffff
+        let mut sum = (0..10)
+            .map(|i| u32::from(BigEndian::read_u16(&raw[2 * i..2 * i + 2])))
+            .sum::<u32>();
+        while (sum & !0xffff) != 0 {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        assert_eq!(0xffff, sum);
+    }
+
+    #[test]
+    fn init_header_other_protocol() {
+        let mut raw = [0u8; 20];
+        let data = Ipv4HeaderData::init(&mut raw, Protocol::Other(PROTOCOL_ICMP), 1, 2, 0);
+        assert_eq!(Protocol::Other(PROTOCOL_ICMP), data.protocol());
+        assert_eq!(20, data.total_length());
+        assert_eq!(PROTOCOL_ICMP, raw[9]);
+    }
+
+    #[test]
+    fn validate_header() {
+        let raw = &create_header()[..];
+        assert_eq!(Ok(()), Ipv4HeaderData::parse(raw).validate());
+    }
+
+    #[test]
+    fn validate_bad_version() {
+        let mut raw = create_header();
+        raw[0] = 6 << 4 | 5;
+        let data = Ipv4HeaderData::parse(&raw);
+        assert_eq!(Err(ParseError::Version(6)), data.validate());
+    }
+
+    #[test]
+    fn validate_bad_header_length() {
+        let mut raw = create_header();
+        raw[0] = 4 << 4 | 4; // IHL < 5
+        let data = Ipv4HeaderData::parse(&raw);
+        assert_eq!(Err(ParseError::HeaderLength(16)), data.validate());
+    }
+
+    #[test]
+    fn validate_bad_total_length() {
+        let mut raw = create_header();
+        raw[0] = 4 << 4 | 6; // 24 bytes of header
+        BigEndian::write_u16(&mut raw[2..4], 22);
+        let data = Ipv4HeaderData::parse(&raw);
+        assert_eq!(Err(ParseError::TotalLength(22)), data.validate());
+    }
+
+    #[test]
+    fn complete_packet() {
+        let mut raw = create_header();
+        raw.resize(28, 0);
+        let data = Ipv4HeaderData::parse(&raw);
+        // exact length
+        assert!(data.is_complete(raw.len()));
+        // followed by another packet
+        assert!(data.is_complete(2 * raw.len()));
+        // truncated
+        assert!(!data.is_complete(raw.len() - 1));
+        assert!(!data.is_complete(20));
+    }
+
+    #[test]
+    fn edit_header() {
+        let raw = &mut create_header()[..];
+        let mut header_data = Ipv4HeaderData::parse(raw);
+        let mut header = header_data.bind_mut(raw);
+
+        header.set_source(0x87654321);
+        header.set_destination(0x24242424);
+        header.set_total_length(42);
+        assert_eq!(0x87654321, header.source());
+        assert_eq!(0x24242424, header.destination());
+        assert_eq!(42, header.total_length());
+
+        // assert that the buffer has been modified
+        let raw_source = BigEndian::read_u32(&header.raw[12..16]);
+        let raw_destination = BigEndian::read_u32(&header.raw[16..20]);
+        let raw_total_length = BigEndian::read_u16(&header.raw[2..4]);
+        assert_eq!(0x87654321, raw_source);
+        assert_eq!(0x24242424, raw_destination);
+        assert_eq!(42, raw_total_length);
+
+        header.swap_source_and_destination();
+
+        assert_eq!(0x24242424, header.source());
+        assert_eq!(0x87654321, header.destination());
+
+        let raw_source = BigEndian::read_u32(&header.raw[12..16]);
+        let raw_destination = BigEndian::read_u32(&header.raw[16..20]);
+        assert_eq!(0x24242424, raw_source);
+        assert_eq!(0x87654321, raw_destination);
+    }
+
+    #[test]
+    fn compute_checksum() {
+        let raw = &mut create_header()[..];
+        let mut header_data = Ipv4HeaderData::parse(raw);
+        let mut header = header_data.bind_mut(raw);
+
+        // set a fake checksum value to assert that it is correctly computed
+        header.set_checksum(0x79);
+
+        header.update_checksum();
+
+        let mut sum: u32 =
+            0x4500 + 0x001C + 0x0000 + 0x0000 + 0x0011 + 0x0000 + 0x1234 + 0x5678 + 0x4242 + 0x4242;
+        while (sum & !0xffff) != 0 {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        let sum = !sum as u16;
+        assert_eq!(sum, header.checksum());
+    }
+
+    #[test]
+    fn read_stored_checksum() {
+        let raw = &mut create_header()[..];
+        raw[10] = 0x12;
+        raw[11] = 0x34;
+        let header_data = Ipv4HeaderData::parse(raw);
+        // the stored value is returned, even if it is wrong
+        assert_eq!(0x1234, header_data.bind(raw).checksum());
+    }
+
+    #[test]
+    fn set_dscp() {
+        let raw = &mut create_header()[..];
+        raw[1] = 46 << 2 | 0b01; // EF, ECT(1)
+        let mut header_data = Ipv4HeaderData::parse(raw);
+        let mut header = header_data.bind_mut(raw);
+        header.update_checksum();
+        assert_eq!(46, header.dscp());
+
+        header.set_dscp(10);
+        assert_eq!(10, header.dscp());
+        // the ECN bits are preserved
+        assert_eq!(10 << 2 | 0b01, header.raw[1]);
+
+        // the checksum updated incrementally is the one computed from scratch
+        let checksum = header.checksum();
+        header.update_checksum();
+        assert_eq!(header.checksum(), checksum);
+    }
+
+    #[test]
+    fn parse_other_protocol() {
+        let mut raw = create_header();
+        raw[9] = PROTOCOL_GRE;
+        let data = Ipv4HeaderData::parse(&raw);
+        assert_eq!(Protocol::Other(47), data.protocol());
+        assert_eq!(PROTOCOL_GRE, data.protocol().number());
+    }
+
+    #[test]
+    fn peek_version_length_unavailable() {
+        let raw: [u8; 0] = [];
+        assert!(peek_version_length(&raw).is_none());
+        let raw = [0x40, 2];
+        assert!(peek_version_length(&raw).is_none());
+    }
+
+    #[test]
+    fn peek_version_length_available() {
+        let raw = [4u8 << 4 | 5, 0, 0x01, 0x23];
+        let (version, length) = peek_version_length(&raw).unwrap();
+        assert_eq!(4, version);
+        assert_eq!(0x123, length);
+    }
+
+    #[test]
+    fn classify_ipv4_packet() {
+        let raw = create_header();
+        assert_eq!(PacketClass::Ipv4 { length: 28 }, classify_packet(&raw));
+    }
+
+    #[test]
+    fn classify_ipv6_packet() {
+        // version 6, then 2 bytes of flow label, then the payload length
+        let raw = [6u8 << 4, 0, 0, 0, 0x01, 0x00];
+        assert_eq!(PacketClass::Ipv6 { length: 296 }, classify_packet(&raw));
+        // the payload length is not available yet
+        assert_eq!(PacketClass::TooShort, classify_packet(&raw[..5]));
+    }
+
+    #[test]
+    fn classify_short_packet() {
+        assert_eq!(PacketClass::TooShort, classify_packet(&[]));
+        assert_eq!(PacketClass::TooShort, classify_packet(&[0x45, 0, 0]));
+    }
+
+    #[test]
+    fn classify_unknown_version() {
+        let raw = [5u8 << 4, 0, 0, 20];
+        assert_eq!(PacketClass::Unknown(5), classify_packet(&raw));
+    }
+
+    #[test]
+    fn transport_range_after_options() {
+        let mut raw = create_header();
+        raw[0] = 4u8 << 4 | 6; // 4 bytes of options
+        raw[3] = 40; // total length
+        raw.extend_from_slice(&[1, 1, 1, 0]); // options (NOP NOP NOP EOL)
+        raw.resize(40, 0);
+        let data = Ipv4HeaderData::parse(&raw);
+        assert_eq!(24, data.header_length());
+        assert_eq!(24..40, data.transport_range(raw.len()));
+        assert_eq!(24..40, data.bind(&raw[..24]).transport_range(raw.len()));
+
+        // trailing bytes after the total length are excluded
+        assert_eq!(24..40, data.transport_range(50));
+        // truncated buffer
+        assert_eq!(24..30, data.transport_range(30));
+        assert_eq!(20..20, data.transport_range(20));
+    }
+}
@@ -0,0 +1,676 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, ByteOrder};
+
+use super::checksum;
+use super::ipv4_header::{Ipv4Header, Ipv4HeaderData, Ipv4HeaderMut};
+use super::transport_header::{TransportHeader, TransportHeaderData, TransportHeaderMut};
+
+pub const MAX_PACKET_LENGTH: usize = 1 << 16;
+
+pub struct Ipv4Packet<'a> {
+    raw: &'a mut [u8],
+    ipv4_header_data: Ipv4HeaderData,
+    transport_header_data: Option<TransportHeaderData>,
+    // assumed valid until verify_checksum() proves otherwise
+    checksum_valid: bool,
+}
+
+impl<'a> Ipv4Packet<'a> {
+    pub fn parse(raw: &'a mut [u8]) -> Self {
+        let ipv4_header_data = Ipv4HeaderData::parse(raw);
+        let transport_header_data = {
+            // never read past the end of the packet, even if the buffer contains more bytes
+            let payload =
+                &raw[ipv4_header_data.transport_offset()..ipv4_header_data.total_length() as usize];
+            TransportHeaderData::parse(ipv4_header_data.protocol(), payload)
+        };
+        Self {
+            raw: &mut raw[..ipv4_header_data.total_length() as usize],
+            ipv4_header_data,
+            transport_header_data,
+            checksum_valid: true,
+        }
+    }
+
+    pub fn new(
+        raw: &'a mut [u8],
+        ipv4_header_data: Ipv4HeaderData,
+        transport_header_data: TransportHeaderData,
+    ) -> Self {
+        Self {
+            raw,
+            ipv4_header_data,
+            transport_header_data: Some(transport_header_data),
+            checksum_valid: true,
+        }
+    }
+
+    #[inline]
+    pub fn raw(&self) -> &[u8] {
+        self.raw
+    }
+
+    #[inline]
+    pub fn headers_data(&self) -> (&Ipv4HeaderData, Option<&TransportHeaderData>) {
+        (&self.ipv4_header_data, self.transport_header_data.as_ref())
+    }
+
+    pub fn headers(&self) -> (Ipv4Header, Option<TransportHeader>) {
+        let transport_index = self.ipv4_header_data.transport_offset();
+        if let Some(ref transport_header_data) = self.transport_header_data {
+            let (ipv4_header_slice, transport_slice) = self.raw.split_at(transport_index);
+            // payload_index is relative to transport
+            let payload_index = transport_header_data.header_length() as usize;
+            let transport_header_slice = &transport_slice[..payload_index];
+            let ipv4_header = self.ipv4_header_data.bind(ipv4_header_slice);
+            let transport_header = transport_header_data.bind(transport_header_slice);
+            (ipv4_header, Some(transport_header))
+        } else {
+            let ipv4_header_slice = &self.raw[..transport_index];
+            let ipv4_header = self.ipv4_header_data.bind(ipv4_header_slice);
+            (ipv4_header, None)
+        }
+    }
+
+    #[inline]
+    #[allow(dead_code)]
+    pub fn ipv4_header_data(&self) -> &Ipv4HeaderData {
+        &self.ipv4_header_data
+    }
+
+    #[inline]
+    #[allow(dead_code)]
+    pub fn ipv4_header(&self) -> Ipv4Header {
+        let slice = &self.raw[..self.ipv4_header_data.header_length() as usize];
+        self.ipv4_header_data.bind(slice)
+    }
+
+    #[inline]
+    #[allow(dead_code)]
+    pub fn ipv4_header_mut(&mut self) -> Ipv4HeaderMut {
+        let slice = &mut self.raw[..self.ipv4_header_data.header_length() as usize];
+        self.ipv4_header_data.bind_mut(slice)
+    }
+
+    #[inline]
+    #[allow(dead_code)]
+    pub fn transport_header_data(&self) -> Option<&TransportHeaderData> {
+        self.transport_header_data.as_ref()
+    }
+
+    #[inline]
+    pub fn transport_header(&self) -> Option<TransportHeader> {
+        if let Some(ref transport_header_data) = self.transport_header_data {
+            let start = self.ipv4_header_data.transport_offset();
+            let end = start + transport_header_data.header_length() as usize;
+            let slice = &self.raw[start..end];
+            Some(transport_header_data.bind(slice))
+        } else {
+            None
+        }
+        /*        self.transport_header_data.as_ref().map(|transport_header_data| {
+            let start = self.ipv4_header_data.transport_offset();
+            let end = start + transport_header_data.header_length() as usize;
+            let slice = &self.raw[start..end];
+            transport_header_data.bind(slice)
+        })*/
+    }
+
+    #[inline]
+    #[allow(dead_code)]
+    fn transport_header_mut(&mut self) -> Option<TransportHeaderMut> {
+        if let Some(ref mut transport_header_data) = self.transport_header_data {
+            let start = self.ipv4_header_data.transport_offset();
+            let end = start + transport_header_data.header_length() as usize;
+            let slice = &mut self.raw[start..end];
+            Some(transport_header_data.bind_mut(slice))
+        } else {
+            None
+        }
+        /*        self.transport_header_data.as_mut().map(|transport_header_data| {
+            let start = self.ipv4_header_data.transport_offset();
+            let end = start + transport_header_data.header_length() as usize;
+            let slice = &mut self.raw[start..end];
+            transport_header_data.bind_mut(slice)
+        })*/
+    }
+
+    /// Devide the packet into parts:
+    ///  - the IP v4 header
+    ///  - the transport header (if any)
+    ///  - the payload (if there is a transport at all)
+    #[allow(dead_code)]
+    pub fn split(&self) -> (Ipv4Header, Option<(TransportHeader, &[u8])>) {
+        let transport_index = self.ipv4_header_data.transport_offset();
+        if let Some(ref transport_header_data) = self.transport_header_data {
+            // payload_index is relative to transport
+            let payload_index = transport_header_data.header_length() as usize;
+            let (ipv4_header_slice, transport_slice) = self.raw.split_at(transport_index);
+            let (transport_header_slice, payload_slice) = transport_slice.split_at(payload_index);
+            let ipv4_header = self.ipv4_header_data.bind(ipv4_header_slice);
+            let transport_header = transport_header_data.bind(transport_header_slice);
+            (ipv4_header, Some((transport_header, payload_slice)))
+        } else {
+            let ipv4_header_slice = &self.raw[..transport_index];
+            let ipv4_header = self.ipv4_header_data.bind(ipv4_header_slice);
+            (ipv4_header, None)
+        }
+    }
+
+    /// Devide the packet into mutable parts:
+    ///  - the IP v4 header
+    ///  - the transport header (if any)
+    ///  - the payload (if there is a transport at all)
+    pub fn split_mut(&mut self) -> (Ipv4HeaderMut, Option<(TransportHeaderMut, &mut [u8])>) {
+        let transport_index = self.ipv4_header_data.transport_offset();
+        if let Some(ref mut transport_header_data) = self.transport_header_data {
+            // payload_index is relative to transport
+            let payload_index = transport_header_data.header_length() as usize;
+            let (ipv4_header_slice, transport_slice) = self.raw.split_at_mut(transport_index);
+            let (transport_header_slice, payload_slice) =
+                transport_slice.split_at_mut(payload_index);
+            let ipv4_header = self.ipv4_header_data.bind_mut(ipv4_header_slice);
+            let transport_header = transport_header_data.bind_mut(transport_header_slice);
+            (ipv4_header, Some((transport_header, payload_slice)))
+        } else {
+            let ipv4_header_slice = &mut self.raw[..transport_index];
+            let ipv4_header = self.ipv4_header_data.bind_mut(ipv4_header_slice);
+            (ipv4_header, None)
+        }
+    }
+
+    #[inline]
+    pub fn is_valid(&self) -> bool {
+        self.transport_header_data.is_some()
+    }
+
+    #[inline]
+    pub fn length(&self) -> u16 {
+        self.ipv4_header_data.total_length()
+    }
+
+    pub fn payload(&self) -> Option<&[u8]> {
+        self.transport_header_data
+            .as_ref()
+            .map(|transport_header_data| {
+                let range = self.ipv4_header_data.transport_offset()
+                    + transport_header_data.header_length() as usize..;
+                &self.raw[range]
+            })
+    }
+
+    /// Whether the checksums of the packet are valid, as found by the last `verify_checksum()`
+    /// (`true` if they have never been verified).
+    #[inline]
+    pub fn checksum_valid(&self) -> bool {
+        self.checksum_valid
+    }
+
+    /// Verify the IPv4 header checksum and the TCP or UDP checksum, record the result (see
+    /// `checksum_valid()`) and return it.
+    ///
+    /// The transport checksum of a fragment cannot be verified, neither can a UDP checksum of 0
+    /// (not computed by the sender, RFC 768).
+    pub fn verify_checksum(&mut self) -> bool {
+        let header_length = self.ipv4_header_data.header_length() as usize;
+        let mut valid = checksum::ones_complement_sum(&self.raw[..header_length]) == 0xFFFF;
+        let fragmented = BigEndian::read_u16(&self.raw[6..8]) & 0x3FFF != 0;
+        if valid && !fragmented {
+            let segment = &self.raw[self.ipv4_header_data.transport_offset()..];
+            let verifiable = match self.transport_header_data {
+                Some(TransportHeaderData::Tcp(_)) => true,
+                Some(TransportHeaderData::Udp(_)) => BigEndian::read_u16(&segment[6..8]) != 0,
+                _ => false,
+            };
+            if verifiable {
+                let protocol = self.ipv4_header_data.protocol().number();
+                let sum = checksum::add(
+                    self.pseudo_header_sum(protocol, segment.len() as u16),
+                    checksum::ones_complement_sum(segment),
+                );
+                valid = sum == 0xFFFF;
+            }
+        }
+        self.checksum_valid = valid;
+        valid
+    }
+
+    // the sum of the pseudo-header covered by the TCP and UDP checksums (RFC 793 section 3.1)
+    fn pseudo_header_sum(&self, protocol: u8, transport_length: u16) -> u16 {
+        let mut pseudo_header = [0u8; 12];
+        BigEndian::write_u32(&mut pseudo_header[0..4], self.ipv4_header_data.source());
+        BigEndian::write_u32(
+            &mut pseudo_header[4..8],
+            self.ipv4_header_data.destination(),
+        );
+        pseudo_header[9] = protocol;
+        BigEndian::write_u16(&mut pseudo_header[10..12], transport_length);
+        checksum::ones_complement_sum(&pseudo_header)
+    }
+
+    /// Replace the source address, and update the checksums incrementally.
+    pub fn rewrite_source(&mut self, source: u32) {
+        let old_source = self.ipv4_header_data.source();
+        self.ipv4_header_mut().set_source(source);
+        self.update_address_checksums(old_source, source);
+    }
+
+    /// Replace the destination address, and update the checksums incrementally.
+    pub fn rewrite_destination(&mut self, destination: u32) {
+        let old_destination = self.ipv4_header_data.destination();
+        self.ipv4_header_mut().set_destination(destination);
+        self.update_address_checksums(old_destination, destination);
+    }
+
+    // the TCP and UDP checksums cover the addresses through the pseudo-header: update them along
+    // with the IPv4 header checksum, without summing the payload again
+    fn update_address_checksums(&mut self, old_address: u32, new_address: u32) {
+        let mut old = [0; 4];
+        BigEndian::write_u32(&mut old, old_address);
+        let mut new = [0; 4];
+        BigEndian::write_u32(&mut new, new_address);
+        let (mut ipv4_header, transport) = self.split_mut();
+        ipv4_header.update_checksum_incremental(&old, &new);
+        if let Some((mut transport_header, _)) = transport {
+            transport_header.update_checksum_incremental(&old, &new);
+        }
+    }
+
+    pub fn compute_checksums(&mut self) {
+        let (mut ipv4_header, transport) = self.split_mut();
+        ipv4_header.update_checksum();
+        if let Some((mut transport_header, payload)) = transport {
+            transport_header.update_checksum(ipv4_header.data(), payload);
+        }
+    }
+
+    /// Update the IPv4 total length and the transport length after the payload length changed,
+    /// then the checksums.
+    ///
+    /// The packet must span the whole buffer it wraps (e.g. created by `new()` on the resized
+    /// buffer): the lengths are derived from the buffer length.
+    pub fn recompute_lengths_and_checksums(&mut self) {
+        let total_length = self.raw.len();
+        assert!(total_length < MAX_PACKET_LENGTH, "Packet too long");
+        {
+            let (mut ipv4_header, transport) = self.split_mut();
+            ipv4_header.set_total_length(total_length as u16);
+            if let Some((mut transport_header, payload)) = transport {
+                transport_header.set_payload_length(payload.len() as u16);
+            }
+        }
+        self.compute_checksums();
+    }
+
+    /// Build a copy of this packet with its payload replaced.
+    ///
+    /// The lengths and checksums of the copy are updated accordingly.
+    pub fn with_payload(&self, payload: &[u8]) -> Vec<u8> {
+        let payload_index = self.raw.len() - self.payload().expect("No payload").len();
+
+        let mut raw = Vec::with_capacity(payload_index + payload.len());
+        raw.extend_from_slice(&self.raw[..payload_index]);
+        raw.extend_from_slice(payload);
+
+        let transport_header_data = self.transport_header_data.clone().unwrap();
+        Ipv4Packet::new(
+            &mut raw,
+            self.ipv4_header_data.clone(),
+            transport_header_data,
+        )
+        .recompute_lengths_and_checksums();
+        raw
+    }
+
+    /// Build a copy of this packet without its IPv4 options.
+    ///
+    /// The lengths and checksums of the copy are updated accordingly.
+    pub fn without_options(&self) -> Vec<u8> {
+        let header_length = self.ipv4_header_data.header_length() as usize;
+        let total_length = 20 + self.raw.len() - header_length;
+
+        let mut raw = Vec::with_capacity(total_length);
+        raw.extend_from_slice(&self.raw[..20]);
+        raw.extend_from_slice(&self.raw[header_length..]);
+
+        {
+            let mut ipv4_header_data = self.ipv4_header_data.clone();
+            let mut ipv4_header = ipv4_header_data.bind_mut(&mut raw[..20]);
+            ipv4_header.set_header_length(20);
+            ipv4_header.set_total_length(total_length as u16);
+        }
+
+        Ipv4Packet::parse(&mut raw).compute_checksums();
+        raw
+    }
+
+    /// Swap the source and destination of both the IPv4 header and the transport header, to turn
+    /// the packet into a reply.
+    #[allow(dead_code)]
+    pub fn swap_source_and_destination(&mut self) {
+        let (mut ipv4_header, transport) = self.split_mut();
+        ipv4_header.swap_source_and_destination();
+        if let Some((mut transport_header, _)) = transport {
+            transport_header.swap_source_and_destination();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::ipv4_header::Protocol;
+    use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+
+    fn create_packet() -> Vec<u8> {
+        let mut raw = Vec::new();
+        raw.reserve(32);
+
+        raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
+        raw.write_u8(0).unwrap(); //ToS
+        raw.write_u16::<BigEndian>(32).unwrap(); // total length 20 + 8 + 4
+        raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+        raw.write_u8(0).unwrap(); // TTL
+        raw.write_u8(17).unwrap(); // protocol (UDP)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x12345678).unwrap(); // source address
+        raw.write_u32::<BigEndian>(0x42424242).unwrap(); // destination address
+
+        raw.write_u16::<BigEndian>(1234).unwrap(); // source port
+        raw.write_u16::<BigEndian>(5678).unwrap(); // destination port
+        raw.write_u16::<BigEndian>(4).unwrap(); // length
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+
+        raw.write_u32::<BigEndian>(0x11223344).unwrap(); // payload
+
+        raw
+    }
+
+    #[test]
+    fn parse_headers() {
+        let raw = &mut create_packet()[..];
+        let ipv4_packet = Ipv4Packet::parse(raw);
+
+        {
+            let ipv4_header = ipv4_packet.ipv4_header();
+            assert_eq!(20, ipv4_header.header_length());
+            assert_eq!(32, ipv4_header.total_length());
+            assert_eq!(Protocol::Udp, ipv4_header.protocol());
+            assert_eq!(0x12345678, ipv4_header.source());
+            assert_eq!(0x42424242, ipv4_header.destination());
+
+            if let Some(&TransportHeaderData::Udp(ref udp_header)) =
+                ipv4_packet.transport_header_data()
+            {
+                assert_eq!(1234, udp_header.source_port());
+                assert_eq!(5678, udp_header.destination_port());
+            } else {
+                panic!("No UDP transport header");
+            }
+        }
+    }
+
+    #[test]
+    fn parse_headers_after_options() {
+        let mut raw = create_packet();
+        raw[0] = 4u8 << 4 | 6; // 4 bytes of options
+        raw[3] = 36; // total length 24 + 8 + 4
+                     // options (NOP NOP NOP EOL) between the IPv4 header and the UDP header
+        raw.splice(20..20, [1, 1, 1, 0].iter().cloned());
+        let mut ipv4_packet = Ipv4Packet::parse(&mut raw);
+        assert_eq!(24, ipv4_packet.ipv4_header_data().transport_offset());
+
+        if let Some(TransportHeader::Udp(udp_header)) = ipv4_packet.transport_header() {
+            assert_eq!(1234, udp_header.source_port());
+            assert_eq!(5678, udp_header.destination_port());
+        } else {
+            panic!("No UDP transport header");
+        }
+        assert_eq!([0x11, 0x22, 0x33, 0x44], ipv4_packet.payload().unwrap());
+
+        ipv4_packet.compute_checksums();
+        // the IPv4 checksum covers the options
+        let mut sum = (0..12)
+            .map(|i| u32::from(BigEndian::read_u16(&ipv4_packet.raw()[2 * i..2 * (i + 1)])))
+            .sum::<u32>();
+        while (sum & !0xffff) != 0 {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        assert_eq!(0xffff, sum);
+    }
+
+    #[test]
+    fn payload() {
+        let raw = &mut create_packet()[..];
+        let ipv4_packet = Ipv4Packet::parse(raw);
+        assert_eq!([0x11, 0x22, 0x33, 0x44], ipv4_packet.payload().unwrap());
+    }
+
+    #[test]
+    fn replace_payload() {
+        let raw = &mut create_packet()[..];
+        let ipv4_packet = Ipv4Packet::parse(raw);
+
+        let mut modified = ipv4_packet.with_payload(&[1, 2, 3, 4, 5, 6]);
+        assert_eq!(34, modified.len());
+        // UDP length
+        assert_eq!(14, BigEndian::read_u16(&modified[24..26]));
+
+        let modified_packet = Ipv4Packet::parse(&mut modified);
+        assert_eq!(34, modified_packet.length());
+        assert_eq!([1, 2, 3, 4, 5, 6], modified_packet.payload().unwrap());
+    }
+
+    #[test]
+    fn recompute_lengths_and_checksums() {
+        let mut raw = create_packet();
+        let (ipv4_header_data, transport_header_data) = {
+            let ipv4_packet = Ipv4Packet::parse(&mut raw);
+            let (ipv4_header_data, transport_header_data) = ipv4_packet.headers_data();
+            (
+                ipv4_header_data.clone(),
+                transport_header_data.unwrap().clone(),
+            )
+        };
+        // grow the payload from 4 to 7 bytes, as an inspector would
+        raw.extend_from_slice(&[0x55, 0x66, 0x77]);
+        Ipv4Packet::new(&mut raw, ipv4_header_data, transport_header_data)
+            .recompute_lengths_and_checksums();
+
+        // IPv4 total length and checksum
+        assert_eq!(35, BigEndian::read_u16(&raw[2..4]));
+        let mut sum = (0..10)
+            .map(|i| u32::from(BigEndian::read_u16(&raw[2 * i..2 * (i + 1)])))
+            .sum::<u32>();
+        while (sum & !0xffff) != 0 {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        assert_eq!(0xffff, sum);
+        // UDP length and checksum (disabled for UDP)
+        assert_eq!(15, BigEndian::read_u16(&raw[24..26]));
+        assert_eq!(0, BigEndian::read_u16(&raw[26..28]));
+
+        // the packet parses back consistently
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        assert_eq!(35, ipv4_packet.length());
+        assert_eq!(
+            [0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77],
+            ipv4_packet.payload().unwrap()
+        );
+    }
+
+    #[test]
+    fn swap_source_and_destination() {
+        let mut raw = create_packet();
+        {
+            let mut ipv4_packet = Ipv4Packet::parse(&mut raw);
+            ipv4_packet.swap_source_and_destination();
+
+            let (ipv4_header, transport) = ipv4_packet.split();
+            assert_eq!(0x42424242, ipv4_header.source());
+            assert_eq!(0x12345678, ipv4_header.destination());
+            let (transport_header, _) = transport.unwrap();
+            assert_eq!(5678, transport_header.source_port());
+            assert_eq!(1234, transport_header.destination_port());
+        }
+
+        // the raw bytes have been swapped as well
+        assert_eq!(0x42424242, BigEndian::read_u32(&raw[12..16]));
+        assert_eq!(0x12345678, BigEndian::read_u32(&raw[16..20]));
+        assert_eq!(5678, BigEndian::read_u16(&raw[20..22]));
+        assert_eq!(1234, BigEndian::read_u16(&raw[22..24]));
+    }
+
+    fn create_tcp_packet() -> Vec<u8> {
+        let mut raw = create_packet();
+        raw[3] = 44; // total length 20 + 20 + 4
+        raw[9] = 6; // protocol (TCP)
+        raw.splice(20..28, [0; 20].iter().cloned());
+        BigEndian::write_u16(&mut raw[20..22], 1234); // source port
+        BigEndian::write_u16(&mut raw[22..24], 5678); // destination port
+        BigEndian::write_u16(&mut raw[32..34], 5 << 12 | 1 << 4); // data offset + flags (ACK)
+        Ipv4Packet::parse(&mut raw).compute_checksums();
+        raw
+    }
+
+    #[test]
+    fn verify_valid_checksums() {
+        let mut raw = create_tcp_packet();
+        let mut ipv4_packet = Ipv4Packet::parse(&mut raw);
+        assert!(ipv4_packet.verify_checksum());
+        assert!(ipv4_packet.checksum_valid());
+    }
+
+    #[test]
+    fn detect_corrupt_payload() {
+        let mut raw = create_tcp_packet();
+        raw[42] ^= 0x01;
+        let mut ipv4_packet = Ipv4Packet::parse(&mut raw);
+        // not verified yet
+        assert!(ipv4_packet.checksum_valid());
+        assert!(!ipv4_packet.verify_checksum());
+        assert!(!ipv4_packet.checksum_valid());
+    }
+
+    #[test]
+    fn detect_corrupt_ipv4_header() {
+        let mut raw = create_tcp_packet();
+        raw[8] = 63; // TTL
+        let mut ipv4_packet = Ipv4Packet::parse(&mut raw);
+        assert!(!ipv4_packet.verify_checksum());
+        assert!(!ipv4_packet.checksum_valid());
+    }
+
+    #[test]
+    fn verify_udp_checksum_only_if_present() {
+        let mut raw = create_packet();
+        // the UDP checksum is not computed (0)
+        Ipv4Packet::parse(&mut raw).compute_checksums();
+        assert!(Ipv4Packet::parse(&mut raw).verify_checksum());
+
+        BigEndian::write_u16(&mut raw[26..28], 0x1234);
+        assert!(!Ipv4Packet::parse(&mut raw).verify_checksum());
+    }
+
+    #[test]
+    fn bare_udp_header() {
+        let mut raw = create_packet();
+        raw.truncate(28);
+        BigEndian::write_u16(&mut raw[2..4], 28); // total length
+        BigEndian::write_u16(&mut raw[24..26], 8); // UDP length
+                                                   // the buffer may contain the beginning of the next packet
+        raw.extend_from_slice(&[0x45, 0, 0, 20]);
+
+        let mut ipv4_packet = Ipv4Packet::parse(&mut raw);
+        assert!(ipv4_packet.is_valid());
+        assert_eq!(28, ipv4_packet.raw().len());
+        assert!(ipv4_packet.payload().unwrap().is_empty());
+        {
+            let (_, transport) = ipv4_packet.split();
+            let (transport_header, payload) = transport.unwrap();
+            assert_eq!(8, transport_header.header_length());
+            assert!(payload.is_empty());
+        }
+        ipv4_packet.compute_checksums();
+        assert!(ipv4_packet.verify_checksum());
+    }
+
+    #[test]
+    fn bare_ipv4_header() {
+        let mut raw = create_packet();
+        BigEndian::write_u16(&mut raw[2..4], 20); // total length
+
+        let mut ipv4_packet = Ipv4Packet::parse(&mut raw);
+        // the UDP header following the IPv4 header is not part of the packet
+        assert!(!ipv4_packet.is_valid());
+        assert_eq!(20, ipv4_packet.raw().len());
+        assert!(ipv4_packet.transport_header().is_none());
+        assert!(ipv4_packet.payload().is_none());
+        ipv4_packet.compute_checksums();
+        assert!(ipv4_packet.verify_checksum());
+
+        // the same for a TCP packet
+        raw[9] = 6; // protocol (TCP)
+        assert!(!Ipv4Packet::parse(&mut raw).is_valid());
+    }
+
+    #[test]
+    fn rewrite_tcp_addresses() {
+        let mut raw = create_tcp_packet();
+        {
+            let mut ipv4_packet = Ipv4Packet::parse(&mut raw);
+            ipv4_packet.rewrite_source(0x0a000002);
+            ipv4_packet.rewrite_destination(0x08080808);
+            assert_eq!(0x0a000002, ipv4_packet.ipv4_header().source());
+            assert_eq!(0x08080808, ipv4_packet.ipv4_header().destination());
+            assert!(ipv4_packet.verify_checksum());
+        }
+
+        // the checksums updated incrementally are the ones computed from scratch
+        let mut recomputed = raw.clone();
+        Ipv4Packet::parse(&mut recomputed).compute_checksums();
+        assert_eq!(recomputed, raw);
+    }
+
+    #[test]
+    fn rewrite_udp_addresses() {
+        let mut raw = create_packet();
+        {
+            // compute the UDP checksum (the relay never does)
+            let mut ipv4_packet = Ipv4Packet::parse(&mut raw);
+            ipv4_packet.compute_checksums();
+            let sum = checksum::add(
+                ipv4_packet.pseudo_header_sum(17, 12),
+                checksum::ones_complement_sum(&ipv4_packet.raw()[20..]),
+            );
+            BigEndian::write_u16(&mut ipv4_packet.raw[26..28], !sum);
+            assert!(ipv4_packet.verify_checksum());
+
+            ipv4_packet.rewrite_destination(0x0a000002);
+            assert!(ipv4_packet.verify_checksum());
+        }
+
+        // a UDP checksum not computed is left unset
+        let mut raw = create_packet();
+        let mut ipv4_packet = Ipv4Packet::parse(&mut raw);
+        ipv4_packet.compute_checksums();
+        ipv4_packet.rewrite_source(0x0a000002);
+        assert_eq!(0, BigEndian::read_u16(&ipv4_packet.raw()[26..28]));
+        assert!(ipv4_packet.verify_checksum());
+    }
+}
@@ -0,0 +1,28 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Parsing and editing of IPv4 packets and of their TCP or UDP headers.
+//!
+//! This module does not depend on the relay, so it is available without the `relay` feature.
+
+pub mod checksum;
+pub mod fragment;
+pub mod ipv4_header;
+pub mod ipv4_packet;
+pub mod tcp_header;
+pub mod transport_header;
+pub mod udp_header;
+pub mod validation;
@@ -14,8 +14,11 @@
  * limitations under the License.
  */
 
+use super::checksum;
 use super::ipv4_header::Ipv4HeaderData;
+use super::ipv4_packet::Ipv4Packet;
 use byteorder::{BigEndian, ByteOrder};
+use std::cmp;
 use std::mem;
 
 pub struct TcpHeader<'a> {
@@ -39,12 +42,202 @@ pub struct TcpHeaderData {
     window: u16,
 }
 
+// the length of a header without options
+pub const TCP_MIN_HEADER_LENGTH: u8 = 20;
+
 pub const FLAG_FIN: u16 = 1;
 pub const FLAG_SYN: u16 = 1 << 1;
 pub const FLAG_RST: u16 = 1 << 2;
 pub const FLAG_PSH: u16 = 1 << 3;
 pub const FLAG_ACK: u16 = 1 << 4;
 
+const OPTION_EOL: u8 = 0;
+const OPTION_NOP: u8 = 1;
+pub const OPTION_MSS: u8 = 2;
+const OPTION_MSS_LENGTH: u8 = 4;
+const OPTION_WINDOW_SCALE: u8 = 3;
+const OPTION_WINDOW_SCALE_LENGTH: u8 = 3;
+pub const OPTION_SACK: u8 = 5;
+const OPTION_TIMESTAMP: u8 = 8;
+const OPTION_TIMESTAMP_LENGTH: u8 = 10;
+
+/// Length of the timestamp option, aligned with 2 NOPs.
+pub const TIMESTAMP_OPTIONS_LENGTH: u8 = 12;
+
+/// Maximum shift of the window scale option (RFC 7323).
+pub const MAX_WINDOW_SCALE: u8 = 14;
+
+/// Iterator over the options region of a TCP header, yielding the `(kind, value)` of each option.
+///
+/// The NOPs are skipped, and the iteration stops at EOL, at the end of the region, or on a
+/// malformed option.
+pub struct TcpOptionsIter<'a> {
+    options: &'a [u8],
+}
+
+impl<'a> TcpOptionsIter<'a> {
+    pub fn new(options: &'a [u8]) -> Self {
+        Self { options }
+    }
+}
+
+impl<'a> Iterator for TcpOptionsIter<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match *self.options.first()? {
+                OPTION_EOL => break,
+                OPTION_NOP => self.options = &self.options[1..],
+                kind => {
+                    if self.options.len() < 2 {
+                        // truncated option
+                        break;
+                    }
+                    let length = self.options[1] as usize;
+                    if length < 2 || length > self.options.len() {
+                        // malformed option
+                        break;
+                    }
+                    let value = &self.options[2..length];
+                    self.options = &self.options[length..];
+                    return Some((kind, value));
+                }
+            }
+        }
+        // do not parse anything after EOL or a malformed option
+        self.options = &[];
+        None
+    }
+}
+
+/// Find the value of the option `kind` in the options region of a TCP header.
+fn find_option(options: &[u8], kind: u8) -> Option<&[u8]> {
+    TcpOptionsIter::new(options)
+        .find(|&(option_kind, _)| option_kind == kind)
+        .map(|(_, value)| value)
+}
+
+/// Build a minimal SYN-ACK packet (with the MSS option only) answering the SYN whose headers are
+/// `syn_ipv4` and `syn_tcp`, announcing the initial sequence number `isn`, the receive window
+/// `window` and the maximum segment size `mss`.
+///
+/// The checksums are computed.
+#[allow(dead_code)]
+pub fn build_syn_ack(
+    syn_ipv4: &Ipv4HeaderData,
+    syn_tcp: &TcpHeaderData,
+    isn: u32,
+    window: u16,
+    mss: u16,
+) -> Vec<u8> {
+    let ack = syn_tcp.sequence_number().wrapping_add(1); // SYN counts for 1 byte
+    let mut raw = build_reply(syn_ipv4, syn_tcp, isn, ack, FLAG_SYN | FLAG_ACK, window);
+    with_mss_option(&Ipv4Packet::parse(&mut raw), mss)
+}
+
+/// Build the RST packet answering the segment whose headers are `ipv4` and `tcp` (carrying
+/// `payload_length` bytes of data), which belongs to no connection (RFC 793 section 3.4).
+///
+/// If the segment acknowledges something, the RST takes its sequence number from that
+/// acknowledgement; otherwise it acknowledges the whole segment.
+///
+/// The checksums are computed.
+pub fn build_rst(ipv4: &Ipv4HeaderData, tcp: &TcpHeaderData, payload_length: u32) -> Vec<u8> {
+    if tcp.is_ack() {
+        build_reply(ipv4, tcp, tcp.acknowledgement_number(), 0, FLAG_RST, 0)
+    } else {
+        let mut segment_length = payload_length;
+        if tcp.is_syn() {
+            segment_length += 1;
+        }
+        if tcp.is_fin() {
+            segment_length += 1;
+        }
+        let ack = tcp.sequence_number().wrapping_add(segment_length);
+        build_reply(ipv4, tcp, 0, ack, FLAG_RST | FLAG_ACK, 0)
+    }
+}
+
+// build a TCP packet without options nor payload, from the destination to the source of the
+// segment whose headers are `ipv4` and `tcp`
+fn build_reply(
+    ipv4: &Ipv4HeaderData,
+    tcp: &TcpHeaderData,
+    seq: u32,
+    ack: u32,
+    flags: u16,
+    window: u16,
+) -> Vec<u8> {
+    let mut raw = vec![0u8; 40];
+    {
+        let ipv4_header = &mut raw[..20];
+        ipv4_header[0] = 4 << 4 | 5; // version_and_ihl
+        BigEndian::write_u16(&mut ipv4_header[2..4], 40); // total length
+        ipv4_header[8] = 64; // TTL
+        ipv4_header[9] = 6; // protocol (TCP)
+        BigEndian::write_u32(&mut ipv4_header[12..16], ipv4.destination());
+        BigEndian::write_u32(&mut ipv4_header[16..20], ipv4.source());
+    }
+    {
+        let tcp_header = &mut raw[20..];
+        BigEndian::write_u16(&mut tcp_header[0..2], tcp.destination_port());
+        BigEndian::write_u16(&mut tcp_header[2..4], tcp.source_port());
+        BigEndian::write_u32(&mut tcp_header[4..8], seq);
+        BigEndian::write_u32(&mut tcp_header[8..12], ack);
+        BigEndian::write_u16(&mut tcp_header[12..14], 5 << 12 | flags);
+        BigEndian::write_u16(&mut tcp_header[14..16], window);
+    }
+    Ipv4Packet::parse(&mut raw).compute_checksums();
+    raw
+}
+
+/// Copy the TCP packet `ipv4_packet` with a window scale option (aligned with a NOP) appended to
+/// its options, to announce `shift` in a SYN or a SYN-ACK.
+///
+/// The checksums are computed.
+pub fn with_window_scale_option(ipv4_packet: &Ipv4Packet, shift: u8) -> Vec<u8> {
+    with_option(
+        ipv4_packet,
+        &[
+            OPTION_NOP,
+            OPTION_WINDOW_SCALE,
+            OPTION_WINDOW_SCALE_LENGTH,
+            shift,
+        ],
+    )
+}
+
+/// Copy the TCP packet `ipv4_packet` with an MSS option appended to its options, to announce the
+/// maximum segment size `mss` in a SYN or a SYN-ACK.
+///
+/// The checksums are computed.
+pub fn with_mss_option(ipv4_packet: &Ipv4Packet, mss: u16) -> Vec<u8> {
+    let mut option = [OPTION_MSS, OPTION_MSS_LENGTH, 0, 0];
+    BigEndian::write_u16(&mut option[2..4], mss);
+    with_option(ipv4_packet, &option)
+}
+
+// copy the TCP packet `ipv4_packet` with the 4 bytes of `option` appended to its options
+fn with_option(ipv4_packet: &Ipv4Packet, option: &[u8; 4]) -> Vec<u8> {
+    let raw = ipv4_packet.raw();
+    let transport_index = ipv4_packet.ipv4_header().header_length() as usize;
+    let tcp_header_length = usize::from(raw[transport_index + 12] >> 4) << 2;
+    let payload_index = transport_index + tcp_header_length;
+
+    let mut result = Vec::with_capacity(raw.len() + 4);
+    result.extend_from_slice(&raw[..payload_index]);
+    result.extend_from_slice(option);
+    result.extend_from_slice(&raw[payload_index..]);
+
+    let total_length = result.len() as u16;
+    BigEndian::write_u16(&mut result[2..4], total_length);
+    let data_offset = ((tcp_header_length + 4) >> 2) as u8;
+    result[transport_index + 12] = data_offset << 4 | result[transport_index + 12] & 0x0F;
+    Ipv4Packet::parse(&mut result).compute_checksums();
+    result
+}
+
 #[allow(dead_code)]
 impl TcpHeaderData {
     pub fn parse(raw: &[u8]) -> Self {
@@ -213,6 +406,53 @@ macro_rules! tcp_header_common {
             pub fn is_ack(&self) -> bool {
                 self.data.is_ack()
             }
+
+            /// The options region, between the fixed header and the payload.
+            pub fn options(&self) -> &[u8] {
+                let end = cmp::min(self.data.header_length as usize, self.raw.len());
+                if end > 20 {
+                    &self.raw[20..end]
+                } else {
+                    &[]
+                }
+            }
+
+            /// The `(kind, value)` of each option.
+            pub fn options_iter(&self) -> TcpOptionsIter<'_> {
+                TcpOptionsIter::new(self.options())
+            }
+
+            /// The `(left_edge, right_edge)` blocks of the SACK option (RFC 2018), if any.
+            pub fn sack_blocks(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+                let value = find_option(self.options(), OPTION_SACK).unwrap_or(&[]);
+                value.chunks_exact(8).map(|block| {
+                    let left_edge = BigEndian::read_u32(&block[0..4]);
+                    let right_edge = BigEndian::read_u32(&block[4..8]);
+                    (left_edge, right_edge)
+                })
+            }
+
+            /// The `(TSval, TSecr)` values of the timestamp option (RFC 7323), if any.
+            pub fn timestamp(&self) -> Option<(u32, u32)> {
+                let value = find_option(self.options(), OPTION_TIMESTAMP)?;
+                if value.len() != 8 {
+                    return None;
+                }
+                let tsval = BigEndian::read_u32(&value[0..4]);
+                let tsecr = BigEndian::read_u32(&value[4..8]);
+                Some((tsval, tsecr))
+            }
+
+            /// The shift of the window scale option (RFC 7323), if any.
+            ///
+            /// A shift greater than `MAX_WINDOW_SCALE` is interpreted as `MAX_WINDOW_SCALE`.
+            pub fn window_scale(&self) -> Option<u8> {
+                let value = find_option(self.options(), OPTION_WINDOW_SCALE)?;
+                if value.len() != 1 {
+                    return None;
+                }
+                Some(cmp::min(value[0], MAX_WINDOW_SCALE))
+            }
         }
     };
 }
@@ -273,11 +513,31 @@ impl<'a> TcpHeaderMut<'a> {
         BigEndian::write_u16(&mut self.raw[12..14], data_offset_and_flags);
     }
 
+    #[inline]
+    pub fn set_window(&mut self, window: u16) {
+        self.data.window = window;
+        BigEndian::write_u16(&mut self.raw[14..16], window);
+    }
+
     #[inline]
     pub fn shrink_options(&mut self) {
         self.set_data_offset(5);
     }
 
+    /// Replace the options by a timestamp option (aligned with 2 NOPs).
+    ///
+    /// The raw header must contain at least `20 + TIMESTAMP_OPTIONS_LENGTH` bytes.
+    pub fn set_timestamp_options(&mut self, tsval: u32, tsecr: u32) {
+        let options = &mut self.raw[20..20 + TIMESTAMP_OPTIONS_LENGTH as usize];
+        options[0] = OPTION_NOP;
+        options[1] = OPTION_NOP;
+        options[2] = OPTION_TIMESTAMP;
+        options[3] = OPTION_TIMESTAMP_LENGTH;
+        BigEndian::write_u32(&mut options[4..8], tsval);
+        BigEndian::write_u32(&mut options[8..12], tsecr);
+        self.set_data_offset((20 + TIMESTAMP_OPTIONS_LENGTH) >> 2);
+    }
+
     #[inline]
     fn set_data_offset(&mut self, data_offset: u8) {
         let mut data_offset_and_flags = BigEndian::read_u16(&self.raw[12..14]);
@@ -296,6 +556,13 @@ impl<'a> TcpHeaderMut<'a> {
         BigEndian::write_u16(&mut self.raw[16..18], checksum);
     }
 
+    /// Update the checksum after the words `old` of the pseudo-header, the header or the payload
+    /// have been replaced by `new` (RFC 1624).
+    pub fn update_checksum_incremental(&mut self, old: &[u8], new: &[u8]) {
+        let checksum = checksum::update_incremental(self.checksum(), old, new);
+        self.set_checksum(checksum);
+    }
+
     pub fn update_checksum(&mut self, ipv4_header_data: &Ipv4HeaderData, payload: &[u8]) {
         // pseudo-header checksum (cf rfc793 section 3.1)
         let source = ipv4_header_data.source();
@@ -319,53 +586,27 @@ impl<'a> TcpHeaderMut<'a> {
         sum += destination >> 16;
         sum += destination & 0xFFFF;
         sum += u32::from(transport_length);
+        while (sum & !0xFFFF) != 0 {
+            sum = (sum & 0xFFFF) + (sum >> 16);
+        }
 
         // reset checksum field, so that it can be added with other bytes
         self.set_checksum(0);
 
-        // checksum computation is the most CPU-intensive task in gnirehtet
-        // prefer optimization over readability/safety
-
-        let mut hsum = 0; // high-order bytes sum
-
-        unsafe {
-            let mut p = self.raw.as_ptr();
-            let end = p.offset(header_length as isize);
-            while p < end {
-                hsum += u32::from(*p);
-                sum += u32::from(*p.offset(1));
-                p = p.offset(2);
-            }
-
-            let mut p = payload.as_ptr();
-            // -1 to ignore the last if payload_length is odd
-            let end = p.offset(payload_length as isize - 1);
-            while p < end {
-                hsum += u32::from(*p);
-                sum += u32::from(*p.offset(1));
-                p = p.offset(2);
-            }
-            if payload_length % 2 != 0 {
-                // if payload length is odd, the last byte is considered high-order
-                hsum += u32::from(*payload.get_unchecked((payload_length - 1) as usize));
-            }
-        }
-
-        // add high-order bytes sum to the global sum
-        sum += hsum << 8;
-
-        while (sum & !0xFFFF) != 0 {
-            sum = (sum & 0xFFFF) + (sum >> 16);
-        }
-        self.set_checksum(!sum as u16);
+        let header_sum = checksum::ones_complement_sum(&self.raw[..header_length as usize]);
+        let payload_sum = checksum::ones_complement_sum(payload);
+        let sum = checksum::add(checksum::add(sum as u16, header_sum), payload_sum);
+        self.set_checksum(!sum);
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::relay::ipv4_packet::Ipv4Packet;
-    use crate::relay::transport_header::TransportHeaderMut;
+    use crate::packet::ipv4_packet::Ipv4Packet;
+    use crate::packet::transport_header::{
+        TransportHeader, TransportHeaderData, TransportHeaderMut,
+    };
     use byteorder::{BigEndian, WriteBytesExt};
 
     fn create_packet() -> Vec<u8> {
@@ -514,6 +755,163 @@ mod tests {
         assert_eq!(1111, raw_destination_port);
     }
 
+    fn create_tcp_header_with_sack() -> Vec<u8> {
+        let mut raw = Vec::new();
+
+        raw.write_u16::<BigEndian>(0x1234).unwrap(); // source port
+        raw.write_u16::<BigEndian>(0x5678).unwrap(); // destination port
+        raw.write_u32::<BigEndian>(0x111).unwrap(); // sequence number
+        raw.write_u32::<BigEndian>(0x222).unwrap(); // acknowledgement number
+        raw.write_u16::<BigEndian>(10 << 12 | FLAG_ACK).unwrap(); // data offset + flags
+        raw.write_u16::<BigEndian>(0).unwrap(); // window (don't care for these tests)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u16::<BigEndian>(0).unwrap(); // urgent pointer
+
+        // options
+        raw.write_u8(OPTION_NOP).unwrap();
+        raw.write_u8(OPTION_NOP).unwrap();
+        raw.write_u8(OPTION_SACK).unwrap();
+        raw.write_u8(18).unwrap(); // option length (2 blocks)
+        raw.write_u32::<BigEndian>(1000).unwrap(); // left edge
+        raw.write_u32::<BigEndian>(2000).unwrap(); // right edge
+        raw.write_u32::<BigEndian>(3000).unwrap(); // left edge
+        raw.write_u32::<BigEndian>(4000).unwrap(); // right edge
+
+        raw
+    }
+
+    #[test]
+    fn parse_sack_blocks() {
+        let raw = &create_tcp_header_with_sack()[..];
+        let header_data = TcpHeaderData::parse(raw);
+        let header = header_data.bind(raw);
+
+        assert_eq!(40, header.header_length());
+        assert_eq!(20, header.options().len());
+        let blocks = header.sack_blocks().collect::<Vec<_>>();
+        assert_eq!(vec![(1000, 2000), (3000, 4000)], blocks);
+    }
+
+    #[test]
+    fn no_sack_blocks() {
+        let raw = &create_tcp_header()[..];
+        let header_data = TcpHeaderData::parse(raw);
+        let header = header_data.bind(raw);
+
+        assert!(header.options().is_empty());
+        assert_eq!(0, header.sack_blocks().count());
+    }
+
+    #[test]
+    fn write_and_parse_timestamp() {
+        let mut raw = create_tcp_header();
+        assert!(TcpHeaderData::parse(&raw).bind(&raw).timestamp().is_none());
+
+        raw.resize(20 + TIMESTAMP_OPTIONS_LENGTH as usize, 0);
+        let mut header_data = TcpHeaderData::parse(&raw);
+        header_data
+            .bind_mut(&mut raw)
+            .set_timestamp_options(1234, 5678);
+
+        let header_data = TcpHeaderData::parse(&raw);
+        let header = header_data.bind(&raw);
+        assert_eq!(32, header.header_length());
+        assert_eq!(Some((1234, 5678)), header.timestamp());
+        // the other options are preserved
+        assert_eq!(0, header.sack_blocks().count());
+    }
+
+    #[test]
+    fn parse_window_scale() {
+        let mut raw = create_tcp_header();
+        assert!(TcpHeaderData::parse(&raw)
+            .bind(&raw)
+            .window_scale()
+            .is_none());
+
+        raw[12] = 6 << 4; // data offset
+        raw.extend_from_slice(&[OPTION_NOP, OPTION_WINDOW_SCALE, 3, 7]);
+        let header_data = TcpHeaderData::parse(&raw);
+        assert_eq!(Some(7), header_data.bind(&raw).window_scale());
+
+        // too large, interpreted as the maximum
+        raw[23] = 15;
+        let header_data = TcpHeaderData::parse(&raw);
+        assert_eq!(
+            Some(MAX_WINDOW_SCALE),
+            header_data.bind(&raw).window_scale()
+        );
+    }
+
+    #[test]
+    fn append_window_scale_option() {
+        let mut raw = create_packet();
+        let original_length = raw.len();
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        let mut raw = with_window_scale_option(&ipv4_packet, 5);
+        assert_eq!(original_length + 4, raw.len());
+        assert!(checksum_is_valid(&raw));
+
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        assert_eq!(original_length as u16 + 4, ipv4_packet.length());
+        match ipv4_packet.transport_header() {
+            Some(TransportHeader::Tcp(tcp_header)) => {
+                assert_eq!(24, tcp_header.header_length());
+                assert_eq!(Some(5), tcp_header.window_scale());
+            }
+            _ => panic!("Not a TCP packet"),
+        }
+        // the payload is preserved
+        assert_eq!(&[0x11, 0x22, 0xEE, 0xFF], ipv4_packet.payload().unwrap());
+    }
+
+    #[test]
+    fn iterate_options() {
+        let mut raw = create_tcp_header();
+        raw[12] = 8 << 4; // data offset
+        raw.extend_from_slice(&[2, 4, 0x05, 0xB4]); // MSS (1460)
+        raw.extend_from_slice(&[OPTION_NOP, OPTION_WINDOW_SCALE, 3, 7]);
+        raw.extend_from_slice(&[4, 2]); // SACK permitted
+        raw.extend_from_slice(&[OPTION_EOL, OPTION_NOP]);
+
+        let header_data = TcpHeaderData::parse(&raw);
+        let header = header_data.bind(&raw);
+        let options = header.options_iter().collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (2, &[0x05, 0xB4][..]),
+                (OPTION_WINDOW_SCALE, &[7][..]),
+                (4, &[][..])
+            ],
+            options
+        );
+    }
+
+    #[test]
+    fn stop_iterating_on_malformed_option() {
+        let options = [OPTION_NOP, 4, 2, OPTION_SACK, 10, 0, 0, 4, 2];
+        let mut iter = TcpOptionsIter::new(&options);
+        assert_eq!(Some((4, &[][..])), iter.next());
+        assert!(iter.next().is_none());
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn find_malformed_option() {
+        // option length exceeding the options region
+        assert!(find_option(&[OPTION_SACK, 10, 0, 0], OPTION_SACK).is_none());
+        // option length too small
+        assert!(find_option(&[OPTION_SACK, 1, 0, 0], OPTION_SACK).is_none());
+        // truncated option
+        assert!(find_option(&[OPTION_NOP, OPTION_SACK], OPTION_SACK).is_none());
+        // options after EOL are ignored
+        assert!(find_option(&[OPTION_EOL, OPTION_SACK, 2], OPTION_SACK).is_none());
+        assert_eq!(
+            Some(&[][..]),
+            find_option(&[OPTION_NOP, OPTION_SACK, 2], OPTION_SACK)
+        );
+    }
+
     #[test]
     fn compute_checksum() {
         let raw = &mut create_packet()[..];
@@ -690,4 +1088,103 @@ mod tests {
             panic!("Not a TCP packet");
         }
     }
+
+    fn checksum_is_valid(raw: &[u8]) -> bool {
+        let sum = |data: &[u8], initial: u32| {
+            let mut sum = data
+                .chunks(2)
+                .map(|chunk| u32::from(BigEndian::read_u16(chunk)))
+                .fold(initial, |acc, value| acc + value);
+            while (sum & !0xFFFF) != 0 {
+                sum = (sum & 0xFFFF) + (sum >> 16);
+            }
+            sum
+        };
+        // pseudo-header: source, destination, protocol and TCP length
+        let pseudo_header = sum(&raw[12..20], 6 + (raw.len() as u32 - 20));
+        sum(&raw[..20], 0) == 0xFFFF && sum(&raw[20..], pseudo_header) == 0xFFFF
+    }
+
+    #[test]
+    fn build_syn_ack_from_syn() {
+        let mut syn = create_packet();
+        syn.truncate(40);
+        BigEndian::write_u16(&mut syn[2..4], 40); // total length, without payload
+        BigEndian::write_u16(&mut syn[32..34], 5 << 12 | FLAG_SYN);
+        let syn_packet = Ipv4Packet::parse(&mut syn);
+        let (syn_ipv4, syn_tcp) = syn_packet.headers_data();
+        let syn_tcp = match syn_tcp {
+            Some(TransportHeaderData::Tcp(tcp_header)) => tcp_header,
+            _ => panic!("Not a TCP packet"),
+        };
+
+        let mut raw = build_syn_ack(syn_ipv4, syn_tcp, 0xFFFF_FFF0, 4242, 1400);
+        assert_eq!(44, raw.len());
+        assert!(checksum_is_valid(&raw));
+
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        let ipv4_header = ipv4_packet.ipv4_header();
+        assert_eq!(44, ipv4_header.total_length());
+        assert_eq!(0xA2A24242, ipv4_header.source());
+        assert_eq!(0x12345678, ipv4_header.destination());
+        match ipv4_packet.transport_header() {
+            Some(TransportHeader::Tcp(tcp_header)) => {
+                assert_eq!(0x5678, tcp_header.source_port());
+                assert_eq!(0x1234, tcp_header.destination_port());
+                assert_eq!(FLAG_SYN | FLAG_ACK, tcp_header.flags());
+                assert_eq!(0xFFFF_FFF0, tcp_header.sequence_number());
+                assert_eq!(0x112, tcp_header.acknowledgement_number());
+                assert_eq!(4242, tcp_header.window());
+                assert_eq!(24, tcp_header.header_length());
+                let mss = find_option(tcp_header.options(), OPTION_MSS);
+                assert_eq!(Some(&[0x05, 0x78][..]), mss); // 1400
+            }
+            _ => panic!("Not a TCP packet"),
+        }
+    }
+
+    // the (seq, ack, flags) of the RST answering the packet from create_packet() with `flags`
+    fn rst_fields(flags: u16) -> (u32, u32, u16) {
+        let mut raw = create_packet();
+        BigEndian::write_u16(&mut raw[32..34], 5 << 12 | flags);
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        let payload_length = ipv4_packet.payload().unwrap().len() as u32;
+        let (ipv4, tcp) = ipv4_packet.headers_data();
+        let tcp = match tcp {
+            Some(TransportHeaderData::Tcp(tcp_header)) => tcp_header,
+            _ => panic!("Not a TCP packet"),
+        };
+
+        let mut rst = build_rst(ipv4, tcp, payload_length);
+        assert_eq!(40, rst.len());
+        assert!(checksum_is_valid(&rst));
+        let rst_packet = Ipv4Packet::parse(&mut rst);
+        assert_eq!(0xA2A24242, rst_packet.ipv4_header().source());
+        assert_eq!(0x12345678, rst_packet.ipv4_header().destination());
+        match rst_packet.transport_header() {
+            Some(TransportHeader::Tcp(tcp_header)) => {
+                assert_eq!(0x5678, tcp_header.source_port());
+                assert_eq!(0x1234, tcp_header.destination_port());
+                assert_eq!(0, tcp_header.window());
+                (
+                    tcp_header.sequence_number(),
+                    tcp_header.acknowledgement_number(),
+                    tcp_header.flags(),
+                )
+            }
+            _ => panic!("Not a TCP packet"),
+        }
+    }
+
+    #[test]
+    fn build_rst_from_ack() {
+        // the RST is sequenced at the acknowledgement of the segment
+        assert_eq!((0x222, 0, FLAG_RST), rst_fields(FLAG_ACK | FLAG_PSH));
+    }
+
+    #[test]
+    fn build_rst_from_segment_without_ack() {
+        // the RST acknowledges the payload (4 bytes) and the FIN
+        assert_eq!((0, 0x116, FLAG_RST | FLAG_ACK), rst_fields(FLAG_FIN));
+    }
 }
@@ -15,7 +15,7 @@
  */
 
 use super::ipv4_header::{Ipv4HeaderData, Protocol};
-use super::tcp_header::{TcpHeader, TcpHeaderData, TcpHeaderMut};
+use super::tcp_header::{TcpHeader, TcpHeaderData, TcpHeaderMut, TCP_MIN_HEADER_LENGTH};
 use super::udp_header::{UdpHeader, UdpHeaderData, UdpHeaderMut, UDP_HEADER_LENGTH};
 
 pub enum TransportHeader<'a> {
@@ -36,10 +36,21 @@ pub enum TransportHeaderData {
 
 #[allow(dead_code)]
 impl TransportHeaderData {
+    /// Parse the transport header at the start of `raw` (the IPv4 payload), if the protocol is
+    /// supported and `raw` is long enough to contain the whole header.
     pub fn parse(protocol: Protocol, raw: &[u8]) -> Option<Self> {
         match protocol {
-            Protocol::Udp => Some(UdpHeaderData::parse(raw).into()),
-            Protocol::Tcp => Some(TcpHeaderData::parse(raw).into()),
+            Protocol::Udp if raw.len() >= UDP_HEADER_LENGTH as usize => {
+                Some(UdpHeaderData::parse(raw).into())
+            }
+            Protocol::Tcp if raw.len() >= TCP_MIN_HEADER_LENGTH as usize => {
+                let tcp_header_data = TcpHeaderData::parse(raw);
+                let header_length = tcp_header_data.header_length() as usize;
+                if header_length < TCP_MIN_HEADER_LENGTH as usize || header_length > raw.len() {
+                    return None;
+                }
+                Some(tcp_header_data.into())
+            }
             _ => None,
         }
     }
@@ -187,6 +198,18 @@ impl<'a> TransportHeaderMut<'a> {
         }
     }
 
+    #[inline]
+    pub fn update_checksum_incremental(&mut self, old: &[u8], new: &[u8]) {
+        match *self {
+            TransportHeaderMut::Tcp(ref mut tcp_header) => {
+                tcp_header.update_checksum_incremental(old, new)
+            }
+            TransportHeaderMut::Udp(ref mut udp_header) => {
+                udp_header.update_checksum_incremental(old, new)
+            }
+        }
+    }
+
     #[inline]
     pub fn update_checksum(&mut self, ipv4_header_data: &Ipv4HeaderData, payload: &[u8]) {
         match *self {
@@ -14,6 +14,7 @@
  * limitations under the License.
  */
 
+use super::checksum;
 use super::ipv4_header::Ipv4HeaderData;
 use byteorder::{BigEndian, ByteOrder};
 use std::mem;
@@ -148,6 +149,19 @@ impl<'a> UdpHeaderMut<'a> {
         BigEndian::write_u16(&mut self.raw[6..8], checksum);
     }
 
+    /// Update the checksum after the words `old` of the pseudo-header, the header or the payload
+    /// have been replaced by `new` (RFC 1624).
+    ///
+    /// A checksum of 0 (not computed) is left unchanged.
+    pub fn update_checksum_incremental(&mut self, old: &[u8], new: &[u8]) {
+        let checksum = BigEndian::read_u16(&self.raw[6..8]);
+        if checksum != 0 {
+            let checksum = checksum::update_incremental(checksum, old, new);
+            // a computed checksum of 0 is transmitted as all ones (RFC 768)
+            self.set_checksum(if checksum == 0 { 0xFFFF } else { checksum });
+        }
+    }
+
     #[inline]
     pub fn update_checksum(&mut self, _ipv4_header_data: &Ipv4HeaderData, _payload: &[u8]) {
         // disable checksum validation
@@ -0,0 +1,317 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, ByteOrder};
+use std::fmt;
+
+use super::checksum;
+use super::ipv4_header::{Ipv4HeaderData, ParseError, Protocol};
+use super::ipv4_packet::Ipv4Packet;
+use super::transport_header::TransportHeaderData;
+
+const MIN_HEADER_LENGTH: usize = 20;
+
+/// A problem found in a packet by `validate_packet()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Issue {
+    /// The data are too short to contain an IPv4 header.
+    TooShort(usize),
+    /// The IPv4 header is inconsistent (the packet cannot be parsed further).
+    Header(ParseError),
+    /// The data are shorter than the total length of the packet.
+    Truncated { total_length: u16, available: usize },
+    /// The data contain bytes after the end of the packet.
+    TrailingData(usize),
+    /// The IPv4 header checksum does not match.
+    HeaderChecksum,
+    /// The TCP or UDP header is missing or truncated.
+    TransportHeader(Protocol),
+    /// The length stored in the UDP header does not match the IPv4 payload length.
+    UdpLength {
+        udp_length: u16,
+        payload_length: u16,
+    },
+    /// The TCP or UDP checksum does not match.
+    TransportChecksum(Protocol),
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Issue::TooShort(length) => write!(f, "Too short for an IPv4 header: {} bytes", length),
+            Issue::Header(ref err) => write!(f, "{}", err),
+            Issue::Truncated {
+                total_length,
+                available,
+            } => write!(
+                f,
+                "Truncated packet: {} bytes available, total length {}",
+                available, total_length
+            ),
+            Issue::TrailingData(length) => {
+                write!(f, "{} bytes after the end of the packet", length)
+            }
+            Issue::HeaderChecksum => write!(f, "Invalid IPv4 header checksum"),
+            Issue::TransportHeader(protocol) => {
+                write!(f, "Missing or truncated {:?} header", protocol)
+            }
+            Issue::UdpLength {
+                udp_length,
+                payload_length,
+            } => write!(
+                f,
+                "UDP length {} does not match the IPv4 payload length {}",
+                udp_length, payload_length
+            ),
+            Issue::TransportChecksum(protocol) => write!(f, "Invalid {:?} checksum", protocol),
+        }
+    }
+}
+
+/// The result of `validate_packet()`: the description of the packet header (if it could be
+/// parsed) and all the issues found.
+#[derive(Debug)]
+pub struct ValidationReport {
+    description: Option<String>,
+    issues: Vec<Issue>,
+}
+
+impl ValidationReport {
+    pub fn is_well_formed(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    pub fn issues(&self) -> &[Issue] {
+        &self.issues
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref description) = self.description {
+            writeln!(f, "{}", description)?;
+        }
+        if self.issues.is_empty() {
+            write!(f, "Well-formed packet")
+        } else {
+            write!(f, "{} issue(s):", self.issues.len())?;
+            for issue in &self.issues {
+                write!(f, "\n  - {}", issue)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Check the IPv4 packet `raw` (e.g. a captured packet) the way the relay parses it: the IPv4
+/// header, its checksum, the TCP or UDP header and its checksum.
+///
+/// All the issues found are reported, the analysis stops only when the packet cannot be parsed
+/// further.
+pub fn validate_packet(raw: &[u8]) -> ValidationReport {
+    let mut report = ValidationReport {
+        description: None,
+        issues: Vec::new(),
+    };
+    if raw.len() < MIN_HEADER_LENGTH {
+        report.issues.push(Issue::TooShort(raw.len()));
+        return report;
+    }
+    let ipv4_header_data = Ipv4HeaderData::parse(raw);
+    report.description = Some(ipv4_header_data.bind(raw).describe());
+    if let Err(err) = ipv4_header_data.validate() {
+        report.issues.push(Issue::Header(err));
+        return report;
+    }
+    let total_length = ipv4_header_data.total_length() as usize;
+    if total_length > raw.len() {
+        report.issues.push(Issue::Truncated {
+            total_length: ipv4_header_data.total_length(),
+            available: raw.len(),
+        });
+        return report;
+    }
+    if raw.len() > total_length {
+        report
+            .issues
+            .push(Issue::TrailingData(raw.len() - total_length));
+    }
+
+    let header_length = ipv4_header_data.header_length() as usize;
+    let header_checksum_valid = checksum::ones_complement_sum(&raw[..header_length]) == 0xFFFF;
+    if !header_checksum_valid {
+        report.issues.push(Issue::HeaderChecksum);
+    }
+
+    let protocol = ipv4_header_data.protocol();
+    if protocol != Protocol::Tcp && protocol != Protocol::Udp {
+        // the relay does not parse the other transport protocols
+        return report;
+    }
+    let fragment_offset = BigEndian::read_u16(&raw[6..8]) & 0x1FFF;
+    if fragment_offset != 0 {
+        // only the first fragment contains the transport header
+        return report;
+    }
+    let segment = &raw[header_length..total_length];
+    let transport_header_data = match TransportHeaderData::parse(protocol, segment) {
+        Some(transport_header_data) => transport_header_data,
+        None => {
+            report.issues.push(Issue::TransportHeader(protocol));
+            return report;
+        }
+    };
+    if let TransportHeaderData::Udp(_) = transport_header_data {
+        let udp_length = BigEndian::read_u16(&segment[4..6]);
+        if udp_length as usize != segment.len() {
+            report.issues.push(Issue::UdpLength {
+                udp_length,
+                payload_length: segment.len() as u16,
+            });
+        }
+    }
+
+    // verify the transport checksum on a copy whose header checksum is correct, so that an invalid
+    // header checksum does not hide it
+    let mut copy = raw[..total_length].to_vec();
+    let mut ipv4_packet = Ipv4Packet::parse(&mut copy);
+    if !header_checksum_valid {
+        ipv4_packet.ipv4_header_mut().update_checksum();
+    }
+    if !ipv4_packet.verify_checksum() {
+        report.issues.push(Issue::TransportChecksum(protocol));
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_udp_packet(payload: &[u8]) -> Vec<u8> {
+        let mut raw = vec![0; 28 + payload.len()];
+        let mut ipv4_header_data = Ipv4HeaderData::init(
+            &mut raw,
+            Protocol::Udp,
+            0x0a000002,
+            0x7f000001,
+            8 + payload.len() as u16,
+        );
+        BigEndian::write_u16(&mut raw[20..22], 1234);
+        BigEndian::write_u16(&mut raw[22..24], 53);
+        BigEndian::write_u16(&mut raw[24..26], 8 + payload.len() as u16);
+        raw[28..].copy_from_slice(payload);
+        ipv4_header_data.bind_mut(&mut raw).update_checksum();
+        // the relay never computes UDP checksums, compute it here so that it is verified
+        let mut pseudo_header = [0u8; 12];
+        pseudo_header[..8].copy_from_slice(&raw[12..20]);
+        pseudo_header[9] = 17;
+        BigEndian::write_u16(&mut pseudo_header[10..12], 8 + payload.len() as u16);
+        let sum = checksum::add(
+            checksum::ones_complement_sum(&pseudo_header),
+            checksum::ones_complement_sum(&raw[20..]),
+        );
+        BigEndian::write_u16(&mut raw[26..28], !sum);
+        raw
+    }
+
+    #[test]
+    fn validate_well_formed_packet() {
+        let raw = create_udp_packet(b"hello");
+        let report = validate_packet(&raw);
+        assert!(report.is_well_formed(), "{}", report);
+        assert!(report.description().unwrap().contains("protocol=Udp"));
+        assert!(report.to_string().ends_with("Well-formed packet"));
+    }
+
+    #[test]
+    fn report_short_data() {
+        let report = validate_packet(&[0x45, 0, 0]);
+        assert_eq!(&[Issue::TooShort(3)], report.issues());
+        assert!(report.description().is_none());
+    }
+
+    #[test]
+    fn report_bad_version() {
+        let mut raw = create_udp_packet(b"hello");
+        raw[0] = 6 << 4 | 5;
+        let report = validate_packet(&raw);
+        assert_eq!(&[Issue::Header(ParseError::Version(6))], report.issues());
+    }
+
+    #[test]
+    fn report_truncated_packet() {
+        let raw = create_udp_packet(b"hello");
+        let report = validate_packet(&raw[..30]);
+        assert_eq!(
+            &[Issue::Truncated {
+                total_length: 33,
+                available: 30
+            }],
+            report.issues()
+        );
+    }
+
+    #[test]
+    fn report_all_checksum_issues() {
+        let mut raw = create_udp_packet(b"hello");
+        raw[10] ^= 0xff; // IPv4 header checksum
+        raw[28] ^= 0xff; // payload
+        let report = validate_packet(&raw);
+        assert_eq!(
+            &[
+                Issue::HeaderChecksum,
+                Issue::TransportChecksum(Protocol::Udp)
+            ],
+            report.issues()
+        );
+        assert!(report.to_string().contains("2 issue(s)"));
+    }
+
+    #[test]
+    fn report_udp_length_and_trailing_data() {
+        let mut raw = create_udp_packet(b"hello");
+        BigEndian::write_u16(&mut raw[24..26], 20);
+        raw.extend_from_slice(&[0; 4]);
+        let report = validate_packet(&raw);
+        assert_eq!(
+            &[
+                Issue::TrailingData(4),
+                Issue::UdpLength {
+                    udp_length: 20,
+                    payload_length: 13
+                },
+                Issue::TransportChecksum(Protocol::Udp)
+            ],
+            report.issues()
+        );
+    }
+
+    #[test]
+    fn report_truncated_tcp_header() {
+        let mut raw = create_udp_packet(b"hello");
+        // 13 bytes cannot hold a TCP header
+        raw[9] = 6;
+        let mut ipv4_header_data = Ipv4HeaderData::parse(&raw);
+        ipv4_header_data.bind_mut(&mut raw).update_checksum();
+        let report = validate_packet(&raw);
+        assert_eq!(&[Issue::TransportHeader(Protocol::Tcp)], report.issues());
+    }
+}
@@ -0,0 +1,144 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, ByteOrder};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use super::client_address::CONTROL_MESSAGE_VERSION;
+
+const TYPE_ADDRESS_FAMILIES: u8 = 3;
+const ADDRESS_FAMILIES_LENGTH: u16 = 5;
+
+const FAMILY_IPV4: u8 = 0b01;
+const FAMILY_IPV6: u8 = 0b10;
+
+/// Address families (IPv4 and/or IPv6) the client sends packets of.
+///
+/// They are declared by the client in a control message (usually at tunnel start):
+///
+/// ```text
+///  0: version (0) and reserved (0)
+///  1: message type (3)
+///  2: total length (5), on 2 bytes
+///  4: families (bit 0: IPv4, bit 1: IPv6)
+/// ```
+///
+/// Until the client declares them, both are expected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddressFamilies {
+    ipv4: bool,
+    ipv6: bool,
+}
+
+impl AddressFamilies {
+    pub fn new(ipv4: bool, ipv6: bool) -> Self {
+        Self { ipv4, ipv6 }
+    }
+
+    /// Parse an address families control message.
+    pub fn parse(raw: &[u8]) -> Option<Self> {
+        if raw.len() < ADDRESS_FAMILIES_LENGTH as usize
+            || raw[0] != CONTROL_MESSAGE_VERSION << 4
+            || raw[1] != TYPE_ADDRESS_FAMILIES
+            || BigEndian::read_u16(&raw[2..4]) != ADDRESS_FAMILIES_LENGTH
+        {
+            return None;
+        }
+        let families = raw[4];
+        if families & !(FAMILY_IPV4 | FAMILY_IPV6) != 0 {
+            return None;
+        }
+        Some(Self::new(
+            families & FAMILY_IPV4 != 0,
+            families & FAMILY_IPV6 != 0,
+        ))
+    }
+
+    /// Indicate whether the packets of IP version `version` are expected from the client.
+    pub fn accepts(&self, version: u8) -> bool {
+        match version {
+            4 => self.ipv4,
+            6 => self.ipv6,
+            _ => false,
+        }
+    }
+}
+
+impl Default for AddressFamilies {
+    fn default() -> Self {
+        Self::new(true, true)
+    }
+}
+
+impl fmt::Display for AddressFamilies {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.ipv4, self.ipv6) {
+            (true, true) => write!(f, "IPv4+IPv6"),
+            (true, false) => write!(f, "IPv4"),
+            (false, true) => write!(f, "IPv6"),
+            (false, false) => write!(f, "none"),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+
+    pub fn create_declaration(ipv4: bool, ipv6: bool) -> Vec<u8> {
+        let mut families = 0;
+        if ipv4 {
+            families |= FAMILY_IPV4;
+        }
+        if ipv6 {
+            families |= FAMILY_IPV6;
+        }
+        let mut raw = Vec::new();
+        raw.write_u8(CONTROL_MESSAGE_VERSION << 4).unwrap();
+        raw.write_u8(TYPE_ADDRESS_FAMILIES).unwrap();
+        raw.write_u16::<BigEndian>(ADDRESS_FAMILIES_LENGTH).unwrap();
+        raw.write_u8(families).unwrap();
+        raw
+    }
+
+    #[test]
+    fn parse_declaration() {
+        let families = AddressFamilies::parse(&create_declaration(true, false)).unwrap();
+        assert!(families.accepts(4));
+        assert!(!families.accepts(6));
+        assert_eq!("IPv4", families.to_string());
+
+        let families = AddressFamilies::parse(&create_declaration(true, true)).unwrap();
+        assert_eq!(AddressFamilies::default(), families);
+        assert_eq!("IPv4+IPv6", families.to_string());
+    }
+
+    #[test]
+    fn reject_invalid_declaration() {
+        let mut raw = create_declaration(true, false);
+        raw[4] = 0b100; // unknown family
+        assert!(AddressFamilies::parse(&raw).is_none());
+
+        let mut raw = create_declaration(true, false);
+        raw[1] = 1; // address announcement
+        assert!(AddressFamilies::parse(&raw).is_none());
+
+        let raw = create_declaration(true, false);
+        assert!(AddressFamilies::parse(&raw[..4]).is_none());
+    }
+}
@@ -0,0 +1,31 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/// How strictly the checksums of the packets sent by the clients are validated.
+///
+/// Some network stacks delegate the checksums to the hardware (checksum offload), so their packets
+/// may reach the relay with checksums left zeroed or wrong, although their content is intact.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChecksumValidation {
+    /// Never verify the checksums (the tunnel is reliable).
+    #[default]
+    Off,
+    /// Verify the checksums, but relay the packets with a wrong checksum anyway (they are logged
+    /// and counted).
+    Lenient,
+    /// Verify the checksums, and drop the packets with a wrong checksum.
+    Strict,
+}
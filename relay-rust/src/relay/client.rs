@@ -14,61 +14,165 @@
  * limitations under the License.
  */
 
+use byteorder::{BigEndian, ByteOrder};
 use log::*;
 use mio::net::TcpStream;
 use mio::{Event, PollOpt, Ready, Token};
+#[cfg(unix)]
+use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
+use std::cmp;
 use std::io::{self, Write};
 use std::mem;
 use std::net::Shutdown;
-use std::rc::Rc;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, BorrowedFd, OwnedFd};
+use std::rc::{Rc, Weak};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
+use super::address_families::AddressFamilies;
 use super::binary;
+use super::client_address::ClientAddress;
+use super::client_auth;
 use super::close_listener::CloseListener;
+use super::config::RelayConfig;
+use super::delay_queue::DelayQueue;
+use super::dscp_remap::DscpRemap;
+use super::fragment;
 use super::ipv4_packet::{Ipv4Packet, MAX_PACKET_LENGTH};
 use super::ipv4_packet_buffer::Ipv4PacketBuffer;
+use super::metrics::Metrics;
+use super::nat::NatPorts;
 use super::packet_source::PacketSource;
 use super::router::Router;
-use super::selector::Selector;
+use super::selector::{Selector, TimerId};
 use super::stream_buffer::StreamBuffer;
+#[cfg(unix)]
+use super::tcp_connection::TcpConnectionState;
+use super::trace_ring::{Direction, TraceRing};
+use super::transport_header::TransportHeader;
+use super::write_coalescer::{Action, WriteCoalescer};
 
 const TAG: &str = "Client";
 
+// maximum size of the packets held to simulate latency
+const DELAY_QUEUE_CAPACITY: usize = 16 * MAX_PACKET_LENGTH;
+
+// the delay between two write attempts while flushing synchronously
+const FLUSH_RETRY_DELAY: Duration = Duration::from_millis(1);
+
+// the maximum delay to wait for the client to read its queue before handing it off
+#[cfg(unix)]
+const HANDOFF_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
 pub struct Client {
+    self_weak: Weak<RefCell<Client>>,
     id: u32,
+    // announced by the client, if any
+    address: Option<ClientAddress>,
+    // the IP versions of the packets the client sends, as declared by the client
+    address_families: AddressFamilies,
     stream: TcpStream,
     interests: Ready,
     token: Token,
+    // do not read the packets sent by the client while paused
+    paused: bool,
     client_to_network: Ipv4PacketBuffer,
     network_to_client: StreamBuffer,
+    coalescer: WriteCoalescer,
+    flush_timer: Option<TimerId>,
+    // packets from the client held to simulate latency, if enabled
+    delay_queue: Option<DelayQueue>,
+    delay_timer: Option<TimerId>,
     router: Router,
+    // shared with the router, if tracing is enabled
+    trace_ring: Option<Rc<RefCell<TraceRing>>>,
+    // rewrites the DSCP of the packets sent to the client, if enabled
+    dscp_remap: Option<DscpRemap>,
+    // the packets longer than the tunnel MTU are fragmented before being sent to the client
+    tunnel_mtu: Option<u16>,
+    // identification of the next packet fragmented by the relay
+    next_fragment_id: u16,
     close_listener: Box<dyn CloseListener<Client>>,
     closed: bool,
     pending_packet_sources: Vec<Rc<RefCell<dyn PacketSource>>>,
     // number of remaining bytes of "id" to send to the client before relaying any data
     pending_id_bytes: usize,
+    // the key the client must present before sending any packet, until it is authenticated
+    pending_auth_key: Option<Vec<u8>>,
+}
+
+/// The state of a client handed off to another relay process.
+///
+/// Its sockets are transferred separately: the tunnel first, then the sockets of the connections,
+/// in the same order.
+#[cfg(unix)]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClientState {
+    id: u32,
+    address: Option<ClientAddress>,
+    address_families: AddressFamilies,
+    authenticated: bool,
+    // the start of a message received from the client
+    pending_input: Vec<u8>,
+    connections: Vec<TcpConnectionState>,
+}
+
+#[cfg(unix)]
+impl ClientState {
+    /// The number of sockets of the client (its tunnel and its connections).
+    pub fn socket_count(&self) -> usize {
+        1 + self.connections.len()
+    }
 }
 
 /// Channel for connections to send back data immediately to the client
 pub struct ClientChannel<'a> {
+    client: &'a Weak<RefCell<Client>>,
     network_to_client: &'a mut StreamBuffer,
+    coalescer: &'a mut WriteCoalescer,
+    flush_timer: &'a mut Option<TimerId>,
     stream: &'a TcpStream,
     token: Token,
     interests: &'a mut Ready,
+    paused: bool,
+    trace_ring: Option<&'a RefCell<TraceRing>>,
+    dscp_remap: Option<&'a DscpRemap>,
+    tunnel_mtu: Option<u16>,
+    next_fragment_id: &'a mut u16,
 }
 
 impl<'a> ClientChannel<'a> {
+    #[allow(clippy::too_many_arguments)]
     fn new(
+        client: &'a Weak<RefCell<Client>>,
         network_to_client: &'a mut StreamBuffer,
+        coalescer: &'a mut WriteCoalescer,
+        flush_timer: &'a mut Option<TimerId>,
         stream: &'a TcpStream,
         token: Token,
         interests: &'a mut Ready,
+        paused: bool,
+        trace_ring: Option<&'a RefCell<TraceRing>>,
+        dscp_remap: Option<&'a DscpRemap>,
+        tunnel_mtu: Option<u16>,
+        next_fragment_id: &'a mut u16,
     ) -> Self {
         Self {
+            client,
             network_to_client,
+            coalescer,
+            flush_timer,
             stream,
             token,
             interests,
+            paused,
+            trace_ring,
+            dscp_remap,
+            tunnel_mtu,
+            next_fragment_id,
         }
     }
 
@@ -79,8 +183,60 @@ impl<'a> ClientChannel<'a> {
         selector: &mut Selector,
         ipv4_packet: &Ipv4Packet,
     ) -> io::Result<()> {
+        if let Some(dscp_remap) = self.dscp_remap {
+            if let Some(mut remapped) = dscp_remap.remap_packet(ipv4_packet) {
+                let remapped_packet = Ipv4Packet::parse(&mut remapped);
+                return self.fragment_and_enqueue(selector, &remapped_packet);
+            }
+        }
+        self.fragment_and_enqueue(selector, ipv4_packet)
+    }
+
+    fn fragment_and_enqueue(
+        &mut self,
+        selector: &mut Selector,
+        ipv4_packet: &Ipv4Packet,
+    ) -> io::Result<()> {
+        match self.tunnel_mtu {
+            Some(mtu)
+                if ipv4_packet.length() > mtu && !fragment::is_dont_fragment(ipv4_packet.raw()) =>
+            {
+                let mut raw = ipv4_packet.raw().to_vec();
+                if BigEndian::read_u16(&raw[4..6]) == 0 {
+                    // the packets generated by the relay have no identification, but the client
+                    // needs one to reassemble the fragments (the checksum is recomputed anyway)
+                    BigEndian::write_u16(&mut raw[4..6], *self.next_fragment_id);
+                    *self.next_fragment_id = self.next_fragment_id.wrapping_add(1).max(1);
+                }
+                let fragments = fragment::fragment_packet(&raw, mtu);
+                // enqueue all the fragments or none
+                let length: usize = fragments.iter().map(Vec::len).sum();
+                if length > self.network_to_client.remaining() {
+                    warn!(target: TAG, "Client buffer full");
+                    return Err(io::Error::new(
+                        io::ErrorKind::WouldBlock,
+                        "Client buffer full",
+                    ));
+                }
+                for mut fragment in fragments {
+                    self.enqueue(selector, &Ipv4Packet::parse(&mut fragment))?;
+                }
+                Ok(())
+            }
+            _ => self.enqueue(selector, ipv4_packet),
+        }
+    }
+
+    fn enqueue(&mut self, selector: &mut Selector, ipv4_packet: &Ipv4Packet) -> io::Result<()> {
         if ipv4_packet.length() as usize <= self.network_to_client.remaining() {
+            if let Some(trace_ring) = self.trace_ring {
+                trace_ring
+                    .borrow_mut()
+                    .record(Direction::ToClient, ipv4_packet, selector.now());
+            }
+            let was_empty = self.network_to_client.is_empty();
             self.network_to_client.read_from(ipv4_packet.raw());
+            self.coalesce(selector, ipv4_packet, was_empty);
             self.update_interests(selector);
             Ok(())
         } else {
@@ -92,12 +248,40 @@ impl<'a> ClientChannel<'a> {
         }
     }
 
+    fn coalesce(&mut self, selector: &mut Selector, ipv4_packet: &Ipv4Packet, was_empty: bool) {
+        let push = match ipv4_packet.transport_header() {
+            Some(TransportHeader::Tcp(ref tcp_header)) => tcp_header.is_psh(),
+            _ => false,
+        };
+        let length = ipv4_packet.length() as usize;
+        match self.coalescer.on_packet(length, push, was_empty) {
+            Action::Flush => {
+                if let Some(timer) = self.flush_timer.take() {
+                    selector.cancel_timer(timer);
+                }
+            }
+            Action::StartTimer(delay) => {
+                let weak = self.client.clone();
+                let handler = move |selector: &mut Selector| {
+                    if let Some(rc) = weak.upgrade() {
+                        rc.borrow_mut().on_flush_timeout(selector);
+                    }
+                };
+                *self.flush_timer = Some(selector.set_timer(delay, handler));
+            }
+            Action::Wait => (),
+        }
+    }
+
     fn update_interests(&mut self, selector: &mut Selector) {
-        let ready = if self.network_to_client.is_empty() {
-            Ready::readable()
+        let mut ready = if self.paused {
+            Ready::empty()
         } else {
-            Ready::readable() | Ready::writable()
+            Ready::readable()
         };
+        if !self.network_to_client.is_empty() && !self.coalescer.is_holding() {
+            ready |= Ready::writable();
+        }
         if *self.interests != ready {
             // interests must be changed
             *self.interests = ready;
@@ -114,25 +298,52 @@ impl Client {
         selector: &mut Selector,
         stream: TcpStream,
         close_listener: Box<dyn CloseListener<Client>>,
+        config: Rc<RelayConfig>,
+        metrics: Arc<Metrics>,
+        nat_ports: Rc<RefCell<NatPorts>>,
     ) -> io::Result<Rc<RefCell<Self>>> {
         // on start, we are interested only in writing (we must first send the client id)
         let interests = Ready::writable();
+        let delay_queue = config
+            .latency()
+            .map(|(delay, jitter)| DelayQueue::new(delay, jitter, DELAY_QUEUE_CAPACITY));
+        let router = Router::new(config.for_client(id), metrics, nat_ports, selector.now());
+        let trace_ring = router.trace_ring().cloned();
         let rc = Rc::new(RefCell::new(Self {
+            self_weak: Weak::new(),
             id,
+            address: None,
+            address_families: AddressFamilies::default(),
             stream,
             interests,
             token: Token(0), // default value, will be set afterwards
+            paused: false,
             client_to_network: Ipv4PacketBuffer::new(),
-            network_to_client: StreamBuffer::new(16 * MAX_PACKET_LENGTH),
-            router: Router::new(),
+            network_to_client: StreamBuffer::new(
+                config
+                    .client_queue_capacity()
+                    .unwrap_or(16 * MAX_PACKET_LENGTH),
+            ),
+            coalescer: WriteCoalescer::new(config.coalescing_window()),
+            flush_timer: None,
+            delay_queue,
+            delay_timer: None,
+            router,
+            trace_ring,
+            dscp_remap: config.dscp_remap().cloned(),
+            tunnel_mtu: config.tunnel_mtu(),
+            next_fragment_id: 1,
             closed: false,
             close_listener,
             pending_packet_sources: Vec::new(),
             pending_id_bytes: 4,
+            pending_auth_key: config.auth_key().map(<[u8]>::to_vec),
         }));
 
         {
             let mut self_ref = rc.borrow_mut();
+            // keep a shared reference to this
+            self_ref.self_weak = Rc::downgrade(&rc);
             // set client as router owner
             self_ref.router.set_client(Rc::downgrade(&rc));
 
@@ -147,25 +358,164 @@ impl Client {
         Ok(rc)
     }
 
+    /// Resume a client handed off by another relay process, from its state and its sockets (the
+    /// tunnel first, then the sockets of its connections).
+    #[cfg(unix)]
+    pub fn restore(
+        selector: &mut Selector,
+        state: &ClientState,
+        mut fds: Vec<OwnedFd>,
+        close_listener: Box<dyn CloseListener<Client>>,
+        config: Rc<RelayConfig>,
+        metrics: Arc<Metrics>,
+        nat_ports: Rc<RefCell<NatPorts>>,
+    ) -> io::Result<Rc<RefCell<Self>>> {
+        if fds.len() != state.socket_count() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Expected {} sockets, got {}",
+                    state.socket_count(),
+                    fds.len()
+                ),
+            ));
+        }
+        let connection_fds = fds.split_off(1);
+        let tunnel_fd = fds.pop().expect("No tunnel socket");
+        let stream = TcpStream::from_stream(std::net::TcpStream::from(tunnel_fd))?;
+        let rc = Self::create(
+            state.id,
+            selector,
+            stream,
+            close_listener,
+            config,
+            metrics,
+            nat_ports,
+        )?;
+        {
+            let mut self_ref = rc.borrow_mut();
+            info!(target: TAG, "Client #{} restored", state.id);
+            // the id has already been sent by the previous relay
+            self_ref.pending_id_bytes = 0;
+            if state.authenticated {
+                self_ref.pending_auth_key = None;
+            }
+            if let Some(address) = state.address {
+                self_ref.set_address(address);
+            }
+            self_ref.set_address_families(state.address_families);
+            self_ref.client_to_network.feed(&state.pending_input);
+            self_ref
+                .router
+                .restore(selector, &state.connections, connection_fds)?;
+            self_ref.update_interests(selector);
+            // the pending input may already contain full messages
+            self_ref.push_to_network(selector);
+        }
+        Ok(rc)
+    }
+
+    /// Flush the queue of the client and capture its state and its sockets (the tunnel first,
+    /// then the sockets of its connections), so that another relay process may resume it.
+    ///
+    /// Return `None` if the client is closed or could not be flushed in time. The packets held to
+    /// simulate latency are not handed off.
+    #[cfg(unix)]
+    pub fn hand_off(&mut self, now: Instant) -> io::Result<Option<(ClientState, Vec<OwnedFd>)>> {
+        if self.closed {
+            return Ok(None);
+        }
+        if !self.flush_blocking(HANDOFF_FLUSH_TIMEOUT) {
+            warn!(target: TAG, "Client #{} not handed off", self.id);
+            self.router.abandon();
+            return Ok(None);
+        }
+        // the socket remains owned by this relay until it is dropped
+        let tunnel_fd = unsafe { BorrowedFd::borrow_raw(self.stream.as_raw_fd()) };
+        let mut fds = vec![tunnel_fd.try_clone_to_owned()?];
+        let (connections, connection_fds) = self.router.hand_off(now)?;
+        fds.extend(connection_fds);
+        let state = ClientState {
+            id: self.id,
+            address: self.address,
+            address_families: self.address_families,
+            authenticated: self.pending_auth_key.is_none(),
+            pending_input: self.client_to_network.pending().to_vec(),
+            connections,
+        };
+        Ok(Some((state, fds)))
+    }
+
     pub fn id(&self) -> u32 {
         self.id
     }
 
+    pub fn address(&self) -> Option<&ClientAddress> {
+        self.address.as_ref()
+    }
+
+    fn set_address(&mut self, address: ClientAddress) {
+        info!(target: TAG, "Client #{} announced address {}", self.id, address);
+        self.address = Some(address);
+        self.router.set_client_address(address);
+    }
+
+    #[allow(dead_code)]
+    pub fn address_families(&self) -> AddressFamilies {
+        self.address_families
+    }
+
+    fn set_address_families(&mut self, families: AddressFamilies) {
+        info!(
+            target: TAG,
+            "Client #{} declared address families {}", self.id, families
+        );
+        self.address_families = families;
+        self.client_to_network.set_address_families(families);
+    }
+
+    /// Stop (or restart) reading the packets sent by the client. The packets from the network are
+    /// still sent to the client while paused.
+    pub fn set_paused(&mut self, selector: &mut Selector, paused: bool) {
+        self.paused = paused;
+        // until the id is sent, only the writable interest is registered
+        if !self.closed && !self.must_send_id() {
+            self.update_interests(selector);
+        }
+    }
+
     pub fn router(&mut self) -> &mut Router {
         &mut self.router
     }
 
     pub fn channel(&mut self) -> ClientChannel {
         ClientChannel::new(
+            &self.self_weak,
             &mut self.network_to_client,
+            &mut self.coalescer,
+            &mut self.flush_timer,
             &self.stream,
             self.token,
             &mut self.interests,
+            self.paused,
+            self.trace_ring.as_deref(),
+            self.dscp_remap.as_ref(),
+            self.tunnel_mtu,
+            &mut self.next_fragment_id,
         )
     }
 
     fn close(&mut self, selector: &mut Selector) {
         self.closed = true;
+        if let Some(timer) = self.flush_timer.take() {
+            selector.cancel_timer(timer);
+        }
+        if let Some(timer) = self.delay_timer.take() {
+            selector.cancel_timer(timer);
+        }
+        if let Some(ref mut delay_queue) = self.delay_queue {
+            delay_queue.clear();
+        }
         selector.deregister(&self.stream, self.token).unwrap();
         // shutdown only (there is no close), the socket will be closed on drop
         if self.stream.shutdown(Shutdown::Both).is_err() {
@@ -258,16 +608,54 @@ impl Client {
         selector: &mut Selector,
         ipv4_packet: &Ipv4Packet,
     ) -> io::Result<()> {
-        if ipv4_packet.length() as usize <= self.network_to_client.remaining() {
-            self.network_to_client.read_from(ipv4_packet.raw());
+        self.channel().send_to_client(selector, ipv4_packet)
+    }
+
+    fn on_flush_timeout(&mut self, selector: &mut Selector) {
+        self.flush_timer = None;
+        self.coalescer.on_timeout();
+        if !self.closed {
             self.update_interests(selector);
-            Ok(())
-        } else {
-            warn!(target: TAG, "Client buffer full");
-            Err(io::Error::new(
-                io::ErrorKind::WouldBlock,
-                "Client buffer full",
-            ))
+        }
+    }
+
+    /// Write the packets queued for the client synchronously, waiting at most `timeout` for the
+    /// client to read them (typically before shutting down the relay).
+    ///
+    /// Return whether the queue has been fully flushed.
+    #[allow(dead_code)]
+    pub fn flush_blocking(&mut self, timeout: Duration) -> bool {
+        if self.closed {
+            return false;
+        }
+        // the thread actually sleeps, so the timeout is measured by the system clock rather than
+        // by the clock of the selector
+        let deadline = Instant::now() + timeout;
+        loop {
+            let result = if self.must_send_id() {
+                self.send_id()
+            } else {
+                self.write()
+            };
+            match result {
+                Ok(_) => {
+                    if !self.must_send_id() && self.network_to_client.is_empty() {
+                        return true;
+                    }
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => (),
+                Err(err) => {
+                    error!(target: TAG, "Cannot flush: [{:?}] {}", err.kind(), err);
+                    return false;
+                }
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                warn!(target: TAG, "Client #{} not flushed in time", self.id);
+                return false;
+            }
+            // the socket is non-blocking, wait for the client to read
+            thread::sleep(cmp::min(FLUSH_RETRY_DELAY, deadline - now));
         }
     }
 
@@ -292,7 +680,12 @@ impl Client {
     }
 
     fn write(&mut self) -> io::Result<()> {
-        self.network_to_client.write_to(&mut self.stream)?;
+        if self.coalescer.is_enabled() {
+            // the buffer may contain a batch of packets, write it at once
+            self.network_to_client.write_vectored_to(&mut self.stream)?;
+        } else {
+            self.network_to_client.write_to(&mut self.stream)?;
+        }
         Ok(())
     }
 
@@ -303,13 +696,37 @@ impl Client {
     }
 
     fn push_one_packet_to_network(&mut self, selector: &mut Selector) -> bool {
+        if self.pending_auth_key.is_some() {
+            return self.authenticate(selector);
+        }
+        if let Some(message) = self.client_to_network.as_control_message() {
+            if let Some(address) = ClientAddress::parse(message) {
+                self.set_address(address);
+            } else if let Some(families) = AddressFamilies::parse(message) {
+                self.set_address_families(families);
+            } else {
+                warn!(target: TAG, "Ignoring unknown control message");
+            }
+            return true;
+        }
+        if self.delay_queue.is_some() {
+            return self.delay_one_packet(selector);
+        }
         match self.client_to_network.as_ipv4_packet() {
-            Some(ref packet) => {
+            Some(ref mut packet) => {
                 let mut client_channel = ClientChannel::new(
+                    &self.self_weak,
                     &mut self.network_to_client,
+                    &mut self.coalescer,
+                    &mut self.flush_timer,
                     &self.stream,
                     self.token,
                     &mut self.interests,
+                    self.paused,
+                    self.trace_ring.as_deref(),
+                    self.dscp_remap.as_ref(),
+                    self.tunnel_mtu,
+                    &mut self.next_fragment_id,
                 );
                 self.router
                     .send_to_network(selector, &mut client_channel, packet);
@@ -319,6 +736,93 @@ impl Client {
         }
     }
 
+    // the first message must be the authentication, any other message closes the client
+    fn authenticate(&mut self, selector: &mut Selector) -> bool {
+        let expected = self.pending_auth_key.as_ref().expect("No pending key");
+        let control_message = self.client_to_network.as_control_message().map(|message| {
+            client_auth::parse_key(message)
+                .is_some_and(|key| client_auth::key_matches(expected, key))
+        });
+        let authenticated = match control_message {
+            Some(authenticated) => authenticated,
+            None if self.client_to_network.as_ipv4_packet().is_some() => false,
+            // no full message available yet
+            None => return false,
+        };
+        if authenticated {
+            info!(target: TAG, "Client #{} authenticated", self.id);
+            self.pending_auth_key = None;
+            true
+        } else {
+            warn!(target: TAG, "Client #{} failed to authenticate", self.id);
+            self.close(selector);
+            false
+        }
+    }
+
+    fn delay_one_packet(&mut self, selector: &mut Selector) -> bool {
+        let delay_queue = self.delay_queue.as_mut().expect("Latency not enabled");
+        match self.client_to_network.as_ipv4_packet() {
+            Some(ref packet) => {
+                if !delay_queue.push(packet.raw(), selector.now()) {
+                    warn!(target: TAG, "Delay queue full, dropping packet");
+                }
+                if self.delay_timer.is_none() {
+                    self.start_delay_timer(selector);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn start_delay_timer(&mut self, selector: &mut Selector) {
+        let delay_queue = self.delay_queue.as_ref().expect("Latency not enabled");
+        if let Some(timeout) = delay_queue.next_timeout(selector.now()) {
+            let weak = self.self_weak.clone();
+            let handler = move |selector: &mut Selector| {
+                if let Some(rc) = weak.upgrade() {
+                    rc.borrow_mut().on_delay_timeout(selector);
+                }
+            };
+            self.delay_timer = Some(selector.set_timer(timeout, handler));
+        }
+    }
+
+    fn on_delay_timeout(&mut self, selector: &mut Selector) {
+        self.delay_timer = None;
+        if self.closed {
+            return;
+        }
+        let now = selector.now();
+        while let Some(mut raw) = self
+            .delay_queue
+            .as_mut()
+            .expect("Latency not enabled")
+            .pop_expired(now)
+        {
+            let mut packet = Ipv4Packet::parse(&mut raw);
+            let mut client_channel = ClientChannel::new(
+                &self.self_weak,
+                &mut self.network_to_client,
+                &mut self.coalescer,
+                &mut self.flush_timer,
+                &self.stream,
+                self.token,
+                &mut self.interests,
+                self.paused,
+                self.trace_ring.as_deref(),
+                self.dscp_remap.as_ref(),
+                self.tunnel_mtu,
+                &mut self.next_fragment_id,
+            );
+            self.router
+                .send_to_network(selector, &mut client_channel, &mut packet);
+        }
+        self.start_delay_timer(selector);
+        self.update_interests(selector);
+    }
+
     fn process_pending(&mut self, selector: &mut Selector) {
         let mut vec = Vec::new();
         mem::swap(&mut self.pending_packet_sources, &mut vec);
@@ -358,3 +862,136 @@ impl Client {
         self.pending_id_bytes > 0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::address_families::tests::create_declaration;
+    use crate::relay::client_auth::tests::create_authentication;
+    use crate::relay::tcp_connection::tests::{
+        connect_tunnel, create_tcp_packet, free_port, handshake, start_relay, start_relay_with,
+        CLIENT_SEQ,
+    };
+    use crate::relay::tcp_header::{FLAG_ACK, FLAG_SYN};
+    use crate::relay::RelayConfigBuilder;
+    use std::io::Read;
+    use std::net::{Ipv4Addr, TcpListener, TcpStream};
+
+    fn start_relay_with_key(key: &'static [u8]) -> u16 {
+        start_relay_with(move |builder| builder.auth_key(key))
+    }
+
+    fn assert_disconnected(tunnel: &mut TcpStream) {
+        let mut buf = [0; 1];
+        assert_eq!(0, tunnel.read(&mut buf).unwrap());
+    }
+
+    #[test]
+    fn accept_valid_key() {
+        let relay_port = start_relay_with_key(b"secret");
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let mut tunnel = connect_tunnel(relay_port);
+        tunnel.write_all(&create_authentication(b"secret")).unwrap();
+
+        // the packets are relayed once authenticated
+        handshake(&mut tunnel, port, 0xffff);
+        server.accept().unwrap();
+    }
+
+    #[test]
+    fn reject_invalid_key() {
+        let relay_port = start_relay_with_key(b"secret");
+        let mut tunnel = connect_tunnel(relay_port);
+        tunnel.write_all(&create_authentication(b"guess")).unwrap();
+        assert_disconnected(&mut tunnel);
+    }
+
+    #[test]
+    fn drop_undeclared_family() {
+        let relay_port = start_relay();
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let mut tunnel = connect_tunnel(relay_port);
+        tunnel.write_all(&create_declaration(true, false)).unwrap();
+
+        // an IPv6 packet (header and 8 bytes of payload), dropped without breaking the stream
+        let mut ipv6 = vec![0; 48];
+        ipv6[0] = 6 << 4;
+        ipv6[5] = 8; // payload length
+        tunnel.write_all(&ipv6).unwrap();
+
+        handshake(&mut tunnel, port, 0xffff);
+        server.accept().unwrap();
+    }
+
+    // create a client connected to a local peer, playing the role of the device
+    fn create_client(selector: &mut Selector) -> (Rc<RefCell<Client>>, TcpStream) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let stream = mio::net::TcpStream::from_stream(stream).unwrap();
+        let config = Rc::new(RelayConfigBuilder::new(0).build());
+        let on_closed = |_: &Client| {};
+        let client = Client::create(
+            0,
+            selector,
+            stream,
+            Box::new(on_closed),
+            config,
+            Arc::new(Metrics::new()),
+            Default::default(),
+        )
+        .unwrap();
+        (client, peer)
+    }
+
+    #[test]
+    fn flush_queue() {
+        let mut selector = Selector::create().unwrap();
+        let (client, mut peer) = create_client(&mut selector);
+        let mut raw = create_tcp_packet(1234, CLIENT_SEQ, 0, FLAG_ACK, 0xffff, b"hello");
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        client
+            .borrow_mut()
+            .send_to_client(&mut selector, &ipv4_packet)
+            .unwrap();
+        assert!(client.borrow_mut().flush_blocking(Duration::from_secs(1)));
+
+        // the client id, then the packet
+        let mut buf = vec![0; 4 + raw.len()];
+        peer.read_exact(&mut buf).unwrap();
+        assert_eq!(&raw[..], &buf[4..]);
+    }
+
+    #[test]
+    fn flush_timeout_on_backpressure() {
+        let mut selector = Selector::create().unwrap();
+        // the peer never reads
+        let (client, _peer) = create_client(&mut selector);
+        let payload = [0; 8192];
+        let mut flushed = true;
+        for seq in 0..4096 {
+            let mut raw = create_tcp_packet(1234, seq, 0, FLAG_ACK, 0xffff, &payload);
+            let ipv4_packet = Ipv4Packet::parse(&mut raw);
+            let mut client = client.borrow_mut();
+            client.send_to_client(&mut selector, &ipv4_packet).unwrap();
+            if !client.flush_blocking(Duration::from_millis(10)) {
+                flushed = false;
+                break;
+            }
+        }
+        // the socket buffers are full, the packets remain queued
+        assert!(!flushed);
+        assert!(!client.borrow().network_to_client.is_empty());
+    }
+
+    #[test]
+    fn reject_packet_before_authentication() {
+        let relay_port = start_relay_with_key(b"secret");
+        let mut tunnel = connect_tunnel(relay_port);
+        let syn = create_tcp_packet(free_port(), CLIENT_SEQ, 0, FLAG_SYN, 0xffff, &[]);
+        tunnel.write_all(&syn).unwrap();
+        assert_disconnected(&mut tunnel);
+    }
+}
@@ -0,0 +1,140 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, ByteOrder};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::Ipv4Addr;
+
+// Besides IPv4 packets, the client may send control messages in the tunnel. They start with a
+// version 0 (instead of 4) and store their total length at the same offset as IPv4 packets, so
+// that the stream is framed the same way.
+pub const CONTROL_MESSAGE_VERSION: u8 = 0;
+
+const TYPE_ADDRESS_ANNOUNCEMENT: u8 = 1;
+const ADDRESS_ANNOUNCEMENT_LENGTH: u16 = 9;
+
+/// Address and subnet assigned to the client device on its tunnel interface.
+///
+/// It is announced by the client in a control message (usually at tunnel start):
+///
+/// ```text
+///  0: version (0) and reserved (0)
+///  1: message type (1)
+///  2: total length (9), on 2 bytes
+///  4: IPv4 address, on 4 bytes
+///  8: prefix length of the subnet
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientAddress {
+    address: Ipv4Addr,
+    prefix_length: u8,
+}
+
+impl ClientAddress {
+    pub fn new(address: Ipv4Addr, prefix_length: u8) -> Self {
+        assert!(prefix_length <= 32, "Invalid prefix length");
+        Self {
+            address,
+            prefix_length,
+        }
+    }
+
+    /// Parse an address announcement control message.
+    pub fn parse(raw: &[u8]) -> Option<Self> {
+        if raw.len() < ADDRESS_ANNOUNCEMENT_LENGTH as usize
+            || raw[0] != CONTROL_MESSAGE_VERSION << 4
+            || raw[1] != TYPE_ADDRESS_ANNOUNCEMENT
+            || BigEndian::read_u16(&raw[2..4]) != ADDRESS_ANNOUNCEMENT_LENGTH
+        {
+            return None;
+        }
+        let address = Ipv4Addr::from(BigEndian::read_u32(&raw[4..8]));
+        let prefix_length = raw[8];
+        if prefix_length > 32 {
+            return None;
+        }
+        Some(Self::new(address, prefix_length))
+    }
+
+    pub fn address(&self) -> Ipv4Addr {
+        self.address
+    }
+
+    /// Indicate whether `addr` belongs to the subnet of the client.
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        let mask = if self.prefix_length == 0 {
+            0
+        } else {
+            !0u32 << (32 - self.prefix_length)
+        };
+        u32::from(addr) & mask == u32::from(self.address) & mask
+    }
+}
+
+impl fmt::Display for ClientAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_length)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+
+    pub fn create_announcement(address: u32, prefix_length: u8) -> Vec<u8> {
+        let mut raw = Vec::new();
+        raw.write_u8(CONTROL_MESSAGE_VERSION << 4).unwrap();
+        raw.write_u8(TYPE_ADDRESS_ANNOUNCEMENT).unwrap();
+        raw.write_u16::<BigEndian>(ADDRESS_ANNOUNCEMENT_LENGTH)
+            .unwrap();
+        raw.write_u32::<BigEndian>(address).unwrap();
+        raw.write_u8(prefix_length).unwrap();
+        raw
+    }
+
+    #[test]
+    fn parse_announcement() {
+        let raw = create_announcement(0x0a000002, 24);
+        let client_address = ClientAddress::parse(&raw).unwrap();
+        assert_eq!(Ipv4Addr::new(10, 0, 0, 2), client_address.address());
+        assert_eq!(24, client_address.prefix_length);
+        assert_eq!("10.0.0.2/24", client_address.to_string());
+    }
+
+    #[test]
+    fn reject_invalid_announcement() {
+        let raw = create_announcement(0x0a000002, 33);
+        assert!(ClientAddress::parse(&raw).is_none());
+
+        let mut raw = create_announcement(0x0a000002, 24);
+        raw[1] = 42; // unknown message type
+        assert!(ClientAddress::parse(&raw).is_none());
+
+        assert!(ClientAddress::parse(&raw[..8]).is_none());
+    }
+
+    #[test]
+    fn subnet_contains() {
+        let client_address = ClientAddress::new(Ipv4Addr::new(10, 0, 0, 2), 24);
+        assert!(client_address.contains(Ipv4Addr::new(10, 0, 0, 255)));
+        assert!(!client_address.contains(Ipv4Addr::new(10, 0, 1, 2)));
+
+        let client_address = ClientAddress::new(Ipv4Addr::new(10, 0, 0, 2), 0);
+        assert!(client_address.contains(Ipv4Addr::new(192, 168, 1, 1)));
+    }
+}
@@ -0,0 +1,101 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, ByteOrder};
+
+use super::client_address::CONTROL_MESSAGE_VERSION;
+
+const TYPE_AUTHENTICATION: u8 = 2;
+const AUTHENTICATION_HEADER_LENGTH: usize = 4;
+
+/// The maximum length of a key, so that the authentication fits in a control message.
+pub const MAX_KEY_LENGTH: usize = 60;
+
+/// Extract the key from an authentication control message.
+///
+/// If the relay requires a key, the client must send it before any packet:
+///
+/// ```text
+///  0: version (0) and reserved (0)
+///  1: message type (2)
+///  2: total length (4 + key length), on 2 bytes
+///  4: key
+/// ```
+pub fn parse_key(raw: &[u8]) -> Option<&[u8]> {
+    if raw.len() < AUTHENTICATION_HEADER_LENGTH
+        || raw[0] != CONTROL_MESSAGE_VERSION << 4
+        || raw[1] != TYPE_AUTHENTICATION
+        || BigEndian::read_u16(&raw[2..4]) as usize != raw.len()
+    {
+        return None;
+    }
+    Some(&raw[AUTHENTICATION_HEADER_LENGTH..])
+}
+
+/// Compare the key presented by the client to the expected one.
+///
+/// The duration does not depend on the position of the first mismatch, so that the key cannot be
+/// guessed byte by byte.
+pub fn key_matches(expected: &[u8], key: &[u8]) -> bool {
+    if expected.len() != key.len() {
+        return false;
+    }
+    expected
+        .iter()
+        .zip(key)
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+
+    pub fn create_authentication(key: &[u8]) -> Vec<u8> {
+        let mut raw = Vec::new();
+        raw.write_u8(CONTROL_MESSAGE_VERSION << 4).unwrap();
+        raw.write_u8(TYPE_AUTHENTICATION).unwrap();
+        raw.write_u16::<BigEndian>((AUTHENTICATION_HEADER_LENGTH + key.len()) as u16)
+            .unwrap();
+        raw.extend_from_slice(key);
+        raw
+    }
+
+    #[test]
+    fn parse_authentication() {
+        let raw = create_authentication(b"secret");
+        assert_eq!(Some(&b"secret"[..]), parse_key(&raw));
+    }
+
+    #[test]
+    fn reject_invalid_authentication() {
+        let mut raw = create_authentication(b"secret");
+        raw[1] = 1; // address announcement
+        assert!(parse_key(&raw).is_none());
+
+        let raw = create_authentication(b"secret");
+        assert!(parse_key(&raw[..8]).is_none());
+    }
+
+    #[test]
+    fn compare_keys() {
+        assert!(key_matches(b"secret", b"secret"));
+        assert!(!key_matches(b"secret", b"secreT"));
+        assert!(!key_matches(b"secret", b"secret!"));
+        assert!(!key_matches(b"secret", b""));
+    }
+}
@@ -0,0 +1,75 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#[cfg(test)]
+use std::cell::Cell;
+#[cfg(test)]
+use std::time::Duration;
+use std::time::Instant;
+
+/// Source of the current time for the timers and the timeouts, so that the tests may control it.
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+/// The real monotonic clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock which only moves when advanced manually.
+#[cfg(test)]
+pub struct MockClock {
+    now: Cell<Instant>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            now: Cell::new(Instant::now()),
+        }
+    }
+
+    pub fn advance(&self, delay: Duration) {
+        self.now.set(self.now.get() + delay);
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.now.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn advance_mock_clock() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        assert_eq!(start, clock.now());
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(start + Duration::from_secs(3), clock.now());
+    }
+}
@@ -0,0 +1,1118 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::rc::Rc;
+use std::time::Duration;
+
+use super::checksum_validation::ChecksumValidation;
+use super::client_auth::MAX_KEY_LENGTH;
+use super::dns::DnsOverride;
+use super::dscp_remap::DscpRemap;
+use super::event_loop::DEFAULT_EVENTS_CAPACITY;
+use super::icmp::IcmpPolicy;
+use super::inspector::Inspector;
+use super::ipv4_packet::MAX_PACKET_LENGTH;
+use super::observer::Observer;
+use super::overflow::OverflowPolicy;
+use super::tcp_connection::{HANDSHAKE_TIMEOUT_SECONDS, MAX_PAYLOAD_LENGTH};
+use super::udp_connection::{IDLE_TIMEOUT_SECONDS, QUIC_IDLE_TIMEOUT_SECONDS, QUIC_PORT};
+use super::upstream_factory::UpstreamFactory;
+
+// every IPv4 link must carry packets of this length without fragmentation (RFC 791)
+const MIN_MTU: u16 = 68;
+
+/// Immutable configuration of the relay, built by a `RelayConfigBuilder`.
+#[derive(Clone)]
+pub struct RelayConfig {
+    port: u16,
+    inspector: Option<Rc<dyn Inspector>>,
+    observer: Option<Rc<dyn Observer>>,
+    coalescing_window: Option<Duration>,
+    control_port: Option<u16>,
+    gre_decapsulation: bool,
+    dns_override: Option<DnsOverride>,
+    rate_limit: Option<u32>,
+    destination_rate_limits: Vec<(Ipv4Addr, u32)>,
+    latency: Option<(Duration, Duration)>,
+    packet_loss: Option<(f64, f64)>,
+    packet_loss_seed: Option<u64>,
+    source_validation: bool,
+    receive_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+    trace_capacity: Option<usize>,
+    trace_snaplen: Option<usize>,
+    strip_ipv4_options: bool,
+    udp_grace_period: Option<Duration>,
+    udp_port_idle_timeouts: Vec<(u16, Duration)>,
+    external_address: Option<Ipv4Addr>,
+    external_address_pool: Vec<Ipv4Addr>,
+    source_nat: bool,
+    client_queue_capacity: Option<usize>,
+    overflow_policy: OverflowPolicy,
+    upstream_fwmark: Option<u32>,
+    max_packet_rate: Option<u32>,
+    auth_key: Option<Vec<u8>>,
+    connect_retries: Option<(u32, Duration)>,
+    dns_cache_capacity: Option<usize>,
+    dscp_remap: Option<DscpRemap>,
+    tcp_time_wait: Option<Duration>,
+    monitor_only: bool,
+    upstream_factory: Option<Rc<dyn UpstreamFactory>>,
+    tcp_mss: u16,
+    max_connection_lifetime: Option<Duration>,
+    checksum_validation: ChecksumValidation,
+    drop_log_sample_rate: Option<u32>,
+    events_capacity: usize,
+    dns_redirect: Option<SocketAddrV4>,
+    max_half_open_connections: Option<usize>,
+    tcp_handshake_timeout: Duration,
+    icmp_policy: IcmpPolicy,
+    allow_tcp: bool,
+    allow_udp: bool,
+    tunnel_mtu: Option<u16>,
+    summary_interval: Option<Duration>,
+}
+
+impl RelayConfig {
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn inspector(&self) -> Option<&Rc<dyn Inspector>> {
+        self.inspector.as_ref()
+    }
+
+    pub fn observer(&self) -> Option<&Rc<dyn Observer>> {
+        self.observer.as_ref()
+    }
+
+    pub fn coalescing_window(&self) -> Option<Duration> {
+        self.coalescing_window
+    }
+
+    pub fn control_port(&self) -> Option<u16> {
+        self.control_port
+    }
+
+    pub fn gre_decapsulation(&self) -> bool {
+        self.gre_decapsulation
+    }
+
+    pub fn dns_override(&self) -> Option<&DnsOverride> {
+        self.dns_override.as_ref()
+    }
+
+    /// The maximum rate (in bytes per second) of the data sent to the clients by a connection to
+    /// `destination`, if limited.
+    pub fn rate_limit(&self, destination: Ipv4Addr) -> Option<u32> {
+        self.destination_rate_limits
+            .iter()
+            .find(|&&(addr, _)| addr == destination)
+            .map(|&(_, rate)| rate)
+            .or(self.rate_limit)
+    }
+
+    /// The delay and the maximum jitter added to the packets sent by the clients, if any.
+    pub fn latency(&self) -> Option<(Duration, Duration)> {
+        self.latency
+    }
+
+    /// The probabilities to drop a packet sent to the network and a UDP packet sent to the
+    /// clients, if packet loss is simulated.
+    pub fn packet_loss(&self) -> Option<(f64, f64)> {
+        self.packet_loss
+    }
+
+    pub fn packet_loss_seed(&self) -> Option<u64> {
+        self.packet_loss_seed
+    }
+
+    /// Whether the packets whose source is outside the subnet announced by their client are
+    /// dropped.
+    pub fn source_validation(&self) -> bool {
+        self.source_validation
+    }
+
+    /// The size of the kernel receive buffer (`SO_RCVBUF`) of the upstream sockets, if set.
+    pub fn receive_buffer_size(&self) -> Option<usize> {
+        self.receive_buffer_size
+    }
+
+    /// The size of the kernel send buffer (`SO_SNDBUF`) of the upstream sockets, if set.
+    pub fn send_buffer_size(&self) -> Option<usize> {
+        self.send_buffer_size
+    }
+
+    /// The number of packets kept in memory for each client to be dumped on demand, if enabled.
+    pub fn trace_capacity(&self) -> Option<usize> {
+        self.trace_capacity
+    }
+
+    /// The maximum number of bytes traced for each packet, if set (by default, the headers and the
+    /// beginning of the payload).
+    pub fn trace_snaplen(&self) -> Option<usize> {
+        self.trace_snaplen
+    }
+
+    /// Whether the IPv4 options of the packets sent by the clients are stripped (instead of
+    /// preserved).
+    pub fn strip_ipv4_options(&self) -> bool {
+        self.strip_ipv4_options
+    }
+
+    /// The delay the idle UDP connections are kept after their idle timeout, if set.
+    pub fn udp_grace_period(&self) -> Option<Duration> {
+        self.udp_grace_period
+    }
+
+    /// The idle timeout of the UDP connections to `destination_port` (before the grace period).
+    pub fn udp_idle_timeout(&self, destination_port: u16) -> Duration {
+        self.udp_port_idle_timeouts
+            .iter()
+            .find(|&&(port, _)| port == destination_port)
+            .map_or(
+                Duration::from_secs(IDLE_TIMEOUT_SECONDS),
+                |&(_, timeout)| timeout,
+            )
+    }
+
+    /// The local address the upstream sockets are bound to, if set.
+    pub fn external_address(&self) -> Option<Ipv4Addr> {
+        self.external_address
+    }
+
+    /// The local addresses assigned to the clients to bind their upstream sockets, if any.
+    pub fn external_address_pool(&self) -> &[Ipv4Addr] {
+        &self.external_address_pool
+    }
+
+    /// Indicate whether the sources of the packets sent by the clients are translated (source NAT).
+    pub fn source_nat(&self) -> bool {
+        self.source_nat
+    }
+
+    /// The configuration used by the client `client_id`, whose upstream sockets are bound to the
+    /// address of the pool assigned to it, if any.
+    pub fn for_client(self: &Rc<Self>, client_id: u32) -> Rc<Self> {
+        if self.external_address_pool.is_empty() {
+            return self.clone();
+        }
+        let index = client_id as usize % self.external_address_pool.len();
+        let mut config = RelayConfig::clone(self);
+        config.external_address = Some(self.external_address_pool[index]);
+        Rc::new(config)
+    }
+
+    /// The capacity (in bytes) of the queue of packets to write to each client, if set.
+    pub fn client_queue_capacity(&self) -> Option<usize> {
+        self.client_queue_capacity
+    }
+
+    pub fn overflow_policy(&self) -> OverflowPolicy {
+        self.overflow_policy
+    }
+
+    /// The mark (`SO_MARK`) set on the upstream sockets, if set.
+    pub fn upstream_fwmark(&self) -> Option<u32> {
+        self.upstream_fwmark
+    }
+
+    /// The maximum number of packets per second accepted from each client, if limited.
+    pub fn max_packet_rate(&self) -> Option<u32> {
+        self.max_packet_rate
+    }
+
+    /// The key the clients must present before sending any packet, if authentication is required.
+    pub fn auth_key(&self) -> Option<&[u8]> {
+        self.auth_key.as_deref()
+    }
+
+    /// The number of retries of a failed upstream TCP connection, and the delay before the first
+    /// one, if enabled.
+    pub fn connect_retries(&self) -> Option<(u32, Duration)> {
+        self.connect_retries
+    }
+
+    /// The number of DNS responses cached for each client, if enabled.
+    pub fn dns_cache_capacity(&self) -> Option<usize> {
+        self.dns_cache_capacity
+    }
+
+    /// The policy rewriting the DSCP of the relayed packets, if any.
+    pub fn dscp_remap(&self) -> Option<&DscpRemap> {
+        self.dscp_remap.as_ref()
+    }
+
+    /// How long a gracefully closed TCP connection lingers in TIME_WAIT, if enabled.
+    pub fn tcp_time_wait(&self) -> Option<Duration> {
+        self.tcp_time_wait
+    }
+
+    /// Whether the packets sent by the clients are only parsed and accounted (never relayed).
+    pub fn monitor_only(&self) -> bool {
+        self.monitor_only
+    }
+
+    pub fn upstream_factory(&self) -> Option<&Rc<dyn UpstreamFactory>> {
+        self.upstream_factory.as_ref()
+    }
+
+    /// The maximum segment size announced to the clients in the SYN-ACK of the TCP connections.
+    pub fn tcp_mss(&self) -> u16 {
+        self.tcp_mss
+    }
+
+    /// How long a connection may live before being closed, whatever its activity, if limited.
+    pub fn max_connection_lifetime(&self) -> Option<Duration> {
+        self.max_connection_lifetime
+    }
+
+    /// Whether the checksums of the packets sent by the clients are verified.
+    pub fn checksum_validation(&self) -> ChecksumValidation {
+        self.checksum_validation
+    }
+
+    /// Whether the checksums are verified at all (even if the packets are relayed anyway).
+    pub fn verify_checksums(&self) -> bool {
+        self.checksum_validation != ChecksumValidation::Off
+    }
+
+    /// Log one dropped packet out of this number, if enabled.
+    pub fn drop_log_sample_rate(&self) -> Option<u32> {
+        self.drop_log_sample_rate
+    }
+
+    /// The maximum number of events received by each poll of the event loop.
+    pub fn events_capacity(&self) -> usize {
+        self.events_capacity
+    }
+
+    pub fn dns_redirect(&self) -> Option<SocketAddrV4> {
+        self.dns_redirect
+    }
+
+    pub fn max_half_open_connections(&self) -> Option<usize> {
+        self.max_half_open_connections
+    }
+
+    /// How long a TCP connection may stay half-open before it is reset.
+    pub fn tcp_handshake_timeout(&self) -> Duration {
+        self.tcp_handshake_timeout
+    }
+
+    pub fn icmp_policy(&self) -> IcmpPolicy {
+        self.icmp_policy
+    }
+
+    pub fn allow_tcp(&self) -> bool {
+        self.allow_tcp
+    }
+
+    pub fn allow_udp(&self) -> bool {
+        self.allow_udp
+    }
+
+    pub fn tunnel_mtu(&self) -> Option<u16> {
+        self.tunnel_mtu
+    }
+
+    pub fn summary_interval(&self) -> Option<Duration> {
+        self.summary_interval
+    }
+}
+
+pub struct RelayConfigBuilder {
+    config: RelayConfig,
+}
+
+impl RelayConfigBuilder {
+    /// Start a configuration for a relay listening for clients on `port` (on localhost).
+    ///
+    /// All the optional features are disabled (the validation of the source addresses is enabled).
+    pub fn new(port: u16) -> Self {
+        Self {
+            config: RelayConfig {
+                port,
+                inspector: None,
+                observer: None,
+                coalescing_window: None,
+                control_port: None,
+                gre_decapsulation: false,
+                dns_override: None,
+                rate_limit: None,
+                destination_rate_limits: Vec::new(),
+                latency: None,
+                packet_loss: None,
+                packet_loss_seed: None,
+                source_validation: true,
+                receive_buffer_size: None,
+                send_buffer_size: None,
+                trace_capacity: None,
+                trace_snaplen: None,
+                strip_ipv4_options: false,
+                udp_grace_period: None,
+                udp_port_idle_timeouts: vec![(
+                    QUIC_PORT,
+                    Duration::from_secs(QUIC_IDLE_TIMEOUT_SECONDS),
+                )],
+                external_address: None,
+                external_address_pool: Vec::new(),
+                source_nat: false,
+                client_queue_capacity: None,
+                overflow_policy: OverflowPolicy::default(),
+                upstream_fwmark: None,
+                max_packet_rate: None,
+                auth_key: None,
+                connect_retries: None,
+                dns_cache_capacity: None,
+                dscp_remap: None,
+                tcp_time_wait: None,
+                monitor_only: false,
+                upstream_factory: None,
+                tcp_mss: MAX_PAYLOAD_LENGTH,
+                max_connection_lifetime: None,
+                checksum_validation: ChecksumValidation::default(),
+                drop_log_sample_rate: None,
+                events_capacity: DEFAULT_EVENTS_CAPACITY,
+                dns_redirect: None,
+                max_half_open_connections: None,
+                tcp_handshake_timeout: Duration::from_secs(HANDSHAKE_TIMEOUT_SECONDS),
+                icmp_policy: IcmpPolicy::default(),
+                allow_tcp: true,
+                allow_udp: true,
+                tunnel_mtu: None,
+                summary_interval: None,
+            },
+        }
+    }
+
+    /// Inspect the payload of every packet sent by the clients before relaying it.
+    pub fn inspector(mut self, inspector: Rc<dyn Inspector>) -> Self {
+        self.config.inspector = Some(inspector);
+        self
+    }
+
+    /// Notify `observer` of the connections opened and closed, and of the packets dropped.
+    pub fn observer(mut self, observer: Rc<dyn Observer>) -> Self {
+        self.config.observer = Some(observer);
+        self
+    }
+
+    /// Batch the small packets sent to the clients during at most `window`, to reduce the number
+    /// of writes on the tunnel. Packets with the PSH flag are always written immediately.
+    pub fn coalescing_window(mut self, window: Duration) -> Self {
+        self.config.coalescing_window = Some(window);
+        self
+    }
+
+    /// Listen on `port` (on localhost) for control commands (`stats`, `handles`, `trace`,
+    /// `reset`, `reset all`).
+    ///
+    /// If `port` is 0, the system chooses it (see `Relay::control_addr()`).
+    pub fn control_port(mut self, port: u16) -> Self {
+        self.config.control_port = Some(port);
+        self
+    }
+
+    /// Relay the IPv4 packets encapsulated in GRE packets (protocol 47) sent by the clients, as if
+    /// they had been sent directly.
+    ///
+    /// The responses are sent back to the client without encapsulation.
+    pub fn gre_decapsulation(mut self, enabled: bool) -> Self {
+        self.config.gre_decapsulation = enabled;
+        self
+    }
+
+    /// Answer the DNS queries sent by the clients (on UDP port 53) from the records of
+    /// `dns_override` instead of their destination, and forward the others to its resolver, if
+    /// any.
+    pub fn dns_override(mut self, dns_override: DnsOverride) -> Self {
+        self.config.dns_override = Some(dns_override);
+        self
+    }
+
+    /// Limit the rate of the data sent to the clients by each connection to `bytes_per_second`.
+    pub fn rate_limit(mut self, bytes_per_second: u32) -> Self {
+        assert!(bytes_per_second > 0, "The rate limit must be positive");
+        self.config.rate_limit = Some(bytes_per_second);
+        self
+    }
+
+    /// Limit the rate of the data sent to the clients by each connection to `destination` to
+    /// `bytes_per_second`, overriding the global rate limit.
+    pub fn destination_rate_limit(mut self, destination: Ipv4Addr, bytes_per_second: u32) -> Self {
+        assert!(bytes_per_second > 0, "The rate limit must be positive");
+        self.config
+            .destination_rate_limits
+            .retain(|&(addr, _)| addr != destination);
+        self.config
+            .destination_rate_limits
+            .push((destination, bytes_per_second));
+        self
+    }
+
+    /// Delay the packets sent by the clients by `delay`, plus a random value up to `jitter`, before
+    /// relaying them, to simulate a high-latency network.
+    ///
+    /// The packets are never reordered.
+    pub fn latency(mut self, delay: Duration, jitter: Duration) -> Self {
+        self.config.latency = Some((delay, jitter));
+        self
+    }
+
+    /// Drop randomly the packets sent by the clients with probability `to_network`, and the UDP
+    /// packets sent to the clients with probability `to_client`, to simulate a lossy network.
+    ///
+    /// TCP packets sent to the clients are never dropped, because the relay does not retransmit
+    /// them.
+    pub fn packet_loss(mut self, to_network: f64, to_client: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&to_network) && (0.0..=1.0).contains(&to_client),
+            "Packet loss probabilities must be between 0 and 1"
+        );
+        self.config.packet_loss = Some((to_network, to_client));
+        self
+    }
+
+    /// Seed the random generator deciding which packets to drop, so that the losses are
+    /// reproducible.
+    pub fn packet_loss_seed(mut self, seed: u64) -> Self {
+        self.config.packet_loss_seed = Some(seed);
+        self
+    }
+
+    /// Drop the packets sent by a client whose source address is outside the subnet it announced
+    /// (enabled by default). It may be disabled for trusted setups.
+    ///
+    /// The packets of the clients which did not announce their address are never dropped.
+    pub fn source_validation(mut self, enabled: bool) -> Self {
+        self.config.source_validation = enabled;
+        self
+    }
+
+    /// Set the size of the kernel receive buffer of the sockets connected to the network, to
+    /// increase the throughput on links with a high bandwidth-delay product.
+    ///
+    /// The kernel may adjust or cap the value.
+    pub fn receive_buffer_size(mut self, bytes: usize) -> Self {
+        self.config.receive_buffer_size = Some(bytes);
+        self
+    }
+
+    /// Set the size of the kernel send buffer of the sockets connected to the network.
+    ///
+    /// The kernel may adjust or cap the value.
+    pub fn send_buffer_size(mut self, bytes: usize) -> Self {
+        self.config.send_buffer_size = Some(bytes);
+        self
+    }
+
+    /// Keep the headers (and the beginning of the payload) of the last `capacity` packets relayed
+    /// for each client in memory, to dump them with the `trace` control command.
+    pub fn trace_capacity(mut self, capacity: usize) -> Self {
+        assert!(capacity > 0, "The trace capacity must be positive");
+        self.config.trace_capacity = Some(capacity);
+        self
+    }
+
+    /// Trace only the first `snaplen` bytes of each packet (headers included), instead of the
+    /// headers and the beginning of the payload. The length of the whole packet is still recorded.
+    pub fn trace_snaplen(mut self, snaplen: usize) -> Self {
+        assert!(snaplen > 0, "The trace snaplen must be positive");
+        self.config.trace_snaplen = Some(snaplen);
+        self
+    }
+
+    /// Strip the IPv4 options of the packets sent by the clients down to a 20-byte header before
+    /// relaying them, so that the packets sent back to the clients have no options either (some
+    /// network paths mishandle them). By default, the options are preserved.
+    pub fn strip_ipv4_options(mut self, enabled: bool) -> Self {
+        self.config.strip_ipv4_options = enabled;
+        self
+    }
+
+    /// Keep the idle UDP connections (and their socket) during `grace` after their idle timeout,
+    /// so that a flow resuming after a pause keeps its source port. They are closed once the grace
+    /// period is over without traffic.
+    pub fn udp_grace_period(mut self, grace: Duration) -> Self {
+        self.config.udp_grace_period = Some(grace);
+        self
+    }
+
+    /// Close the UDP connections to `destination_port` once idle for `timeout` instead of the
+    /// default idle timeout.
+    ///
+    /// The flows to port 443 (QUIC, long-lived like TCP connections) already get a longer idle
+    /// timeout by default.
+    pub fn udp_port_idle_timeout(mut self, destination_port: u16, timeout: Duration) -> Self {
+        assert!(
+            timeout > Duration::from_secs(0),
+            "The UDP idle timeout must be positive"
+        );
+        self.config
+            .udp_port_idle_timeouts
+            .retain(|&(port, _)| port != destination_port);
+        self.config
+            .udp_port_idle_timeouts
+            .push((destination_port, timeout));
+        self
+    }
+
+    /// Bind the upstream sockets to the local `address`, so that the peers see the connections
+    /// coming from this address (on a host with several interfaces). The source ports are
+    /// allocated by the kernel (unless `source_nat` is enabled), and the replies are relayed back
+    /// to the client owning the socket.
+    /// By default, the address is chosen by the kernel according to the routes.
+    pub fn external_address(mut self, address: Ipv4Addr) -> Self {
+        self.config.external_address = Some(address);
+        self
+    }
+
+    /// Bind the upstream sockets of each client to an address of the `pool`, assigned in turn
+    /// from the client id, so that the peers can tell the clients apart. It overrides the
+    /// `external_address` for the clients, and must not be empty.
+    ///
+    /// With `source_nat`, the translated sources of each client are on its assigned address, so
+    /// the replies are restored to the right client even if several allocate the same port.
+    pub fn external_address_pool(mut self, pool: Vec<Ipv4Addr>) -> Self {
+        assert!(
+            !pool.is_empty(),
+            "The external address pool must not be empty"
+        );
+        self.config.external_address_pool = pool;
+        self
+    }
+
+    /// Translate the source of the packets sent by the clients (source NAT): each flow is mapped
+    /// to the external address (if set, otherwise the address chosen by the kernel) and a port
+    /// allocated by the relay, the upstream socket is bound to this translated source, and the
+    /// replies are translated back to the client address and port.
+    ///
+    /// The translated sources are ignored by the `UpstreamFactory`, if any.
+    pub fn source_nat(mut self, enabled: bool) -> Self {
+        self.config.source_nat = enabled;
+        self
+    }
+
+    /// Set the capacity (in bytes) of the queue of packets to write to each client. It must be
+    /// able to store at least one packet of the maximum length.
+    ///
+    /// By default, the capacity is 16 packets of the maximum length.
+    pub fn client_queue_capacity(mut self, capacity: usize) -> Self {
+        assert!(
+            capacity >= MAX_PACKET_LENGTH,
+            "The client queue capacity must be at least {} bytes",
+            MAX_PACKET_LENGTH
+        );
+        self.config.client_queue_capacity = Some(capacity);
+        self
+    }
+
+    /// Choose what to do with the UDP datagrams received from the network while the client queue
+    /// is full. By default, they are dropped (`OverflowPolicy::DropNewest`).
+    pub fn overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.config.overflow_policy = policy;
+        self
+    }
+
+    /// Set the mark `fwmark` (`SO_MARK`) on the upstream sockets, so that the policy routing of the
+    /// host may handle the relayed traffic specially (Linux only, it requires `CAP_NET_ADMIN`).
+    pub fn upstream_fwmark(mut self, fwmark: u32) -> Self {
+        self.config.upstream_fwmark = Some(fwmark);
+        self
+    }
+
+    /// Drop the packets sent by a client beyond `packets_per_second`, to protect the relay from
+    /// floods. Short bursts (up to a tenth of the rate) are accepted.
+    pub fn max_packet_rate(mut self, packets_per_second: u32) -> Self {
+        assert!(packets_per_second > 0, "The packet rate must be positive");
+        self.config.max_packet_rate = Some(packets_per_second);
+        self
+    }
+
+    /// Require the clients to authenticate with the pre-shared `key` (at most 60 bytes) before
+    /// relaying their packets. The clients which send anything else first are disconnected.
+    pub fn auth_key(mut self, key: &[u8]) -> Self {
+        assert!(
+            !key.is_empty() && key.len() <= MAX_KEY_LENGTH,
+            "The key length must be between 1 and {} bytes",
+            MAX_KEY_LENGTH
+        );
+        self.config.auth_key = Some(key.to_vec());
+        self
+    }
+
+    /// Retry a failed upstream TCP connection up to `retries` times before resetting it, waiting
+    /// `initial_backoff` before the first retry and twice as long before each subsequent one.
+    pub fn connect_retries(mut self, retries: u32, initial_backoff: Duration) -> Self {
+        assert!(retries > 0, "The number of retries must be positive");
+        assert!(
+            initial_backoff > Duration::from_secs(0),
+            "The backoff must be positive"
+        );
+        self.config.connect_retries = Some((retries, initial_backoff));
+        self
+    }
+
+    /// Cache the latest `capacity` DNS responses received from upstream for each client, to
+    /// answer the repeated queries locally until their TTL expires.
+    pub fn dns_cache_capacity(mut self, capacity: usize) -> Self {
+        assert!(capacity > 0, "The DNS cache capacity must be positive");
+        self.config.dns_cache_capacity = Some(capacity);
+        self
+    }
+
+    /// Rewrite the DSCP of the packets relayed in both directions according to `dscp_remap`.
+    pub fn dscp_remap(mut self, dscp_remap: DscpRemap) -> Self {
+        self.config.dscp_remap = Some(dscp_remap);
+        self
+    }
+
+    /// Keep a TCP connection in TIME_WAIT for `duration` after it has been closed gracefully, so
+    /// that the late segments of the client are answered by the old connection instead of opening
+    /// a new one.
+    pub fn tcp_time_wait(mut self, duration: Duration) -> Self {
+        assert!(
+            duration > Duration::from_secs(0),
+            "The TIME_WAIT duration must be positive"
+        );
+        self.config.tcp_time_wait = Some(duration);
+        self
+    }
+
+    /// Only monitor the packets sent by the clients: they are parsed, accounted in the metrics and
+    /// traced (if enabled), but no upstream connection is ever created, so nothing is relayed.
+    pub fn monitor_only(mut self, enabled: bool) -> Self {
+        self.config.monitor_only = enabled;
+        self
+    }
+
+    /// Create the upstream sockets of the connections with `upstream_factory` instead of the
+    /// relay's own sockets.
+    pub fn upstream_factory(mut self, upstream_factory: Rc<dyn UpstreamFactory>) -> Self {
+        self.config.upstream_factory = Some(upstream_factory);
+        self
+    }
+
+    /// Announce `mss` as the maximum segment size of the TCP connections to the clients, instead of
+    /// the MTU of the tunnel minus the IPv4 and TCP headers (40 bytes), e.g. to leave room for the
+    /// overhead of an outer tunnel.
+    pub fn tcp_mss(mut self, mss: u16) -> Self {
+        assert!(
+            mss > 0 && mss <= MAX_PAYLOAD_LENGTH,
+            "The TCP MSS must be positive and fit in the MTU"
+        );
+        self.config.tcp_mss = mss;
+        self
+    }
+
+    /// Close every connection `lifetime` after its creation, even if it is still active, to bound
+    /// the resources held by stuck flows.
+    pub fn max_connection_lifetime(mut self, lifetime: Duration) -> Self {
+        assert!(
+            lifetime > Duration::from_secs(0),
+            "The maximum connection lifetime must be positive"
+        );
+        self.config.max_connection_lifetime = Some(lifetime);
+        self
+    }
+
+    /// Verify the IPv4, TCP and UDP checksums of the packets sent by the clients, and drop the
+    /// corrupt ones instead of relaying them (disabled by default, the tunnel being reliable).
+    ///
+    /// It is a shortcut for `checksum_validation(ChecksumValidation::Strict)` (or `Off`).
+    pub fn verify_checksums(self, enabled: bool) -> Self {
+        self.checksum_validation(if enabled {
+            ChecksumValidation::Strict
+        } else {
+            ChecksumValidation::Off
+        })
+    }
+
+    /// Set how strictly the checksums of the packets sent by the clients are validated.
+    ///
+    /// By default, they are not verified.
+    pub fn checksum_validation(mut self, validation: ChecksumValidation) -> Self {
+        self.config.checksum_validation = validation;
+        self
+    }
+
+    /// Log one packet dropped by the router out of `sample_rate` (with the reason of the drop and
+    /// the 5-tuple of the packet), so that the drops remain visible without flooding the logs
+    /// when a client sends many packets to drop. All the drops are counted in the metrics anyway.
+    pub fn drop_log_sample_rate(mut self, sample_rate: u32) -> Self {
+        assert!(sample_rate > 0, "The drop log sample rate must be positive");
+        self.config.drop_log_sample_rate = Some(sample_rate);
+        self
+    }
+
+    /// Receive up to `capacity` events on each poll of the event loop (1024 by default), so that
+    /// a relay handling many connections needs fewer polls (and syscalls) to process them.
+    pub fn events_capacity(mut self, capacity: usize) -> Self {
+        assert!(capacity > 0, "The events capacity must be positive");
+        self.config.events_capacity = capacity;
+        self
+    }
+
+    /// Relay the DNS queries sent by the clients (on UDP port 53) to `resolver`, whatever their
+    /// destination. The responses are sent back to the clients from the original destination.
+    ///
+    /// The resolver of the `dns_override`, if any, takes precedence.
+    pub fn dns_redirect(mut self, resolver: SocketAddrV4) -> Self {
+        self.config.dns_redirect = Some(resolver);
+        self
+    }
+
+    /// Refuse (by a RST) the SYNs of a client which already has `max` TCP connections being opened
+    /// (not established yet), until some of them are established or closed, so that a SYN flood
+    /// does not exhaust the resources of the relay.
+    pub fn max_half_open_connections(mut self, max: usize) -> Self {
+        assert!(
+            max > 0,
+            "The maximum number of half-open connections must be positive"
+        );
+        self.config.max_half_open_connections = Some(max);
+        self
+    }
+
+    /// Reset the TCP connections which are not established within `timeout` (the upstream server
+    /// did not answer, or the client did not acknowledge the SYN-ACK), so that they do not count
+    /// as half-open forever.
+    pub fn tcp_handshake_timeout(mut self, timeout: Duration) -> Self {
+        assert!(
+            timeout > Duration::from_secs(0),
+            "The TCP handshake timeout must be positive"
+        );
+        self.config.tcp_handshake_timeout = timeout;
+        self
+    }
+
+    /// Set what to do with the ICMP echo requests sent by the clients. `IcmpPolicy::Forward`
+    /// requires unprivileged ICMP sockets (on Linux, see the `net.ipv4.ping_group_range` sysctl).
+    ///
+    /// By default, they are dropped.
+    pub fn icmp_policy(mut self, policy: IcmpPolicy) -> Self {
+        self.config.icmp_policy = policy;
+        self
+    }
+
+    /// Enable or disable the relaying of TCP. If disabled, the segments sent by the clients are
+    /// answered by a RST.
+    ///
+    /// By default, TCP is relayed.
+    pub fn allow_tcp(mut self, value: bool) -> Self {
+        self.config.allow_tcp = value;
+        self
+    }
+
+    /// Enable or disable the relaying of UDP. If disabled, the datagrams sent by the clients are
+    /// dropped silently (including the DNS queries).
+    ///
+    /// By default, UDP is relayed.
+    pub fn allow_udp(mut self, value: bool) -> Self {
+        self.config.allow_udp = value;
+        self
+    }
+
+    /// Consider that the tunnel cannot carry packets longer than `mtu`: a longer packet sent by a
+    /// client with the "don't fragment" flag is dropped and answered by an ICMP "fragmentation
+    /// needed" announcing `mtu`, so that its path MTU discovery converges.
+    ///
+    /// The packets without the flag are relayed whatever their length, and the longer packets sent
+    /// to the client without the flag are fragmented.
+    pub fn tunnel_mtu(mut self, mtu: u16) -> Self {
+        assert!(
+            mtu >= MIN_MTU && mtu as usize <= MAX_PACKET_LENGTH,
+            "The tunnel MTU must be between {} and {}",
+            MIN_MTU,
+            MAX_PACKET_LENGTH
+        );
+        self.config.tunnel_mtu = Some(mtu);
+        self
+    }
+
+    /// Emit a summary of the connections (active count, top talkers and drops) every `interval`,
+    /// to the log and to the `Observer`.
+    pub fn summary_interval(mut self, interval: Duration) -> Self {
+        assert!(
+            interval > Duration::from_secs(0),
+            "The summary interval must be positive"
+        );
+        self.config.summary_interval = Some(interval);
+        self
+    }
+
+    pub fn build(self) -> RelayConfig {
+        self.config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::connection::ConnectionId;
+    use crate::relay::inspector::Verdict;
+    use crate::relay::json_lines_sink::JsonLinesSink;
+    use std::io;
+    use std::net::{TcpStream, UdpSocket};
+
+    struct LoopbackFactory;
+
+    impl UpstreamFactory for LoopbackFactory {
+        fn connect_tcp(&self, destination: SocketAddrV4) -> io::Result<TcpStream> {
+            TcpStream::connect(destination)
+        }
+
+        fn bind_udp(&self, _: SocketAddrV4) -> io::Result<UdpSocket> {
+            UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        }
+    }
+
+    #[test]
+    fn defaults() {
+        let config = RelayConfigBuilder::new(31416).build();
+        assert_eq!(31416, config.port());
+        assert!(config.inspector().is_none());
+        assert!(config.observer().is_none());
+        assert!(config.coalescing_window().is_none());
+        assert!(config.control_port().is_none());
+        assert!(!config.gre_decapsulation());
+        assert!(config.dns_override().is_none());
+        assert!(config.rate_limit(Ipv4Addr::new(1, 2, 3, 4)).is_none());
+        assert!(config.latency().is_none());
+        assert!(config.packet_loss().is_none());
+        assert!(config.packet_loss_seed().is_none());
+        assert!(config.source_validation());
+        assert!(config.receive_buffer_size().is_none());
+        assert!(config.send_buffer_size().is_none());
+        assert!(config.trace_capacity().is_none());
+        assert!(config.trace_snaplen().is_none());
+        assert!(!config.strip_ipv4_options());
+        assert!(config.udp_grace_period().is_none());
+        assert_eq!(
+            Duration::from_secs(IDLE_TIMEOUT_SECONDS),
+            config.udp_idle_timeout(53)
+        );
+        assert_eq!(
+            Duration::from_secs(QUIC_IDLE_TIMEOUT_SECONDS),
+            config.udp_idle_timeout(443)
+        );
+        assert!(config.external_address().is_none());
+        assert!(config.external_address_pool().is_empty());
+        assert!(!config.source_nat());
+        assert!(config.client_queue_capacity().is_none());
+        assert_eq!(OverflowPolicy::DropNewest, config.overflow_policy());
+        assert!(config.upstream_fwmark().is_none());
+        assert!(config.max_packet_rate().is_none());
+        assert!(config.auth_key().is_none());
+        assert!(config.connect_retries().is_none());
+        assert!(config.dns_cache_capacity().is_none());
+        assert!(config.dscp_remap().is_none());
+        assert!(config.tcp_time_wait().is_none());
+        assert!(!config.monitor_only());
+        assert!(config.upstream_factory().is_none());
+        assert_eq!(MAX_PAYLOAD_LENGTH, config.tcp_mss());
+        assert!(config.max_connection_lifetime().is_none());
+        assert!(!config.verify_checksums());
+        assert_eq!(ChecksumValidation::Off, config.checksum_validation());
+        assert!(config.drop_log_sample_rate().is_none());
+        assert_eq!(DEFAULT_EVENTS_CAPACITY, config.events_capacity());
+        assert!(config.dns_redirect().is_none());
+        assert!(config.max_half_open_connections().is_none());
+        assert_eq!(
+            Duration::from_secs(HANDSHAKE_TIMEOUT_SECONDS),
+            config.tcp_handshake_timeout()
+        );
+        assert_eq!(IcmpPolicy::Drop, config.icmp_policy());
+        assert!(config.allow_tcp());
+        assert!(config.allow_udp());
+        assert!(config.tunnel_mtu().is_none());
+        assert!(config.summary_interval().is_none());
+    }
+
+    #[test]
+    fn build_config() {
+        let inspector = |_: &ConnectionId, _: &[u8]| Verdict::Accept;
+        let config = RelayConfigBuilder::new(1234)
+            .inspector(Rc::new(inspector))
+            .observer(Rc::new(JsonLinesSink::new(Box::new(io::sink()))))
+            .coalescing_window(Duration::from_millis(5))
+            .control_port(4321)
+            .gre_decapsulation(true)
+            .dns_override(DnsOverride::new())
+            .latency(Duration::from_millis(200), Duration::from_millis(20))
+            .packet_loss(0.1, 0.2)
+            .packet_loss_seed(42)
+            .source_validation(false)
+            .receive_buffer_size(1 << 20)
+            .send_buffer_size(1 << 19)
+            .trace_capacity(64)
+            .trace_snaplen(96)
+            .strip_ipv4_options(true)
+            .udp_grace_period(Duration::from_secs(60))
+            .external_address(Ipv4Addr::new(192, 168, 1, 42))
+            .external_address_pool(vec![Ipv4Addr::new(192, 168, 1, 43)])
+            .source_nat(true)
+            .client_queue_capacity(4 * MAX_PACKET_LENGTH)
+            .overflow_policy(OverflowPolicy::BlockUpstream)
+            .upstream_fwmark(0x42)
+            .max_packet_rate(10_000)
+            .auth_key(b"secret")
+            .connect_retries(3, Duration::from_millis(100))
+            .dns_cache_capacity(64)
+            .dscp_remap(DscpRemap::clear_all())
+            .tcp_time_wait(Duration::from_secs(2))
+            .monitor_only(true)
+            .upstream_factory(Rc::new(LoopbackFactory))
+            .tcp_mss(1360)
+            .max_connection_lifetime(Duration::from_secs(3600))
+            .verify_checksums(true)
+            .drop_log_sample_rate(100)
+            .events_capacity(4096)
+            .dns_redirect(SocketAddrV4::new(Ipv4Addr::new(9, 9, 9, 9), 53))
+            .max_half_open_connections(32)
+            .tcp_handshake_timeout(Duration::from_secs(10))
+            .icmp_policy(IcmpPolicy::Synthesize)
+            .allow_tcp(false)
+            .allow_udp(false)
+            .tunnel_mtu(1280)
+            .summary_interval(Duration::from_secs(30))
+            .build();
+        assert_eq!(1234, config.port());
+        assert!(config.inspector().is_some());
+        assert!(config.observer().is_some());
+        assert_eq!(Some(Duration::from_millis(5)), config.coalescing_window());
+        assert_eq!(Some(4321), config.control_port());
+        assert!(config.gre_decapsulation());
+        assert!(config.dns_override().is_some());
+        assert_eq!(
+            Some((Duration::from_millis(200), Duration::from_millis(20))),
+            config.latency()
+        );
+        assert_eq!(Some((0.1, 0.2)), config.packet_loss());
+        assert_eq!(Some(42), config.packet_loss_seed());
+        assert!(!config.source_validation());
+        assert_eq!(Some(1 << 20), config.receive_buffer_size());
+        assert_eq!(Some(1 << 19), config.send_buffer_size());
+        assert_eq!(Some(64), config.trace_capacity());
+        assert_eq!(Some(96), config.trace_snaplen());
+        assert!(config.strip_ipv4_options());
+        assert_eq!(Some(Duration::from_secs(60)), config.udp_grace_period());
+        assert_eq!(
+            Some(Ipv4Addr::new(192, 168, 1, 42)),
+            config.external_address()
+        );
+        assert_eq!(
+            &[Ipv4Addr::new(192, 168, 1, 43)][..],
+            config.external_address_pool()
+        );
+        assert!(config.source_nat());
+        assert_eq!(Some(4 * MAX_PACKET_LENGTH), config.client_queue_capacity());
+        assert_eq!(OverflowPolicy::BlockUpstream, config.overflow_policy());
+        assert_eq!(Some(0x42), config.upstream_fwmark());
+        assert_eq!(Some(10_000), config.max_packet_rate());
+        assert_eq!(Some(&b"secret"[..]), config.auth_key());
+        assert_eq!(
+            Some((3, Duration::from_millis(100))),
+            config.connect_retries()
+        );
+        assert_eq!(Some(64), config.dns_cache_capacity());
+        assert_eq!(Some(&DscpRemap::clear_all()), config.dscp_remap());
+        assert_eq!(Some(Duration::from_secs(2)), config.tcp_time_wait());
+        assert!(config.monitor_only());
+        assert!(config.upstream_factory().is_some());
+        assert_eq!(1360, config.tcp_mss());
+        assert_eq!(
+            Some(Duration::from_secs(3600)),
+            config.max_connection_lifetime()
+        );
+        assert!(config.verify_checksums());
+        assert_eq!(ChecksumValidation::Strict, config.checksum_validation());
+        assert_eq!(Some(100), config.drop_log_sample_rate());
+        assert_eq!(4096, config.events_capacity());
+        assert_eq!(
+            Some(SocketAddrV4::new(Ipv4Addr::new(9, 9, 9, 9), 53)),
+            config.dns_redirect()
+        );
+        assert_eq!(Some(32), config.max_half_open_connections());
+        assert_eq!(Duration::from_secs(10), config.tcp_handshake_timeout());
+        assert_eq!(IcmpPolicy::Synthesize, config.icmp_policy());
+        assert!(!config.allow_tcp());
+        assert!(!config.allow_udp());
+        assert_eq!(Some(1280), config.tunnel_mtu());
+        assert_eq!(Some(Duration::from_secs(30)), config.summary_interval());
+    }
+
+    #[test]
+    fn override_rate_limit_per_destination() {
+        let config = RelayConfigBuilder::new(1234)
+            .rate_limit(10_000)
+            .destination_rate_limit(Ipv4Addr::new(1, 2, 3, 4), 500)
+            .destination_rate_limit(Ipv4Addr::new(1, 2, 3, 4), 1000)
+            .build();
+        assert_eq!(Some(1000), config.rate_limit(Ipv4Addr::new(1, 2, 3, 4)));
+        assert_eq!(Some(10_000), config.rate_limit(Ipv4Addr::new(5, 6, 7, 8)));
+
+        let config = RelayConfigBuilder::new(1234)
+            .destination_rate_limit(Ipv4Addr::new(1, 2, 3, 4), 500)
+            .build();
+        assert!(config.rate_limit(Ipv4Addr::new(5, 6, 7, 8)).is_none());
+    }
+
+    #[test]
+    fn override_udp_idle_timeout_per_port() {
+        let config = RelayConfigBuilder::new(1234)
+            .udp_port_idle_timeout(5353, Duration::from_secs(10))
+            .udp_port_idle_timeout(443, Duration::from_secs(600))
+            .build();
+        assert_eq!(Duration::from_secs(10), config.udp_idle_timeout(5353));
+        assert_eq!(Duration::from_secs(600), config.udp_idle_timeout(443));
+        assert_eq!(
+            Duration::from_secs(IDLE_TIMEOUT_SECONDS),
+            config.udp_idle_timeout(53)
+        );
+    }
+
+    #[test]
+    fn assign_external_address_per_client() {
+        let config = Rc::new(
+            RelayConfigBuilder::new(1234)
+                .external_address(Ipv4Addr::new(192, 168, 1, 42))
+                .external_address_pool(vec![
+                    Ipv4Addr::new(192, 168, 1, 43),
+                    Ipv4Addr::new(192, 168, 1, 44),
+                ])
+                .build(),
+        );
+        assert_eq!(
+            Some(Ipv4Addr::new(192, 168, 1, 43)),
+            config.for_client(0).external_address()
+        );
+        assert_eq!(
+            Some(Ipv4Addr::new(192, 168, 1, 44)),
+            config.for_client(1).external_address()
+        );
+        assert_eq!(
+            Some(Ipv4Addr::new(192, 168, 1, 43)),
+            config.for_client(2).external_address()
+        );
+
+        // without a pool, the clients share the configuration
+        let config = Rc::new(RelayConfigBuilder::new(1234).build());
+        assert!(Rc::ptr_eq(&config, &config.for_client(1)));
+    }
+}
@@ -0,0 +1,122 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::cmp;
+
+// initial window, in segments (RFC 6928)
+const INITIAL_WINDOW_SEGMENTS: u32 = 10;
+// the window never shrinks below this number of segments
+const MIN_WINDOW_SEGMENTS: u32 = 2;
+
+/// Limit the number of bytes in flight toward the client, to avoid overwhelming a slow tunnel.
+///
+/// The window grows exponentially on ACKs (slow start) until it reaches the threshold, then
+/// linearly (congestion avoidance), as described in RFC 5681. It is halved on timeout.
+pub struct CongestionWindow {
+    mss: u32,
+    cwnd: u32,
+    ssthresh: u32,
+    // bytes acknowledged during congestion avoidance, not accounted in the window yet
+    acked: u32,
+}
+
+impl CongestionWindow {
+    pub fn new(mss: u32) -> Self {
+        assert!(mss > 0, "The MSS must be positive");
+        Self {
+            mss,
+            cwnd: INITIAL_WINDOW_SEGMENTS * mss,
+            ssthresh: u32::MAX,
+            acked: 0,
+        }
+    }
+
+    /// The maximum number of bytes in flight.
+    pub fn size(&self) -> u32 {
+        self.cwnd
+    }
+
+    /// Grow the window when the client acknowledges `bytes` new bytes.
+    pub fn on_ack(&mut self, bytes: u32) {
+        if self.cwnd < self.ssthresh {
+            // slow start
+            self.cwnd = self.cwnd.saturating_add(cmp::min(bytes, self.mss));
+        } else {
+            // congestion avoidance: about one segment per window acknowledged
+            self.acked = self.acked.saturating_add(bytes);
+            if self.acked >= self.cwnd {
+                self.acked -= self.cwnd;
+                self.cwnd = self.cwnd.saturating_add(self.mss);
+            }
+        }
+    }
+
+    /// Shrink the window when the data in flight are not acknowledged in time.
+    pub fn on_timeout(&mut self) {
+        self.ssthresh = cmp::max(self.cwnd / 2, MIN_WINDOW_SEGMENTS * self.mss);
+        self.cwnd = self.ssthresh;
+        self.acked = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSS: u32 = 1000;
+
+    #[test]
+    fn slow_start() {
+        let mut cwnd = CongestionWindow::new(MSS);
+        assert_eq!(10 * MSS, cwnd.size());
+        // each ACK of a full segment grows the window by one segment
+        for _ in 0..10 {
+            cwnd.on_ack(MSS);
+        }
+        assert_eq!(20 * MSS, cwnd.size());
+        // but never by more than one segment per ACK
+        cwnd.on_ack(5 * MSS);
+        assert_eq!(21 * MSS, cwnd.size());
+    }
+
+    #[test]
+    fn timeout_halves_window() {
+        let mut cwnd = CongestionWindow::new(MSS);
+        cwnd.on_timeout();
+        assert_eq!(5 * MSS, cwnd.size());
+        cwnd.on_timeout();
+        assert_eq!(2 * MSS + MSS / 2, cwnd.size());
+        // never below the minimum
+        cwnd.on_timeout();
+        assert_eq!(2 * MSS, cwnd.size());
+    }
+
+    #[test]
+    fn congestion_avoidance() {
+        let mut cwnd = CongestionWindow::new(MSS);
+        cwnd.on_timeout();
+        assert_eq!(5 * MSS, cwnd.size());
+        // steady ACKs of a whole window grow it by one segment
+        for _ in 0..5 {
+            cwnd.on_ack(MSS);
+        }
+        assert_eq!(6 * MSS, cwnd.size());
+        for _ in 0..6 {
+            cwnd.on_ack(MSS);
+        }
+        assert_eq!(7 * MSS, cwnd.size());
+    }
+}
@@ -14,19 +14,30 @@
  * limitations under the License.
  */
 
+use byteorder::{BigEndian, ByteOrder};
 use std::fmt;
 use std::net::SocketAddrV4;
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
 
 use super::client::ClientChannel;
 use super::ipv4_header::{Ipv4HeaderData, Protocol};
 use super::ipv4_packet::Ipv4Packet;
 use super::net;
-use super::selector::Selector;
+use super::observer::CloseReason;
+use super::selector::{Selector, TimerId};
+#[cfg(unix)]
+use super::tcp_connection::TcpConnectionState;
 use super::transport_header::TransportHeaderData;
 
 const LOCALHOST_FORWARD: u32 = 0x0A_00_02_02; // 10.0.2.2
 const LOCALHOST: u32 = 0x7F_00_00_01; // 127.0.0.1
 
+// FNV-1a (64 bits) parameters
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
 pub trait Connection {
     fn id(&self) -> &ConnectionId;
     fn send_to_network(
@@ -35,9 +46,175 @@ pub trait Connection {
         client_channel: &mut ClientChannel,
         ipv4_packet: &Ipv4Packet,
     );
-    fn close(&mut self, selector: &mut Selector);
-    fn is_expired(&self) -> bool;
-    fn is_closed(&self) -> bool;
+    fn close(&mut self, selector: &mut Selector, reason: CloseReason);
+    /// Whether the connection has timed out at `now` (according to the clock of the selector).
+    fn is_expired(&self, now: Instant) -> bool;
+    /// Why the connection has been closed, or `None` if it is still open.
+    fn close_reason(&self) -> Option<CloseReason>;
+    fn stats(&self) -> &ConnectionStats;
+    fn stats_mut(&mut self) -> &mut ConnectionStats;
+    /// Keep the timer closing the connection at its maximum lifetime, to cancel it on close.
+    fn set_lifetime_timer(&mut self, timer: TimerId);
+
+    /// Whether the connection is still being opened (a TCP connection not established yet).
+    fn is_half_open(&self) -> bool {
+        false
+    }
+
+    /// The smoothed round-trip time to the client, if it is measured.
+    fn rtt_estimate(&self) -> Option<Duration> {
+        None
+    }
+
+    /// The last time a packet has been relayed in either direction, if it is tracked.
+    fn last_activity(&self) -> Option<Instant> {
+        None
+    }
+
+    /// The state of the connection and its socket, to hand it off to another relay process, if
+    /// supported.
+    #[cfg(unix)]
+    fn handoff(&self, _now: Instant) -> Option<(TcpConnectionState, RawFd)> {
+        None
+    }
+}
+
+/// Traffic counters of a single connection (the payload lengths are counted).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+    pub packets_to_network: u64,
+    pub bytes_to_network: u64,
+    pub packets_to_client: u64,
+    pub bytes_to_client: u64,
+}
+
+impl ConnectionStats {
+    pub fn count_to_network(&mut self, payload_length: usize) {
+        self.packets_to_network += 1;
+        self.bytes_to_network += payload_length as u64;
+    }
+
+    pub fn count_to_client(&mut self, payload_length: usize) {
+        self.packets_to_client += 1;
+        self.bytes_to_client += payload_length as u64;
+    }
+
+    pub fn reset(&mut self) {
+        *self = Default::default();
+    }
+}
+
+/// Snapshot of a connection, as seen when the connections of a router are queried.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub id: ConnectionId,
+    pub stats: ConnectionStats,
+    pub rtt_estimate: Option<Duration>,
+    /// How long no packet has been relayed, if the activity of the connection is tracked.
+    pub idle: Option<Duration>,
+    pub half_open: bool,
+}
+
+/// The 5-tuple of a flow (protocol, source and destination addresses and ports), to be used as a
+/// key in maps or sets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    protocol: Protocol,
+    source: SocketAddrV4,
+    destination: SocketAddrV4,
+}
+
+impl FlowKey {
+    pub fn from_headers(
+        ipv4_header_data: &Ipv4HeaderData,
+        transport_header_data: &TransportHeaderData,
+    ) -> Self {
+        Self {
+            protocol: ipv4_header_data.protocol(),
+            source: net::to_socket_addr(
+                ipv4_header_data.source(),
+                transport_header_data.source_port(),
+            ),
+            destination: net::to_socket_addr(
+                ipv4_header_data.destination(),
+                transport_header_data.destination_port(),
+            ),
+        }
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    pub fn source(&self) -> SocketAddrV4 {
+        self.source
+    }
+
+    /// The same flow from another source (e.g. translated by the source NAT).
+    pub fn with_source(self, source: SocketAddrV4) -> Self {
+        Self { source, ..self }
+    }
+
+    /// A hash of the 5-tuple to spread the flows over several paths (ECMP-style): the same flow
+    /// always maps to the same value, independently of the process and of the Rust version
+    /// (unlike the `Hash` implementation, meant for maps).
+    #[allow(dead_code)]
+    pub fn hash_ecmp(&self) -> u64 {
+        let mut bytes = [0; 13];
+        bytes[0] = self.protocol.number();
+        bytes[1..5].copy_from_slice(&self.source.ip().octets());
+        BigEndian::write_u16(&mut bytes[5..7], self.source.port());
+        bytes[7..11].copy_from_slice(&self.destination.ip().octets());
+        BigEndian::write_u16(&mut bytes[11..13], self.destination.port());
+        let hash = bytes.iter().fold(FNV_OFFSET_BASIS, |hash, &byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+        });
+        mix(hash)
+    }
+}
+
+// the finalizer of MurmurHash3, so that the low bits (used to pick a path by modulo) depend on all
+// the input bits
+fn mix(mut hash: u64) -> u64 {
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ hash >> 33
+}
+
+/// Read the 5-tuple of a TCP or UDP packet directly from the raw bytes, without parsing the
+/// headers.
+///
+/// Return `None` if the packet is not a TCP or UDP over IPv4 packet, or if it is too short to
+/// contain the ports.
+#[allow(dead_code)]
+pub fn peek_flow(raw: &[u8]) -> Option<FlowKey> {
+    if raw.len() < 20 || raw[0] >> 4 != 4 {
+        return None;
+    }
+    let protocol = match raw[9] {
+        6 => Protocol::Tcp,
+        17 => Protocol::Udp,
+        _ => return None,
+    };
+    let header_length = ((raw[0] & 0xf) as usize) * 4;
+    // the ports are the first 4 bytes of both the TCP and UDP headers
+    if header_length < 20 || raw.len() < header_length + 4 {
+        return None;
+    }
+    let ports = &raw[header_length..header_length + 4];
+    Some(FlowKey {
+        protocol,
+        source: net::to_socket_addr(
+            BigEndian::read_u32(&raw[12..16]),
+            BigEndian::read_u16(&ports[0..2]),
+        ),
+        destination: net::to_socket_addr(
+            BigEndian::read_u32(&raw[16..20]),
+            BigEndian::read_u16(&ports[2..4]),
+        ),
+    })
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -74,10 +251,37 @@ impl ConnectionId {
         }
     }
 
+    /// The id of the same flow from another `source` (e.g. a translated source).
+    pub fn with_source(&self, source: SocketAddrV4) -> Self {
+        let id_string = format!("{} -> {}", source, self.destination());
+        Self {
+            source_ip: u32::from(*source.ip()),
+            source_port: source.port(),
+            id_string,
+            ..self.clone()
+        }
+    }
+
     pub fn protocol(&self) -> Protocol {
         self.protocol
     }
 
+    pub fn flow_key(&self) -> FlowKey {
+        FlowKey {
+            protocol: self.protocol,
+            source: self.source(),
+            destination: self.destination(),
+        }
+    }
+
+    pub fn source(&self) -> SocketAddrV4 {
+        net::to_socket_addr(self.source_ip, self.source_port)
+    }
+
+    pub fn destination(&self) -> SocketAddrV4 {
+        net::to_socket_addr(self.destination_ip, self.destination_port)
+    }
+
     pub fn rewritten_destination(&self) -> SocketAddrV4 {
         let ip = if self.destination_ip == LOCALHOST_FORWARD {
             LOCALHOST
@@ -134,3 +338,150 @@ macro_rules! cx_error {
         log::error!(target: $target, "{}", cx_format!($id, $($arg)+));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::ipv4_packet::Ipv4Packet;
+    use crate::relay::tcp_connection::tests::create_tcp_packet;
+    use std::collections::hash_map::DefaultHasher;
+    use std::collections::HashSet;
+    use std::hash::{Hash, Hasher};
+
+    fn flow_key(raw: &mut [u8]) -> FlowKey {
+        let packet = Ipv4Packet::parse(raw);
+        let (ipv4_header_data, transport_header_data) = packet.headers_data();
+        FlowKey::from_headers(ipv4_header_data, transport_header_data.unwrap())
+    }
+
+    fn hash(key: &FlowKey) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn same_flow_same_key() {
+        // only the 5-tuple matters, not the other fields of the headers
+        let key1 = flow_key(&mut create_tcp_packet(80, 1000, 0, 0, 0, &[]));
+        let key2 = flow_key(&mut create_tcp_packet(80, 2000, 42, 0, 0, b"data"));
+        assert_eq!(key1, key2);
+        assert_eq!(hash(&key1), hash(&key2));
+    }
+
+    #[test]
+    fn different_flows_different_keys() {
+        let mut raw = create_tcp_packet(80, 1000, 0, 0, 0, &[]);
+        let key = flow_key(&mut raw);
+
+        let mut keys = vec![key];
+        keys.push(flow_key(&mut create_tcp_packet(81, 1000, 0, 0, 0, &[])));
+        // swap the source and destination addresses
+        let mut swapped = raw.clone();
+        let (source, destination) = swapped[12..20].split_at_mut(4);
+        source.swap_with_slice(destination);
+        keys.push(flow_key(&mut swapped));
+        // same addresses and ports, but UDP
+        let mut udp = raw.clone();
+        udp[9] = 17;
+        keys.push(flow_key(&mut udp));
+
+        let distinct: HashSet<FlowKey> = keys.iter().cloned().collect();
+        assert_eq!(keys.len(), distinct.len());
+        let hashes: HashSet<u64> = keys.iter().map(hash).collect();
+        assert_eq!(keys.len(), hashes.len());
+    }
+
+    #[test]
+    fn hash_ecmp_is_stable() {
+        let key1 = flow_key(&mut create_tcp_packet(80, 1000, 0, 0, 0, &[]));
+        let key2 = flow_key(&mut create_tcp_packet(80, 2000, 42, 0, 0, b"data"));
+        assert_eq!(key1.hash_ecmp(), key2.hash_ecmp());
+        // the value must never change, the same flow must keep its path across restarts
+        assert_eq!(0x92a3_82ff_060d_b53a, key1.hash_ecmp());
+
+        let key3 = flow_key(&mut create_tcp_packet(81, 1000, 0, 0, 0, &[]));
+        assert_ne!(key1.hash_ecmp(), key3.hash_ecmp());
+    }
+
+    #[test]
+    fn hash_ecmp_distribution() {
+        const PATHS: usize = 8;
+        const FLOWS: usize = 8000;
+        let mut counts = [0; PATHS];
+        for i in 0..FLOWS {
+            let key = FlowKey {
+                protocol: Protocol::Tcp,
+                source: SocketAddrV4::new([10, 0, 0, 2 + (i % 4) as u8].into(), 40000 + i as u16),
+                destination: SocketAddrV4::new([93, 184, 216, 34].into(), 443),
+            };
+            counts[(key.hash_ecmp() % PATHS as u64) as usize] += 1;
+        }
+        // each path gets its share of flows, within 10%
+        let expected = FLOWS / PATHS;
+        for &count in &counts {
+            assert!(
+                count > expected * 9 / 10 && count < expected * 11 / 10,
+                "{:?}",
+                counts
+            );
+        }
+    }
+
+    #[test]
+    fn peek_tcp_flow() {
+        let mut raw = create_tcp_packet(80, 1000, 0, 0, 0, b"data");
+        assert_eq!(Some(flow_key(&mut raw)), peek_flow(&raw));
+    }
+
+    #[test]
+    fn peek_udp_flow() {
+        let mut raw = create_tcp_packet(53, 1000, 0, 0, 0, &[]);
+        // the ports are at the same offsets in UDP, the other fields are not read
+        raw[9] = 17;
+        let key = peek_flow(&raw).unwrap();
+        assert_eq!(flow_key(&mut raw), key);
+        assert_eq!(Protocol::Udp, key.protocol);
+        assert_eq!(53, key.destination.port());
+    }
+
+    #[test]
+    fn peek_flow_too_short() {
+        let raw = create_tcp_packet(80, 1000, 0, 0, 0, &[]);
+        assert!(peek_flow(&raw[..23]).is_none());
+        assert!(peek_flow(&raw[..10]).is_none());
+        assert!(peek_flow(&raw[..24]).is_some());
+
+        let mut raw = raw;
+        raw[9] = 1; // ICMP
+        assert!(peek_flow(&raw).is_none());
+    }
+
+    #[ignore]
+    #[test]
+    fn bench_peek_flow() {
+        use std::time::Instant;
+        let mut raw = create_tcp_packet(80, 1000, 0, 0, 0, &[0; 1000]);
+
+        let start = Instant::now();
+        for _ in 0..5000000 {
+            assert!(peek_flow(&raw).is_some());
+        }
+        println!("5000000 flows peeked: {}ms", start.elapsed().as_millis());
+
+        let start = Instant::now();
+        for _ in 0..5000000 {
+            flow_key(&mut raw);
+        }
+        println!("5000000 flows parsed: {}ms", start.elapsed().as_millis());
+    }
+
+    #[test]
+    fn connection_id_flow_key() {
+        let mut raw = create_tcp_packet(80, 1000, 0, 0, 0, &[]);
+        let packet = Ipv4Packet::parse(&mut raw);
+        let (ipv4_header_data, transport_header_data) = packet.headers_data();
+        let id = ConnectionId::from_headers(ipv4_header_data, transport_header_data.unwrap());
+        assert_eq!(flow_key(&mut raw), id.flow_key());
+    }
+}
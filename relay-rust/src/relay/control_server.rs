@@ -0,0 +1,439 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use log::*;
+use mio::net::{TcpListener, TcpStream};
+use mio::{Event, PollOpt, Ready, Token};
+use std::cell::RefCell;
+use std::fmt::Write as FmtWrite;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::connection::{ConnectionId, ConnectionInfo, ConnectionStats};
+use super::metrics::{self, Counter, Metrics};
+use super::selector::Selector;
+use super::tunnel_server::TunnelServer;
+
+const TAG: &str = "ControlServer";
+
+// a command line is tiny, a longer line is garbage
+const MAX_LINE_LENGTH: usize = 256;
+
+/// Line-based command socket, listening on localhost, to query the relay at runtime.
+///
+/// Accepted commands:
+///  - `stats`: print the relay-wide counters, then the counters of every connection (with the
+///    round-trip time estimate of the TCP connections, once measured);
+///  - `handles`: print the state of the handles registered in the selector;
+///  - `connections [half-open | idle <seconds> | port <port>]`: print the connections (the
+///    half-open TCP connections, the connections idle for at least `seconds`, or the connections
+///    to the destination `port`), with their counters, their idle duration and their state;
+///  - `trace`: print the last packets relayed for every client (if tracing is enabled);
+///  - `reset`: reset the relay-wide counters (the counters of the connections are preserved);
+///  - `reset all`: reset the relay-wide counters and the counters of the connections.
+pub struct ControlServer {
+    tcp_listener: TcpListener,
+    metrics: Arc<Metrics>,
+    tunnel_server: Rc<RefCell<TunnelServer>>,
+}
+
+struct ControlClient {
+    stream: TcpStream,
+    token: Token,
+    interests: Ready,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    closed: bool,
+    metrics: Arc<Metrics>,
+    tunnel_server: Rc<RefCell<TunnelServer>>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    Stats,
+    Handles,
+    Connections(ConnectionFilter),
+    Trace,
+    Reset { connections: bool },
+}
+
+// the connections printed by the `connections` command
+#[derive(Debug, PartialEq, Eq)]
+enum ConnectionFilter {
+    All,
+    HalfOpen,
+    IdleFor(Duration),
+    Port(u16),
+}
+
+impl Command {
+    fn parse(line: &str) -> Result<Self, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words[..] {
+            ["stats"] => Ok(Command::Stats),
+            ["handles"] => Ok(Command::Handles),
+            ["connections"] => Ok(Command::Connections(ConnectionFilter::All)),
+            ["connections", "half-open"] => Ok(Command::Connections(ConnectionFilter::HalfOpen)),
+            ["connections", "idle", seconds] => match seconds.parse() {
+                Ok(seconds) => Ok(Command::Connections(ConnectionFilter::IdleFor(
+                    Duration::from_secs(seconds),
+                ))),
+                Err(_) => Err(format!("Invalid duration: \"{}\"", seconds)),
+            },
+            ["connections", "port", port] => match port.parse() {
+                Ok(port) => Ok(Command::Connections(ConnectionFilter::Port(port))),
+                Err(_) => Err(format!("Invalid port: \"{}\"", port)),
+            },
+            ["trace"] => Ok(Command::Trace),
+            ["reset"] => Ok(Command::Reset { connections: false }),
+            ["reset", "all"] => Ok(Command::Reset { connections: true }),
+            _ => Err(format!("Unknown command: \"{}\"", line.trim())),
+        }
+    }
+}
+
+impl ConnectionFilter {
+    fn matches(&self, info: &ConnectionInfo) -> bool {
+        match *self {
+            ConnectionFilter::All => true,
+            ConnectionFilter::HalfOpen => info.half_open,
+            ConnectionFilter::IdleFor(duration) => info.idle.is_some_and(|idle| idle >= duration),
+            ConnectionFilter::Port(port) => info.id.destination().port() == port,
+        }
+    }
+}
+
+impl ControlServer {
+    pub fn create(
+        port: u16,
+        selector: &mut Selector,
+        metrics: Arc<Metrics>,
+        tunnel_server: Rc<RefCell<TunnelServer>>,
+    ) -> io::Result<Rc<RefCell<Self>>> {
+        let localhost = Ipv4Addr::new(127, 0, 0, 1).into();
+        let addr = SocketAddr::new(localhost, port);
+        let tcp_listener = TcpListener::bind(&addr)?;
+        let rc = Rc::new(RefCell::new(Self {
+            tcp_listener,
+            metrics,
+            tunnel_server,
+        }));
+
+        let rc2 = rc.clone();
+        // must anotate selector type: https://stackoverflow.com/a/44004103/1987178
+        let handler =
+            move |selector: &mut Selector, event| rc2.borrow_mut().on_ready(selector, event);
+        selector.register(
+            &rc.borrow().tcp_listener,
+            handler,
+            Ready::readable(),
+            PollOpt::edge(),
+        )?;
+        let port = rc.borrow().local_addr()?.port();
+        info!(target: TAG, "Control server listening on port {}", port);
+        Ok(rc)
+    }
+
+    /// The address the control clients connect to (its port is chosen by the system if the
+    /// control port is 0).
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp_listener.local_addr()
+    }
+
+    fn on_ready(&mut self, selector: &mut Selector, _: Event) {
+        // edge-triggered: accept all the pending connections
+        loop {
+            match self.tcp_listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(err) = ControlClient::create(
+                        selector,
+                        stream,
+                        self.metrics.clone(),
+                        self.tunnel_server.clone(),
+                    ) {
+                        error!(target: TAG, "Cannot register control client: {}", err);
+                    } else {
+                        debug!(target: TAG, "New control client accepted");
+                    }
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    error!(target: TAG, "Cannot accept control client: {}", err);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl ControlClient {
+    fn create(
+        selector: &mut Selector,
+        stream: TcpStream,
+        metrics: Arc<Metrics>,
+        tunnel_server: Rc<RefCell<TunnelServer>>,
+    ) -> io::Result<()> {
+        let interests = Ready::readable();
+        let rc = Rc::new(RefCell::new(Self {
+            stream,
+            token: Token(0), // default value, will be set afterwards
+            interests,
+            input: Vec::new(),
+            output: Vec::new(),
+            closed: false,
+            metrics,
+            tunnel_server,
+        }));
+
+        // the selector owns the client: it is dropped once deregistered
+        let rc2 = rc.clone();
+        let handler =
+            move |selector: &mut Selector, event| rc2.borrow_mut().on_ready(selector, event);
+        let mut self_ref = rc.borrow_mut();
+        let token = selector.register(&self_ref.stream, handler, interests, PollOpt::level())?;
+        self_ref.token = token;
+        Ok(())
+    }
+
+    fn on_ready(&mut self, selector: &mut Selector, event: Event) {
+        if self.closed {
+            return;
+        }
+        let ready = event.readiness();
+        if ready.is_readable() {
+            if let Err(err) = self.process_receive(selector) {
+                debug!(target: TAG, "Control client read: {}", err);
+                self.close(selector);
+                return;
+            }
+        }
+        if let Err(err) = self.process_send() {
+            debug!(target: TAG, "Control client write: {}", err);
+            self.close(selector);
+            return;
+        }
+        self.update_interests(selector);
+    }
+
+    fn process_receive(&mut self, selector: &Selector) -> io::Result<()> {
+        let mut buf = [0u8; 512];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "EOF reached"));
+                }
+                Ok(r) => self.input.extend_from_slice(&buf[..r]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+        while let Some(pos) = self.input.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.input.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line).into_owned();
+            if !line.trim().is_empty() {
+                let response = self.execute(selector, &line);
+                self.output.extend_from_slice(response.as_bytes());
+            }
+        }
+        if self.input.len() > MAX_LINE_LENGTH {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Line too long"));
+        }
+        Ok(())
+    }
+
+    fn process_send(&mut self) -> io::Result<()> {
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(w) => {
+                    self.output.drain(..w);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    fn execute(&self, selector: &Selector, line: &str) -> String {
+        match Command::parse(line) {
+            Ok(Command::Stats) => self.stats(),
+            Ok(Command::Handles) => Self::handles(selector),
+            Ok(Command::Connections(filter)) => self.connections(selector.now(), &filter),
+            Ok(Command::Trace) => self.trace(selector.now()),
+            Ok(Command::Reset { connections }) => {
+                self.metrics.reset();
+                if connections {
+                    self.tunnel_server.borrow_mut().reset_connection_stats();
+                }
+                info!(target: TAG, "Counters reset");
+                String::from("OK\n")
+            }
+            Err(err) => format!("ERROR {}\n", err),
+        }
+    }
+
+    fn stats(&self) -> String {
+        let mut result = String::new();
+        for &counter in &Counter::ALL {
+            writeln!(result, "{} {}", counter.name(), self.metrics.get(counter)).unwrap();
+        }
+        for (protocol, packets) in self.metrics.protocols() {
+            let name = metrics::protocol_packets_name(protocol);
+            writeln!(result, "{} {}", name, packets).unwrap();
+        }
+        for (id, stats, rtt) in self.tunnel_server.borrow().connection_stats() {
+            Self::write_connection(&mut result, &id, &stats, rtt);
+            result.push('\n');
+        }
+        result.push_str("OK\n");
+        result
+    }
+
+    fn connections(&self, now: Instant, filter: &ConnectionFilter) -> String {
+        let mut result = String::new();
+        let connections = self
+            .tunnel_server
+            .borrow()
+            .connections_where(now, |info| filter.matches(info));
+        for info in connections {
+            Self::write_connection(&mut result, &info.id, &info.stats, info.rtt_estimate);
+            if let Some(idle) = info.idle {
+                write!(result, " idle_ms={}", idle.as_millis()).unwrap();
+            }
+            if info.half_open {
+                result.push_str(" half_open");
+            }
+            result.push('\n');
+        }
+        result.push_str("OK\n");
+        result
+    }
+
+    fn write_connection(
+        result: &mut String,
+        id: &ConnectionId,
+        stats: &ConnectionStats,
+        rtt: Option<Duration>,
+    ) {
+        write!(
+            result,
+            "connection {} {:?} packets_to_network={} bytes_to_network={} \
+             packets_to_client={} bytes_to_client={}",
+            id,
+            id.protocol(),
+            stats.packets_to_network,
+            stats.bytes_to_network,
+            stats.packets_to_client,
+            stats.bytes_to_client
+        )
+        .unwrap();
+        if let Some(rtt) = rtt {
+            write!(result, " rtt_ms={:.1}", rtt.as_secs_f64() * 1000.0).unwrap();
+        }
+    }
+
+    fn handles(selector: &Selector) -> String {
+        let mut result = String::new();
+        for state in selector.debug_dump() {
+            writeln!(result, "{}", state).unwrap();
+        }
+        result.push_str("OK\n");
+        result
+    }
+
+    fn trace(&self, now: Instant) -> String {
+        let mut result = String::new();
+        for (client_id, entry) in self.tunnel_server.borrow().trace_entries() {
+            let age = now.saturating_duration_since(entry.time());
+            writeln!(
+                result,
+                "client #{} -{}ms {}",
+                client_id,
+                age.as_millis(),
+                entry
+            )
+            .unwrap();
+        }
+        result.push_str("OK\n");
+        result
+    }
+
+    fn update_interests(&mut self, selector: &mut Selector) {
+        let ready = if self.output.is_empty() {
+            Ready::readable()
+        } else {
+            Ready::readable() | Ready::writable()
+        };
+        if self.interests != ready {
+            self.interests = ready;
+            selector
+                .reregister(&self.stream, self.token, ready, PollOpt::level())
+                .expect("Cannot register on poll");
+        }
+    }
+
+    fn close(&mut self, selector: &mut Selector) {
+        self.closed = true;
+        if let Err(err) = selector.deregister(&self.stream, self.token) {
+            warn!(target: TAG, "Cannot deregister control client: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(Ok(Command::Stats), Command::parse("stats\n"));
+        assert_eq!(Ok(Command::Handles), Command::parse("handles\n"));
+        assert_eq!(Ok(Command::Trace), Command::parse("trace\n"));
+        assert_eq!(
+            Ok(Command::Reset { connections: false }),
+            Command::parse("reset\r\n")
+        );
+        assert_eq!(
+            Ok(Command::Reset { connections: true }),
+            Command::parse("  reset   all\n")
+        );
+        assert_eq!(
+            Ok(Command::Connections(ConnectionFilter::All)),
+            Command::parse("connections\n")
+        );
+        assert_eq!(
+            Ok(Command::Connections(ConnectionFilter::HalfOpen)),
+            Command::parse("connections half-open\n")
+        );
+        assert_eq!(
+            Ok(Command::Connections(ConnectionFilter::IdleFor(
+                Duration::from_secs(30)
+            ))),
+            Command::parse("connections idle 30\n")
+        );
+        assert_eq!(
+            Ok(Command::Connections(ConnectionFilter::Port(53))),
+            Command::parse("connections port 53\n")
+        );
+        assert!(Command::parse("connections idle forever\n").is_err());
+        assert!(Command::parse("connections port 65536\n").is_err());
+        assert!(Command::parse("reset everything\n").is_err());
+        assert!(Command::parse("quit\n").is_err());
+    }
+}
@@ -87,6 +87,7 @@ pub mod tests {
     pub struct MockDatagramSocket {
         buf: [u8; MAX_DATAGRAM_LENGTH],
         len: usize,
+        blocked: bool,
     }
 
     impl MockDatagramSocket {
@@ -94,6 +95,7 @@ pub mod tests {
             Self {
                 buf: [0; MAX_DATAGRAM_LENGTH],
                 len: 0,
+                blocked: false,
             }
         }
 
@@ -106,10 +108,18 @@ pub mod tests {
         pub fn data(&self) -> &[u8] {
             &self.buf[..self.len]
         }
+
+        // simulate a full socket buffer: send() fails with WouldBlock
+        pub fn set_blocked(&mut self, blocked: bool) {
+            self.blocked = blocked;
+        }
     }
 
     impl DatagramSender for MockDatagramSocket {
         fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.blocked {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "Blocked"));
+            }
             let len = cmp::min(self.buf.len(), buf.len());
             &mut self.buf[..len].copy_from_slice(&buf[..len]);
             self.len = len;
@@ -72,13 +72,15 @@ impl DatagramBuffer {
             !self.is_empty(),
             "DatagramBuffer.write_to() called while empty"
         );
-        let length = self.read_length() as usize;
-        let source_slice = &self.buf[self.tail..self.tail + length];
-        self.tail += length;
+        let length = BigEndian::read_u16(&self.buf[self.tail..self.tail + HEADER_LENGTH]) as usize;
+        let start = self.tail + HEADER_LENGTH;
+        let source_slice = &self.buf[start..start + length];
+        // on error (typically WouldBlock), keep the datagram to send it later
+        let w = destination.send(source_slice)?;
+        self.tail = start + length;
         if self.tail >= self.circular_buffer_length {
             self.tail = 0;
         }
-        let w = destination.send(source_slice)?;
         if w != length {
             error!(
                 target: TAG,
@@ -115,11 +117,6 @@ impl DatagramBuffer {
         Ok(())
     }
 
-    fn read_length(&mut self) -> u16 {
-        self.tail += 2;
-        BigEndian::read_u16(&self.buf[self.tail - 2..self.tail])
-    }
-
     fn write_length(&mut self, length: u16) {
         BigEndian::write_u16(&mut self.buf[self.head..self.head + 2], length);
         self.head += 2;
@@ -187,6 +184,26 @@ mod tests {
         assert_eq!(read_datagram(&mut datagram_buffer), datagram3);
     }
 
+    #[test]
+    fn keep_datagram_on_blocked_send() {
+        let datagram5 = create_datagram(5);
+        let datagram3 = create_datagram(3);
+        let mut datagram_buffer = DatagramBuffer::new(32);
+        datagram_buffer.read_from(&datagram5).unwrap();
+        datagram_buffer.read_from(&datagram3).unwrap();
+
+        let mut blocked = MockDatagramSocket::new();
+        blocked.set_blocked(true);
+        let err = datagram_buffer.write_to(&mut blocked).unwrap_err();
+        assert_eq!(io::ErrorKind::WouldBlock, err.kind());
+        assert!(!datagram_buffer.is_empty());
+
+        // once unblocked, the queued datagrams are flushed in order
+        assert_eq!(read_datagram(&mut datagram_buffer), datagram5);
+        assert_eq!(read_datagram(&mut datagram_buffer), datagram3);
+        assert!(datagram_buffer.is_empty());
+    }
+
     fn read_datagram(datagram_buffer: &mut DatagramBuffer) -> Vec<u8> {
         let mut mock = MockDatagramSocket::new();
         datagram_buffer.write_to(&mut mock).unwrap();
@@ -0,0 +1,134 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use rand::random;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Hold packets for a configured delay, to simulate the latency of a network.
+///
+/// Each packet is delayed by `delay` plus a random value up to `jitter`. The packets are never
+/// reordered: a packet is not released before the packets pushed before it.
+pub struct DelayQueue {
+    delay: Duration,
+    jitter: Duration,
+    capacity: usize,
+    size: usize,
+    packets: VecDeque<(Instant, Vec<u8>)>,
+}
+
+impl DelayQueue {
+    pub fn new(delay: Duration, jitter: Duration, capacity: usize) -> Self {
+        Self {
+            delay,
+            jitter,
+            capacity,
+            size: 0,
+            packets: VecDeque::new(),
+        }
+    }
+
+    /// Hold a copy of the packet `raw`.
+    ///
+    /// Return `false` if there is not enough space (the packet is dropped).
+    pub fn push(&mut self, raw: &[u8], now: Instant) -> bool {
+        if self.size + raw.len() > self.capacity {
+            return false;
+        }
+        let mut deadline = now + self.delay + self.jitter.mul_f64(random::<f64>());
+        if let Some(&(last_deadline, _)) = self.packets.back() {
+            // keep the packets in order
+            deadline = deadline.max(last_deadline);
+        }
+        self.size += raw.len();
+        self.packets.push_back((deadline, raw.to_vec()));
+        true
+    }
+
+    /// Return the next packet whose delay has expired, if any.
+    pub fn pop_expired(&mut self, now: Instant) -> Option<Vec<u8>> {
+        match self.packets.front() {
+            Some(&(deadline, _)) if deadline <= now => {
+                let (_, raw) = self.packets.pop_front().unwrap();
+                self.size -= raw.len();
+                Some(raw)
+            }
+            _ => None,
+        }
+    }
+
+    /// The delay before the next packet may be released, if any packet is held.
+    pub fn next_timeout(&self, now: Instant) -> Option<Duration> {
+        self.packets
+            .front()
+            .map(|&(deadline, _)| deadline.saturating_duration_since(now))
+    }
+
+    pub fn clear(&mut self) {
+        self.packets.clear();
+        self.size = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DELAY: Duration = Duration::from_millis(100);
+
+    #[test]
+    fn hold_for_delay() {
+        let now = Instant::now();
+        let mut queue = DelayQueue::new(DELAY, Duration::from_millis(0), 1000);
+        assert!(queue.push(&[1, 2, 3], now));
+        assert_eq!(Some(DELAY), queue.next_timeout(now));
+
+        assert!(queue.pop_expired(now).is_none());
+        assert!(queue.pop_expired(now + Duration::from_millis(99)).is_none());
+        assert_eq!(Some(vec![1, 2, 3]), queue.pop_expired(now + DELAY));
+        assert!(queue.next_timeout(now).is_none());
+    }
+
+    #[test]
+    fn jitter_keeps_order() {
+        let jitter = Duration::from_millis(50);
+        let now = Instant::now();
+        let mut queue = DelayQueue::new(DELAY, jitter, 1000);
+        for i in 0..20 {
+            assert!(queue.push(&[i], now + Duration::from_millis(u64::from(i))));
+        }
+        let timeout = queue.next_timeout(now).unwrap();
+        assert!(timeout >= DELAY && timeout <= DELAY + jitter);
+
+        let end = now + Duration::from_millis(20) + DELAY + jitter;
+        for i in 0..20 {
+            assert_eq!(Some(vec![i]), queue.pop_expired(end));
+        }
+        assert!(queue.pop_expired(end).is_none());
+    }
+
+    #[test]
+    fn drop_when_full() {
+        let now = Instant::now();
+        let mut queue = DelayQueue::new(DELAY, Duration::from_millis(0), 5);
+        assert!(queue.push(&[1, 2, 3], now));
+        assert!(!queue.push(&[4, 5, 6], now));
+        assert!(queue.push(&[4, 5], now));
+
+        queue.clear();
+        assert!(queue.push(&[1, 2, 3, 4, 5], now));
+    }
+}
@@ -0,0 +1,332 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use std::net::{IpAddr, SocketAddrV4};
+
+pub const DNS_PORT: u16 = 53;
+
+const HEADER_LENGTH: usize = 12;
+const MAX_NAME_LENGTH: usize = 255;
+
+const FLAG_QR: u16 = 1 << 15;
+const OPCODE_MASK: u16 = 0xf << 11;
+const FLAG_AA: u16 = 1 << 10;
+const FLAG_RD: u16 = 1 << 8;
+const FLAG_RA: u16 = 1 << 7;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+pub const CLASS_IN: u16 = 1;
+
+// pointer to the name of the question, always at offset 12
+const NAME_POINTER: u16 = 0xc000 | HEADER_LENGTH as u16;
+
+const TTL_SECONDS: u32 = 60;
+
+/// The single question of a DNS query (RFC 1035 section 4.1.2).
+#[derive(Debug, PartialEq, Eq)]
+pub struct DnsQuestion {
+    // lowercase, without the trailing dot
+    name: String,
+    qtype: u16,
+    qclass: u16,
+    // index of the end of the question in the message
+    end: usize,
+}
+
+impl DnsQuestion {
+    /// Parse the question of a standard query containing exactly one question.
+    pub fn parse(raw: &[u8]) -> Option<Self> {
+        Self::parse_with_flags(raw, 0)
+    }
+
+    /// Parse the question of the response to a standard query containing exactly one question.
+    pub fn parse_response(raw: &[u8]) -> Option<Self> {
+        Self::parse_with_flags(raw, FLAG_QR)
+    }
+
+    fn parse_with_flags(raw: &[u8], expected_flags: u16) -> Option<Self> {
+        if raw.len() < HEADER_LENGTH {
+            return None;
+        }
+        let flags = BigEndian::read_u16(&raw[2..4]);
+        if flags & (FLAG_QR | OPCODE_MASK) != expected_flags {
+            // not a standard query (or response)
+            return None;
+        }
+        if BigEndian::read_u16(&raw[4..6]) != 1 {
+            return None;
+        }
+
+        let mut name = String::new();
+        let mut index = HEADER_LENGTH;
+        loop {
+            let label_length = *raw.get(index)? as usize;
+            index += 1;
+            if label_length == 0 {
+                break;
+            }
+            if label_length > 63 {
+                // compression pointers are not expected in the question
+                return None;
+            }
+            let label = raw.get(index..index + label_length)?;
+            if !name.is_empty() {
+                name.push('.');
+            }
+            name.extend(label.iter().map(|&c| c.to_ascii_lowercase() as char));
+            if name.len() > MAX_NAME_LENGTH {
+                return None;
+            }
+            index += label_length;
+        }
+        let fields = raw.get(index..index + 4)?;
+        Some(Self {
+            name,
+            qtype: BigEndian::read_u16(&fields[0..2]),
+            qclass: BigEndian::read_u16(&fields[2..4]),
+            end: index + 4,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn qtype(&self) -> u16 {
+        self.qtype
+    }
+
+    pub fn qclass(&self) -> u16 {
+        self.qclass
+    }
+
+    /// The index of the end of the question in the message, where the answers start.
+    pub fn end(&self) -> usize {
+        self.end
+    }
+}
+
+/// Local DNS records, to answer the queries sent by the clients on UDP port 53 without reaching
+/// their destination.
+#[derive(Clone, Debug, Default)]
+pub struct DnsOverride {
+    records: Vec<(String, IpAddr)>,
+    resolver: Option<SocketAddrV4>,
+}
+
+impl DnsOverride {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Answer the A (for an IPv4 address) or AAAA (for an IPv6 address) queries for `name` with
+    /// `addr`.
+    ///
+    /// Several addresses may be added for the same name.
+    pub fn add_record(&mut self, name: &str, addr: IpAddr) {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        self.records.push((name, addr));
+    }
+
+    /// Forward the queries which are not answered locally to `resolver`, instead of the
+    /// destination of the packet.
+    pub fn set_resolver(&mut self, resolver: SocketAddrV4) {
+        self.resolver = Some(resolver);
+    }
+
+    pub fn resolver(&self) -> Option<SocketAddrV4> {
+        self.resolver
+    }
+
+    fn is_overridden(&self, name: &str) -> bool {
+        self.records.iter().any(|(n, _)| n == name)
+    }
+
+    /// Build the response to the DNS message `query`, if the queried name is overridden.
+    ///
+    /// If no address of the queried type is known for the name, the response contains no answer.
+    pub fn answer(&self, query: &[u8]) -> Option<Vec<u8>> {
+        let question = DnsQuestion::parse(query)?;
+        if question.qclass != CLASS_IN
+            || (question.qtype() != TYPE_A && question.qtype() != TYPE_AAAA)
+            || !self.is_overridden(question.name())
+        {
+            return None;
+        }
+
+        let addrs: Vec<&IpAddr> = self
+            .records
+            .iter()
+            .filter(|(name, addr)| {
+                name == question.name()
+                    && match addr {
+                        IpAddr::V4(_) => question.qtype() == TYPE_A,
+                        IpAddr::V6(_) => question.qtype() == TYPE_AAAA,
+                    }
+            })
+            .map(|(_, addr)| addr)
+            .collect();
+
+        // the header and the question are copied from the query, the other sections are dropped
+        let mut response = Vec::with_capacity(question.end + addrs.len() * 28);
+        response.extend_from_slice(&query[..question.end]);
+        let query_flags = BigEndian::read_u16(&query[2..4]);
+        let flags = FLAG_QR | FLAG_AA | (query_flags & FLAG_RD) | FLAG_RA;
+        BigEndian::write_u16(&mut response[2..4], flags);
+        BigEndian::write_u16(&mut response[6..8], addrs.len() as u16); // ANCOUNT
+        BigEndian::write_u16(&mut response[8..10], 0); // NSCOUNT
+        BigEndian::write_u16(&mut response[10..12], 0); // ARCOUNT
+
+        for addr in addrs {
+            response.write_u16::<BigEndian>(NAME_POINTER).unwrap();
+            response.write_u16::<BigEndian>(question.qtype()).unwrap();
+            response.write_u16::<BigEndian>(CLASS_IN).unwrap();
+            response.write_u32::<BigEndian>(TTL_SECONDS).unwrap();
+            match addr {
+                IpAddr::V4(addr) => {
+                    response.write_u16::<BigEndian>(4).unwrap();
+                    response.extend_from_slice(&addr.octets());
+                }
+                IpAddr::V6(addr) => {
+                    response.write_u16::<BigEndian>(16).unwrap();
+                    response.extend_from_slice(&addr.octets());
+                }
+            }
+        }
+        Some(response)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    // build a standard query with recursion desired, and an EDNS OPT record
+    pub fn create_query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut raw = Vec::new();
+        raw.write_u16::<BigEndian>(0x1234).unwrap(); // id
+        raw.write_u16::<BigEndian>(FLAG_RD).unwrap(); // flags
+        raw.write_u16::<BigEndian>(1).unwrap(); // QDCOUNT
+        raw.write_u16::<BigEndian>(0).unwrap(); // ANCOUNT
+        raw.write_u16::<BigEndian>(0).unwrap(); // NSCOUNT
+        raw.write_u16::<BigEndian>(1).unwrap(); // ARCOUNT
+        for label in name.split('.') {
+            raw.write_u8(label.len() as u8).unwrap();
+            raw.extend_from_slice(label.as_bytes());
+        }
+        raw.write_u8(0).unwrap();
+        raw.write_u16::<BigEndian>(qtype).unwrap();
+        raw.write_u16::<BigEndian>(CLASS_IN).unwrap();
+
+        // OPT record
+        raw.write_u8(0).unwrap(); // root name
+        raw.write_u16::<BigEndian>(41).unwrap(); // type OPT
+        raw.write_u16::<BigEndian>(4096).unwrap(); // UDP payload size
+        raw.write_u32::<BigEndian>(0).unwrap(); // extended RCODE and flags
+        raw.write_u16::<BigEndian>(0).unwrap(); // RDLENGTH
+        raw
+    }
+
+    pub fn create_override() -> DnsOverride {
+        let mut dns_override = DnsOverride::new();
+        dns_override.add_record("Example.com.", IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3)));
+        dns_override.add_record("example.com", IpAddr::V4(Ipv4Addr::new(10, 1, 2, 4)));
+        dns_override.add_record("ipv6.example.com", IpAddr::V6(Ipv6Addr::LOCALHOST));
+        dns_override
+    }
+
+    #[test]
+    fn parse_question() {
+        let raw = create_query("www.Example.com", TYPE_AAAA);
+        let question = DnsQuestion::parse(&raw).unwrap();
+        assert_eq!("www.example.com", question.name());
+        assert_eq!(TYPE_AAAA, question.qtype());
+        assert_eq!(12 + 17 + 4, question.end);
+    }
+
+    #[test]
+    fn reject_response() {
+        let mut raw = create_query("example.com", TYPE_A);
+        BigEndian::write_u16(&mut raw[2..4], FLAG_QR);
+        assert!(DnsQuestion::parse(&raw).is_none());
+    }
+
+    #[test]
+    fn reject_truncated_question() {
+        let raw = create_query("example.com", TYPE_A);
+        assert!(DnsQuestion::parse(&raw[..20]).is_none());
+    }
+
+    #[test]
+    fn answer_overridden_name() {
+        let query = create_query("example.com", TYPE_A);
+        let response = create_override().answer(&query).unwrap();
+        let question_end = 12 + 13 + 4;
+        assert_eq!(question_end + 2 * 16, response.len());
+        assert_eq!(0x1234, BigEndian::read_u16(&response[0..2]));
+        assert_eq!(
+            FLAG_QR | FLAG_AA | FLAG_RD | FLAG_RA,
+            BigEndian::read_u16(&response[2..4])
+        );
+        assert_eq!(1, BigEndian::read_u16(&response[4..6])); // QDCOUNT
+        assert_eq!(2, BigEndian::read_u16(&response[6..8])); // ANCOUNT
+        assert_eq!(0, BigEndian::read_u16(&response[10..12])); // ARCOUNT
+        assert_eq!(&query[12..question_end], &response[12..question_end]);
+
+        let answer = &response[question_end..question_end + 16];
+        assert_eq!(NAME_POINTER, BigEndian::read_u16(&answer[0..2]));
+        assert_eq!(TYPE_A, BigEndian::read_u16(&answer[2..4]));
+        assert_eq!(CLASS_IN, BigEndian::read_u16(&answer[4..6]));
+        assert_eq!(TTL_SECONDS, BigEndian::read_u32(&answer[6..10]));
+        assert_eq!(4, BigEndian::read_u16(&answer[10..12]));
+        assert_eq!([10, 1, 2, 3], answer[12..16]);
+        assert_eq!([10, 1, 2, 4], response[question_end + 28..]);
+    }
+
+    #[test]
+    fn answer_ipv6() {
+        let query = create_query("ipv6.example.com", TYPE_AAAA);
+        let response = create_override().answer(&query).unwrap();
+        assert_eq!(1, BigEndian::read_u16(&response[6..8]));
+        assert_eq!(
+            Ipv6Addr::LOCALHOST.octets(),
+            response[response.len() - 16..]
+        );
+    }
+
+    #[test]
+    fn answer_no_data_for_other_address_type() {
+        let query = create_query("example.com", TYPE_AAAA);
+        let response = create_override().answer(&query).unwrap();
+        assert_eq!(0, BigEndian::read_u16(&response[6..8]));
+        assert_eq!(12 + 13 + 4, response.len());
+    }
+
+    #[test]
+    fn do_not_answer_other_names() {
+        let dns_override = create_override();
+        assert!(dns_override
+            .answer(&create_query("www.example.com", TYPE_A))
+            .is_none());
+        // MX query
+        assert!(dns_override
+            .answer(&create_query("example.com", 15))
+            .is_none());
+    }
+}
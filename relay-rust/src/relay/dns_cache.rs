@@ -0,0 +1,280 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, ByteOrder};
+use lru::LruCache;
+use std::cmp;
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant};
+
+use super::dns::{DnsQuestion, CLASS_IN};
+
+const FLAG_TC: u16 = 1 << 9;
+const RCODE_MASK: u16 = 0xf;
+
+// the TTL field of an OPT pseudo-record (EDNS) holds flags
+const TYPE_OPT: u16 = 41;
+
+// do not trust the resolver to keep a response for more than a day
+const MAX_TTL_SECONDS: u32 = 24 * 60 * 60;
+
+// the question name and type
+type Key = (String, u16);
+
+struct Entry {
+    response: Vec<u8>,
+    // the offsets of the TTL fields in the response, to decrease them on each hit
+    ttl_offsets: Vec<usize>,
+    stored: Instant,
+    expiration: Instant,
+}
+
+/// Keep the latest responses to the DNS queries forwarded upstream, to answer the repeated queries
+/// locally until their TTL expires.
+///
+/// Only the successful responses containing at least one answer are cached. Once the capacity is
+/// reached, storing a response evicts the least recently used one (the bookkeeping is delegated to
+/// the `lru` crate).
+pub struct DnsCache {
+    entries: LruCache<Key, Entry>,
+}
+
+impl Entry {
+    fn parse(response: &[u8], now: Instant) -> Option<(Key, Self)> {
+        let question = DnsQuestion::parse_response(response)?;
+        let flags = BigEndian::read_u16(&response[2..4]);
+        if flags & (FLAG_TC | RCODE_MASK) != 0 || question.qclass() != CLASS_IN {
+            // truncated, failed or not an Internet query
+            return None;
+        }
+        let answer_count = BigEndian::read_u16(&response[6..8]) as usize;
+        let record_count = answer_count
+            + BigEndian::read_u16(&response[8..10]) as usize
+            + BigEndian::read_u16(&response[10..12]) as usize;
+
+        let mut ttl_offsets = Vec::with_capacity(record_count);
+        let mut min_ttl = None;
+        let mut index = question.end();
+        for i in 0..record_count {
+            index = skip_name(response, index)?;
+            let fields = response.get(index..index + 10)?;
+            let rtype = BigEndian::read_u16(&fields[0..2]);
+            let ttl = BigEndian::read_u32(&fields[4..8]);
+            let rdlength = BigEndian::read_u16(&fields[8..10]) as usize;
+            if rtype != TYPE_OPT {
+                ttl_offsets.push(index + 4);
+                if i < answer_count {
+                    min_ttl = Some(min_ttl.map_or(ttl, |min_ttl| cmp::min(min_ttl, ttl)));
+                }
+            }
+            index += 10 + rdlength;
+            if index > response.len() {
+                return None;
+            }
+        }
+        let ttl = cmp::min(min_ttl?, MAX_TTL_SECONDS);
+        if ttl == 0 {
+            return None;
+        }
+        let key = (question.name().to_string(), question.qtype());
+        let entry = Self {
+            response: response.to_vec(),
+            ttl_offsets,
+            stored: now,
+            expiration: now + Duration::from_secs(u64::from(ttl)),
+        };
+        Some((key, entry))
+    }
+}
+
+// return the index following the name (possibly compressed) starting at `index`
+fn skip_name(raw: &[u8], mut index: usize) -> Option<usize> {
+    loop {
+        let label_length = *raw.get(index)?;
+        if label_length & 0xc0 == 0xc0 {
+            // a pointer always ends the name
+            index += 2;
+            return if index <= raw.len() {
+                Some(index)
+            } else {
+                None
+            };
+        }
+        if label_length > 63 {
+            return None;
+        }
+        index += 1 + label_length as usize;
+        if label_length == 0 {
+            return Some(index);
+        }
+    }
+}
+
+impl DnsCache {
+    pub fn new(capacity: usize) -> Self {
+        let capacity =
+            NonZeroUsize::new(capacity).expect("The DNS cache capacity must be positive");
+        Self {
+            entries: LruCache::new(capacity),
+        }
+    }
+
+    /// Build the response to the DNS message `query` from the cache, if a response to the same
+    /// question has been stored and is not expired.
+    pub fn lookup(&mut self, query: &[u8], now: Instant) -> Option<Vec<u8>> {
+        let question = DnsQuestion::parse(query)?;
+        if question.qclass() != CLASS_IN {
+            return None;
+        }
+        let key = (question.name().to_string(), question.qtype());
+        // now the most recently used
+        let entry = self.entries.get(&key)?;
+        if now >= entry.expiration {
+            self.entries.pop(&key);
+            return None;
+        }
+
+        let mut response = entry.response.clone();
+        // the response must carry the id of the query
+        response[0..2].copy_from_slice(&query[0..2]);
+        let elapsed = now.duration_since(entry.stored).as_secs() as u32;
+        for &offset in &entry.ttl_offsets {
+            let ttl = BigEndian::read_u32(&response[offset..offset + 4]);
+            BigEndian::write_u32(
+                &mut response[offset..offset + 4],
+                ttl.saturating_sub(elapsed),
+            );
+        }
+        Some(response)
+    }
+
+    /// Store the DNS message `response` received from upstream, if it may be cached.
+    pub fn store(&mut self, response: &[u8], now: Instant) {
+        if let Some((key, entry)) = Entry::parse(response, now) {
+            // replace the previous response to the same question, or evict the least recently used
+            self.entries.put(key, entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::dns::tests::{create_override, create_query};
+
+    const TYPE_A: u16 = 1;
+    const TYPE_AAAA: u16 = 28;
+
+    fn create_response(name: &str, qtype: u16) -> Vec<u8> {
+        create_override()
+            .answer(&create_query(name, qtype))
+            .unwrap()
+    }
+
+    // the TTL of the first answer of a response to a query for "example.com"
+    fn first_ttl(response: &[u8]) -> u32 {
+        // the answer starts after the header (12 bytes) and the question (17 bytes), its TTL after
+        // the name pointer, the type and the class (6 bytes)
+        BigEndian::read_u32(&response[35..39])
+    }
+
+    #[test]
+    fn hit_within_ttl() {
+        let now = Instant::now();
+        let mut cache = DnsCache::new(4);
+        let response = create_response("example.com", TYPE_A);
+        assert_eq!(60, first_ttl(&response));
+        cache.store(&response, now);
+
+        let mut query = create_query("Example.com", TYPE_A);
+        BigEndian::write_u16(&mut query[0..2], 0x4321);
+        let cached = cache.lookup(&query, now + Duration::from_secs(10)).unwrap();
+        assert_eq!(0x4321, BigEndian::read_u16(&cached[0..2]));
+        assert_eq!(&response[2..35], &cached[2..35]);
+        // the TTL is decreased by the time spent in the cache
+        assert_eq!(50, first_ttl(&cached));
+    }
+
+    #[test]
+    fn miss_after_expiration() {
+        let now = Instant::now();
+        let mut cache = DnsCache::new(4);
+        cache.store(&create_response("example.com", TYPE_A), now);
+
+        let query = create_query("example.com", TYPE_A);
+        assert!(cache
+            .lookup(&query, now + Duration::from_secs(60))
+            .is_none());
+        // the expired entry has been removed
+        assert!(cache.entries.is_empty());
+    }
+
+    #[test]
+    fn miss_other_question() {
+        let now = Instant::now();
+        let mut cache = DnsCache::new(4);
+        cache.store(&create_response("example.com", TYPE_A), now);
+        assert!(cache
+            .lookup(&create_query("www.example.com", TYPE_A), now)
+            .is_none());
+        assert!(cache
+            .lookup(&create_query("example.com", TYPE_AAAA), now)
+            .is_none());
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let now = Instant::now();
+        let mut cache = DnsCache::new(2);
+        cache.store(&create_response("example.com", TYPE_A), now);
+        cache.store(&create_response("ipv6.example.com", TYPE_AAAA), now);
+        // make "example.com" the most recently used
+        assert!(cache
+            .lookup(&create_query("example.com", TYPE_A), now)
+            .is_some());
+
+        let mut dns_override = create_override();
+        dns_override.add_record("other.example.com", "10.0.0.1".parse().unwrap());
+        let query = create_query("other.example.com", TYPE_A);
+        cache.store(&dns_override.answer(&query).unwrap(), now);
+
+        assert!(cache.lookup(&query, now).is_some());
+        assert!(cache
+            .lookup(&create_query("example.com", TYPE_A), now)
+            .is_some());
+        assert!(cache
+            .lookup(&create_query("ipv6.example.com", TYPE_AAAA), now)
+            .is_none());
+    }
+
+    #[test]
+    fn do_not_store_uncacheable_messages() {
+        let now = Instant::now();
+        let mut cache = DnsCache::new(4);
+        // a query is not a response
+        cache.store(&create_query("example.com", TYPE_A), now);
+        // a response without any answer
+        cache.store(&create_response("example.com", TYPE_AAAA), now);
+        // a truncated response
+        let mut response = create_response("ipv6.example.com", TYPE_AAAA);
+        response[2] |= (FLAG_TC >> 8) as u8;
+        cache.store(&response, now);
+        // a malformed response
+        let response = create_response("example.com", TYPE_A);
+        cache.store(&response[..response.len() - 1], now);
+        assert!(cache.entries.is_empty());
+    }
+}
@@ -0,0 +1,107 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use log::*;
+use std::cell::Cell;
+
+use super::connection::ConnectionId;
+use super::observer::DropReason;
+
+const TAG: &str = "DropLogger";
+
+/// Log one dropped packet out of `sample_rate`: the first one, then every `sample_rate`-th.
+///
+/// The sampling is deterministic, so that a flood of drops produces a bounded and predictable
+/// amount of logs.
+pub struct DropLogger {
+    sample_rate: u32,
+    drops: Cell<u64>,
+    logged: Cell<u64>,
+}
+
+impl DropLogger {
+    pub fn new(sample_rate: u32) -> Self {
+        assert!(sample_rate > 0, "The drop log sample rate must be positive");
+        Self {
+            sample_rate,
+            drops: Cell::new(0),
+            logged: Cell::new(0),
+        }
+    }
+
+    /// Account the drop of a packet of `id`, and log it if it is sampled.
+    ///
+    /// Return whether it has been logged.
+    pub fn log(&self, id: &ConnectionId, reason: DropReason) -> bool {
+        let drops = self.drops.get();
+        self.drops.set(drops + 1);
+        if !drops.is_multiple_of(u64::from(self.sample_rate)) {
+            return false;
+        }
+        self.logged.set(self.logged.get() + 1);
+        info!(
+            target: TAG,
+            "Packet dropped ({}, {} drops so far): {:?} {}",
+            reason.name(),
+            drops + 1,
+            id.protocol(),
+            id
+        );
+        true
+    }
+
+    #[allow(dead_code)]
+    pub fn logged(&self) -> u64 {
+        self.logged.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::ipv4_packet::Ipv4Packet;
+    use crate::relay::tcp_connection::tests::create_tcp_packet;
+
+    fn create_id() -> ConnectionId {
+        let mut raw = create_tcp_packet(8080, 0, 0, 0, 0, &[]);
+        let packet = Ipv4Packet::parse(&mut raw);
+        let (ipv4_header_data, transport_header_data) = packet.headers_data();
+        ConnectionId::from_headers(ipv4_header_data, transport_header_data.unwrap())
+    }
+
+    #[test]
+    fn log_one_drop_out_of_sample_rate() {
+        let logger = DropLogger::new(10);
+        let id = create_id();
+        let logged: Vec<bool> = (0..25)
+            .map(|_| logger.log(&id, DropReason::RateLimited))
+            .collect();
+        // the first drop is always logged
+        assert!(logged[0]);
+        assert!(logged[1..10].iter().all(|&l| !l));
+        assert!(logged[10]);
+        assert!(logged[20]);
+        assert_eq!(3, logger.logged());
+    }
+
+    #[test]
+    fn log_every_drop() {
+        let logger = DropLogger::new(1);
+        let id = create_id();
+        assert!((0..5).all(|_| logger.log(&id, DropReason::Spoofed)));
+        assert_eq!(5, logger.logged());
+    }
+}
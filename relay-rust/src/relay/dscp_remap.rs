@@ -0,0 +1,162 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use super::ipv4_packet::Ipv4Packet;
+
+// the DSCP is a 6-bit field
+const DSCP_COUNT: usize = 64;
+
+/// Policy rewriting the DSCP of the packets relayed in both directions, typically to clear the
+/// markings penalized by the upstream network.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DscpRemap {
+    // the DSCP replacing each DSCP value
+    table: [u8; DSCP_COUNT],
+}
+
+impl DscpRemap {
+    /// Keep all the markings, except those remapped by `map()`.
+    pub fn new() -> Self {
+        let mut table = [0; DSCP_COUNT];
+        for (dscp, entry) in table.iter_mut().enumerate() {
+            *entry = dscp as u8;
+        }
+        Self { table }
+    }
+
+    /// Clear all the markings (every DSCP is replaced by 0, the default class), except those
+    /// remapped by `map()`.
+    pub fn clear_all() -> Self {
+        Self {
+            table: [0; DSCP_COUNT],
+        }
+    }
+
+    /// Replace the DSCP `from` by `to`.
+    pub fn map(mut self, from: u8, to: u8) -> Self {
+        assert!(
+            from < 64 && to < 64,
+            "Invalid DSCP mapping: {} -> {}",
+            from,
+            to
+        );
+        self.table[from as usize] = to;
+        self
+    }
+
+    pub fn remap(&self, dscp: u8) -> u8 {
+        self.table[dscp as usize]
+    }
+
+    /// Return a copy of the packet with its DSCP remapped, if the policy changes it.
+    pub fn remap_packet(&self, ipv4_packet: &Ipv4Packet) -> Option<Vec<u8>> {
+        let dscp = ipv4_packet.ipv4_header().dscp();
+        let remapped = self.remap(dscp);
+        if remapped == dscp {
+            return None;
+        }
+        let mut raw = ipv4_packet.raw().to_vec();
+        {
+            let mut remapped_packet = Ipv4Packet::parse(&mut raw);
+            remapped_packet.ipv4_header_mut().set_dscp(remapped);
+        }
+        Some(raw)
+    }
+}
+
+impl Default for DscpRemap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+
+    fn create_packet(tos: u8) -> Vec<u8> {
+        let mut raw = Vec::new();
+        raw.write_u8(4u8 << 4 | 5).unwrap();
+        raw.write_u8(tos).unwrap(); // ToS
+        raw.write_u16::<BigEndian>(32).unwrap(); // total length 20 + 8 + 4
+        raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+        raw.write_u8(64).unwrap(); // TTL
+        raw.write_u8(17).unwrap(); // protocol (UDP)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x0a000002).unwrap(); // source address
+        raw.write_u32::<BigEndian>(0x7f000001).unwrap(); // destination address
+
+        raw.write_u16::<BigEndian>(1234).unwrap(); // source port
+        raw.write_u16::<BigEndian>(5678).unwrap(); // destination port
+        raw.write_u16::<BigEndian>(12).unwrap(); // length
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+
+        raw.write_u32::<BigEndian>(0x11223344).unwrap(); // payload
+        Ipv4Packet::parse(&mut raw)
+            .ipv4_header_mut()
+            .update_checksum();
+        raw
+    }
+
+    fn ipv4_checksum_is_valid(raw: &[u8]) -> bool {
+        let mut sum = (0..10)
+            .map(|i| u32::from(BigEndian::read_u16(&raw[2 * i..2 * (i + 1)])))
+            .sum::<u32>();
+        while (sum & !0xffff) != 0 {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        sum == 0xffff
+    }
+
+    #[test]
+    fn map_classes() {
+        let remap = DscpRemap::new().map(46, 0).map(8, 10);
+        assert_eq!(0, remap.remap(46));
+        assert_eq!(10, remap.remap(8));
+        assert_eq!(34, remap.remap(34));
+    }
+
+    #[test]
+    fn clear_all_markings() {
+        let remap = DscpRemap::clear_all().map(26, 26);
+        assert_eq!(0, remap.remap(46));
+        assert_eq!(0, remap.remap(8));
+        assert_eq!(26, remap.remap(26));
+    }
+
+    #[test]
+    fn remap_packet() {
+        let remap = DscpRemap::new().map(46, 10);
+        let mut raw = create_packet(46 << 2 | 0b10); // EF, ECT(0)
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+
+        let mut remapped = remap.remap_packet(&ipv4_packet).unwrap();
+        assert_eq!(10 << 2 | 0b10, remapped[1]);
+        assert!(ipv4_checksum_is_valid(&remapped));
+        let remapped_packet = Ipv4Packet::parse(&mut remapped);
+        assert_eq!(10, remapped_packet.ipv4_header().dscp());
+        assert_eq!([0x11, 0x22, 0x33, 0x44], remapped_packet.payload().unwrap());
+    }
+
+    #[test]
+    fn keep_unchanged_packet() {
+        let remap = DscpRemap::new().map(46, 10);
+        let mut raw = create_packet(8 << 2);
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        assert!(remap.remap_packet(&ipv4_packet).is_none());
+    }
+}
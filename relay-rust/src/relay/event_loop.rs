@@ -0,0 +1,362 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use log::*;
+use mio::net::TcpStream;
+use mio::{Events, PollOpt, Ready, Registration, SetReadiness};
+use std::cell::RefCell;
+use std::cmp;
+use std::io;
+use std::net::{self, SocketAddr};
+use std::rc::Rc;
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::config::RelayConfig;
+use super::control_server::ControlServer;
+#[cfg(unix)]
+use super::handoff::{Handoff, HandoffRequest};
+use super::metrics::Metrics;
+use super::pause_switch::PauseSwitch;
+use super::selector::Selector;
+use super::summary;
+use super::tunnel_server::TunnelServer;
+use super::udp_connection::IDLE_TIMEOUT_SECONDS;
+
+const TAG: &str = "EventLoop";
+const CLEANING_INTERVAL_SECONDS: u64 = 60;
+
+pub const DEFAULT_EVENTS_CAPACITY: usize = 1024;
+
+/// The servers of the relay and their selector, independently of the source of the wakeups.
+///
+/// A backend waits for the selector to be ready (or for `timeout()` to expire), then calls
+/// `poll()` and `dispatch()`.
+pub struct EventLoop {
+    selector: Selector,
+    events: Events,
+    tunnel_server: Rc<RefCell<TunnelServer>>,
+    next_cleaning_deadline: Instant,
+    // woken up by the pause switch
+    _pause_registration: Registration,
+    // woken up by the acceptor thread, for a shard
+    _accept_registration: Option<Registration>,
+    // the address of the control server, if enabled
+    control_addr: Option<SocketAddr>,
+}
+
+impl EventLoop {
+    pub fn create(
+        config: Rc<RelayConfig>,
+        metrics: Arc<Metrics>,
+        pause_switch: Arc<PauseSwitch>,
+    ) -> io::Result<Self> {
+        let mut selector = Self::create_selector(&config)?;
+        let tunnel_server = TunnelServer::create(&mut selector, config.clone(), metrics.clone())?;
+        Self::with_servers(selector, tunnel_server, config, metrics, pause_switch)
+    }
+
+    /// Create the event loop of a relay resuming the clients handed off by another relay process.
+    #[cfg(unix)]
+    pub fn restore(
+        config: Rc<RelayConfig>,
+        metrics: Arc<Metrics>,
+        pause_switch: Arc<PauseSwitch>,
+        handoff: Handoff,
+    ) -> io::Result<Self> {
+        let mut selector = Self::create_selector(&config)?;
+        let tunnel_server =
+            TunnelServer::restore(&mut selector, config.clone(), metrics.clone(), handoff)?;
+        Self::with_servers(selector, tunnel_server, config, metrics, pause_switch)
+    }
+
+    fn create_selector(config: &RelayConfig) -> io::Result<Selector> {
+        let mut selector = Selector::create()?;
+        if let Some(observer) = config.observer() {
+            selector.set_observer(observer.clone());
+        }
+        Ok(selector)
+    }
+
+    // start the servers reporting about the tunnel server, if enabled
+    fn with_servers(
+        mut selector: Selector,
+        tunnel_server: Rc<RefCell<TunnelServer>>,
+        config: Rc<RelayConfig>,
+        metrics: Arc<Metrics>,
+        pause_switch: Arc<PauseSwitch>,
+    ) -> io::Result<Self> {
+        let events_capacity = config.events_capacity();
+        if let Some(interval) = config.summary_interval() {
+            summary::schedule(
+                &mut selector,
+                interval,
+                tunnel_server.clone(),
+                config.clone(),
+                metrics.clone(),
+            );
+        }
+        let control_addr = match config.control_port() {
+            Some(port) => {
+                // the selector keeps it alive
+                let control_server =
+                    ControlServer::create(port, &mut selector, metrics, tunnel_server.clone())?;
+                let control_addr = control_server.borrow().local_addr()?;
+                Some(control_addr)
+            }
+            None => None,
+        };
+        let mut event_loop =
+            Self::with_tunnel_server(selector, tunnel_server, events_capacity, pause_switch, None)?;
+        event_loop.control_addr = control_addr;
+        Ok(event_loop)
+    }
+
+    /// Create the event loop of a shard, handling the clients accepted by another thread.
+    ///
+    /// The accepted clients (with their id) are received from `clients`; the returned
+    /// `SetReadiness` must be set readable after each one is sent.
+    pub fn create_shard(
+        config: Rc<RelayConfig>,
+        metrics: Arc<Metrics>,
+        pause_switch: Arc<PauseSwitch>,
+        clients: Receiver<(u32, net::TcpStream)>,
+    ) -> io::Result<(Self, SetReadiness)> {
+        let mut selector = Self::create_selector(&config)?;
+        let events_capacity = config.events_capacity();
+        let tunnel_server = TunnelServer::create_shard(config.clone(), metrics.clone());
+        if let Some(interval) = config.summary_interval() {
+            summary::schedule(
+                &mut selector,
+                interval,
+                tunnel_server.clone(),
+                config,
+                metrics,
+            );
+        }
+        let (registration, set_readiness) = Registration::new2();
+        let waker = set_readiness.clone();
+        let tunnel_server_rc = tunnel_server.clone();
+        let handler = move |selector: &mut Selector, _| {
+            // acknowledge the wakeup before receiving, so that no client is missed
+            if let Err(err) = waker.set_readiness(Ready::empty()) {
+                error!(target: TAG, "Cannot reset the accept readiness: {}", err);
+            }
+            for (client_id, stream) in clients.try_iter() {
+                let result = TcpStream::from_stream(stream).and_then(|stream| {
+                    tunnel_server_rc
+                        .borrow_mut()
+                        .add_client(selector, client_id, stream)
+                });
+                if let Err(err) = result {
+                    error!(target: TAG, "Cannot add client #{}: {}", client_id, err);
+                }
+            }
+        };
+        selector.register(&registration, handler, Ready::readable(), PollOpt::edge())?;
+        let event_loop = Self::with_tunnel_server(
+            selector,
+            tunnel_server,
+            events_capacity,
+            pause_switch,
+            Some(registration),
+        )?;
+        Ok((event_loop, set_readiness))
+    }
+
+    fn with_tunnel_server(
+        mut selector: Selector,
+        tunnel_server: Rc<RefCell<TunnelServer>>,
+        events_capacity: usize,
+        pause_switch: Arc<PauseSwitch>,
+        accept_registration: Option<Registration>,
+    ) -> io::Result<Self> {
+        let pause_registration = Self::register_pause_switch(
+            &mut selector,
+            pause_switch.clone(),
+            tunnel_server.clone(),
+        )?;
+        // the relay may have been paused before running
+        tunnel_server
+            .borrow_mut()
+            .set_paused(&mut selector, pause_switch.is_paused());
+        // no connection may expire before the UDP idle timeout delay
+        let next_cleaning_deadline = selector.now() + Duration::from_secs(IDLE_TIMEOUT_SECONDS);
+        Ok(Self {
+            selector,
+            events: Events::with_capacity(events_capacity),
+            tunnel_server,
+            next_cleaning_deadline,
+            _pause_registration: pause_registration,
+            _accept_registration: accept_registration,
+            control_addr: None,
+        })
+    }
+
+    /// The address the clients connect to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.tunnel_server.borrow().local_addr()
+    }
+
+    /// The address the control clients connect to, if the control server is enabled.
+    pub fn control_addr(&self) -> Option<SocketAddr> {
+        self.control_addr
+    }
+
+    fn register_pause_switch(
+        selector: &mut Selector,
+        pause_switch: Arc<PauseSwitch>,
+        tunnel_server: Rc<RefCell<TunnelServer>>,
+    ) -> io::Result<Registration> {
+        let (registration, set_readiness) = Registration::new2();
+        let waker = set_readiness.clone();
+        pause_switch.add_waker(set_readiness);
+        let handler = move |selector: &mut Selector, _| {
+            // acknowledge the wakeup before reading the state, so that no change is missed
+            if let Err(err) = waker.set_readiness(Ready::empty()) {
+                error!(target: TAG, "Cannot reset the pause readiness: {}", err);
+            }
+            tunnel_server
+                .borrow_mut()
+                .set_paused(selector, pause_switch.is_paused());
+        };
+        selector.register(&registration, handler, Ready::readable(), PollOpt::edge())?;
+        Ok(registration)
+    }
+
+    #[cfg(all(feature = "tokio", unix))]
+    pub fn selector(&self) -> &Selector {
+        &self.selector
+    }
+
+    /// Poll and dispatch forever (until an error occurs), blocking the current thread.
+    pub fn run(&mut self) -> io::Result<()> {
+        loop {
+            let timeout = self.timeout();
+            self.poll(timeout)?;
+            self.dispatch();
+        }
+    }
+
+    /// Poll and dispatch until a hand-off is requested, then return the state to hand off.
+    #[cfg(unix)]
+    pub fn run_until_handoff(&mut self, request: &HandoffRequest) -> io::Result<Handoff> {
+        let (registration, set_readiness) = Registration::new2();
+        request.add_waker(set_readiness);
+        // the request only needs to interrupt the poll
+        let handler = |_: &mut Selector, _| ();
+        self.selector
+            .register(&registration, handler, Ready::readable(), PollOpt::edge())?;
+        while !request.is_requested() {
+            let timeout = self.timeout();
+            self.poll(timeout)?;
+            self.dispatch();
+        }
+        info!(target: TAG, "Hand-off requested");
+        let now = self.selector.now();
+        self.tunnel_server.borrow_mut().hand_off(now)
+    }
+
+    /// The maximum delay to wait before calling `dispatch()`.
+    pub fn timeout(&self) -> Duration {
+        let timeout = self
+            .next_cleaning_deadline
+            .saturating_duration_since(self.selector.now());
+        match self.selector.next_timer_timeout() {
+            Some(timer_timeout) => cmp::min(timeout, timer_timeout),
+            None => timeout,
+        }
+    }
+
+    /// Poll the selector for at most `timeout`, and return the number of events received.
+    pub fn poll(&mut self, timeout: Duration) -> io::Result<usize> {
+        let selector = &mut self.selector;
+        let events = &mut self.events;
+        retry_on_intr!(selector.poll(events, Some(timeout)))
+    }
+
+    /// Fire the expired timers, clean up the expired connections if necessary, and handle the
+    /// events received by the last `poll()`.
+    pub fn dispatch(&mut self) {
+        let timers_fired = self.selector.run_expired_timers();
+
+        let now = self.selector.now();
+        if now >= self.next_cleaning_deadline {
+            self.tunnel_server.borrow_mut().clean_up(&mut self.selector);
+            self.next_cleaning_deadline = now + Duration::from_secs(CLEANING_INTERVAL_SECONDS);
+        } else if self.events.is_empty() {
+            if timers_fired == 0 {
+                debug!(
+                    target: TAG,
+                    "Spurious wakeup: poll() returned without any event"
+                );
+            }
+            return;
+        }
+
+        self.selector.run_handlers(&self.events);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::RelayConfigBuilder;
+
+    const READY_HANDLES: usize = 64;
+
+    // the number of polls needed by an event loop receiving up to `events_capacity` events per
+    // poll to handle many handles ready at once
+    fn polls_to_handle_all(events_capacity: usize) -> usize {
+        let config = RelayConfigBuilder::new(0)
+            .events_capacity(events_capacity)
+            .build();
+        let pause_switch = Arc::new(PauseSwitch::new());
+        let mut event_loop =
+            EventLoop::create(Rc::new(config), Arc::new(Metrics::new()), pause_switch).unwrap();
+
+        let handled = Rc::new(RefCell::new(0));
+        let mut registrations = Vec::new();
+        for _ in 0..READY_HANDLES {
+            let (registration, set_readiness) = Registration::new2();
+            let handled = handled.clone();
+            let handler = move |_: &mut Selector, _| *handled.borrow_mut() += 1;
+            event_loop
+                .selector
+                .register(&registration, handler, Ready::readable(), PollOpt::edge())
+                .unwrap();
+            set_readiness.set_readiness(Ready::readable()).unwrap();
+            registrations.push((registration, set_readiness));
+        }
+
+        let mut polls = 0;
+        while *handled.borrow() < READY_HANDLES {
+            assert!(polls < 2 * READY_HANDLES);
+            event_loop.poll(Duration::from_secs(1)).unwrap();
+            event_loop.dispatch();
+            polls += 1;
+        }
+        polls
+    }
+
+    #[test]
+    fn fewer_polls_with_larger_batches() {
+        assert_eq!(1, polls_to_handle_all(READY_HANDLES));
+        assert!(polls_to_handle_all(8) >= READY_HANDLES / 8);
+        assert!(polls_to_handle_all(1) >= READY_HANDLES);
+    }
+}
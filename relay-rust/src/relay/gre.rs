@@ -0,0 +1,171 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, ByteOrder};
+
+use super::ipv4_header::{self, PacketClass};
+
+const FLAG_CHECKSUM: u16 = 1 << 15;
+const FLAG_KEY: u16 = 1 << 13;
+const FLAG_SEQUENCE_NUMBER: u16 = 1 << 12;
+const VERSION_MASK: u16 = 0x7;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+
+/// GRE header (RFC 2784), with the optional key and sequence number fields (RFC 2890).
+#[derive(Debug, PartialEq, Eq)]
+pub struct GreHeader {
+    header_length: usize,
+    protocol_type: u16,
+}
+
+impl GreHeader {
+    pub fn parse(raw: &[u8]) -> Option<Self> {
+        if raw.len() < 4 {
+            return None;
+        }
+        let flags_and_version = BigEndian::read_u16(&raw[0..2]);
+        if flags_and_version & VERSION_MASK != 0 {
+            // version 1 is the "enhanced GRE" used by PPTP, not supported
+            return None;
+        }
+        let mut header_length = 4;
+        if flags_and_version & FLAG_CHECKSUM != 0 {
+            // checksum and reserved1 fields
+            header_length += 4;
+        }
+        if flags_and_version & FLAG_KEY != 0 {
+            header_length += 4;
+        }
+        if flags_and_version & FLAG_SEQUENCE_NUMBER != 0 {
+            header_length += 4;
+        }
+        if raw.len() < header_length {
+            return None;
+        }
+        Some(Self {
+            header_length,
+            protocol_type: BigEndian::read_u16(&raw[2..4]),
+        })
+    }
+
+    pub fn header_length(&self) -> usize {
+        self.header_length
+    }
+
+    pub fn protocol_type(&self) -> u16 {
+        self.protocol_type
+    }
+}
+
+/// Return the IPv4 packet encapsulated in the GRE packet `raw` (the payload of the outer IPv4
+/// packet), if any.
+pub fn inner_ipv4_packet(raw: &[u8]) -> Option<&[u8]> {
+    let gre_header = GreHeader::parse(raw)?;
+    if gre_header.protocol_type() != ETHERTYPE_IPV4 {
+        return None;
+    }
+    let inner = &raw[gre_header.header_length()..];
+    let length = match ipv4_header::classify_packet(inner) {
+        PacketClass::Ipv4 { length } => length as usize,
+        _ => return None,
+    };
+    let header_length = (inner[0] & 0xf) as usize * 4;
+    if header_length < 20 || length < header_length || length > inner.len() {
+        // not a full IPv4 packet
+        return None;
+    }
+    Some(&inner[..length])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+
+    fn create_inner_packet() -> Vec<u8> {
+        let mut raw = Vec::new();
+        raw.write_u8(4u8 << 4 | 5).unwrap();
+        raw.write_u8(0).unwrap(); // ToS
+        raw.write_u16::<BigEndian>(32).unwrap(); // total length 20 + 8 + 4
+        raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+        raw.write_u8(0).unwrap(); // TTL
+        raw.write_u8(17).unwrap(); // protocol (UDP)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x0a000001).unwrap(); // source address
+        raw.write_u32::<BigEndian>(0x0a000002).unwrap(); // destination address
+
+        raw.write_u16::<BigEndian>(1234).unwrap(); // source port
+        raw.write_u16::<BigEndian>(5678).unwrap(); // destination port
+        raw.write_u16::<BigEndian>(12).unwrap(); // length
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+
+        raw.write_u32::<BigEndian>(0x11223344).unwrap(); // payload
+        raw
+    }
+
+    fn create_gre_packet(flags: u16) -> Vec<u8> {
+        let mut raw = Vec::new();
+        raw.write_u16::<BigEndian>(flags).unwrap();
+        raw.write_u16::<BigEndian>(ETHERTYPE_IPV4).unwrap();
+        if flags & FLAG_CHECKSUM != 0 {
+            raw.write_u32::<BigEndian>(0).unwrap(); // checksum + reserved1
+        }
+        if flags & FLAG_KEY != 0 {
+            raw.write_u32::<BigEndian>(0xcafe).unwrap(); // key
+        }
+        if flags & FLAG_SEQUENCE_NUMBER != 0 {
+            raw.write_u32::<BigEndian>(42).unwrap(); // sequence number
+        }
+        raw.extend_from_slice(&create_inner_packet());
+        raw
+    }
+
+    #[test]
+    fn parse_gre_header() {
+        let raw = create_gre_packet(0);
+        let gre_header = GreHeader::parse(&raw).unwrap();
+        assert_eq!(4, gre_header.header_length());
+        assert_eq!(ETHERTYPE_IPV4, gre_header.protocol_type());
+    }
+
+    #[test]
+    fn parse_gre_header_with_optional_fields() {
+        let raw = create_gre_packet(FLAG_CHECKSUM | FLAG_KEY | FLAG_SEQUENCE_NUMBER);
+        let gre_header = GreHeader::parse(&raw).unwrap();
+        assert_eq!(16, gre_header.header_length());
+    }
+
+    #[test]
+    fn reject_enhanced_gre() {
+        let raw = create_gre_packet(FLAG_KEY | 1);
+        assert!(GreHeader::parse(&raw).is_none());
+    }
+
+    #[test]
+    fn extract_inner_packet() {
+        let raw = create_gre_packet(FLAG_KEY);
+        let inner = inner_ipv4_packet(&raw).unwrap();
+        assert_eq!(&create_inner_packet()[..], inner);
+    }
+
+    #[test]
+    fn reject_truncated_inner_packet() {
+        let mut raw = create_gre_packet(0);
+        raw.truncate(raw.len() - 1);
+        assert!(inner_ipv4_packet(&raw).is_none());
+    }
+}
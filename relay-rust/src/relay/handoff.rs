@@ -0,0 +1,88 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use log::*;
+use mio::{Ready, SetReadiness};
+use std::os::unix::io::OwnedFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+const TAG: &str = "Handoff";
+
+/// The state of a relay handed off to another relay process, for a live upgrade.
+///
+/// It contains the serialized bookkeeping of the clients and of their TCP connections, and the
+/// sockets they use: the listening socket first, then for each client its tunnel socket followed
+/// by the sockets of its connections. The sockets must be transferred to the other process (e.g.
+/// with `SCM_RIGHTS`) in the same order.
+#[derive(Debug)]
+pub struct Handoff {
+    data: Vec<u8>,
+    fds: Vec<OwnedFd>,
+}
+
+impl Handoff {
+    /// Rebuild a hand-off received from another relay process.
+    pub fn new(data: Vec<u8>, fds: Vec<OwnedFd>) -> Self {
+        Self { data, fds }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn fds(&self) -> &[OwnedFd] {
+        &self.fds
+    }
+
+    pub fn into_parts(self) -> (Vec<u8>, Vec<OwnedFd>) {
+        (self.data, self.fds)
+    }
+}
+
+/// Request a relay running `Relay::run_until_handoff()` to stop and hand off its state.
+///
+/// It may be triggered from any thread.
+#[derive(Default)]
+pub struct HandoffRequest {
+    requested: AtomicBool,
+    // wake up the event loop, once it is running
+    wakers: Mutex<Vec<SetReadiness>>,
+}
+
+impl HandoffRequest {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        for waker in self.wakers.lock().unwrap().iter() {
+            if let Err(err) = waker.set_readiness(Ready::readable()) {
+                error!(target: TAG, "Cannot wake up the event loop: {}", err);
+            }
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Notify `waker` on request, so that its event loop stops.
+    pub fn add_waker(&self, waker: SetReadiness) {
+        self.wakers.lock().unwrap().push(waker);
+    }
+}
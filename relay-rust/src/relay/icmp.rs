@@ -0,0 +1,268 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, ByteOrder};
+use std::cmp;
+
+use super::checksum;
+use super::ipv4_header::{Ipv4HeaderData, Protocol, PROTOCOL_ICMP};
+
+pub const TYPE_ECHO_REPLY: u8 = 0;
+pub const TYPE_DESTINATION_UNREACHABLE: u8 = 3;
+pub const CODE_PORT_UNREACHABLE: u8 = 3;
+pub const CODE_FRAGMENTATION_NEEDED: u8 = 4;
+pub const TYPE_ECHO_REQUEST: u8 = 8;
+
+// type, code, checksum and 4 unused bytes
+const ICMP_HEADER_LENGTH: usize = 8;
+// the error quotes the IPv4 header of the original datagram and its first 8 bytes (RFC 792)
+const QUOTED_PAYLOAD_LENGTH: usize = 8;
+
+/// What to do with the ICMP echo requests (pings) sent by the clients.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IcmpPolicy {
+    /// Drop the requests, like any other ICMP packet.
+    #[default]
+    Drop,
+    /// Reply from the relay itself, as if every destination were reachable.
+    Synthesize,
+    /// Send a real echo request to the destination, and relay its reply.
+    Forward,
+}
+
+/// Return the ICMP message of the IPv4 packet `raw`, if it is an echo request.
+///
+/// A fragment is never considered as an echo request: it cannot be answered without the rest of
+/// the datagram.
+pub fn echo_request_message(raw: &[u8]) -> Option<&[u8]> {
+    let ipv4_header = Ipv4HeaderData::parse(raw);
+    let fragmented = BigEndian::read_u16(&raw[6..8]) & 0x3FFF != 0;
+    if ipv4_header.protocol() != Protocol::Other(PROTOCOL_ICMP) || fragmented {
+        return None;
+    }
+    let message =
+        raw.get(ipv4_header.header_length() as usize..ipv4_header.total_length() as usize)?;
+    if message.len() < ICMP_HEADER_LENGTH || message[0] != TYPE_ECHO_REQUEST {
+        return None;
+    }
+    Some(message)
+}
+
+/// Build an ICMP echo reply packet sent from `source` to `destination`, echoing the identifier,
+/// the sequence number and the data of the echo message `message`.
+pub fn build_echo_reply(source: u32, destination: u32, message: &[u8]) -> Vec<u8> {
+    let mut raw = vec![0; 20 + message.len()];
+    let mut ipv4_header_data = Ipv4HeaderData::init(
+        &mut raw,
+        Protocol::Other(PROTOCOL_ICMP),
+        source,
+        destination,
+        message.len() as u16,
+    );
+
+    {
+        let icmp = &mut raw[20..];
+        icmp.copy_from_slice(message);
+        icmp[0] = TYPE_ECHO_REPLY;
+        icmp[1] = 0;
+        BigEndian::write_u16(&mut icmp[2..4], 0);
+        let checksum = checksum(icmp);
+        BigEndian::write_u16(&mut icmp[2..4], checksum);
+    }
+    ipv4_header_data.bind_mut(&mut raw).update_checksum();
+    raw
+}
+
+/// Build an ICMP "port unreachable" packet replying to the datagram `original` (its IPv4 header
+/// followed by at least the beginning of its payload).
+///
+/// The packet is sent from the destination of the original datagram to its source.
+pub fn build_port_unreachable(original: &[u8]) -> Vec<u8> {
+    build_destination_unreachable(original, CODE_PORT_UNREACHABLE, 0)
+}
+
+/// Build an ICMP "fragmentation needed" packet replying to the datagram `original`, which has the
+/// "don't fragment" flag but exceeds `mtu`, the MTU of the next hop (RFC 1191).
+///
+/// The packet is sent from the destination of the original datagram to its source.
+pub fn build_frag_needed(original: &[u8], mtu: u16) -> Vec<u8> {
+    build_destination_unreachable(original, CODE_FRAGMENTATION_NEEDED, mtu)
+}
+
+// the last 2 bytes of the ICMP header are unused, except for the next-hop MTU of a "fragmentation
+// needed" message
+fn build_destination_unreachable(original: &[u8], code: u8, next_hop_mtu: u16) -> Vec<u8> {
+    let original_header = Ipv4HeaderData::parse(original);
+    let quoted_length = cmp::min(
+        original.len(),
+        original_header.header_length() as usize + QUOTED_PAYLOAD_LENGTH,
+    );
+    let icmp_length = ICMP_HEADER_LENGTH + quoted_length;
+
+    let mut raw = vec![0; 20 + icmp_length];
+    let mut ipv4_header_data = Ipv4HeaderData::init(
+        &mut raw,
+        Protocol::Other(PROTOCOL_ICMP),
+        original_header.destination(),
+        original_header.source(),
+        icmp_length as u16,
+    );
+
+    {
+        let icmp = &mut raw[20..];
+        icmp[0] = TYPE_DESTINATION_UNREACHABLE;
+        icmp[1] = code;
+        BigEndian::write_u16(&mut icmp[6..8], next_hop_mtu);
+        icmp[ICMP_HEADER_LENGTH..].copy_from_slice(&original[..quoted_length]);
+        let checksum = checksum(icmp);
+        BigEndian::write_u16(&mut icmp[2..4], checksum);
+    }
+    ipv4_header_data.bind_mut(&mut raw).update_checksum();
+    raw
+}
+
+// the Internet checksum (RFC 1071) of `data`
+fn checksum(data: &[u8]) -> u16 {
+    !checksum::ones_complement_sum(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+
+    fn create_udp_packet(payload: &[u8]) -> Vec<u8> {
+        let udp_length = 8 + payload.len() as u16;
+        let mut raw = vec![0; 20];
+        Ipv4HeaderData::init(&mut raw, Protocol::Udp, 0x0a000002, 0x7f000001, udp_length);
+        raw.write_u16::<BigEndian>(1234).unwrap(); // source port
+        raw.write_u16::<BigEndian>(5678).unwrap(); // destination port
+        raw.write_u16::<BigEndian>(udp_length).unwrap(); // length
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.extend_from_slice(payload);
+        raw
+    }
+
+    #[test]
+    fn build_port_unreachable_reply() {
+        let original = create_udp_packet(&[1, 2, 3, 4]);
+        let raw = build_port_unreachable(&original);
+        assert_eq!(20 + 8 + 28, raw.len());
+
+        let ipv4_header = Ipv4HeaderData::parse(&raw);
+        assert_eq!(Protocol::Other(PROTOCOL_ICMP), ipv4_header.protocol());
+        assert_eq!(56, ipv4_header.total_length());
+        assert_eq!(0x7f000001, ipv4_header.source());
+        assert_eq!(0x0a000002, ipv4_header.destination());
+
+        let icmp = &raw[20..];
+        assert_eq!(TYPE_DESTINATION_UNREACHABLE, icmp[0]);
+        assert_eq!(CODE_PORT_UNREACHABLE, icmp[1]);
+        assert_eq!(0, checksum(icmp));
+        // the original IPv4 header and UDP header, without the rest of the payload
+        assert_eq!(&original[..28], &icmp[8..]);
+        // the IPv4 header checksum is valid
+        assert_eq!(0, checksum(&raw[..20]));
+    }
+
+    #[test]
+    fn quote_short_datagram() {
+        let original = create_udp_packet(&[]);
+        let raw = build_port_unreachable(&original[..24]);
+        assert_eq!(20 + 8 + 24, raw.len());
+        assert_eq!(&original[..24], &raw[28..]);
+    }
+
+    #[test]
+    fn build_frag_needed_reply() {
+        let original = create_udp_packet(&[0; 1400]);
+        let raw = build_frag_needed(&original, 1280);
+        assert_eq!(20 + 8 + 28, raw.len());
+
+        let ipv4_header = Ipv4HeaderData::parse(&raw);
+        assert_eq!(0x7f000001, ipv4_header.source());
+        assert_eq!(0x0a000002, ipv4_header.destination());
+        assert_eq!(0, checksum(&raw[..20]));
+
+        let icmp = &raw[20..];
+        assert_eq!(TYPE_DESTINATION_UNREACHABLE, icmp[0]);
+        assert_eq!(CODE_FRAGMENTATION_NEEDED, icmp[1]);
+        assert_eq!(0, BigEndian::read_u16(&icmp[4..6])); // unused
+        assert_eq!(1280, BigEndian::read_u16(&icmp[6..8])); // next-hop MTU
+        assert_eq!(0, checksum(icmp));
+        assert_eq!(&original[..28], &icmp[8..]);
+    }
+
+    fn create_echo_request(data: &[u8]) -> Vec<u8> {
+        let icmp_length = ICMP_HEADER_LENGTH as u16 + data.len() as u16;
+        let mut raw = vec![0; 20];
+        let mut ipv4_header_data = Ipv4HeaderData::init(
+            &mut raw,
+            Protocol::Other(PROTOCOL_ICMP),
+            0x0a000002,
+            0x08080808,
+            icmp_length,
+        );
+        ipv4_header_data.bind_mut(&mut raw).update_checksum();
+        raw.write_u8(TYPE_ECHO_REQUEST).unwrap();
+        raw.write_u8(0).unwrap(); // code
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u16::<BigEndian>(0x1234).unwrap(); // identifier
+        raw.write_u16::<BigEndian>(7).unwrap(); // sequence number
+        raw.extend_from_slice(data);
+        let checksum = checksum(&raw[20..]);
+        BigEndian::write_u16(&mut raw[22..24], checksum);
+        raw
+    }
+
+    #[test]
+    fn detect_echo_request() {
+        let mut raw = create_echo_request(b"ping");
+        assert_eq!(&raw[20..], echo_request_message(&raw).unwrap());
+
+        // a fragment
+        raw[6] = 0x20; // more fragments
+        assert!(echo_request_message(&raw).is_none());
+        raw[6] = 0;
+
+        // an echo reply
+        raw[20] = TYPE_ECHO_REPLY;
+        assert!(echo_request_message(&raw).is_none());
+
+        // not ICMP
+        assert!(echo_request_message(&create_udp_packet(&[1, 2, 3, 4])).is_none());
+    }
+
+    #[test]
+    fn build_echo_reply_to_request() {
+        let request = create_echo_request(b"ping");
+        let message = echo_request_message(&request).unwrap();
+        let raw = build_echo_reply(0x08080808, 0x0a000002, message);
+        assert_eq!(request.len(), raw.len());
+
+        let ipv4_header = Ipv4HeaderData::parse(&raw);
+        assert_eq!(Protocol::Other(PROTOCOL_ICMP), ipv4_header.protocol());
+        assert_eq!(0x08080808, ipv4_header.source());
+        assert_eq!(0x0a000002, ipv4_header.destination());
+        assert_eq!(0, checksum(&raw[..20]));
+
+        let icmp = &raw[20..];
+        assert_eq!(TYPE_ECHO_REPLY, icmp[0]);
+        assert_eq!(0, checksum(icmp));
+        // the identifier, the sequence number and the data are echoed
+        assert_eq!(&request[24..], &icmp[4..]);
+    }
+}
@@ -0,0 +1,169 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, ByteOrder};
+use log::*;
+use mio::net::UdpSocket;
+use mio::{PollOpt, Ready, Token};
+use std::cell::{Cell, RefCell};
+use std::io;
+use std::rc::{Rc, Weak};
+use std::time::Duration;
+
+use super::client::Client;
+use super::config::RelayConfig;
+use super::icmp;
+use super::ipv4_packet::{Ipv4Packet, MAX_PACKET_LENGTH};
+use super::net;
+use super::selector::{Selector, TimerId};
+
+const TAG: &str = "IcmpEcho";
+
+// a request without reply is given up after this delay
+const REPLY_TIMEOUT_SECONDS: u64 = 10;
+
+/// The maximum number of echo requests of a client waiting for their reply.
+///
+/// Each of them holds an ICMP socket until its reply or its timeout.
+pub const MAX_PENDING_ECHOES: usize = 16;
+
+/// An ICMP echo request forwarded upstream, waiting for its reply.
+///
+/// The request is sent through an unprivileged ICMP socket, whose kernel replaces the identifier
+/// of the message: the original identifier is restored in the reply relayed to the client.
+///
+/// The selector owns it (through its handler) until the reply is received or the timeout expires.
+pub struct IcmpEcho {
+    client: Weak<RefCell<Client>>,
+    socket: UdpSocket,
+    token: Token,
+    timer: Option<TimerId>,
+    // the addresses of the reply
+    source: u32,
+    destination: u32,
+    identifier: u16,
+    // the number of pending echo requests of the client, this one included
+    pending: Rc<Cell<usize>>,
+    closed: bool,
+}
+
+impl IcmpEcho {
+    /// Send the echo request `message` (the ICMP part of the packet) to `destination`, and relay
+    /// the reply to the client, from `destination` to `reply_to`.
+    ///
+    /// `pending` counts the echo requests of the client waiting for their reply; the caller is
+    /// responsible for not exceeding `MAX_PENDING_ECHOES`.
+    pub fn forward(
+        selector: &mut Selector,
+        client: Weak<RefCell<Client>>,
+        destination: u32,
+        reply_to: u32,
+        message: &[u8],
+        config: &RelayConfig,
+        pending: Rc<Cell<usize>>,
+    ) -> io::Result<()> {
+        let socket = net::connect_icmp_socket(net::to_addr(destination), config)?;
+        socket.send(message)?;
+        let rc = Rc::new(RefCell::new(Self {
+            client,
+            socket,
+            token: Token(0), // default value, will be set afterwards
+            timer: None,
+            source: destination,
+            destination: reply_to,
+            identifier: BigEndian::read_u16(&message[4..6]),
+            pending: pending.clone(),
+            closed: false,
+        }));
+
+        let mut self_ref = rc.borrow_mut();
+        let rc2 = rc.clone();
+        // must anotate selector type: https://stackoverflow.com/a/44004103/1987178
+        let handler = move |selector: &mut Selector, _| rc2.borrow_mut().on_ready(selector);
+        self_ref.token = selector.register(
+            &self_ref.socket,
+            handler,
+            Ready::readable(),
+            PollOpt::level(),
+        )?;
+
+        let weak = Rc::downgrade(&rc);
+        let timeout_handler = move |selector: &mut Selector| {
+            if let Some(rc) = weak.upgrade() {
+                rc.borrow_mut().on_timeout(selector);
+            }
+        };
+        let delay = Duration::from_secs(REPLY_TIMEOUT_SECONDS);
+        self_ref.timer = Some(selector.set_timer(delay, timeout_handler));
+        pending.set(pending.get() + 1);
+        Ok(())
+    }
+
+    fn on_ready(&mut self, selector: &mut Selector) {
+        if self.closed {
+            return;
+        }
+        let mut buf = [0; MAX_PACKET_LENGTH - 20];
+        match self.socket.recv(&mut buf) {
+            Ok(length) if length >= 8 && buf[0] == icmp::TYPE_ECHO_REPLY => {
+                let message = &mut buf[..length];
+                BigEndian::write_u16(&mut message[4..6], self.identifier);
+                let mut raw = icmp::build_echo_reply(self.source, self.destination, message);
+                self.send_to_client(selector, &Ipv4Packet::parse(&mut raw));
+                self.close(selector);
+            }
+            Ok(_) => debug!(target: TAG, "Ignoring unexpected ICMP message"),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                debug!(target: TAG, "Spurious event, ignoring")
+            }
+            Err(err) => {
+                // e.g. the destination is unreachable
+                debug!(target: TAG, "No ICMP echo reply: {}", err);
+                self.close(selector);
+            }
+        }
+    }
+
+    fn on_timeout(&mut self, selector: &mut Selector) {
+        self.timer = None;
+        debug!(target: TAG, "ICMP echo request timed out");
+        self.close(selector);
+    }
+
+    fn send_to_client(&self, selector: &mut Selector, ipv4_packet: &Ipv4Packet) {
+        // the client may have been closed in the meantime
+        if let Some(client_rc) = self.client.upgrade() {
+            if let Err(err) = client_rc.borrow_mut().send_to_client(selector, ipv4_packet) {
+                warn!(target: TAG, "Cannot send ICMP echo reply to client: {}", err);
+            }
+        }
+    }
+
+    fn close(&mut self, selector: &mut Selector) {
+        if self.closed {
+            return;
+        }
+        self.closed = true;
+        self.pending.set(self.pending.get() - 1);
+        if let Some(timer) = self.timer.take() {
+            selector.cancel_timer(timer);
+        }
+        // the handler, which owns this, is removed along with the registration
+        if let Err(err) = selector.deregister(&self.socket, self.token) {
+            warn!(target: TAG, "Fail to deregister ICMP socket: {:?}", err);
+        }
+    }
+}
@@ -0,0 +1,48 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use super::connection::ConnectionId;
+
+/// Decision taken by an `Inspector` for a packet sent by the client.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// Relay the packet unchanged.
+    Accept,
+    /// Drop the packet silently.
+    Drop,
+    /// Relay the packet with its payload replaced by the given one.
+    ///
+    /// The relay acknowledges TCP data to the client on its own, so for TCP the new payload must
+    /// have the same length as the original one (otherwise the original is relayed).
+    Modify(Vec<u8>),
+}
+
+/// Hook called on the transport payload of every TCP or UDP packet sent by the client, before it
+/// is relayed to the network.
+///
+/// It allows to observe the traffic (e.g. log DNS requests) or to filter it.
+pub trait Inspector {
+    fn inspect(&self, id: &ConnectionId, payload: &[u8]) -> Verdict;
+}
+
+impl<F> Inspector for F
+where
+    F: Fn(&ConnectionId, &[u8]) -> Verdict,
+{
+    fn inspect(&self, id: &ConnectionId, payload: &[u8]) -> Verdict {
+        self(id, payload)
+    }
+}
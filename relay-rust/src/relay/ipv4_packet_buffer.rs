@@ -14,68 +14,255 @@
  * limitations under the License.
  */
 
+use super::address_families::AddressFamilies;
 use super::binary;
 use super::byte_buffer::ByteBuffer;
-use super::ipv4_header;
+use super::client_address::CONTROL_MESSAGE_VERSION;
+use super::ipv4_header::{self, Ipv4HeaderData, PacketClass};
 use super::ipv4_packet::{Ipv4Packet, MAX_PACKET_LENGTH};
 
+use byteorder::{BigEndian, ByteOrder};
 use log::*;
+use std::convert::TryFrom;
 use std::io;
 
+const TAG: &str = "Ipv4PacketBuffer";
+
+// the relay does not support IPv6, but a misconfigured client may send IPv6 packets
+const IPV6_VERSION: u8 = 6;
+
+// control messages are tiny, a longer one is garbage
+const MAX_CONTROL_MESSAGE_LENGTH: u16 = 64;
+
+const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+const TCP_HEADER_LENGTH: usize = 20;
+const UDP_HEADER_LENGTH: usize = 8;
+
 pub struct Ipv4PacketBuffer {
     buf: ByteBuffer,
+    // the packets of the other families are dropped
+    families: AddressFamilies,
+}
+
+// what is in front of the buffer
+enum Frame {
+    // a message (IPv4 packet, IPv6 packet or control message) with its version and length
+    Message(u8, u16),
+    // not enough data to know
+    Incomplete,
+    // not the start of a message
+    Invalid(u8),
 }
 
 impl Ipv4PacketBuffer {
     pub fn new() -> Self {
         Self {
             buf: ByteBuffer::new(MAX_PACKET_LENGTH),
+            families: AddressFamilies::default(),
         }
     }
 
+    /// Drop the packets of the families not declared by the client, from the next message.
+    pub fn set_address_families(&mut self, families: AddressFamilies) {
+        self.families = families;
+    }
+
     pub fn read_from<R: io::Read>(&mut self, source: &mut R) -> io::Result<bool> {
-        self.buf.read_from(source)
+        let result = self.buf.read_from(source)?;
+        self.resynchronize();
+        Ok(result)
+    }
+
+    /// Append the data received from the client (as much as fits in the buffer), and return the
+    /// number of bytes consumed.
+    ///
+    /// This is the counterpart of `read_from()` for data not read from a stream: all the messages
+    /// it completes are then available through `as_ipv4_packet()` (or `as_control_message()`) and
+    /// `next()`. The client feeds it the input pending when the relay was handed off.
+    #[cfg(any(unix, test))]
+    pub fn feed(&mut self, data: &[u8]) -> usize {
+        let mut source = data;
+        self.read_from(&mut source)
+            .expect("Reading from a slice never fails");
+        data.len() - source.len()
     }
 
-    fn available_packet_length(&self) -> Option<u16> {
+    /// The data received from the client but not consumed yet (the start of a message).
+    #[cfg(unix)]
+    pub fn pending(&self) -> &[u8] {
+        self.buf.peek()
+    }
+
+    fn peek_frame(data: &[u8]) -> Frame {
+        match ipv4_header::classify_packet(data) {
+            PacketClass::Ipv4 { length } => {
+                let header_length = u16::from(data[0] & 0xf) * 4;
+                if header_length >= 20 && length >= header_length {
+                    Frame::Message(4, length)
+                } else {
+                    Frame::Invalid(4)
+                }
+            }
+            PacketClass::Ipv6 { length } => match u16::try_from(length) {
+                Ok(length) => Frame::Message(IPV6_VERSION, length),
+                Err(_) => Frame::Invalid(IPV6_VERSION),
+            },
+            PacketClass::TooShort => Frame::Incomplete,
+            PacketClass::Unknown(CONTROL_MESSAGE_VERSION) => {
+                let length = BigEndian::read_u16(&data[2..4]);
+                // the reserved bits are 0 and the message type is never 0
+                if data[0] == 0
+                    && data[1] != 0
+                    && (4..=MAX_CONTROL_MESSAGE_LENGTH).contains(&length)
+                {
+                    Frame::Message(CONTROL_MESSAGE_VERSION, length)
+                } else {
+                    Frame::Invalid(CONTROL_MESSAGE_VERSION)
+                }
+            }
+            PacketClass::Unknown(version) => Frame::Invalid(version),
+        }
+    }
+
+    // drop the data in front of the buffer until it starts with a supported message, so that a
+    // client sending garbage (e.g. non-IP frames) does not break the framing of the stream
+    fn resynchronize(&mut self) {
+        let mut dropped = 0;
+        let mut first_version = None;
+        loop {
+            match Self::peek_frame(self.buf.peek()) {
+                Frame::Invalid(version) => {
+                    first_version.get_or_insert(version);
+                    self.buf.consume(1);
+                    dropped += 1;
+                }
+                Frame::Message(version, length)
+                    if version != CONTROL_MESSAGE_VERSION
+                        && !self.families.accepts(version)
+                        && length as usize <= self.buf.peek().len() =>
+                {
+                    warn!(
+                        target: TAG,
+                        "Dropping IPv{} packet, not declared by the client", version
+                    );
+                    self.buf.consume(length as usize);
+                }
+                Frame::Message(IPV6_VERSION, length)
+                    if length as usize <= self.buf.peek().len() =>
+                {
+                    warn!(target: TAG, "Dropping IPv6 packet, only IPv4 is supported");
+                    self.buf.consume(length as usize);
+                }
+                Frame::Message(4, length)
+                    if length as usize <= self.buf.peek().len()
+                        && !Self::has_consistent_length(&self.buf.peek()[..length as usize]) =>
+                {
+                    let discarded = self.resync();
+                    warn!(
+                        target: TAG,
+                        "Corrupt IPv4 frame, discarded {} bytes to resynchronize", discarded
+                    );
+                }
+                _ => break,
+            }
+        }
+        if let Some(version) = first_version {
+            warn!(
+                target: TAG,
+                "Dropped {} bytes of non-IP data (version={})", dropped, version
+            );
+        }
+    }
+
+    // a TCP or UDP packet which cannot hold its transport header, or a UDP datagram whose length
+    // does not match the IPv4 total length, reveals a wrong frame length
+    fn has_consistent_length(packet: &[u8]) -> bool {
+        let header_length = usize::from(packet[0] & 0xf) * 4;
+        let fragmented = BigEndian::read_u16(&packet[6..8]) & 0x3fff != 0;
+        if fragmented || packet.len() < header_length {
+            // only the first fragment contains the transport header
+            return true;
+        }
+        let transport = &packet[header_length..];
+        match packet[9] {
+            PROTOCOL_TCP => transport.len() >= TCP_HEADER_LENGTH,
+            PROTOCOL_UDP => {
+                transport.len() >= UDP_HEADER_LENGTH
+                    && BigEndian::read_u16(&transport[4..6]) as usize == transport.len()
+            }
+            _ => true,
+        }
+    }
+
+    /// Discard the message in front of the buffer, assumed corrupt (e.g. its length is wrong), up
+    /// to the next plausible message start. Return the number of bytes discarded.
+    ///
+    /// This bounds the damage of a single corrupt frame, which would otherwise break the framing
+    /// of all the following messages.
+    pub fn resync(&mut self) -> usize {
+        let data = self.buf.peek();
+        let discarded = (1..data.len())
+            .find(|&i| !matches!(Self::peek_frame(&data[i..]), Frame::Invalid(_)))
+            .unwrap_or(data.len());
+        self.buf.consume(discarded);
+        discarded
+    }
+
+    // return the version and the length of the message (IPv4 packet or control message) in front
+    // of the buffer, if it is fully available
+    fn available_message(&self) -> Option<(u8, u16)> {
         let data = self.buf.peek();
-        trace!("Parse packet: {}", binary::build_packet_string(data));
-        if let Some((version, length)) = ipv4_header::peek_version_length(data) {
-            assert!(version == 4, "Not an Ipv4 packet, version={}", version);
-            if length as usize <= data.len() {
-                // full packet available
-                Some(length)
-            } else {
-                // no full packet available
-                None
+        trace!(target: TAG, "Parse packet: {}", binary::build_packet_string(data));
+        match Self::peek_frame(data) {
+            // full packet available (its header is at least 20 bytes long)
+            Frame::Message(4, length)
+                if data.len() >= 20 && Ipv4HeaderData::parse(data).is_complete(data.len()) =>
+            {
+                Some((4, length))
             }
-        } else {
-            // no packet
-            None
+            // full control message available
+            Frame::Message(CONTROL_MESSAGE_VERSION, length) if length as usize <= data.len() => {
+                Some((CONTROL_MESSAGE_VERSION, length))
+            }
+            // no full message available (invalid data are dropped on read)
+            _ => None,
         }
     }
 
     pub fn as_ipv4_packet(&mut self) -> Option<Ipv4Packet> {
-        if self.available_packet_length().is_some() {
-            let data = self.buf.peek_mut();
-            Some(Ipv4Packet::parse(data))
-        } else {
-            None
+        match self.available_message() {
+            Some((4, _)) => {
+                let data = self.buf.peek_mut();
+                Some(Ipv4Packet::parse(data))
+            }
+            _ => None,
+        }
+    }
+
+    /// Return the control message in front of the buffer, if any.
+    pub fn as_control_message(&self) -> Option<&[u8]> {
+        match self.available_message() {
+            Some((CONTROL_MESSAGE_VERSION, length)) => Some(&self.buf.peek()[..length as usize]),
+            _ => None,
         }
     }
 
     pub fn next(&mut self) {
         // remove the packet in front of the buffer
-        let length = self
-            .available_packet_length()
-            .expect("next() called while there was no packet") as usize;
-        self.buf.consume(length);
+        let (_, length) = self
+            .available_message()
+            .expect("next() called while there was no packet");
+        self.buf.consume(length as usize);
+        self.resynchronize();
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::relay::address_families::tests::create_declaration;
+    use crate::relay::client_address::tests::create_announcement;
     use crate::relay::ipv4_header::Protocol;
     use crate::relay::transport_header::TransportHeaderData;
     use byteorder::{BigEndian, WriteBytesExt};
@@ -213,4 +400,184 @@ mod tests {
 
         assert!(packet_buffer.as_ipv4_packet().is_none());
     }
+
+    #[test]
+    fn parse_control_message() {
+        let mut raw = create_announcement(0x0a000002, 24);
+        write_packet_to(&mut raw);
+        let mut packet_buffer = Ipv4PacketBuffer::new();
+
+        let mut cursor = io::Cursor::new(raw);
+        packet_buffer.read_from(&mut cursor).unwrap();
+
+        assert!(packet_buffer.as_ipv4_packet().is_none());
+        assert_eq!(
+            create_announcement(0x0a000002, 24),
+            packet_buffer.as_control_message().unwrap()
+        );
+        packet_buffer.next();
+        assert!(packet_buffer.as_control_message().is_none());
+        check_packet_headers(&packet_buffer.as_ipv4_packet().unwrap());
+    }
+
+    #[test]
+    fn drop_version_15() {
+        let mut raw = vec![0xF4, 0x00, 0x00, 0x20];
+        write_packet_to(&mut raw);
+        write_another_packet_to(&mut raw);
+        let mut packet_buffer = Ipv4PacketBuffer::new();
+
+        let mut cursor = io::Cursor::new(raw);
+        packet_buffer.read_from(&mut cursor).unwrap();
+
+        check_packet_headers(&packet_buffer.as_ipv4_packet().unwrap());
+        packet_buffer.next();
+        check_another_packet_headers(&packet_buffer.as_ipv4_packet().unwrap());
+    }
+
+    #[test]
+    fn drop_version_0() {
+        // version 0, but not a control message
+        let mut raw = vec![0x0F, 0x00, 0x00, 0x04];
+        write_packet_to(&mut raw);
+        // garbage between two packets
+        raw.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
+        write_another_packet_to(&mut raw);
+        let mut packet_buffer = Ipv4PacketBuffer::new();
+
+        let mut cursor = io::Cursor::new(raw);
+        packet_buffer.read_from(&mut cursor).unwrap();
+
+        assert!(packet_buffer.as_control_message().is_none());
+        check_packet_headers(&packet_buffer.as_ipv4_packet().unwrap());
+        packet_buffer.next();
+        assert!(packet_buffer.as_control_message().is_none());
+        check_another_packet_headers(&packet_buffer.as_ipv4_packet().unwrap());
+    }
+
+    #[test]
+    fn drop_ipv6_packet() {
+        let mut raw = Vec::new();
+        raw.write_u32::<BigEndian>(6 << 28).unwrap(); // version, traffic class, flow label
+        raw.write_u16::<BigEndian>(8).unwrap(); // payload length
+        raw.resize(40 + 8, 0); // rest of the header and payload
+        write_packet_to(&mut raw);
+        let mut packet_buffer = Ipv4PacketBuffer::new();
+
+        // the IPv6 packet is received in two parts
+        let mut cursor = io::Cursor::new(&raw[..30]);
+        packet_buffer.read_from(&mut cursor).unwrap();
+        assert!(packet_buffer.as_ipv4_packet().is_none());
+
+        let mut cursor = io::Cursor::new(&raw[30..]);
+        packet_buffer.read_from(&mut cursor).unwrap();
+        check_packet_headers(&packet_buffer.as_ipv4_packet().unwrap());
+    }
+
+    fn create_ipv6_packet() -> Vec<u8> {
+        let mut raw = Vec::new();
+        raw.write_u32::<BigEndian>(6 << 28).unwrap(); // version, traffic class, flow label
+        raw.write_u16::<BigEndian>(8).unwrap(); // payload length
+        raw.resize(40 + 8, 0); // rest of the header and payload
+        raw
+    }
+
+    #[test]
+    fn drop_undeclared_ipv6_packet() {
+        let mut raw = create_declaration(true, false);
+        raw.extend_from_slice(&create_ipv6_packet());
+        write_packet_to(&mut raw);
+        let mut packet_buffer = Ipv4PacketBuffer::new();
+        packet_buffer.feed(&raw);
+
+        let families = AddressFamilies::parse(packet_buffer.as_control_message().unwrap());
+        packet_buffer.set_address_families(families.unwrap());
+        packet_buffer.next();
+        check_packet_headers(&packet_buffer.as_ipv4_packet().unwrap());
+    }
+
+    #[test]
+    fn drop_undeclared_ipv4_packet() {
+        let mut raw = create_declaration(false, true);
+        write_packet_to(&mut raw);
+        raw.extend_from_slice(&create_announcement(0x0a000002, 24));
+        let mut packet_buffer = Ipv4PacketBuffer::new();
+        packet_buffer.feed(&raw);
+
+        let families = AddressFamilies::parse(packet_buffer.as_control_message().unwrap());
+        packet_buffer.set_address_families(families.unwrap());
+        packet_buffer.next();
+        // the IPv4 packet is skipped, the control messages are still accepted
+        assert!(packet_buffer.as_ipv4_packet().is_none());
+        assert_eq!(
+            &create_announcement(0x0a000002, 24)[..],
+            packet_buffer.as_control_message().unwrap()
+        );
+    }
+
+    #[test]
+    fn resync_after_bad_length() {
+        let mut raw = create_packet();
+        // the total length is wrong, the UDP length does not match
+        raw[3] = 36;
+        raw.extend_from_slice(&[0xff; 4]);
+        write_another_packet_to(&mut raw);
+        let mut packet_buffer = Ipv4PacketBuffer::new();
+
+        let mut cursor = io::Cursor::new(raw);
+        packet_buffer.read_from(&mut cursor).unwrap();
+
+        check_another_packet_headers(&packet_buffer.as_ipv4_packet().unwrap());
+    }
+
+    #[test]
+    fn resync_on_demand() {
+        // plausible header announcing a huge packet, which would be awaited forever
+        let mut raw = vec![0x45, 0x00, 0xff, 0x00];
+        write_packet_to(&mut raw);
+        let mut packet_buffer = Ipv4PacketBuffer::new();
+
+        let mut cursor = io::Cursor::new(raw);
+        packet_buffer.read_from(&mut cursor).unwrap();
+        assert!(packet_buffer.as_ipv4_packet().is_none());
+
+        assert_eq!(4, packet_buffer.resync());
+        check_packet_headers(&packet_buffer.as_ipv4_packet().unwrap());
+    }
+
+    #[test]
+    fn feed_packets() {
+        let mut raw = create_multi_packets();
+        write_another_packet_to(&mut raw);
+        // the last packet is incomplete
+        let partial_length = raw.len() - 10;
+        let mut packet_buffer = Ipv4PacketBuffer::new();
+
+        assert_eq!(partial_length, packet_buffer.feed(&raw[..partial_length]));
+        let mut count = 0;
+        while packet_buffer.as_ipv4_packet().is_some() {
+            packet_buffer.next();
+            count += 1;
+        }
+        assert_eq!(3, count);
+
+        assert_eq!(10, packet_buffer.feed(&raw[partial_length..]));
+        check_another_packet_headers(&packet_buffer.as_ipv4_packet().unwrap());
+    }
+
+    #[test]
+    fn feed_until_full() {
+        let mut raw = Vec::new();
+        while raw.len() <= MAX_PACKET_LENGTH {
+            write_packet_to(&mut raw);
+        }
+        let mut packet_buffer = Ipv4PacketBuffer::new();
+
+        // only the data fitting in the buffer are consumed
+        assert_eq!(MAX_PACKET_LENGTH, packet_buffer.feed(&raw));
+        assert_eq!(0, packet_buffer.feed(&raw[MAX_PACKET_LENGTH..]));
+
+        packet_buffer.next();
+        assert_eq!(32, packet_buffer.feed(&raw[MAX_PACKET_LENGTH..]));
+    }
 }
@@ -0,0 +1,267 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use chrono::Utc;
+use log::*;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+
+use super::connection::{ConnectionId, ConnectionStats};
+use super::observer::{AuditRecord, CloseReason, DropReason, Observer};
+
+const TAG: &str = "JsonLinesSink";
+
+// one line of output, the timestamp is in milliseconds since the Unix epoch
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Record<'a> {
+    Open {
+        id: String,
+        ts: i64,
+    },
+    Close {
+        id: String,
+        ts: i64,
+        bytes_to_network: u64,
+        bytes_to_client: u64,
+        reason: &'a str,
+    },
+    Drop {
+        id: String,
+        ts: i64,
+        reason: &'a str,
+    },
+    Capacity {
+        capacity: usize,
+        ts: i64,
+    },
+    Audit {
+        id: String,
+        ts: i64,
+        decision: &'a str,
+        upstream: Option<String>,
+        close_reason: Option<&'a str>,
+    },
+}
+
+/// `Observer` writing the connection events as JSON lines, e.g.:
+///
+/// ```text
+/// {"event":"open","id":"10.0.0.2:41000 -> 1.2.3.4:80","ts":1500000000000}
+/// ```
+pub struct JsonLinesSink {
+    writer: RefCell<Box<dyn Write>>,
+}
+
+impl JsonLinesSink {
+    pub fn new(writer: Box<dyn Write>) -> Self {
+        Self {
+            writer: RefCell::new(writer),
+        }
+    }
+
+    /// Append the events to the file at `path` (created if necessary).
+    pub fn to_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::new(Box::new(file)))
+    }
+
+    pub fn stdout() -> Self {
+        Self::new(Box::new(io::stdout()))
+    }
+
+    fn write(&self, record: &Record) {
+        let mut writer = self.writer.borrow_mut();
+        let result = serde_json::to_writer(&mut *writer, record)
+            .map_err(io::Error::from)
+            .and_then(|_| writer.write_all(b"\n"))
+            .and_then(|_| writer.flush());
+        if let Err(err) = result {
+            error!(target: TAG, "Cannot write event: {}", err);
+        }
+    }
+}
+
+impl Observer for JsonLinesSink {
+    fn on_open(&self, id: &ConnectionId) {
+        self.write(&Record::Open {
+            id: id.to_string(),
+            ts: Utc::now().timestamp_millis(),
+        });
+    }
+
+    fn on_close(&self, id: &ConnectionId, stats: &ConnectionStats, reason: CloseReason) {
+        self.write(&Record::Close {
+            id: id.to_string(),
+            ts: Utc::now().timestamp_millis(),
+            bytes_to_network: stats.bytes_to_network,
+            bytes_to_client: stats.bytes_to_client,
+            reason: reason.name(),
+        });
+    }
+
+    fn on_drop(&self, id: &ConnectionId, reason: DropReason) {
+        self.write(&Record::Drop {
+            id: id.to_string(),
+            ts: Utc::now().timestamp_millis(),
+            reason: reason.name(),
+        });
+    }
+
+    fn on_capacity_grown(&self, capacity: usize) {
+        self.write(&Record::Capacity {
+            capacity,
+            ts: Utc::now().timestamp_millis(),
+        });
+    }
+
+    fn on_audit(&self, record: &AuditRecord) {
+        self.write(&Record::Audit {
+            id: record.id.to_string(),
+            ts: Utc::now().timestamp_millis(),
+            decision: record.decision.name(),
+            upstream: record.upstream.map(|upstream| upstream.to_string()),
+            close_reason: record.close_reason.map(CloseReason::name),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::ipv4_packet::Ipv4Packet;
+    use crate::relay::observer::Decision;
+    use crate::relay::tcp_connection::tests::create_tcp_packet;
+    use serde_json::Value;
+    use std::rc::Rc;
+
+    // writer whose content remains readable after being moved into the sink
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn lines(&self) -> Vec<Value> {
+            let content = String::from_utf8(self.0.borrow().clone()).unwrap();
+            content
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        }
+    }
+
+    fn connection_id() -> ConnectionId {
+        let mut raw = create_tcp_packet(8080, 0, 0, 0, 0, &[]);
+        let packet = Ipv4Packet::parse(&mut raw);
+        let (ipv4_header_data, transport_header_data) = packet.headers_data();
+        ConnectionId::from_headers(ipv4_header_data, transport_header_data.unwrap())
+    }
+
+    #[test]
+    fn open_event() {
+        let buffer = SharedBuffer::default();
+        let sink = JsonLinesSink::new(Box::new(buffer.clone()));
+        sink.on_open(&connection_id());
+
+        let lines = buffer.lines();
+        assert_eq!(1, lines.len());
+        let record = lines[0].as_object().unwrap();
+        assert_eq!(3, record.len());
+        assert_eq!("open", record["event"]);
+        assert_eq!("10.0.0.2:41000 -> 127.0.0.1:8080", record["id"]);
+        assert!(record["ts"].as_i64().unwrap() > 0);
+    }
+
+    #[test]
+    fn close_event() {
+        let buffer = SharedBuffer::default();
+        let sink = JsonLinesSink::new(Box::new(buffer.clone()));
+        let mut stats = ConnectionStats::default();
+        stats.count_to_network(100);
+        stats.count_to_client(1000);
+        stats.count_to_client(500);
+        sink.on_close(&connection_id(), &stats, CloseReason::Timeout);
+
+        let lines = buffer.lines();
+        assert_eq!(1, lines.len());
+        let record = lines[0].as_object().unwrap();
+        assert_eq!(6, record.len());
+        assert_eq!("close", record["event"]);
+        assert_eq!("10.0.0.2:41000 -> 127.0.0.1:8080", record["id"]);
+        assert!(record["ts"].as_i64().unwrap() > 0);
+        assert_eq!(100, record["bytes_to_network"]);
+        assert_eq!(1500, record["bytes_to_client"]);
+        assert_eq!("timeout", record["reason"]);
+    }
+
+    #[test]
+    fn audit_normal_close() {
+        let buffer = SharedBuffer::default();
+        let sink = JsonLinesSink::new(Box::new(buffer.clone()));
+        let id = connection_id();
+        sink.on_audit(&AuditRecord {
+            id: &id,
+            decision: Decision::Accepted,
+            upstream: Some("127.0.0.1:8080".parse().unwrap()),
+            close_reason: Some(CloseReason::ClientFin),
+        });
+
+        let lines = buffer.lines();
+        assert_eq!(1, lines.len());
+        let record = lines[0].as_object().unwrap();
+        assert_eq!(6, record.len());
+        assert_eq!("audit", record["event"]);
+        assert_eq!("10.0.0.2:41000 -> 127.0.0.1:8080", record["id"]);
+        assert!(record["ts"].as_i64().unwrap() > 0);
+        assert_eq!("accepted", record["decision"]);
+        assert_eq!("127.0.0.1:8080", record["upstream"]);
+        assert_eq!("client_fin", record["close_reason"]);
+    }
+
+    #[test]
+    fn audit_filtered_block() {
+        let buffer = SharedBuffer::default();
+        let sink = JsonLinesSink::new(Box::new(buffer.clone()));
+        let id = connection_id();
+        sink.on_audit(&AuditRecord {
+            id: &id,
+            decision: Decision::Blocked,
+            upstream: None,
+            close_reason: None,
+        });
+
+        let lines = buffer.lines();
+        assert_eq!(1, lines.len());
+        let record = lines[0].as_object().unwrap();
+        assert_eq!(6, record.len());
+        assert_eq!("audit", record["event"]);
+        assert_eq!("blocked", record["decision"]);
+        assert!(record["upstream"].is_null());
+        assert!(record["close_reason"].is_null());
+    }
+}
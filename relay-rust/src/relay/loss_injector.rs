@@ -0,0 +1,81 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// Decide randomly which packets to drop, to simulate a lossy network.
+///
+/// With a fixed seed, the sequence of decisions is reproducible.
+pub struct LossInjector {
+    to_network: f64,
+    to_client: f64,
+    rng: StdRng,
+}
+
+impl LossInjector {
+    /// Drop the packets sent to the network with probability `to_network`, and the packets sent to
+    /// the client with probability `to_client`.
+    pub fn new(to_network: f64, to_client: f64, seed: Option<u64>) -> Self {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Self {
+            to_network,
+            to_client,
+            rng,
+        }
+    }
+
+    pub fn drop_to_network(&mut self) -> bool {
+        self.to_network > 0.0 && self.rng.gen_bool(self.to_network)
+    }
+
+    pub fn drop_to_client(&mut self) -> bool {
+        self.to_client > 0.0 && self.rng.gen_bool(self.to_client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEED: u64 = 42;
+
+    fn count_drops(injector: &mut LossInjector, packets: usize) -> usize {
+        (0..packets).filter(|_| injector.drop_to_network()).count()
+    }
+
+    #[test]
+    fn reproducible_with_seed() {
+        let mut injector1 = LossInjector::new(0.1, 0.0, Some(SEED));
+        let mut injector2 = LossInjector::new(0.1, 0.0, Some(SEED));
+        let drops = count_drops(&mut injector1, 10_000);
+        assert_eq!(drops, count_drops(&mut injector2, 10_000));
+        // about 10% of the packets
+        assert!(drops > 900 && drops < 1100, "{} drops", drops);
+    }
+
+    #[test]
+    fn separate_directions() {
+        let mut injector = LossInjector::new(1.0, 0.0, Some(SEED));
+        for _ in 0..100 {
+            assert!(injector.drop_to_network());
+            assert!(!injector.drop_to_client());
+        }
+    }
+}
@@ -0,0 +1,265 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::ipv4_header::{Protocol, PROTOCOL_ICMP};
+
+/// Relay-wide counters.
+///
+/// They are updated by the relay thread, but may be read from any thread.
+pub struct Metrics {
+    counters: [AtomicU64; COUNTER_COUNT],
+    // packets sent by the clients, indexed by protocol number
+    protocol_packets: [AtomicU64; 256],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Counter {
+    /// Sends to the network which would have blocked, so the datagram has been queued.
+    UdpSendsBlocked,
+    /// Datagrams dropped because the queue of the connection was full.
+    UdpDatagramsDropped,
+    /// DNS queries answered by the relay from the overridden records.
+    DnsQueriesAnswered,
+    /// Packets sent by the clients dropped to simulate packet loss.
+    InjectedLossToNetwork,
+    /// Packets sent to the clients dropped to simulate packet loss.
+    InjectedLossToClient,
+    /// Packets sent by the clients dropped because their source was outside the announced subnet.
+    SpoofedPacketsDropped,
+    /// Datagrams from the network dropped because the queue of the client was full.
+    ClientQueueOverflows,
+    /// Packets sent by the clients dropped because they exceeded the maximum packet rate.
+    RateLimitedPackets,
+    /// IGMP packets sent by the clients, which are never relayed.
+    IgmpPacketsDropped,
+    /// Upstream TCP connections retried after a failure.
+    UpstreamConnectRetries,
+    /// DNS queries answered from the cache of the responses received from upstream.
+    DnsCacheHits,
+    /// DNS queries not found in the cache, forwarded upstream.
+    DnsCacheMisses,
+    /// Packets sent by the clients not relayed because the relay only monitors them.
+    MonitoredPackets,
+    /// Packets sent by the clients with a wrong checksum (dropped, unless the `ChecksumValidation`
+    /// is lenient).
+    InvalidChecksums,
+    /// Packets sent by the clients dropped by the router, whatever the `DropReason`.
+    DroppedPackets,
+    /// ICMP echo requests sent by the clients, dropped by the `IcmpPolicy`.
+    IcmpEchoDropped,
+    /// ICMP echo requests answered by the relay itself.
+    IcmpEchoSynthesized,
+    /// ICMP echo requests forwarded upstream.
+    IcmpEchoForwarded,
+    /// ICMP echo requests not forwarded because too many were still waiting for their reply.
+    IcmpEchoOverflows,
+    /// Upstream sockets which could not be created because no file descriptor was available.
+    DescriptorsExhausted,
+    /// Packets sent by the clients exceeding the tunnel MTU with the "don't fragment" flag,
+    /// answered by an ICMP "fragmentation needed".
+    FragmentationNeeded,
+}
+
+const COUNTER_COUNT: usize = 21;
+
+impl Counter {
+    pub const ALL: [Counter; COUNTER_COUNT] = [
+        Counter::UdpSendsBlocked,
+        Counter::UdpDatagramsDropped,
+        Counter::DnsQueriesAnswered,
+        Counter::InjectedLossToNetwork,
+        Counter::InjectedLossToClient,
+        Counter::SpoofedPacketsDropped,
+        Counter::ClientQueueOverflows,
+        Counter::RateLimitedPackets,
+        Counter::IgmpPacketsDropped,
+        Counter::UpstreamConnectRetries,
+        Counter::DnsCacheHits,
+        Counter::DnsCacheMisses,
+        Counter::MonitoredPackets,
+        Counter::InvalidChecksums,
+        Counter::DroppedPackets,
+        Counter::IcmpEchoDropped,
+        Counter::IcmpEchoSynthesized,
+        Counter::IcmpEchoForwarded,
+        Counter::IcmpEchoOverflows,
+        Counter::DescriptorsExhausted,
+        Counter::FragmentationNeeded,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Counter::UdpSendsBlocked => "udp_sends_blocked",
+            Counter::UdpDatagramsDropped => "udp_datagrams_dropped",
+            Counter::DnsQueriesAnswered => "dns_queries_answered",
+            Counter::InjectedLossToNetwork => "injected_loss_to_network",
+            Counter::InjectedLossToClient => "injected_loss_to_client",
+            Counter::SpoofedPacketsDropped => "spoofed_packets_dropped",
+            Counter::ClientQueueOverflows => "client_queue_overflows",
+            Counter::RateLimitedPackets => "rate_limited_packets",
+            Counter::IgmpPacketsDropped => "igmp_packets_dropped",
+            Counter::UpstreamConnectRetries => "upstream_connect_retries",
+            Counter::DnsCacheHits => "dns_cache_hits",
+            Counter::DnsCacheMisses => "dns_cache_misses",
+            Counter::MonitoredPackets => "monitored_packets",
+            Counter::InvalidChecksums => "invalid_checksums",
+            Counter::DroppedPackets => "dropped_packets",
+            Counter::IcmpEchoDropped => "icmp_echo_dropped",
+            Counter::IcmpEchoSynthesized => "icmp_echo_synthesized",
+            Counter::IcmpEchoForwarded => "icmp_echo_forwarded",
+            Counter::IcmpEchoOverflows => "icmp_echo_overflows",
+            Counter::DescriptorsExhausted => "descriptors_exhausted",
+            Counter::FragmentationNeeded => "fragmentation_needed",
+        }
+    }
+}
+
+/// The name of the counter of the packets of `protocol` (`packets_tcp`, `packets_udp`,
+/// `packets_icmp`, or `packets_protocol_<number>` for the other protocols).
+pub fn protocol_packets_name(protocol: Protocol) -> String {
+    match protocol {
+        Protocol::Tcp => "packets_tcp".into(),
+        Protocol::Udp => "packets_udp".into(),
+        Protocol::Other(PROTOCOL_ICMP) => "packets_icmp".into(),
+        Protocol::Other(n) => format!("packets_protocol_{}", n),
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            counters: Default::default(),
+            protocol_packets: [(); 256].map(|_| AtomicU64::new(0)),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    #[inline]
+    pub fn increment(&self, counter: Counter) {
+        self.add(counter, 1);
+    }
+
+    #[inline]
+    pub fn add(&self, counter: Counter, value: u64) {
+        self.counters[counter as usize].fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, counter: Counter) -> u64 {
+        self.counters[counter as usize].load(Ordering::Relaxed)
+    }
+
+    /// Count a packet of `protocol` sent by a client.
+    #[inline]
+    pub fn count_protocol(&self, protocol: Protocol) {
+        self.protocol_packets[protocol.number() as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn protocol_packets(&self, protocol: Protocol) -> u64 {
+        self.protocol_packets[protocol.number() as usize].load(Ordering::Relaxed)
+    }
+
+    /// The number of packets sent by the clients for each protocol seen, by protocol number.
+    pub fn protocols(&self) -> Vec<(Protocol, u64)> {
+        (0..=u8::MAX)
+            .map(|n| {
+                let protocol = Protocol::from_number(n);
+                (protocol, self.protocol_packets(protocol))
+            })
+            .filter(|&(_, packets)| packets > 0)
+            .collect()
+    }
+
+    /// Set all the counters to 0.
+    pub fn reset(&self) {
+        for counter in self.counters.iter().chain(self.protocol_packets.iter()) {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn count() {
+        let metrics = Metrics::new();
+        metrics.increment(Counter::UdpDatagramsDropped);
+        metrics.add(Counter::UdpDatagramsDropped, 2);
+        assert_eq!(3, metrics.get(Counter::UdpDatagramsDropped));
+        assert_eq!(0, metrics.get(Counter::UdpSendsBlocked));
+    }
+
+    #[test]
+    fn reset() {
+        let metrics = Metrics::new();
+        metrics.increment(Counter::UdpSendsBlocked);
+        metrics.increment(Counter::UdpDatagramsDropped);
+        metrics.reset();
+        for &counter in &Counter::ALL {
+            assert_eq!(0, metrics.get(counter));
+        }
+    }
+
+    #[test]
+    fn count_protocols() {
+        let metrics = Metrics::new();
+        metrics.count_protocol(Protocol::Udp);
+        metrics.count_protocol(Protocol::Tcp);
+        metrics.count_protocol(Protocol::Udp);
+        metrics.count_protocol(Protocol::Other(PROTOCOL_ICMP));
+        assert_eq!(2, metrics.protocol_packets(Protocol::Udp));
+        assert_eq!(
+            vec![
+                (Protocol::Other(PROTOCOL_ICMP), 1),
+                (Protocol::Tcp, 1),
+                (Protocol::Udp, 2)
+            ],
+            metrics.protocols()
+        );
+
+        metrics.reset();
+        assert!(metrics.protocols().is_empty());
+    }
+
+    #[test]
+    fn protocol_names() {
+        assert_eq!("packets_tcp", protocol_packets_name(Protocol::Tcp));
+        assert_eq!("packets_udp", protocol_packets_name(Protocol::Udp));
+        assert_eq!(
+            "packets_icmp",
+            protocol_packets_name(Protocol::Other(PROTOCOL_ICMP))
+        );
+        assert_eq!(
+            "packets_protocol_47",
+            protocol_packets_name(Protocol::Other(47))
+        );
+    }
+
+    #[test]
+    fn counter_indexes() {
+        for (i, &counter) in Counter::ALL.iter().enumerate() {
+            assert_eq!(i, counter as usize);
+        }
+    }
+}
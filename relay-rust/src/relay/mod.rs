@@ -14,32 +14,87 @@
  * limitations under the License.
  */
 
+pub use self::checksum_validation::ChecksumValidation;
+pub use self::config::{RelayConfig, RelayConfigBuilder};
+pub use self::connection::ConnectionId;
+pub use self::dns::DnsOverride;
+pub use self::dscp_remap::DscpRemap;
+#[cfg(unix)]
+pub use self::handoff::{Handoff, HandoffRequest};
+pub use self::icmp::IcmpPolicy;
+pub use self::inspector::{Inspector, Verdict};
+pub use self::json_lines_sink::JsonLinesSink;
+pub use self::metrics::{Counter, Metrics};
+pub use self::observer::{AuditRecord, CloseReason, Decision, DropReason, Observer};
+pub use self::overflow::OverflowPolicy;
+pub use self::pause_switch::PauseSwitch;
 pub use self::relay::Relay;
+pub use self::sharded_relay::ShardedRelay;
+pub use self::summary::ConnectionSummary;
+pub use self::upstream_factory::UpstreamFactory;
 pub mod byte_buffer;
 
+// the packets are parsed by the packet module, which does not depend on the relay
+use crate::packet::{checksum, fragment, ipv4_header, ipv4_packet, tcp_header, transport_header};
+
+// declared first, its macros are used by the other modules
+#[macro_use]
+mod interrupt;
+
+mod address_families;
 mod binary;
+mod checksum_validation;
 mod client;
+mod client_address;
+mod client_auth;
+mod clock;
 mod close_listener;
+mod config;
+mod congestion_window;
 #[macro_use]
 mod connection;
+mod control_server;
 mod datagram;
 mod datagram_buffer;
-#[macro_use]
-mod interrupt;
-mod ipv4_header;
-mod ipv4_packet;
+mod delay_queue;
+mod dns;
+mod dns_cache;
+mod drop_logger;
+mod dscp_remap;
+mod event_loop;
+mod gre;
+#[cfg(unix)]
+mod handoff;
+mod icmp;
+mod icmp_echo;
+mod inspector;
 mod ipv4_packet_buffer;
+mod json_lines_sink;
+mod loss_injector;
+mod metrics;
+mod nat;
 mod net;
+mod observer;
+mod overflow;
 mod packet_source;
 mod packetizer;
+mod pause_switch;
 #[allow(clippy::module_inception)] // relay.rs is in relay/
 mod relay;
+mod retry_backoff;
 mod router;
+mod rtt_estimator;
 mod selector;
+mod sharded_relay;
 mod stream_buffer;
+mod summary;
 mod tcp_connection;
-mod tcp_header;
-mod transport_header;
+mod token_bucket;
+#[cfg(all(feature = "tokio", unix))]
+mod tokio_backend;
+mod trace_ring;
 mod tunnel_server;
 mod udp_connection;
-mod udp_header;
+mod unacked_queue;
+mod upstream_factory;
+mod write_coalescer;
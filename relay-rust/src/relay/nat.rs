@@ -0,0 +1,238 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::rc::Rc;
+
+use super::config::RelayConfig;
+use super::connection::{ConnectionId, FlowKey};
+use super::ipv4_header::Protocol;
+
+// the ports allocated to the translated sources (the IANA dynamic range)
+const FIRST_PORT: u16 = 49152;
+const LAST_PORT: u16 = 65535;
+
+/// The translated sources allocated to all the clients of a relay.
+///
+/// The clients translated on the same external address share its ports: a port allocated to a
+/// client is not allocated to another one until it is released.
+#[derive(Default)]
+pub struct NatPorts {
+    allocated: HashSet<(Protocol, SocketAddrV4)>,
+    // the next port to allocate on each external address
+    next_ports: HashMap<Ipv4Addr, u16>,
+}
+
+impl NatPorts {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // allocate a port of `address` not allocated yet for `protocol`
+    fn allocate(&mut self, protocol: Protocol, address: Ipv4Addr) -> Option<SocketAddrV4> {
+        let next_port = self.next_ports.entry(address).or_insert(FIRST_PORT);
+        for _ in FIRST_PORT..=LAST_PORT {
+            let port = *next_port;
+            *next_port = if port == LAST_PORT {
+                FIRST_PORT
+            } else {
+                port + 1
+            };
+            let source = SocketAddrV4::new(address, port);
+            if self.allocated.insert((protocol, source)) {
+                return Some(source);
+            }
+        }
+        None
+    }
+
+    fn release(&mut self, protocol: Protocol, source: SocketAddrV4) {
+        self.allocated.remove(&(protocol, source));
+    }
+}
+
+/// Source NAT table of a client.
+///
+/// Each flow of the client is mapped to a translated source: the external address of the client
+/// and a port allocated from the dynamic range, unique per protocol among all the clients. The
+/// connections are identified by the translated source (so their upstream sockets are bound to
+/// it), but they are created from the packets as sent by the client, so the packets they send
+/// back are addressed to the client source without any rewriting.
+pub struct NatTable {
+    external_address: Ipv4Addr,
+    // shared by all the clients of the relay
+    ports: Rc<RefCell<NatPorts>>,
+    // the translated source of each flow
+    outbound: HashMap<FlowKey, SocketAddrV4>,
+    // the flow of each translated source, by protocol
+    inbound: HashMap<(Protocol, SocketAddrV4), FlowKey>,
+}
+
+impl NatTable {
+    pub fn new(external_address: Ipv4Addr, ports: Rc<RefCell<NatPorts>>) -> Self {
+        Self {
+            external_address,
+            ports,
+            outbound: HashMap::new(),
+            inbound: HashMap::new(),
+        }
+    }
+
+    /// The translated source of the flow `id`, if it is mapped.
+    pub fn translated_source(&self, id: &ConnectionId) -> Option<SocketAddrV4> {
+        self.outbound.get(&id.flow_key()).cloned()
+    }
+
+    /// Map the flow `id` to a translated source whose port is not allocated yet for its protocol.
+    ///
+    /// Return `None` if all the ports are allocated.
+    pub fn allocate(&mut self, id: &ConnectionId) -> Option<SocketAddrV4> {
+        let protocol = id.protocol();
+        let source = self
+            .ports
+            .borrow_mut()
+            .allocate(protocol, self.external_address)?;
+        let internal = id.flow_key();
+        self.inbound.insert((protocol, source), internal);
+        self.outbound.insert(internal, source);
+        Some(source)
+    }
+
+    /// Remove the mapping of the translated `source`, so that its port may be allocated again.
+    pub fn release(&mut self, protocol: Protocol, source: SocketAddrV4) {
+        if let Some(internal) = self.inbound.remove(&(protocol, source)) {
+            self.outbound.remove(&internal);
+            self.ports.borrow_mut().release(protocol, source);
+        }
+    }
+}
+
+/// The local address to bind the upstream socket of the connection `id` to, if any.
+///
+/// With source NAT, the connections are identified by their translated source.
+pub fn upstream_source(id: &ConnectionId, config: &RelayConfig) -> Option<SocketAddrV4> {
+    if config.source_nat() {
+        Some(id.source())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::ipv4_packet::Ipv4Packet;
+    use crate::relay::tcp_connection::tests::create_tcp_packet;
+    use crate::relay::tcp_header::FLAG_SYN;
+
+    const EXTERNAL_ADDRESS: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 42);
+
+    fn connection_id(raw: &mut [u8]) -> ConnectionId {
+        let ipv4_packet = Ipv4Packet::parse(raw);
+        let (ipv4_header_data, transport_header_data) = ipv4_packet.headers_data();
+        ConnectionId::from_headers(ipv4_header_data, transport_header_data.unwrap())
+    }
+
+    fn create_nat_table(ports: &Rc<RefCell<NatPorts>>) -> NatTable {
+        NatTable::new(EXTERNAL_ADDRESS, ports.clone())
+    }
+
+    #[test]
+    fn allocate_distinct_ports() {
+        let mut nat_table = create_nat_table(&Default::default());
+        let mut raw1 = create_tcp_packet(80, 1000, 0, FLAG_SYN, 0xffff, &[]);
+        let mut raw2 = create_tcp_packet(443, 1000, 0, FLAG_SYN, 0xffff, &[]);
+        let id1 = connection_id(&mut raw1);
+        let id2 = connection_id(&mut raw2);
+        assert!(nat_table.translated_source(&id1).is_none());
+
+        let source1 = nat_table.allocate(&id1).unwrap();
+        let source2 = nat_table.allocate(&id2).unwrap();
+        assert_eq!(SocketAddrV4::new(EXTERNAL_ADDRESS, FIRST_PORT), source1);
+        assert_eq!(SocketAddrV4::new(EXTERNAL_ADDRESS, FIRST_PORT + 1), source2);
+        assert_eq!(Some(source1), nat_table.translated_source(&id1));
+        assert_eq!(Some(source2), nat_table.translated_source(&id2));
+
+        nat_table.release(Protocol::Tcp, source1);
+        assert!(nat_table.translated_source(&id1).is_none());
+        assert_eq!(Some(source2), nat_table.translated_source(&id2));
+    }
+
+    #[test]
+    fn skip_allocated_ports() {
+        let ports = Rc::new(RefCell::new(NatPorts::new()));
+        let mut nat_table = create_nat_table(&ports);
+        ports
+            .borrow_mut()
+            .next_ports
+            .insert(EXTERNAL_ADDRESS, LAST_PORT);
+        let mut raw1 = create_tcp_packet(80, 1000, 0, FLAG_SYN, 0xffff, &[]);
+        let mut raw2 = create_tcp_packet(443, 1000, 0, FLAG_SYN, 0xffff, &[]);
+        let id1 = connection_id(&mut raw1);
+        let id2 = connection_id(&mut raw2);
+        let source1 = nat_table.allocate(&id1).unwrap();
+        assert_eq!(LAST_PORT, source1.port());
+
+        // wrap around the range, skipping the ports already allocated
+        ports
+            .borrow_mut()
+            .next_ports
+            .insert(EXTERNAL_ADDRESS, LAST_PORT);
+        let source2 = nat_table.allocate(&id2).unwrap();
+        assert_eq!(FIRST_PORT, source2.port());
+    }
+
+    #[test]
+    fn share_ports_between_clients() {
+        let ports = Rc::new(RefCell::new(NatPorts::new()));
+        let mut nat_table1 = create_nat_table(&ports);
+        let mut nat_table2 = create_nat_table(&ports);
+        // both clients open the same flow
+        let mut raw = create_tcp_packet(80, 1000, 0, FLAG_SYN, 0xffff, &[]);
+        let id = connection_id(&mut raw);
+
+        let source1 = nat_table1.allocate(&id).unwrap();
+        let source2 = nat_table2.allocate(&id).unwrap();
+        assert_ne!(source1, source2);
+
+        // a released port may be allocated to any client
+        nat_table1.release(Protocol::Tcp, source1);
+        ports
+            .borrow_mut()
+            .next_ports
+            .insert(EXTERNAL_ADDRESS, source1.port());
+        assert_eq!(Some(source1), nat_table2.allocate(&id));
+    }
+
+    #[test]
+    fn allocate_per_protocol() {
+        let mut nat_table = create_nat_table(&Default::default());
+        let mut raw = create_tcp_packet(80, 1000, 0, FLAG_SYN, 0xffff, &[]);
+        let id = connection_id(&mut raw);
+        let source = nat_table.allocate(&id).unwrap();
+
+        // the same port is free for UDP
+        let ports = nat_table.ports.clone();
+        let mut ports = ports.borrow_mut();
+        ports.next_ports.insert(EXTERNAL_ADDRESS, source.port());
+        assert_eq!(
+            Some(source),
+            ports.allocate(Protocol::Udp, EXTERNAL_ADDRESS)
+        );
+    }
+}
@@ -14,8 +14,24 @@
  * limitations under the License.
  */
 
+use mio::net::{TcpStream, UdpSocket};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{self, Ipv4Addr, SocketAddrV4};
+
 use super::binary;
-use std::net::{Ipv4Addr, SocketAddrV4};
+use super::config::RelayConfig;
+
+#[cfg(unix)]
+const ENOBUFS: i32 = libc::ENOBUFS;
+#[cfg(windows)]
+const ENOBUFS: i32 = 10055; // WSAENOBUFS
+
+// too many descriptors open by the process, or by the whole system
+#[cfg(unix)]
+const DESCRIPTORS_EXHAUSTED: [i32; 2] = [libc::EMFILE, libc::ENFILE];
+#[cfg(windows)]
+const DESCRIPTORS_EXHAUSTED: [i32; 1] = [10024]; // WSAEMFILE
 
 pub fn to_addr(ipv4: u32) -> Ipv4Addr {
     let raw = binary::to_byte_array(ipv4);
@@ -26,3 +42,310 @@ pub fn to_socket_addr(ipv4: u32, port: u16) -> SocketAddrV4 {
     let addr = to_addr(ipv4);
     SocketAddrV4::new(addr, port)
 }
+
+/// Indicate whether the error is ENOBUFS, returned on send when the kernel buffers are (temporarily)
+/// exhausted.
+pub fn is_no_buffer_space(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(ENOBUFS)
+}
+
+/// Indicate whether the error is EMFILE or ENFILE, returned on socket creation when no file
+/// descriptor is available anymore.
+pub fn is_descriptors_exhausted(err: &io::Error) -> bool {
+    err.raw_os_error()
+        .is_some_and(|code| DESCRIPTORS_EXHAUSTED.contains(&code))
+}
+
+// create an IPv4 socket, with the kernel buffer sizes configured for the upstream sockets
+fn create_socket(socket_type: Type, config: &RelayConfig) -> io::Result<Socket> {
+    create_protocol_socket(socket_type, None, config)
+}
+
+fn create_protocol_socket(
+    socket_type: Type,
+    protocol: Option<Protocol>,
+    config: &RelayConfig,
+) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::IPV4, socket_type, protocol)?;
+    if let Some(size) = config.receive_buffer_size() {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = config.send_buffer_size() {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(fwmark) = config.upstream_fwmark() {
+        set_mark(&socket, fwmark)?;
+    }
+    Ok(socket)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_mark(socket: &Socket, fwmark: u32) -> io::Result<()> {
+    socket.set_mark(fwmark)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_mark(_: &Socket, _: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Socket marks (SO_MARK) are only supported on Linux",
+    ))
+}
+
+/// Start connecting a TCP stream to `destination` (asynchronously).
+pub fn connect_tcp_stream(
+    destination: SocketAddrV4,
+    config: &RelayConfig,
+) -> io::Result<TcpStream> {
+    let socket = create_socket(Type::STREAM, config)?;
+    if let Some(address) = config.external_address() {
+        socket.bind(&SocketAddrV4::new(address, 0).into())?;
+    }
+    TcpStream::connect_stream(socket.into(), &destination.into())
+}
+
+/// Start connecting a TCP stream from the translated `source` (source NAT) to `destination`.
+pub fn connect_tcp_stream_from(
+    source: SocketAddrV4,
+    destination: SocketAddrV4,
+    config: &RelayConfig,
+) -> io::Result<TcpStream> {
+    let socket = create_socket(Type::STREAM, config)?;
+    // a connection retrying to connect binds its new stream while the previous one is still open
+    socket.set_reuse_address(true)?;
+    socket.bind(&source.into())?;
+    TcpStream::connect_stream(socket.into(), &destination.into())
+}
+
+/// Start connecting the upstream TCP stream of a connection to `destination`, through the
+/// `UpstreamFactory` if any, otherwise from the translated `source` if any.
+pub fn connect_upstream_tcp(
+    source: Option<SocketAddrV4>,
+    destination: SocketAddrV4,
+    config: &RelayConfig,
+) -> io::Result<TcpStream> {
+    match (config.upstream_factory(), source) {
+        (Some(factory), _) => TcpStream::from_stream(factory.connect_tcp(destination)?),
+        (None, Some(source)) => connect_tcp_stream_from(source, destination, config),
+        (None, None) => connect_tcp_stream(destination, config),
+    }
+}
+
+/// Create the upstream UDP socket of a connection, connected to `destination`, through the
+/// `UpstreamFactory` if any, otherwise bound to the translated `source` if any.
+pub fn connect_upstream_udp(
+    source: Option<SocketAddrV4>,
+    destination: SocketAddrV4,
+    config: &RelayConfig,
+) -> io::Result<UdpSocket> {
+    match (config.upstream_factory(), source) {
+        (Some(factory), _) => {
+            let udp_socket = UdpSocket::from_socket(factory.bind_udp(destination)?)?;
+            udp_socket.connect(destination.into())?;
+            Ok(udp_socket)
+        }
+        (None, Some(source)) => connect_udp_socket_from(source, destination, config),
+        (None, None) => connect_udp_socket(destination, config),
+    }
+}
+
+/// Create a UDP socket connected to `destination`.
+pub fn connect_udp_socket(
+    destination: SocketAddrV4,
+    config: &RelayConfig,
+) -> io::Result<UdpSocket> {
+    let address = config.external_address().unwrap_or(Ipv4Addr::UNSPECIFIED);
+    connect_udp_socket_from(SocketAddrV4::new(address, 0), destination, config)
+}
+
+/// Create a UDP socket bound to `source` and connected to `destination`.
+pub fn connect_udp_socket_from(
+    source: SocketAddrV4,
+    destination: SocketAddrV4,
+    config: &RelayConfig,
+) -> io::Result<UdpSocket> {
+    let socket = create_socket(Type::DGRAM, config)?;
+    socket.bind(&source.into())?;
+    let udp_socket = UdpSocket::from_socket(net::UdpSocket::from(socket))?;
+    udp_socket.connect(destination.into())?;
+    Ok(udp_socket)
+}
+
+/// Create an unprivileged ICMP socket (exchanging the ICMP messages without their IPv4 header)
+/// connected to `destination`, to send echo requests.
+///
+/// On Linux, the group of the process must be allowed by the `net.ipv4.ping_group_range` sysctl.
+pub fn connect_icmp_socket(destination: Ipv4Addr, config: &RelayConfig) -> io::Result<UdpSocket> {
+    let socket = create_protocol_socket(Type::DGRAM, Some(Protocol::ICMPV4), config)?;
+    if let Some(address) = config.external_address() {
+        socket.bind(&SocketAddrV4::new(address, 0).into())?;
+    }
+    let udp_socket = UdpSocket::from_socket(net::UdpSocket::from(socket))?;
+    udp_socket.connect(SocketAddrV4::new(destination, 0).into())?;
+    Ok(udp_socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::config::RelayConfigBuilder;
+    use std::net::SocketAddr;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn default_buffer_sizes() {
+        let config = RelayConfigBuilder::new(0).build();
+        let default_socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        let socket = create_socket(Type::STREAM, &config).unwrap();
+        assert_eq!(
+            default_socket.recv_buffer_size().unwrap(),
+            socket.recv_buffer_size().unwrap()
+        );
+    }
+
+    #[test]
+    fn configure_buffer_sizes() {
+        let config = RelayConfigBuilder::new(0)
+            .receive_buffer_size(256 * 1024)
+            .send_buffer_size(128 * 1024)
+            .build();
+        for &socket_type in &[Type::STREAM, Type::DGRAM] {
+            let socket = create_socket(socket_type, &config).unwrap();
+            // the kernel may adjust the value (Linux doubles it), or cap it
+            let size = socket.recv_buffer_size().unwrap();
+            assert!(size >= 128 * 1024, "SO_RCVBUF={}", size);
+            let size = socket.send_buffer_size().unwrap();
+            assert!(size >= 64 * 1024, "SO_SNDBUF={}", size);
+        }
+    }
+
+    #[test]
+    fn connect_udp_socket_with_buffer_sizes() {
+        let config = RelayConfigBuilder::new(0)
+            .receive_buffer_size(256 * 1024)
+            .build();
+        let destination = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 9);
+        let socket = connect_udp_socket(destination, &config).unwrap();
+        assert_ne!(0, socket.local_addr().unwrap().port());
+        // connected, so send() is allowed
+        assert_eq!(4, socket.send(b"test").unwrap());
+    }
+
+    #[test]
+    fn bind_udp_socket_to_external_address() {
+        let config = RelayConfigBuilder::new(0)
+            .external_address(Ipv4Addr::LOCALHOST)
+            .build();
+        let server = net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let destination = match server.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            _ => panic!("Not an IPv4 address"),
+        };
+        let socket = connect_udp_socket(destination, &config).unwrap();
+        socket.send(b"test").unwrap();
+        let mut buf = [0; 4];
+        let (_, source) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(socket.local_addr().unwrap(), source);
+        assert_eq!(Ipv4Addr::LOCALHOST, source.ip());
+    }
+
+    #[test]
+    fn bind_tcp_stream_to_external_address() {
+        let config = RelayConfigBuilder::new(0)
+            .external_address(Ipv4Addr::LOCALHOST)
+            .build();
+        let listener = net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let destination = match listener.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            _ => panic!("Not an IPv4 address"),
+        };
+        let stream = connect_tcp_stream(destination, &config).unwrap();
+        let (_, source) = listener.accept().unwrap();
+        assert_eq!(Ipv4Addr::LOCALHOST, source.ip());
+        assert_eq!(stream.local_addr().unwrap().port(), source.port());
+    }
+
+    #[test]
+    fn bind_upstream_sockets_to_translated_source() {
+        let config = RelayConfigBuilder::new(0).build();
+        let listener = net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let server = net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let tcp_destination = match listener.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            _ => panic!("Not an IPv4 address"),
+        };
+        let udp_destination = match server.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            _ => panic!("Not an IPv4 address"),
+        };
+
+        // let the kernel choose a free port, then bind to it explicitly
+        let source = match net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+        {
+            SocketAddr::V4(addr) => addr,
+            _ => panic!("Not an IPv4 address"),
+        };
+        let socket = connect_upstream_udp(Some(source), udp_destination, &config).unwrap();
+        socket.send(b"test").unwrap();
+        let mut buf = [0; 4];
+        let (_, peer) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(SocketAddr::V4(source), peer);
+
+        let _stream = connect_upstream_tcp(Some(source), tcp_destination, &config).unwrap();
+        let (_, peer) = listener.accept().unwrap();
+        assert_eq!(SocketAddr::V4(source), peer);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn set_upstream_fwmark() {
+        let config = RelayConfigBuilder::new(0).upstream_fwmark(0x2a).build();
+        for &socket_type in &[Type::STREAM, Type::DGRAM] {
+            match create_socket(socket_type, &config) {
+                Ok(socket) => assert_eq!(0x2a, socket.mark().unwrap()),
+                Err(ref err) if err.raw_os_error() == Some(libc::EPERM) => {
+                    // setting a mark requires CAP_NET_ADMIN
+                    return;
+                }
+                Err(err) => panic!("Cannot set the mark: {}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn ping_through_icmp_socket() {
+        let config = RelayConfigBuilder::new(0).build();
+        let socket = match connect_icmp_socket(Ipv4Addr::LOCALHOST, &config) {
+            Ok(socket) => socket,
+            Err(ref err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                // the group is not allowed by net.ipv4.ping_group_range
+                return;
+            }
+            Err(err) => panic!("Cannot create ICMP socket: {}", err),
+        };
+        // echo request, identifier 0x1234, sequence number 1, the checksum is computed by the
+        // kernel
+        let request = [8, 0, 0, 0, 0x12, 0x34, 0, 1, b'p', b'i', b'n', b'g'];
+        socket.send(&request).unwrap();
+
+        let mut buf = [0; 64];
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let length = loop {
+            match socket.recv(&mut buf) {
+                Ok(length) => break length,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    assert!(Instant::now() < deadline, "No ICMP echo reply");
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Err(err) => panic!("Cannot receive ICMP echo reply: {}", err),
+            }
+        };
+        assert_eq!(request.len(), length);
+        assert_eq!(0, buf[0]); // echo reply
+        assert_eq!(&request[6..], &buf[6..length]);
+    }
+}
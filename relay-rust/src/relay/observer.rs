@@ -0,0 +1,156 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::net::SocketAddrV4;
+
+use super::connection::{ConnectionId, ConnectionStats};
+use super::summary::ConnectionSummary;
+
+/// Why a connection has been removed from the router.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The client closed the connection first (FIN).
+    ClientFin,
+    /// The upstream server closed the connection first (EOF).
+    UpstreamFin,
+    /// The connection has been reset by the client, or because of an invalid client packet.
+    ClientRst,
+    /// The upstream socket failed, e.g. the connection was refused or reset by the server.
+    UpstreamRst,
+    /// The connection has been idle for too long.
+    Timeout,
+    /// The connection has been removed by the relay to make room for another one, because the
+    /// file descriptors were exhausted.
+    Evicted,
+    /// The client has been disconnected from the relay.
+    ClientDisconnected,
+    /// The relay stopped relaying the connection, because it has been handed off to another relay
+    /// process without it.
+    RelayShutdown,
+    /// The connection reached its maximum lifetime, whatever its activity.
+    MaxLifetime,
+}
+
+/// Why a packet sent by the client has not been relayed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropReason {
+    /// The `Inspector` rejected the packet.
+    Inspector,
+    /// The source of the packet was outside the subnet announced by the client.
+    Spoofed,
+    /// The packet has been dropped to simulate packet loss.
+    InjectedLoss,
+    /// The client exceeded its maximum packet rate.
+    RateLimited,
+    /// No connection could be created to relay the packet.
+    Unroutable,
+    /// A TCP segment other than a SYN did not belong to any connection (it has been answered by a
+    /// RST).
+    OutOfState,
+    /// The checksum of the packet was wrong.
+    InvalidChecksum,
+    /// A SYN has been refused (answered by a RST) because too many connections of the client were
+    /// being opened.
+    HalfOpenLimit,
+    /// The transport protocol of the packet is not allowed by the configuration (a TCP segment has
+    /// been answered by a RST).
+    ProtocolDisabled,
+    /// No connection has been created because the file descriptors were exhausted recently.
+    DescriptorsExhausted,
+    /// The packet exceeded the tunnel MTU with the "don't fragment" flag (it has been answered by an
+    /// ICMP "fragmentation needed").
+    ExceedsMtu,
+}
+
+/// What the relay decided for a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decision {
+    /// The connection has been relayed.
+    Accepted,
+    /// The `Inspector` rejected the first packet, so the connection has never been created.
+    Blocked,
+}
+
+/// Summary of the decisions taken by the relay for one connection, reported once: when it is
+/// closed, or when it is blocked.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditRecord<'a> {
+    pub id: &'a ConnectionId,
+    pub decision: Decision,
+    /// The address actually connected to, after the rewriting of the destination (e.g. the
+    /// localhost forward or the DNS resolver override), if the connection has been relayed.
+    pub upstream: Option<SocketAddrV4>,
+    pub close_reason: Option<CloseReason>,
+}
+
+impl CloseReason {
+    pub fn name(self) -> &'static str {
+        match self {
+            CloseReason::ClientFin => "client_fin",
+            CloseReason::UpstreamFin => "upstream_fin",
+            CloseReason::ClientRst => "client_rst",
+            CloseReason::UpstreamRst => "upstream_rst",
+            CloseReason::Timeout => "timeout",
+            CloseReason::Evicted => "evicted",
+            CloseReason::ClientDisconnected => "client_disconnected",
+            CloseReason::RelayShutdown => "relay_shutdown",
+            CloseReason::MaxLifetime => "max_lifetime",
+        }
+    }
+}
+
+impl Decision {
+    pub fn name(self) -> &'static str {
+        match self {
+            Decision::Accepted => "accepted",
+            Decision::Blocked => "blocked",
+        }
+    }
+}
+
+impl DropReason {
+    pub fn name(self) -> &'static str {
+        match self {
+            DropReason::Inspector => "inspector",
+            DropReason::Spoofed => "spoofed",
+            DropReason::InjectedLoss => "injected_loss",
+            DropReason::RateLimited => "rate_limited",
+            DropReason::Unroutable => "unroutable",
+            DropReason::OutOfState => "out_of_state",
+            DropReason::InvalidChecksum => "invalid_checksum",
+            DropReason::HalfOpenLimit => "half_open_limit",
+            DropReason::ProtocolDisabled => "protocol_disabled",
+            DropReason::DescriptorsExhausted => "descriptors_exhausted",
+            DropReason::ExceedsMtu => "exceeds_mtu",
+        }
+    }
+}
+
+/// Hook notified of the lifecycle of the connections (and of the relay resources), e.g. to export
+/// them to a log pipeline.
+///
+/// All the methods do nothing by default.
+pub trait Observer {
+    fn on_open(&self, _id: &ConnectionId) {}
+    fn on_close(&self, _id: &ConnectionId, _stats: &ConnectionStats, _reason: CloseReason) {}
+    fn on_drop(&self, _id: &ConnectionId, _reason: DropReason) {}
+    /// Called once per connection, after `on_close()` or the drop blocking its creation.
+    fn on_audit(&self, _record: &AuditRecord) {}
+    /// Called when the selector needs more handles than its capacity, with the new capacity.
+    fn on_capacity_grown(&self, _capacity: usize) {}
+    /// Called periodically with an overview of the connections, if a summary interval is set.
+    fn on_summary(&self, _summary: &ConnectionSummary) {}
+}
@@ -0,0 +1,130 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/// What to do with a datagram received from the network while the client write queue is full.
+///
+/// It only applies to UDP: the relay does not retransmit, so the TCP connections always stop
+/// reading from the network until the client queue has some space (dropping segments would
+/// corrupt the streams).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Hold the datagram and stop reading from the upstream socket until it is sent to the
+    /// client, so that the kernel buffers (and then the peer) absorb the backpressure.
+    BlockUpstream,
+    /// Hold the datagram and keep reading: each new datagram replaces the one held.
+    DropOldest,
+    /// Drop the datagram.
+    #[default]
+    DropNewest,
+}
+
+impl OverflowPolicy {
+    pub fn name(self) -> &'static str {
+        match self {
+            OverflowPolicy::BlockUpstream => "block_upstream",
+            OverflowPolicy::DropOldest => "drop_oldest",
+            OverflowPolicy::DropNewest => "drop_newest",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    /// The datagram is held, the connection must register as a pending packet source.
+    Held,
+    /// The datagram replaces the one already held (which is dropped).
+    Replaced,
+    Dropped,
+}
+
+/// Slot for the datagram (of which only the length is stored, the data stay in the packetizer)
+/// waiting for some space in the client write queue, applying an `OverflowPolicy`.
+pub struct OverflowSlot {
+    policy: OverflowPolicy,
+    held: Option<u16>,
+}
+
+impl OverflowSlot {
+    pub fn new(policy: OverflowPolicy) -> Self {
+        Self { policy, held: None }
+    }
+
+    /// The length of the datagram held, if any.
+    pub fn held(&self) -> Option<u16> {
+        self.held
+    }
+
+    /// Whether the upstream socket may be read.
+    pub fn may_read(&self) -> bool {
+        self.policy != OverflowPolicy::BlockUpstream || self.held.is_none()
+    }
+
+    /// Apply the policy to a datagram of `length` bytes which does not fit in the client queue.
+    pub fn overflow(&mut self, length: u16) -> Overflow {
+        match self.policy {
+            OverflowPolicy::DropNewest => Overflow::Dropped,
+            OverflowPolicy::BlockUpstream | OverflowPolicy::DropOldest => {
+                match self.held.replace(length) {
+                    Some(_) => Overflow::Replaced,
+                    None => Overflow::Held,
+                }
+            }
+        }
+    }
+
+    /// Release the datagram held, once it has been sent to the client.
+    pub fn release(&mut self) -> Option<u16> {
+        self.held.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_upstream() {
+        let mut slot = OverflowSlot::new(OverflowPolicy::BlockUpstream);
+        assert!(slot.may_read());
+        assert_eq!(Overflow::Held, slot.overflow(100));
+        assert_eq!(Some(100), slot.held());
+        // no more datagrams are read until the held one is sent
+        assert!(!slot.may_read());
+        assert_eq!(Some(100), slot.release());
+        assert!(slot.may_read());
+        assert!(slot.held().is_none());
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let mut slot = OverflowSlot::new(OverflowPolicy::DropOldest);
+        assert_eq!(Overflow::Held, slot.overflow(100));
+        assert!(slot.may_read());
+        assert_eq!(Overflow::Replaced, slot.overflow(200));
+        assert_eq!(Overflow::Replaced, slot.overflow(300));
+        assert_eq!(Some(300), slot.release());
+        assert_eq!(Overflow::Held, slot.overflow(400));
+    }
+
+    #[test]
+    fn drop_newest() {
+        let mut slot = OverflowSlot::new(OverflowPolicy::DropNewest);
+        assert_eq!(Overflow::Dropped, slot.overflow(100));
+        assert_eq!(Overflow::Dropped, slot.overflow(200));
+        assert!(slot.held().is_none());
+        assert!(slot.may_read());
+    }
+}
@@ -19,13 +19,12 @@ use super::selector::Selector;
 
 /// Source that may produce packets.
 ///
-/// When a `TcpConnection` sends a packet to the `Client` while its buffers are full, then it
-/// fails. To recover, once some space becomes available, the `Client` must pull the available
+/// When a connection sends a packet to the `Client` while its buffers are full, then it fails. To recover, once some space becomes available, the `Client` must pull the available
 /// packets.
 ///
 /// This trait provides the abstraction of a packet source from which it can pull packets.
 ///
-/// It is implemented by `TcpConnection`.
+/// It is implemented by `TcpConnection` and `UdpConnection` (depending on its overflow policy).
 pub trait PacketSource {
     fn get(&mut self) -> Option<Ipv4Packet>;
     fn next(&mut self, selector: &mut Selector);
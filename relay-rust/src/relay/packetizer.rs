@@ -70,6 +70,13 @@ impl Packetizer {
         self.build(0)
     }
 
+    /// Packetize the given payload, which must fit in the buffer.
+    pub fn packetize_payload(&mut self, payload: &[u8]) -> Ipv4Packet {
+        let payload_end = self.payload_index + payload.len();
+        self.buffer[self.payload_index..payload_end].copy_from_slice(payload);
+        self.build(payload.len() as u16)
+    }
+
     pub fn packetize<R: DatagramReceiver>(&mut self, source: &mut R) -> io::Result<Ipv4Packet> {
         let r = source.recv(&mut self.buffer[self.payload_index..])?;
         let ipv4_packet = self.build(r as u16);
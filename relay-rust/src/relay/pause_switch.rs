@@ -0,0 +1,65 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use log::*;
+use mio::{Ready, SetReadiness};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+const TAG: &str = "PauseSwitch";
+
+/// Stop reading the packets sent by the clients (for maintenance), then restart.
+///
+/// While paused, the connections are kept alive and the data received from the network are still
+/// sent to the clients. It may be toggled from any thread.
+#[derive(Default)]
+pub struct PauseSwitch {
+    paused: AtomicBool,
+    // wake up the event loops (one per shard), once they are running
+    wakers: Mutex<Vec<SetReadiness>>,
+}
+
+impl PauseSwitch {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn pause(&self) {
+        self.set_paused(true);
+    }
+
+    pub fn resume(&self) {
+        self.set_paused(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+        for waker in self.wakers.lock().unwrap().iter() {
+            if let Err(err) = waker.set_readiness(Ready::readable()) {
+                error!(target: TAG, "Cannot wake up the event loop: {}", err);
+            }
+        }
+    }
+
+    /// Notify `waker` on every change, so that its event loop applies it.
+    pub fn add_waker(&self, waker: SetReadiness) {
+        self.wakers.lock().unwrap().push(waker);
+    }
+}
@@ -14,66 +14,346 @@
  * limitations under the License.
  */
 
-use chrono::Local;
 use log::*;
-use mio::Events;
-use std::cell::RefCell;
-use std::cmp::max;
+use std::cell::{RefCell, RefMut};
 use std::io;
+use std::net::SocketAddr;
 use std::rc::Rc;
-use std::time::Duration;
+use std::sync::Arc;
 
-use super::selector::Selector;
-use super::tunnel_server::TunnelServer;
-use super::udp_connection::IDLE_TIMEOUT_SECONDS;
+use super::config::{RelayConfig, RelayConfigBuilder};
+use super::event_loop::EventLoop;
+#[cfg(unix)]
+use super::handoff::{Handoff, HandoffRequest};
+use super::ipv4_header::Protocol;
+use super::metrics::Metrics;
+use super::pause_switch::PauseSwitch;
+#[cfg(all(feature = "tokio", unix))]
+use super::tokio_backend;
 
 const TAG: &str = "Relay";
-const CLEANING_INTERVAL_SECONDS: i64 = 60;
+
+// the protocols for which the router creates connections (ICMP is not relayed: the relay only
+// reports the port unreachable errors of the UDP connections to the clients)
+const SUPPORTED_PROTOCOLS: &[Protocol] = &[Protocol::Tcp, Protocol::Udp];
 
 pub struct Relay {
-    port: u16,
+    config: Rc<RelayConfig>,
+    metrics: Arc<Metrics>,
+    pause_switch: Arc<PauseSwitch>,
+    #[cfg(unix)]
+    handoff_request: Arc<HandoffRequest>,
+    // created early if the address is requested before running
+    event_loop: RefCell<Option<EventLoop>>,
 }
 
 impl Relay {
     pub fn new(port: u16) -> Self {
-        Self { port }
+        Self::with_config(RelayConfigBuilder::new(port).build())
+    }
+
+    pub fn with_config(config: RelayConfig) -> Self {
+        Self {
+            config: Rc::new(config),
+            metrics: Arc::new(Metrics::new()),
+            pause_switch: Arc::new(PauseSwitch::new()),
+            #[cfg(unix)]
+            handoff_request: Arc::new(HandoffRequest::new()),
+            event_loop: RefCell::new(None),
+        }
+    }
+
+    /// Create a relay resuming the clients (and their TCP connections) handed off by another relay
+    /// process, typically for a live upgrade.
+    ///
+    /// The config should be the same as the one of the relay which handed off.
+    #[cfg(unix)]
+    pub fn restore(config: RelayConfig, handoff: Handoff) -> io::Result<Self> {
+        let relay = Self::with_config(config);
+        let event_loop = EventLoop::restore(
+            relay.config.clone(),
+            relay.metrics.clone(),
+            relay.pause_switch.clone(),
+            handoff,
+        )?;
+        *relay.event_loop.borrow_mut() = Some(event_loop);
+        Ok(relay)
+    }
+
+    /// The protocols actually relayed by this build, e.g. to show the capabilities of the relay in
+    /// a UI.
+    pub fn supported_protocols() -> &'static [Protocol] {
+        SUPPORTED_PROTOCOLS
+    }
+
+    /// The counters of the relay, which may be read from another thread.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Stop reading the packets sent by the clients, keeping the connections alive.
+    pub fn pause(&self) {
+        self.pause_switch.pause();
+    }
+
+    /// Restart reading the packets sent by the clients after `pause()`.
+    pub fn resume(&self) {
+        self.pause_switch.resume();
+    }
+
+    /// The switch to pause and resume the relay, which may be used from another thread.
+    pub fn pause_switch(&self) -> Arc<PauseSwitch> {
+        self.pause_switch.clone()
+    }
+
+    /// The request to stop a relay running `run_until_handoff()`, which may be used from another
+    /// thread.
+    #[cfg(unix)]
+    pub fn handoff_request(&self) -> Arc<HandoffRequest> {
+        self.handoff_request.clone()
+    }
+
+    /// Bind the relay (if not already done) and return the address the clients connect to.
+    ///
+    /// This gives the actual port when the relay is configured to listen on port 0.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.bound_event_loop()?.local_addr()
+    }
+
+    /// Bind the relay (if not already done) and return the address of its control server, if
+    /// enabled.
+    ///
+    /// This gives the actual port when the control server is configured to listen on port 0.
+    pub fn control_addr(&self) -> io::Result<Option<SocketAddr>> {
+        Ok(self.bound_event_loop()?.control_addr())
+    }
+
+    fn bound_event_loop(&self) -> io::Result<RefMut<'_, EventLoop>> {
+        let mut event_loop = self.event_loop.borrow_mut();
+        if event_loop.is_none() {
+            *event_loop = Some(self.create_event_loop()?);
+        }
+        Ok(RefMut::map(event_loop, |event_loop| {
+            event_loop.as_mut().unwrap()
+        }))
+    }
+
+    fn take_event_loop(&self) -> io::Result<EventLoop> {
+        match self.event_loop.borrow_mut().take() {
+            Some(event_loop) => Ok(event_loop),
+            None => self.create_event_loop(),
+        }
+    }
+
+    fn create_event_loop(&self) -> io::Result<EventLoop> {
+        EventLoop::create(
+            self.config.clone(),
+            self.metrics.clone(),
+            self.pause_switch.clone(),
+        )
     }
 
     pub fn run(&self) -> io::Result<()> {
-        let mut selector = Selector::create().unwrap();
-        let tunnel_server = TunnelServer::create(self.port, &mut selector)?;
+        let mut event_loop = self.take_event_loop()?;
         info!(target: TAG, "Relay server started");
-        self.poll_loop(&mut selector, &tunnel_server)
+        event_loop.run()
     }
 
-    fn poll_loop(
-        &self,
-        selector: &mut Selector,
-        tunnel_server: &Rc<RefCell<TunnelServer>>,
-    ) -> io::Result<()> {
-        let mut events = Events::with_capacity(1024);
-        // no connection may expire before the UDP idle timeout delay
-        let mut next_cleaning_deadline = Local::now().timestamp() + IDLE_TIMEOUT_SECONDS as i64;
+    /// Run the relay until a hand-off is requested, then return the state to give to
+    /// `Relay::restore()` in another relay process.
+    ///
+    /// The UDP associations are not handed off, nor the TCP connections in TIME_WAIT. The relay
+    /// must be dropped once the sockets have been transferred.
+    #[cfg(unix)]
+    pub fn run_until_handoff(&self) -> io::Result<Handoff> {
+        if self.config.source_nat() {
+            // the translations are not handed off
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Cannot hand off a relay with source NAT",
+            ));
+        }
+        let mut event_loop = self.take_event_loop()?;
+        info!(target: TAG, "Relay server started");
+        event_loop.run_until_handoff(&self.handoff_request)
+    }
+
+    /// Run the relay on the current tokio runtime, instead of blocking the current thread.
+    ///
+    /// The future is not `Send`: it must be run on a current-thread runtime or in a
+    /// `tokio::task::LocalSet`.
+    #[cfg(all(feature = "tokio", unix))]
+    pub async fn run_async(&self) -> io::Result<()> {
+        let event_loop = self.take_event_loop()?;
+        info!(target: TAG, "Relay server started (tokio)");
+        tokio_backend::run(event_loop).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::ipv4_header::PROTOCOL_ICMP;
+    use crate::relay::tcp_connection::tests::{
+        connect_tunnel, create_tcp_packet, handshake, read_tcp_packet, CLIENT_SEQ,
+    };
+    use crate::relay::tcp_header::{FLAG_ACK, FLAG_SYN};
+    use std::io::{Read, Write};
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn pause_and_resume() {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let relay = Relay::with_config(RelayConfigBuilder::new(0).build());
+            relay.pause();
+            let relay_port = relay.local_addr().unwrap().port();
+            sender.send((relay_port, relay.pause_switch())).unwrap();
+            relay.run().unwrap();
+        });
+        let (relay_port, pause_switch) = receiver.recv().unwrap();
+
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        server.set_nonblocking(true).unwrap();
+        let port = server.local_addr().unwrap().port();
+        // the client id is sent even while paused
+        let mut tunnel = connect_tunnel(relay_port);
+        let syn = create_tcp_packet(port, CLIENT_SEQ, 0, FLAG_SYN, 0xffff, &[]);
+        tunnel.write_all(&syn).unwrap();
+
+        // the SYN is not processed
+        tunnel
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let mut buf = [0; 1];
+        let err = tunnel.read(&mut buf).unwrap_err();
+        assert!(
+            err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut,
+            "{:?}",
+            err
+        );
+        assert!(server.accept().is_err());
+
+        pause_switch.resume();
+        tunnel
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let (_, flags, _) = read_tcp_packet(&mut tunnel);
+        assert_eq!(FLAG_SYN | FLAG_ACK, flags);
+    }
+
+    #[test]
+    fn bind_ephemeral_port() {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let relay = Relay::with_config(RelayConfigBuilder::new(0).build());
+            sender.send(relay.local_addr().unwrap()).unwrap();
+            relay.run().unwrap();
+        });
+        let local_addr = receiver.recv().unwrap();
+        assert_ne!(0, local_addr.port());
+        assert!(local_addr.ip().is_loopback());
+
+        // the relay accepts clients on this port
+        let mut tunnel = connect_tunnel(local_addr.port());
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let syn = create_tcp_packet(port, CLIENT_SEQ, 0, FLAG_SYN, 0xffff, &[]);
+        tunnel.write_all(&syn).unwrap();
+        let (_, flags, _) = read_tcp_packet(&mut tunnel);
+        assert_eq!(FLAG_SYN | FLAG_ACK, flags);
+    }
+
+    #[test]
+    fn bind_ephemeral_control_port() {
+        let relay = Relay::with_config(RelayConfigBuilder::new(0).control_port(0).build());
+        let control_addr = relay.control_addr().unwrap().unwrap();
+        assert_ne!(0, control_addr.port());
+        assert_ne!(relay.local_addr().unwrap(), control_addr);
+
+        let relay = Relay::with_config(RelayConfigBuilder::new(0).build());
+        assert!(relay.control_addr().unwrap().is_none());
+    }
+
+    #[test]
+    fn enumerate_supported_protocols() {
+        let protocols = Relay::supported_protocols();
+        assert!(protocols.contains(&Protocol::Tcp));
+        assert!(protocols.contains(&Protocol::Udp));
+        // no handler relays ICMP
+        assert!(!protocols.contains(&Protocol::Other(PROTOCOL_ICMP)));
+    }
+
+    // read the packets sent through the tunnel until one has a payload
+    #[cfg(unix)]
+    fn read_payload(tunnel: &mut std::net::TcpStream) -> (u32, Vec<u8>) {
         loop {
-            retry_on_intr!({
-                let timeout_seconds = max(0, next_cleaning_deadline - Local::now().timestamp());
-                let timeout = Some(Duration::new(timeout_seconds as u64, 0));
-                selector.poll(&mut events, timeout)
-            })?;
-
-            let now = Local::now().timestamp();
-            if now >= next_cleaning_deadline {
-                tunnel_server.borrow_mut().clean_up(selector);
-                next_cleaning_deadline = now + CLEANING_INTERVAL_SECONDS;
-            } else if events.is_empty() {
-                debug!(
-                    target: TAG,
-                    "Spurious wakeup: poll() returned without any event"
-                );
-                continue;
+            let (seq, _, payload) = read_tcp_packet(tunnel);
+            if !payload.is_empty() {
+                return (seq, payload);
             }
-
-            selector.run_handlers(&events);
         }
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn hand_off_to_another_relay() {
+        let (sender, receiver) = mpsc::channel();
+        let (handoff_sender, handoff_receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let relay = Relay::with_config(RelayConfigBuilder::new(0).build());
+            let relay_port = relay.local_addr().unwrap().port();
+            sender.send((relay_port, relay.handoff_request())).unwrap();
+            let handoff = relay.run_until_handoff().unwrap();
+            // the sockets must survive the relay which handed them off
+            drop(relay);
+            handoff_sender.send(handoff).unwrap();
+        });
+        let (relay_port, handoff_request) = receiver.recv().unwrap();
+
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let mut tunnel = connect_tunnel(relay_port);
+        let relay_seq = handshake(&mut tunnel, port, 0xffff);
+        let (mut upstream, _) = server.accept().unwrap();
+
+        let client_seq = CLIENT_SEQ + 1;
+        let packet = create_tcp_packet(port, client_seq, relay_seq, FLAG_ACK, 0xffff, b"ping");
+        tunnel.write_all(&packet).unwrap();
+        let mut buf = [0; 4];
+        upstream.read_exact(&mut buf).unwrap();
+        assert_eq!(b"ping", &buf);
+        upstream.write_all(b"pong").unwrap();
+        let (seq, payload) = read_payload(&mut tunnel);
+        assert_eq!(relay_seq, seq);
+        assert_eq!(b"pong", &payload[..]);
+
+        handoff_request.request();
+        let handoff = handoff_receiver.recv().unwrap();
+        thread::spawn(move || {
+            let relay = Relay::restore(RelayConfigBuilder::new(0).build(), handoff).unwrap();
+            relay.run().unwrap();
+        });
+
+        // the flow goes on through the same tunnel
+        let client_seq = client_seq + 4;
+        let relay_seq = relay_seq + 4;
+        let packet = create_tcp_packet(port, client_seq, relay_seq, FLAG_ACK, 0xffff, b"ping");
+        tunnel.write_all(&packet).unwrap();
+        upstream.read_exact(&mut buf).unwrap();
+        assert_eq!(b"ping", &buf);
+        upstream.write_all(b"pong").unwrap();
+        let (seq, payload) = read_payload(&mut tunnel);
+        assert_eq!(relay_seq, seq);
+        assert_eq!(b"pong", &payload[..]);
+
+        // the restored relay still accepts new clients, with the next id
+        let mut tunnel = std::net::TcpStream::connect((Ipv4Addr::LOCALHOST, relay_port)).unwrap();
+        let mut client_id = [0; 4];
+        tunnel.read_exact(&mut client_id).unwrap();
+        assert_eq!([0, 0, 0, 1], client_id);
+    }
 }
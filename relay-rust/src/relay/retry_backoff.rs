@@ -0,0 +1,72 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::cmp;
+use std::time::Duration;
+
+// the delay between two retries never exceeds this value, whatever the number of retries
+const MAX_DELAY: Duration = Duration::from_secs(10);
+
+/// Space out a limited number of retries, doubling the delay before each one (exponential
+/// backoff).
+pub struct RetryBackoff {
+    remaining: u32,
+    delay: Duration,
+}
+
+impl RetryBackoff {
+    pub fn new(retries: u32, initial_delay: Duration) -> Self {
+        Self {
+            remaining: retries,
+            delay: cmp::min(initial_delay, MAX_DELAY),
+        }
+    }
+
+    /// The delay to wait before the next retry, or `None` if all the retries have been consumed.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let delay = self.delay;
+        self.delay = cmp::min(self.delay * 2, MAX_DELAY);
+        Some(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn double_delay() {
+        let mut backoff = RetryBackoff::new(3, Duration::from_millis(100));
+        assert_eq!(Some(Duration::from_millis(100)), backoff.next_delay());
+        assert_eq!(Some(Duration::from_millis(200)), backoff.next_delay());
+        assert_eq!(Some(Duration::from_millis(400)), backoff.next_delay());
+        assert_eq!(None, backoff.next_delay());
+        assert_eq!(None, backoff.next_delay());
+    }
+
+    #[test]
+    fn cap_delay() {
+        let mut backoff = RetryBackoff::new(10, Duration::from_secs(3));
+        assert_eq!(Some(Duration::from_secs(3)), backoff.next_delay());
+        assert_eq!(Some(Duration::from_secs(6)), backoff.next_delay());
+        assert_eq!(Some(MAX_DELAY), backoff.next_delay());
+        assert_eq!(Some(MAX_DELAY), backoff.next_delay());
+    }
+}
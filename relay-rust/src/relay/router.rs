@@ -15,32 +15,134 @@
  */
 
 use log::*;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+#[cfg(unix)]
+use std::os::unix::io::{BorrowedFd, OwnedFd};
 use std::rc::{Rc, Weak};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use super::binary;
+use super::checksum_validation::ChecksumValidation;
 use super::client::{Client, ClientChannel};
-use super::connection::{Connection, ConnectionId};
-use super::ipv4_header::Protocol;
-use super::ipv4_packet::Ipv4Packet;
+use super::client_address::ClientAddress;
+use super::config::RelayConfig;
+use super::connection::{Connection, ConnectionId, ConnectionInfo, ConnectionStats, FlowKey};
+use super::dns;
+use super::dns_cache::DnsCache;
+use super::drop_logger::DropLogger;
+use super::fragment;
+use super::gre;
+use super::icmp::{self, IcmpPolicy};
+use super::icmp_echo::{IcmpEcho, MAX_PENDING_ECHOES};
+use super::inspector::Verdict;
+use super::ipv4_header::{Protocol, PROTOCOL_GRE, PROTOCOL_IGMP};
+use super::ipv4_packet::{Ipv4Packet, MAX_PACKET_LENGTH};
+use super::loss_injector::LossInjector;
+use super::metrics::{Counter, Metrics};
+use super::nat::{NatPorts, NatTable};
+use super::net;
+use super::observer::{AuditRecord, CloseReason, Decision, DropReason};
+use super::packetizer::Packetizer;
 use super::selector::Selector;
 use super::tcp_connection::TcpConnection;
+#[cfg(unix)]
+use super::tcp_connection::TcpConnectionState;
+use super::tcp_header;
+use super::token_bucket::TokenBucket;
+use super::trace_ring::{Direction, TraceRing};
+use super::transport_header::TransportHeaderData;
 use super::udp_connection::UdpConnection;
 
 const TAG: &str = "Router";
 
+// a GRE packet may encapsulate another GRE packet, but there is no reason to go very deep
+const MAX_GRE_NESTING: usize = 4;
+
+// once the file descriptors are exhausted, no new connection is attempted during this delay
+const DESCRIPTORS_EXHAUSTED_BACKOFF: Duration = Duration::from_secs(1);
+
+// the number of translated sources tried for a new connection, when their ports are already used
+// by other sockets of the host
+const MAX_NAT_ATTEMPTS: usize = 8;
+
 pub struct Router {
     client: Weak<RefCell<Client>>,
     // there are typically only few connections per client, HashMap would be less efficient
     connections: Vec<Rc<RefCell<dyn Connection>>>,
+    // the number of TCP connections being opened (maintained by the connections themselves)
+    half_open: Rc<Cell<usize>>,
+    // the number of ICMP echo requests waiting for their reply (maintained by the requests)
+    pending_echoes: Rc<Cell<usize>>,
+    config: Rc<RelayConfig>,
+    metrics: Arc<Metrics>,
+    loss_injector: Option<LossInjector>,
+    // the address and subnet of the client, if announced
+    client_address: Option<ClientAddress>,
+    // the last packets relayed, if tracing is enabled (shared with the client channel)
+    trace_ring: Option<Rc<RefCell<TraceRing>>>,
+    // the translated sources, if source NAT is enabled (shared with the client channel)
+    nat_table: Option<RefCell<NatTable>>,
+    // one token per packet sent by the client, if the packet rate is limited
+    packet_rate_limiter: Option<TokenBucket>,
+    // the responses to the DNS queries relayed upstream, if caching is enabled
+    dns_cache: Option<DnsCache>,
+    // logs a sample of the dropped packets, if enabled
+    drop_logger: Option<DropLogger>,
+    // no new connection is created before this instant, because the file descriptors were
+    // exhausted
+    descriptors_backoff_until: Option<Instant>,
+}
+
+// result of the inspection of a packet
+enum Inspection {
+    Accepted,
+    Modified(Vec<u8>),
+    Dropped,
 }
 
 impl Router {
-    pub fn new() -> Self {
+    pub fn new(
+        config: Rc<RelayConfig>,
+        metrics: Arc<Metrics>,
+        nat_ports: Rc<RefCell<NatPorts>>,
+        now: Instant,
+    ) -> Self {
+        let loss_injector = config.packet_loss().map(|(to_network, to_client)| {
+            LossInjector::new(to_network, to_client, config.packet_loss_seed())
+        });
+        let trace_ring = config.trace_capacity().map(|capacity| {
+            let trace_ring = TraceRing::new(capacity, config.trace_snaplen());
+            Rc::new(RefCell::new(trace_ring))
+        });
+        let nat_table = if config.source_nat() {
+            let external_address = config.external_address().unwrap_or(Ipv4Addr::UNSPECIFIED);
+            Some(RefCell::new(NatTable::new(external_address, nat_ports)))
+        } else {
+            None
+        };
+        let packet_rate_limiter = config
+            .max_packet_rate()
+            .map(|rate| TokenBucket::new(rate, now));
+        let dns_cache = config.dns_cache_capacity().map(DnsCache::new);
+        let drop_logger = config.drop_log_sample_rate().map(DropLogger::new);
         Self {
             client: Weak::new(),
             connections: Vec::new(),
+            half_open: Rc::new(Cell::new(0)),
+            pending_echoes: Rc::new(Cell::new(0)),
+            config,
+            metrics,
+            loss_injector,
+            client_address: None,
+            trace_ring,
+            nat_table,
+            packet_rate_limiter,
+            dns_cache,
+            drop_logger,
+            descriptors_backoff_until: None,
         }
     }
 
@@ -49,39 +151,97 @@ impl Router {
         self.client = client;
     }
 
+    /// Address the packets sent to the client to `address` instead of the source of the packets
+    /// received from the client, and (if enabled) drop the packets whose source is outside its
+    /// subnet.
+    ///
+    /// Only the connections created afterwards are affected.
+    pub fn set_client_address(&mut self, address: ClientAddress) {
+        self.client_address = Some(address);
+    }
+
+    pub fn trace_ring(&self) -> Option<&Rc<RefCell<TraceRing>>> {
+        self.trace_ring.as_ref()
+    }
+
     pub fn send_to_network(
+        &mut self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        ipv4_packet: &mut Ipv4Packet,
+    ) {
+        if let Some(ref trace_ring) = self.trace_ring {
+            trace_ring
+                .borrow_mut()
+                .record(Direction::ToNetwork, ipv4_packet, selector.now());
+        }
+        let checksum_validation = self.config.checksum_validation();
+        if checksum_validation != ChecksumValidation::Off {
+            ipv4_packet.verify_checksum();
+        }
+        if !ipv4_packet.checksum_valid() {
+            if checksum_validation == ChecksumValidation::Lenient {
+                self.accept_invalid_checksum(ipv4_packet);
+            } else {
+                self.drop_invalid_checksum(ipv4_packet);
+                return;
+            }
+        }
+        if let Some(mtu) = self.exceeded_tunnel_mtu(ipv4_packet) {
+            self.refuse_oversized(selector, client_channel, ipv4_packet, mtu);
+            return;
+        }
+        if let Some(mut remapped) = self.remap_dscp(ipv4_packet) {
+            let remapped_packet = Ipv4Packet::parse(&mut remapped);
+            self.send_to_network_nested(selector, client_channel, &remapped_packet, 0);
+        } else {
+            self.send_to_network_nested(selector, client_channel, ipv4_packet, 0);
+        }
+    }
+
+    fn send_to_network_nested(
         &mut self,
         selector: &mut Selector,
         client_channel: &mut ClientChannel,
         ipv4_packet: &Ipv4Packet,
+        gre_nesting: usize,
     ) {
+        if let Err(err) = ipv4_packet.ipv4_header_data().validate() {
+            warn!(target: TAG, "Dropping inconsistent packet: {}", err);
+            debug!(target: TAG, "{}", ipv4_packet.ipv4_header().describe());
+            return;
+        }
+        if let Some(mut stripped) = self.strip_options(ipv4_packet) {
+            let stripped_packet = Ipv4Packet::parse(&mut stripped);
+            self.send_to_network_nested(selector, client_channel, &stripped_packet, gre_nesting);
+            return;
+        }
+        if gre_nesting == 0 {
+            // the packets encapsulated in GRE are accounted as GRE only
+            self.count_protocol(ipv4_packet);
+        }
         if ipv4_packet.is_valid() {
-            match self.connection(selector, ipv4_packet) {
-                Ok(index) => {
-                    let closed = {
-                        let connection_ref = &self.connections[index];
-                        let mut connection = connection_ref.borrow_mut();
-                        connection.send_to_network(selector, client_channel, ipv4_packet);
-                        if connection.is_closed() {
-                            debug!(
-                                target: TAG,
-                                "Removing connection from router: {}",
-                                connection.id()
-                            );
-                            true
-                        } else {
-                            false
-                        }
-                    };
-                    if closed {
-                        // the connection is closed, remove it
-                        self.connections.swap_remove(index);
-                    }
+            let id = Self::connection_id(ipv4_packet);
+            match self.inspect(&id, ipv4_packet) {
+                Inspection::Accepted => self.route(selector, client_channel, id, ipv4_packet),
+                Inspection::Modified(mut raw) => {
+                    let modified_packet = Ipv4Packet::parse(&mut raw);
+                    self.route(selector, client_channel, id, &modified_packet);
                 }
-                Err(err) => error!(target: TAG, "Cannot create route, dropping packet: {}", err),
+                Inspection::Dropped => self.drop_inspected(&id),
             }
+        } else if let Some(mut inner) = self.decapsulate(ipv4_packet, gre_nesting) {
+            debug!(target: TAG, "Routing IPv4 packet encapsulated in GRE");
+            let inner_packet = Ipv4Packet::parse(&mut inner);
+            self.send_to_network_nested(selector, client_channel, &inner_packet, gre_nesting + 1);
+        } else if self.is_igmp(ipv4_packet) {
+            // multicast group management is local to the client network, it cannot be relayed
+            debug!(target: TAG, "Dropping IGMP packet");
+        } else if let Some(message) = icmp::echo_request_message(ipv4_packet.raw()) {
+            self.handle_icmp_echo(selector, client_channel, ipv4_packet, message);
         } else {
             warn!(target: TAG, "Dropping invalid packet");
+            debug!(target: TAG, "{}", ipv4_packet.ipv4_header().describe());
             if log_enabled!(target: TAG, Level::Trace) {
                 trace!(
                     target: TAG,
@@ -92,19 +252,630 @@ impl Router {
         }
     }
 
-    fn connection(
+    fn route(
         &mut self,
         selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        id: ConnectionId,
         ipv4_packet: &Ipv4Packet,
-    ) -> io::Result<usize> {
+    ) {
+        if self.is_spoofed(&id) {
+            warn!(target: TAG, "Dropping packet with spoofed source: {}", id);
+            self.notify_drop(&id, DropReason::Spoofed);
+            return;
+        }
+        if self.config.monitor_only() {
+            // the packet has been parsed, accounted and traced, it goes no further
+            debug!(target: TAG, "Packet monitored only: {}", id);
+            self.metrics.increment(Counter::MonitoredPackets);
+            return;
+        }
+        if !self.is_protocol_allowed(id.protocol()) {
+            self.refuse_protocol(selector, client_channel, &id, ipv4_packet);
+            return;
+        }
+        if self.exceeds_packet_rate(selector.now()) {
+            debug!(target: TAG, "Packet dropped by the rate limiter: {}", id);
+            self.notify_drop(&id, DropReason::RateLimited);
+            return;
+        }
+        if self.inject_loss_to_network() {
+            debug!(target: TAG, "Packet dropped to simulate loss: {}", id);
+            self.notify_drop(&id, DropReason::InjectedLoss);
+            return;
+        }
+        let dns_response = self
+            .dns_response(&id, ipv4_packet)
+            .or_else(|| self.cached_dns_response(&id, ipv4_packet, selector.now()));
+        if let Some(mut response) = dns_response {
+            let response_packet = Ipv4Packet::parse(&mut response);
+            match client_channel.send_to_client(selector, &response_packet) {
+                Ok(_) => {
+                    debug!(target: TAG, "DNS query answered locally: {}", id);
+                    self.metrics.increment(Counter::DnsQueriesAnswered);
+                }
+                Err(_) => warn!(target: TAG, "Cannot send DNS response to client: {}", id),
+            }
+            return;
+        }
+        let existing = self.find_index(&id);
+        if existing.is_none() {
+            // the packet would open a new connection
+            if Self::is_out_of_state(&id, ipv4_packet) {
+                self.reset_out_of_state(selector, client_channel, &id, ipv4_packet);
+                return;
+            }
+            if self.exceeds_half_open(&id) {
+                self.refuse_half_open(selector, client_channel, &id, ipv4_packet);
+                return;
+            }
+            if self.is_descriptors_backoff(selector.now()) {
+                // drop silently, the client will retransmit later
+                debug!(target: TAG, "File descriptors exhausted, dropping: {}", id);
+                self.notify_drop(&id, DropReason::DescriptorsExhausted);
+                return;
+            }
+        }
+        match self.connection(selector, existing, &id, ipv4_packet) {
+            Ok(index) => {
+                let closed = {
+                    let connection_ref = &self.connections[index];
+                    let mut connection = connection_ref.borrow_mut();
+                    let payload_length = ipv4_packet.payload().expect("No payload").len();
+                    connection.stats_mut().count_to_network(payload_length);
+                    connection.send_to_network(selector, client_channel, ipv4_packet);
+                    if let Some(reason) = connection.close_reason() {
+                        debug!(
+                            target: TAG,
+                            "Removing connection from router: {} ({})",
+                            connection.id(),
+                            reason.name()
+                        );
+                        self.notify_close(&*connection, reason);
+                        self.release_source(&*connection);
+                        true
+                    } else {
+                        false
+                    }
+                };
+                if closed {
+                    // the connection is closed, remove it
+                    self.connections.swap_remove(index);
+                }
+            }
+            Err(ref err) if net::is_descriptors_exhausted(err) => {
+                self.metrics.increment(Counter::DescriptorsExhausted);
+                if self.evict_idlest_udp_connection(selector) {
+                    // its socket is released before the next poll, the client will retransmit
+                    // meanwhile
+                    warn!(
+                        target: TAG,
+                        "Cannot create route ({}), evicted the idlest UDP connection: {}",
+                        err,
+                        id
+                    );
+                } else {
+                    warn!(
+                        target: TAG,
+                        "Cannot create route ({}), refusing new connections for {:?}: {}",
+                        err,
+                        DESCRIPTORS_EXHAUSTED_BACKOFF,
+                        id
+                    );
+                    self.descriptors_backoff_until =
+                        Some(selector.now() + DESCRIPTORS_EXHAUSTED_BACKOFF);
+                }
+                self.notify_drop(&id, DropReason::DescriptorsExhausted);
+            }
+            Err(err) => {
+                error!(target: TAG, "Cannot create route, dropping packet: {}", err);
+                self.notify_drop(&id, DropReason::Unroutable);
+            }
+        }
+    }
+
+    // decide whether a packet sent by the client has a source outside of the announced subnet
+    fn is_spoofed(&self, id: &ConnectionId) -> bool {
+        let spoofed = match self.client_address {
+            Some(ref client_address) if self.config.source_validation() => {
+                !client_address.contains(*id.source().ip())
+            }
+            _ => false,
+        };
+        if spoofed {
+            self.metrics.increment(Counter::SpoofedPacketsDropped);
+        }
+        spoofed
+    }
+
+    // decide whether a packet sent by the client must be dropped because the client sends too many
+    // packets
+    fn exceeds_packet_rate(&mut self, now: Instant) -> bool {
+        let exceeded = match self.packet_rate_limiter {
+            Some(ref mut limiter) => {
+                if limiter.available(now) > 0 {
+                    limiter.consume(1, now);
+                    false
+                } else {
+                    true
+                }
+            }
+            None => false,
+        };
+        if exceeded {
+            self.metrics.increment(Counter::RateLimitedPackets);
+        }
+        exceeded
+    }
+
+    // decide whether a packet sent by the client must be dropped to simulate packet loss
+    fn inject_loss_to_network(&mut self) -> bool {
+        let dropped = match self.loss_injector {
+            Some(ref mut loss_injector) => loss_injector.drop_to_network(),
+            None => false,
+        };
+        if dropped {
+            self.metrics.increment(Counter::InjectedLossToNetwork);
+        }
+        dropped
+    }
+
+    /// Decide whether a UDP packet sent to the client must be dropped to simulate packet loss.
+    pub fn inject_loss_to_client(&mut self) -> bool {
+        let dropped = match self.loss_injector {
+            Some(ref mut loss_injector) => loss_injector.drop_to_client(),
+            None => false,
+        };
+        if dropped {
+            self.metrics.increment(Counter::InjectedLossToClient);
+        }
+        dropped
+    }
+
+    /// Cache the response to a DNS query received from upstream, to answer the same query locally
+    /// until it expires.
+    pub fn cache_dns_response(&mut self, id: &ConnectionId, response: &[u8], now: Instant) {
+        if let Some(ref mut dns_cache) = self.dns_cache {
+            if Self::is_dns_query(id) {
+                dns_cache.store(response, now);
+            }
+        }
+    }
+
+    // return a copy of the packet with its DSCP remapped, if the remap policy changes it
+    fn remap_dscp(&self, ipv4_packet: &Ipv4Packet) -> Option<Vec<u8>> {
+        let dscp_remap = self.config.dscp_remap()?;
+        if ipv4_packet.ipv4_header_data().validate().is_err() {
+            // dropped afterwards
+            return None;
+        }
+        dscp_remap.remap_packet(ipv4_packet)
+    }
+
+    // return a copy of the packet without its IPv4 options, if it has options to strip
+    fn strip_options(&self, ipv4_packet: &Ipv4Packet) -> Option<Vec<u8>> {
+        if !self.config.strip_ipv4_options() || ipv4_packet.ipv4_header().options().is_empty() {
+            return None;
+        }
+        debug!(target: TAG, "Stripping IPv4 options");
+        Some(ipv4_packet.without_options())
+    }
+
+    // return the inner IPv4 packet of a GRE packet, if GRE decapsulation is enabled
+    fn decapsulate(&self, ipv4_packet: &Ipv4Packet, gre_nesting: usize) -> Option<Vec<u8>> {
+        let ipv4_header = ipv4_packet.ipv4_header();
+        if !self.config.gre_decapsulation()
+            || ipv4_header.protocol() != Protocol::Other(PROTOCOL_GRE)
+            || gre_nesting >= MAX_GRE_NESTING
+        {
+            return None;
+        }
+        let raw = ipv4_packet.raw();
+        let payload = &raw[ipv4_header.transport_range(raw.len())];
+        gre::inner_ipv4_packet(payload).map(|inner| inner.to_vec())
+    }
+
+    fn is_igmp(&self, ipv4_packet: &Ipv4Packet) -> bool {
+        let igmp = ipv4_packet.ipv4_header().protocol() == Protocol::Other(PROTOCOL_IGMP);
+        if igmp {
+            self.metrics.increment(Counter::IgmpPacketsDropped);
+        }
+        igmp
+    }
+
+    fn handle_icmp_echo(
+        &self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        ipv4_packet: &Ipv4Packet,
+        message: &[u8],
+    ) {
+        let ipv4_header = ipv4_packet.ipv4_header();
+        // the reply is addressed to the client as it announced itself
+        let reply_to = self
+            .client_address
+            .map_or(ipv4_header.source(), |client_address| {
+                u32::from(client_address.address())
+            });
+        match self.config.icmp_policy() {
+            IcmpPolicy::Drop => {
+                debug!(target: TAG, "Dropping ICMP echo request");
+                self.metrics.increment(Counter::IcmpEchoDropped);
+            }
+            IcmpPolicy::Synthesize => {
+                let mut raw = icmp::build_echo_reply(ipv4_header.destination(), reply_to, message);
+                let reply_packet = Ipv4Packet::parse(&mut raw);
+                match client_channel.send_to_client(selector, &reply_packet) {
+                    Ok(_) => {
+                        debug!(target: TAG, "ICMP echo request answered locally");
+                        self.metrics.increment(Counter::IcmpEchoSynthesized);
+                    }
+                    Err(_) => warn!(target: TAG, "Cannot send ICMP echo reply to client"),
+                }
+            }
+            IcmpPolicy::Forward if self.exceeds_pending_echoes() => {
+                debug!(target: TAG, "Too many pending ICMP echo requests, dropping");
+                self.metrics.increment(Counter::IcmpEchoOverflows);
+            }
+            IcmpPolicy::Forward => {
+                let result = IcmpEcho::forward(
+                    selector,
+                    self.client.clone(),
+                    ipv4_header.destination(),
+                    reply_to,
+                    message,
+                    &self.config,
+                    self.pending_echoes.clone(),
+                );
+                match result {
+                    Ok(_) => self.metrics.increment(Counter::IcmpEchoForwarded),
+                    Err(err) => {
+                        warn!(target: TAG, "Cannot forward ICMP echo request: {}", err);
+                        self.metrics.increment(Counter::IcmpEchoDropped);
+                    }
+                }
+            }
+        }
+    }
+
+    // every pending echo request holds a socket, so their number is limited
+    fn exceeds_pending_echoes(&self) -> bool {
+        self.pending_echoes.get() >= MAX_PENDING_ECHOES
+    }
+
+    fn count_protocol(&self, ipv4_packet: &Ipv4Packet) {
+        self.metrics
+            .count_protocol(ipv4_packet.ipv4_header().protocol());
+    }
+
+    // only a SYN may open a TCP connection
+    fn is_out_of_state(id: &ConnectionId, ipv4_packet: &Ipv4Packet) -> bool {
+        match ipv4_packet.headers_data().1 {
+            Some(TransportHeaderData::Tcp(tcp_header)) => {
+                id.protocol() == Protocol::Tcp && !tcp_header.is_syn()
+            }
+            _ => false,
+        }
+    }
+
+    // answer a TCP segment which belongs to no connection, without creating any
+    fn reset_out_of_state(
+        &self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        id: &ConnectionId,
+        ipv4_packet: &Ipv4Packet,
+    ) {
+        self.notify_drop(id, DropReason::OutOfState);
+        self.reset_segment(selector, client_channel, id, ipv4_packet);
+    }
+
+    // the tunnel MTU, if the packet exceeds it while it must not be fragmented
+    fn exceeded_tunnel_mtu(&self, ipv4_packet: &Ipv4Packet) -> Option<u16> {
+        let mtu = self.config.tunnel_mtu()?;
+        if fragment::is_dont_fragment(ipv4_packet.raw()) && ipv4_packet.length() > mtu {
+            Some(mtu)
+        } else {
+            None
+        }
+    }
+
+    // drop a packet exceeding the tunnel MTU, and tell the client the MTU to use instead
+    fn refuse_oversized(
+        &self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        ipv4_packet: &Ipv4Packet,
+        mtu: u16,
+    ) {
+        self.metrics.increment(Counter::FragmentationNeeded);
+        if ipv4_packet.is_valid() {
+            let id = Self::connection_id(ipv4_packet);
+            debug!(target: TAG, "Packet exceeds the tunnel MTU ({}): {}", mtu, id);
+            self.notify_drop(&id, DropReason::ExceedsMtu);
+        } else {
+            debug!(target: TAG, "Packet exceeds the tunnel MTU ({})", mtu);
+        }
+        let mut raw = icmp::build_frag_needed(ipv4_packet.raw(), mtu);
+        let mut reply = Ipv4Packet::parse(&mut raw);
+        if let Some(client_address) = self.client_address {
+            reply.rewrite_destination(u32::from(client_address.address()));
+        }
+        if client_channel.send_to_client(selector, &reply).is_err() {
+            warn!(target: TAG, "Cannot send ICMP error to client");
+        }
+    }
+
+    fn is_protocol_allowed(&self, protocol: Protocol) -> bool {
+        match protocol {
+            Protocol::Tcp => self.config.allow_tcp(),
+            Protocol::Udp => self.config.allow_udp(),
+            _ => true,
+        }
+    }
+
+    // drop a packet whose protocol is disabled, answering a TCP segment by a RST
+    fn refuse_protocol(
+        &self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        id: &ConnectionId,
+        ipv4_packet: &Ipv4Packet,
+    ) {
+        debug!(target: TAG, "Protocol disabled, dropping: {}", id);
+        self.notify_drop(id, DropReason::ProtocolDisabled);
+        if id.protocol() == Protocol::Tcp {
+            self.reset_segment(selector, client_channel, id, ipv4_packet);
+        }
+    }
+
+    // decide whether no new connection must be attempted, because the file descriptors have been
+    // exhausted recently
+    fn is_descriptors_backoff(&mut self, now: Instant) -> bool {
+        match self.descriptors_backoff_until {
+            Some(until) if now < until => true,
+            Some(_) => {
+                // retry creating connections
+                self.descriptors_backoff_until = None;
+                false
+            }
+            None => false,
+        }
+    }
+
+    // decide whether a SYN must be refused because too many connections of the client are being
+    // opened
+    fn exceeds_half_open(&self, id: &ConnectionId) -> bool {
+        match self.config.max_half_open_connections() {
+            Some(max) if id.protocol() == Protocol::Tcp => self.half_open.get() >= max,
+            _ => false,
+        }
+    }
+
+    // answer a SYN by a RST, without creating any connection
+    fn refuse_half_open(
+        &self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        id: &ConnectionId,
+        ipv4_packet: &Ipv4Packet,
+    ) {
+        debug!(target: TAG, "Too many half-open connections, refusing: {}", id);
+        self.notify_drop(id, DropReason::HalfOpenLimit);
+        self.reset_segment(selector, client_channel, id, ipv4_packet);
+    }
+
+    // send a RST to the client in answer to a TCP segment
+    fn reset_segment(
+        &self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        id: &ConnectionId,
+        ipv4_packet: &Ipv4Packet,
+    ) {
+        let (ipv4_header, transport_header) = ipv4_packet.headers_data();
+        let tcp_header = match transport_header {
+            Some(TransportHeaderData::Tcp(tcp_header)) => tcp_header,
+            _ => panic!("Not a TCP packet"),
+        };
+        if tcp_header.is_rst() {
+            // never answer a RST by a RST
+            debug!(target: TAG, "Ignoring RST for unknown connection: {}", id);
+            return;
+        }
+        debug!(target: TAG, "Resetting segment for unknown connection: {}", id);
+        let payload_length = ipv4_packet.payload().expect("No payload").len() as u32;
+        let mut rst = tcp_header::build_rst(ipv4_header, tcp_header, payload_length);
+        let mut rst_packet = Ipv4Packet::parse(&mut rst);
+        if let Some(client_address) = self.client_address {
+            rst_packet
+                .ipv4_header_mut()
+                .set_destination(u32::from(client_address.address()));
+            rst_packet.compute_checksums();
+        }
+        if client_channel
+            .send_to_client(selector, &rst_packet)
+            .is_err()
+        {
+            warn!(target: TAG, "Cannot send RST to client: {}", id);
+        }
+    }
+
+    fn is_dns_query(id: &ConnectionId) -> bool {
+        id.protocol() == Protocol::Udp && id.destination().port() == dns::DNS_PORT
+    }
+
+    // return the response packet to a DNS query, if it is answered locally
+    fn dns_response(&self, id: &ConnectionId, ipv4_packet: &Ipv4Packet) -> Option<Vec<u8>> {
+        let dns_override = self.config.dns_override()?;
+        if !Self::is_dns_query(id) {
+            return None;
+        }
+        let query = ipv4_packet.payload().expect("No payload");
+        let answer = dns_override.answer(query)?;
+        self.dns_response_packet(id, ipv4_packet, &answer)
+    }
+
+    // return the response packet to a DNS query, if it is answered from the cache
+    fn cached_dns_response(
+        &mut self,
+        id: &ConnectionId,
+        ipv4_packet: &Ipv4Packet,
+        now: Instant,
+    ) -> Option<Vec<u8>> {
+        if !Self::is_dns_query(id) {
+            return None;
+        }
+        let dns_cache = self.dns_cache.as_mut()?;
+        let query = ipv4_packet.payload().expect("No payload");
+        match dns_cache.lookup(query, now) {
+            Some(answer) => {
+                self.metrics.increment(Counter::DnsCacheHits);
+                self.dns_response_packet(id, ipv4_packet, &answer)
+            }
+            None => {
+                self.metrics.increment(Counter::DnsCacheMisses);
+                None
+            }
+        }
+    }
+
+    // wrap the DNS message `answer` into a packet replying to the query `ipv4_packet`
+    fn dns_response_packet(
+        &self,
+        id: &ConnectionId,
+        ipv4_packet: &Ipv4Packet,
+        answer: &[u8],
+    ) -> Option<Vec<u8>> {
+        let query = ipv4_packet.payload().expect("No payload");
+        let headers_length = ipv4_packet.length() as usize - query.len();
+        if headers_length + answer.len() >= MAX_PACKET_LENGTH {
+            warn!(target: TAG, "DNS response would be too long: {}", id);
+            return None;
+        }
+        let (ipv4_header, transport_header) = ipv4_packet.headers();
+        let transport_header = transport_header.expect("No transport");
+        let mut packetizer = Packetizer::new(&ipv4_header, &transport_header);
+        if let Some(client_address) = self.client_address {
+            packetizer
+                .ipv4_header_mut()
+                .set_destination(u32::from(client_address.address()));
+        }
+        let response_packet = packetizer.packetize_payload(answer);
+        Some(response_packet.raw().to_vec())
+    }
+
+    // the address the connection must actually connect to
+    fn upstream_destination(&self, id: &ConnectionId) -> SocketAddrV4 {
+        if Self::is_dns_query(id) {
+            let resolver = self
+                .config
+                .dns_override()
+                .and_then(|o| o.resolver())
+                .or_else(|| self.config.dns_redirect());
+            if let Some(resolver) = resolver {
+                return resolver;
+            }
+        }
+        id.rewritten_destination()
+    }
+
+    fn connection_id(ipv4_packet: &Ipv4Packet) -> ConnectionId {
         let (ipv4_header_data, transport_header_data) = ipv4_packet.headers_data();
         let transport_header_data = transport_header_data.expect("No transport");
-        let id = ConnectionId::from_headers(ipv4_header_data, transport_header_data);
-        let index = match self.find_index(&id) {
+        ConnectionId::from_headers(ipv4_header_data, transport_header_data)
+    }
+
+    fn inspect(&self, id: &ConnectionId, ipv4_packet: &Ipv4Packet) -> Inspection {
+        let inspector = match self.config.inspector() {
+            Some(inspector) => inspector,
+            None => return Inspection::Accepted,
+        };
+        let payload = ipv4_packet.payload().expect("No payload");
+        match inspector.inspect(id, payload) {
+            Verdict::Accept => Inspection::Accepted,
+            Verdict::Drop => Inspection::Dropped,
+            Verdict::Modify(new_payload) => {
+                let headers_length = ipv4_packet.length() as usize - payload.len();
+                if id.protocol() == Protocol::Tcp && new_payload.len() != payload.len() {
+                    warn!(
+                        target: TAG,
+                        "Inspector cannot change the TCP payload length, relaying original: {}",
+                        id
+                    );
+                    Inspection::Accepted
+                } else if headers_length + new_payload.len() >= MAX_PACKET_LENGTH {
+                    warn!(
+                        target: TAG,
+                        "Modified packet would be too long, dropping: {}", id
+                    );
+                    Inspection::Dropped
+                } else {
+                    Inspection::Modified(ipv4_packet.with_payload(&new_payload))
+                }
+            }
+        }
+    }
+
+    // close the least recently active UDP connection to release its file descriptor, and return
+    // whether there was any (it will be recreated on its next datagram)
+    fn evict_idlest_udp_connection(&mut self, selector: &mut Selector) -> bool {
+        let index = self
+            .connections
+            .iter()
+            .enumerate()
+            .filter(|(_, connection)| connection.borrow().id().protocol() == Protocol::Udp)
+            .min_by_key(|(_, connection)| connection.borrow().last_activity())
+            .map(|(index, _)| index);
+        let index = match index {
+            Some(index) => index,
+            None => return false,
+        };
+        let connection_rc = self.connections.swap_remove(index);
+        let mut connection = connection_rc.borrow_mut();
+        debug!(
+            target: TAG,
+            "Evicting connection from router: {}",
+            connection.id()
+        );
+        connection.close(selector, CloseReason::Evicted);
+        self.notify_close(&*connection, CloseReason::Evicted);
+        self.release_source(&*connection);
+        true
+    }
+
+    // the index of the connection, `existing` if it is already known, or of a new connection
+    fn connection(
+        &mut self,
+        selector: &mut Selector,
+        existing: Option<usize>,
+        id: &ConnectionId,
+        ipv4_packet: &Ipv4Packet,
+    ) -> io::Result<usize> {
+        let index = match existing {
             Some(index) => index,
             None => {
-                let connection =
-                    Self::create_connection(selector, id, self.client.clone(), ipv4_packet)?;
+                let connection = match self.nat_table {
+                    Some(ref nat_table) => {
+                        self.create_translated_connection(selector, nat_table, id, ipv4_packet)?
+                    }
+                    None => Self::create_connection(
+                        selector,
+                        id.clone(),
+                        self.upstream_destination(id),
+                        self.client.clone(),
+                        self.client_address
+                            .map(|client_address| client_address.address()),
+                        ipv4_packet,
+                        &self.config,
+                        self.metrics.clone(),
+                        self.half_open.clone(),
+                    )?,
+                };
+                if let Some(observer) = self.config.observer() {
+                    observer.on_open(id);
+                }
+                self.set_deadline(selector, &connection);
                 let index = self.connections.len();
                 self.connections.push(connection);
                 index
@@ -113,11 +884,97 @@ impl Router {
         Ok(index)
     }
 
+    // close the connection once it reaches its maximum lifetime, if limited
+    fn set_deadline(&self, selector: &mut Selector, connection: &Rc<RefCell<dyn Connection>>) {
+        let lifetime = match self.config.max_connection_lifetime() {
+            Some(lifetime) => lifetime,
+            None => return,
+        };
+        let weak_connection = Rc::downgrade(connection);
+        let weak_client = self.client.clone();
+        // cancelled when the connection is closed earlier
+        let handler = move |selector: &mut Selector| {
+            let connection_rc = match weak_connection.upgrade() {
+                Some(connection_rc) => connection_rc,
+                None => return,
+            };
+            let mut connection = connection_rc.borrow_mut();
+            if connection.close_reason().is_some() {
+                return;
+            }
+            if let Some(client) = weak_client.upgrade() {
+                debug!(
+                    target: TAG,
+                    "Connection reached its maximum lifetime: {}",
+                    connection.id()
+                );
+                connection.close(selector, CloseReason::MaxLifetime);
+                client.borrow_mut().router().remove(&*connection);
+            }
+        };
+        let timer = selector.set_timer(lifetime, handler);
+        connection.borrow_mut().set_lifetime_timer(timer);
+    }
+
+    // create a connection identified by a newly allocated source, from the packet as sent by the
+    // client (so that the packets it sends back are addressed to the client)
+    fn create_translated_connection(
+        &self,
+        selector: &mut Selector,
+        nat_table: &RefCell<NatTable>,
+        id: &ConnectionId,
+        ipv4_packet: &Ipv4Packet,
+    ) -> io::Result<Rc<RefCell<dyn Connection>>> {
+        let destination = self.upstream_destination(id);
+        for _ in 0..MAX_NAT_ATTEMPTS {
+            let source = nat_table.borrow_mut().allocate(id).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::AddrNotAvailable, "No port left to translate")
+            })?;
+            let result = Self::create_connection(
+                selector,
+                id.with_source(source),
+                destination,
+                self.client.clone(),
+                self.client_address
+                    .map(|client_address| client_address.address()),
+                ipv4_packet,
+                &self.config,
+                self.metrics.clone(),
+                self.half_open.clone(),
+            );
+            match result {
+                Ok(connection) => {
+                    debug!(target: TAG, "Source translated to {}: {}", source, id);
+                    return Ok(connection);
+                }
+                Err(err) => {
+                    nat_table.borrow_mut().release(id.protocol(), source);
+                    match err.kind() {
+                        // the port is used by another socket of the host (possibly connected to
+                        // the same destination), try the next one
+                        io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable => (),
+                        _ => return Err(err),
+                    }
+                }
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "No free port to translate",
+        ))
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn create_connection(
         selector: &mut Selector,
         id: ConnectionId,
+        destination: SocketAddrV4,
         client: Weak<RefCell<Client>>,
+        client_address: Option<Ipv4Addr>,
         ipv4_packet: &Ipv4Packet,
+        config: &Rc<RelayConfig>,
+        metrics: Arc<Metrics>,
+        half_open: Rc<Cell<usize>>,
     ) -> io::Result<Rc<RefCell<dyn Connection>>> {
         let (ipv4_header, transport_header) = ipv4_packet.headers();
         let transport_header = transport_header.expect("No transport");
@@ -126,15 +983,23 @@ impl Router {
                 selector,
                 id,
                 client,
+                client_address,
                 ipv4_header,
                 transport_header,
+                config,
+                metrics,
+                half_open,
             )?),
             Protocol::Udp => Ok(UdpConnection::create(
                 selector,
                 id,
+                destination,
                 client,
+                client_address,
                 ipv4_header,
                 transport_header,
+                config,
+                metrics,
             )?),
             p => Err(io::Error::new(
                 io::ErrorKind::Other,
@@ -143,10 +1008,90 @@ impl Router {
         }
     }
 
-    fn find_index(&self, id: &ConnectionId) -> Option<usize> {
+    fn accept_invalid_checksum(&self, ipv4_packet: &Ipv4Packet) {
+        self.metrics.increment(Counter::InvalidChecksums);
+        if ipv4_packet.is_valid() {
+            let id = Self::connection_id(ipv4_packet);
+            info!(target: TAG, "Relaying packet with invalid checksum: {}", id);
+        } else {
+            info!(target: TAG, "Relaying packet with invalid checksum");
+        }
+    }
+
+    fn drop_invalid_checksum(&self, ipv4_packet: &Ipv4Packet) {
+        self.metrics.increment(Counter::InvalidChecksums);
+        if ipv4_packet.is_valid() {
+            let id = Self::connection_id(ipv4_packet);
+            debug!(target: TAG, "Dropping packet with invalid checksum: {}", id);
+            self.notify_drop(&id, DropReason::InvalidChecksum);
+        } else {
+            debug!(target: TAG, "Dropping packet with invalid checksum");
+        }
+    }
+
+    fn drop_inspected(&self, id: &ConnectionId) {
+        debug!(target: TAG, "Packet dropped by inspector: {}", id);
+        self.notify_drop(id, DropReason::Inspector);
+        if self.find_index(id).is_none() {
+            // the connection will never be created, audit it now
+            if let Some(observer) = self.config.observer() {
+                observer.on_audit(&AuditRecord {
+                    id,
+                    decision: Decision::Blocked,
+                    upstream: None,
+                    close_reason: None,
+                });
+            }
+        }
+    }
+
+    fn notify_close(&self, connection: &dyn Connection, reason: CloseReason) {
+        if let Some(observer) = self.config.observer() {
+            let id = connection.id();
+            observer.on_close(id, connection.stats(), reason);
+            observer.on_audit(&AuditRecord {
+                id,
+                decision: Decision::Accepted,
+                upstream: Some(self.upstream_destination(id)),
+                close_reason: Some(reason),
+            });
+        }
+    }
+
+    fn notify_drop(&self, id: &ConnectionId, reason: DropReason) {
+        self.metrics.increment(Counter::DroppedPackets);
+        if let Some(ref drop_logger) = self.drop_logger {
+            drop_logger.log(id, reason);
+        }
+        if let Some(observer) = self.config.observer() {
+            observer.on_drop(id, reason);
+        }
+    }
+
+    fn find_flow(&self, key: FlowKey) -> Option<usize> {
+        // compare the 5-tuples rather than the whole ids (which contain a string)
         self.connections
             .iter()
-            .position(|connection| connection.borrow().id() == id)
+            .position(|connection| connection.borrow().id().flow_key() == key)
+    }
+
+    // the connections are identified by their translated source if source NAT is enabled
+    fn find_index(&self, id: &ConnectionId) -> Option<usize> {
+        match self.nat_table {
+            Some(ref nat_table) => {
+                let source = nat_table.borrow().translated_source(id)?;
+                self.find_flow(id.flow_key().with_source(source))
+            }
+            None => self.find_flow(id.flow_key()),
+        }
+    }
+
+    // release the translated source of a connection removed from the router, if any
+    fn release_source(&self, connection: &dyn Connection) {
+        if let Some(ref nat_table) = self.nat_table {
+            let id = connection.id();
+            nat_table.borrow_mut().release(id.protocol(), id.source());
+        }
     }
 
     pub fn remove(&mut self, connection: &dyn Connection) {
@@ -158,33 +1103,157 @@ impl Router {
                 binary::ptr_data_eq(connection, item.as_ptr())
             })
             .expect("Removing an unknown connection");
+        let reason = connection
+            .close_reason()
+            .expect("Removing a connection not closed");
         debug!(
             target: TAG,
-            "Self-removing connection from router: {}",
-            connection.id()
+            "Self-removing connection from router: {} ({})",
+            connection.id(),
+            reason.name()
         );
+        self.notify_close(connection, reason);
+        self.release_source(connection);
         self.connections.swap_remove(index);
     }
 
     pub fn clear(&mut self, selector: &mut Selector) {
-        for connection in &mut self.connections {
-            connection.borrow_mut().close(selector);
+        for connection in &self.connections {
+            let mut connection = connection.borrow_mut();
+            connection.close(selector, CloseReason::ClientDisconnected);
+            self.notify_close(&*connection, CloseReason::ClientDisconnected);
+            self.release_source(&*connection);
         }
         self.connections.clear();
     }
 
+    /// The id, the counters and the round-trip time estimate (if any) of every connection.
+    pub fn connection_stats(&self) -> Vec<(ConnectionId, ConnectionStats, Option<Duration>)> {
+        self.connections
+            .iter()
+            .map(|connection| {
+                let connection = connection.borrow();
+                (
+                    connection.id().clone(),
+                    *connection.stats(),
+                    connection.rtt_estimate(),
+                )
+            })
+            .collect()
+    }
+
+    /// A snapshot of the connections for which `predicate` returns true, their idle durations
+    /// measured at `now`.
+    pub fn connections_where<P>(&self, now: Instant, mut predicate: P) -> Vec<ConnectionInfo>
+    where
+        P: FnMut(&ConnectionInfo) -> bool,
+    {
+        self.connections
+            .iter()
+            .map(|connection| {
+                let connection = connection.borrow();
+                ConnectionInfo {
+                    id: connection.id().clone(),
+                    stats: *connection.stats(),
+                    rtt_estimate: connection.rtt_estimate(),
+                    idle: connection
+                        .last_activity()
+                        .map(|last_activity| now.saturating_duration_since(last_activity)),
+                    half_open: connection.is_half_open(),
+                }
+            })
+            .filter(|info| predicate(info))
+            .collect()
+    }
+
+    /// The bookkeeping of the TCP connections, to hand them off to another relay process along
+    /// with (duplicates of) their sockets, in the same order.
+    ///
+    /// The UDP connections are not handed off: they are stateless, and will be recreated on the
+    /// next datagram. They are reported closed by `RelayShutdown`.
+    #[cfg(unix)]
+    pub fn hand_off(&self, now: Instant) -> io::Result<(Vec<TcpConnectionState>, Vec<OwnedFd>)> {
+        let mut states = Vec::new();
+        let mut fds = Vec::new();
+        let mut dropped = Vec::new();
+        for connection in &self.connections {
+            if let Some((state, fd)) = connection.borrow().handoff(now) {
+                // the socket remains owned by this relay until it is dropped
+                let fd = unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()?;
+                states.push(state);
+                fds.push(fd);
+            } else {
+                dropped.push(connection);
+            }
+        }
+        for connection in dropped {
+            self.notify_close(&*connection.borrow(), CloseReason::RelayShutdown);
+        }
+        Ok((states, fds))
+    }
+
+    /// Report all the connections closed by `RelayShutdown`, when the client is not handed off.
+    #[cfg(unix)]
+    pub fn abandon(&self) {
+        for connection in &self.connections {
+            self.notify_close(&*connection.borrow(), CloseReason::RelayShutdown);
+        }
+    }
+
+    /// Rebuild the TCP connections handed off by `hand_off()` in another relay process, given
+    /// their sockets.
+    #[cfg(unix)]
+    pub fn restore(
+        &mut self,
+        selector: &mut Selector,
+        states: &[TcpConnectionState],
+        fds: Vec<OwnedFd>,
+    ) -> io::Result<()> {
+        if states.len() != fds.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Expected {} sockets, got {}", states.len(), fds.len()),
+            ));
+        }
+        for (state, fd) in states.iter().zip(fds) {
+            let stream = mio::net::TcpStream::from_stream(std::net::TcpStream::from(fd))?;
+            let connection: Rc<RefCell<dyn Connection>> = TcpConnection::restore(
+                selector,
+                state,
+                stream,
+                self.client.clone(),
+                self.client_address
+                    .map(|client_address| client_address.address()),
+                &self.config,
+                self.half_open.clone(),
+            )?;
+            self.set_deadline(selector, &connection);
+            self.connections.push(connection);
+        }
+        Ok(())
+    }
+
+    pub fn reset_connection_stats(&mut self) {
+        for connection in &self.connections {
+            connection.borrow_mut().stats_mut().reset();
+        }
+    }
+
     pub fn clean_expired_connections(&mut self, selector: &mut Selector) {
+        let now = selector.now();
         // remove the last items first, otherwise i might not be less than len() on swap_remove(i)
         for i in (0..self.connections.len()).rev() {
             let expired = {
                 let mut connection = self.connections[i].borrow_mut();
-                if connection.is_expired() {
+                if connection.is_expired(now) {
                     debug!(
                         target: TAG,
                         "Removing expired connection from router: {}",
                         connection.id()
                     );
-                    connection.close(selector);
+                    connection.close(selector, CloseReason::Timeout);
+                    self.notify_close(&*connection, CloseReason::Timeout);
+                    self.release_source(&*connection);
                     true
                 } else {
                     false
@@ -196,3 +1265,890 @@ impl Router {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::clock::MockClock;
+    use crate::relay::config::RelayConfigBuilder;
+    use crate::relay::observer::Observer;
+    use crate::relay::selector::TimerId;
+    use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+    use std::thread;
+
+    fn create_router(config_builder: RelayConfigBuilder) -> Router {
+        Router::new(
+            Rc::new(config_builder.build()),
+            Arc::new(Metrics::new()),
+            Default::default(),
+            Instant::now(),
+        )
+    }
+
+    fn create_packet() -> Vec<u8> {
+        let mut raw = Vec::new();
+        raw.write_u8(4u8 << 4 | 5).unwrap();
+        raw.write_u8(0).unwrap(); // ToS
+        raw.write_u16::<BigEndian>(32).unwrap(); // total length 20 + 8 + 4
+        raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+        raw.write_u8(0).unwrap(); // TTL
+        raw.write_u8(17).unwrap(); // protocol (UDP)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x12345678).unwrap(); // source address
+        raw.write_u32::<BigEndian>(0x42424242).unwrap(); // destination address
+
+        raw.write_u16::<BigEndian>(1234).unwrap(); // source port
+        raw.write_u16::<BigEndian>(53).unwrap(); // destination port
+        raw.write_u16::<BigEndian>(12).unwrap(); // length
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+
+        raw.write_u32::<BigEndian>(0x11223344).unwrap(); // payload
+        raw
+    }
+
+    fn ipv4_checksum_is_valid(raw: &[u8]) -> bool {
+        let mut sum = (0..10)
+            .map(|i| u32::from(BigEndian::read_u16(&raw[2 * i..2 * (i + 1)])))
+            .sum::<u32>();
+        while (sum & !0xffff) != 0 {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        sum == 0xffff
+    }
+
+    #[test]
+    fn inspector_drops_destination_port() {
+        let inspector = |id: &ConnectionId, _: &[u8]| {
+            if id.destination().port() == 53 {
+                Verdict::Drop
+            } else {
+                Verdict::Accept
+            }
+        };
+        let router = create_router(RelayConfigBuilder::new(0).inspector(Rc::new(inspector)));
+
+        let raw = &mut create_packet()[..];
+        let ipv4_packet = Ipv4Packet::parse(raw);
+        let id = Router::connection_id(&ipv4_packet);
+        assert!(matches!(
+            router.inspect(&id, &ipv4_packet),
+            Inspection::Dropped
+        ));
+
+        let raw = &mut create_packet()[..];
+        // destination port 80
+        BigEndian::write_u16(&mut raw[22..24], 80);
+        let ipv4_packet = Ipv4Packet::parse(raw);
+        let id = Router::connection_id(&ipv4_packet);
+        assert!(matches!(
+            router.inspect(&id, &ipv4_packet),
+            Inspection::Accepted
+        ));
+    }
+
+    #[test]
+    fn inspector_rewrites_payload() {
+        let inspector = |_: &ConnectionId, payload: &[u8]| {
+            let mut modified = payload.to_vec();
+            modified[0] = 0x99;
+            Verdict::Modify(modified)
+        };
+        let router = create_router(RelayConfigBuilder::new(0).inspector(Rc::new(inspector)));
+
+        let raw = &mut create_packet()[..];
+        let ipv4_packet = Ipv4Packet::parse(raw);
+        let id = Router::connection_id(&ipv4_packet);
+        if let Inspection::Modified(mut modified) = router.inspect(&id, &ipv4_packet) {
+            assert!(ipv4_checksum_is_valid(&modified));
+            let modified_packet = Ipv4Packet::parse(&mut modified);
+            assert_eq!(32, modified_packet.length());
+            assert_eq!([0x99, 0x22, 0x33, 0x44], modified_packet.payload().unwrap());
+        } else {
+            panic!("Packet not modified");
+        }
+    }
+
+    #[test]
+    fn reset_connection_stats() {
+        let mut selector = Selector::create().unwrap();
+        let mut router = create_router(RelayConfigBuilder::new(0));
+
+        let raw = &mut create_packet()[..];
+        let ipv4_packet = Ipv4Packet::parse(raw);
+        let id = Router::connection_id(&ipv4_packet);
+        let index = router
+            .connection(&mut selector, None, &id, &ipv4_packet)
+            .unwrap();
+        router.connections[index]
+            .borrow_mut()
+            .stats_mut()
+            .count_to_network(4);
+        assert_eq!(4, router.connection_stats()[0].1.bytes_to_network);
+
+        router.reset_connection_stats();
+        let stats = router.connection_stats();
+        // the connection is still up
+        assert_eq!(1, stats.len());
+        assert_eq!(ConnectionStats::default(), stats[0].1);
+
+        router.clear(&mut selector);
+    }
+
+    #[test]
+    fn expire_udp_connection_on_clock() {
+        let recorder = Rc::new(CloseRecorder::default());
+        let clock = Rc::new(MockClock::new());
+        let mut selector = Selector::with_clock(clock.clone()).unwrap();
+        let mut router = create_router(RelayConfigBuilder::new(0).observer(recorder.clone()));
+        let raw = &mut create_packet()[..];
+        let ipv4_packet = Ipv4Packet::parse(raw);
+        let id = Router::connection_id(&ipv4_packet);
+        router
+            .connection(&mut selector, None, &id, &ipv4_packet)
+            .unwrap();
+        let idle_timeout = router.config.udp_idle_timeout(id.destination().port());
+
+        clock.advance(idle_timeout);
+        router.clean_expired_connections(&mut selector);
+        assert_eq!(1, router.connections.len());
+
+        clock.advance(Duration::from_secs(1));
+        router.clean_expired_connections(&mut selector);
+        assert!(router.connections.is_empty());
+        assert_eq!(vec![CloseReason::Timeout], *recorder.reasons.borrow());
+    }
+
+    #[test]
+    fn query_connections() {
+        let mut selector = Selector::create().unwrap();
+        let mut router = create_router(RelayConfigBuilder::new(0));
+        let mut open = |router: &mut Router, source_port: u16, destination_port: u16| {
+            let raw = &mut create_packet()[..];
+            BigEndian::write_u16(&mut raw[20..22], source_port);
+            BigEndian::write_u16(&mut raw[22..24], destination_port);
+            let ipv4_packet = Ipv4Packet::parse(raw);
+            let id = Router::connection_id(&ipv4_packet);
+            router
+                .connection(&mut selector, None, &id, &ipv4_packet)
+                .unwrap();
+        };
+
+        open(&mut router, 1234, 53);
+        thread::sleep(Duration::from_millis(100));
+        open(&mut router, 1235, 53);
+        open(&mut router, 1236, 80);
+        assert_eq!(3, router.connections_where(Instant::now(), |_| true).len());
+
+        let dns =
+            router.connections_where(Instant::now(), |info| info.id.destination().port() == 53);
+        let mut source_ports = dns
+            .iter()
+            .map(|info| info.id.source().port())
+            .collect::<Vec<_>>();
+        source_ports.sort_unstable();
+        assert_eq!(vec![1234, 1235], source_ports);
+
+        let idle = router.connections_where(Instant::now(), |info| {
+            info.idle.expect("Activity not tracked") >= Duration::from_millis(100)
+        });
+        assert_eq!(1, idle.len());
+        assert_eq!(1234, idle[0].id.source().port());
+        assert!(!idle[0].half_open);
+
+        let none =
+            router.connections_where(Instant::now(), |info| info.id.destination().port() == 443);
+        assert!(none.is_empty());
+
+        router.clear(&mut selector);
+    }
+
+    fn create_gre_packet() -> Vec<u8> {
+        let inner = create_packet();
+        let mut raw = Vec::new();
+        raw.write_u8(4u8 << 4 | 5).unwrap();
+        raw.write_u8(0).unwrap(); // ToS
+        raw.write_u16::<BigEndian>(20 + 4 + inner.len() as u16)
+            .unwrap(); // total length
+        raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+        raw.write_u8(0).unwrap(); // TTL
+        raw.write_u8(PROTOCOL_GRE).unwrap(); // protocol
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x0a000001).unwrap(); // source address
+        raw.write_u32::<BigEndian>(0x0a000002).unwrap(); // destination address
+
+        raw.write_u16::<BigEndian>(0).unwrap(); // flags and version
+        raw.write_u16::<BigEndian>(0x0800).unwrap(); // protocol type (IPv4)
+
+        raw.extend_from_slice(&inner);
+        raw
+    }
+
+    #[test]
+    fn decapsulate_gre() {
+        let raw = &mut create_gre_packet()[..];
+        let ipv4_packet = Ipv4Packet::parse(raw);
+        assert!(!ipv4_packet.is_valid());
+
+        let router = create_router(RelayConfigBuilder::new(0).gre_decapsulation(true));
+        let mut inner = router.decapsulate(&ipv4_packet, 0).unwrap();
+        assert_eq!(create_packet(), inner);
+        let inner_packet = Ipv4Packet::parse(&mut inner);
+        assert!(inner_packet.is_valid());
+        assert_eq!(Protocol::Udp, inner_packet.ipv4_header().protocol());
+
+        assert!(router.decapsulate(&ipv4_packet, MAX_GRE_NESTING).is_none());
+
+        let router = create_router(RelayConfigBuilder::new(0));
+        assert!(router.decapsulate(&ipv4_packet, 0).is_none());
+    }
+
+    fn create_packet_with_options() -> Vec<u8> {
+        let mut raw = create_packet();
+        raw[0] = 4u8 << 4 | 6; // 4 bytes of options
+        raw[3] = 36; // total length
+        raw.splice(20..20, vec![1, 1, 1, 0]); // options (NOP NOP NOP EOL)
+        Ipv4Packet::parse(&mut raw).compute_checksums();
+        raw
+    }
+
+    #[test]
+    fn strip_ipv4_options() {
+        let raw = &mut create_packet_with_options()[..];
+        let ipv4_packet = Ipv4Packet::parse(raw);
+
+        let router = create_router(RelayConfigBuilder::new(0).strip_ipv4_options(true));
+        let mut stripped = router.strip_options(&ipv4_packet).unwrap();
+        assert_eq!(32, stripped.len());
+        assert!(ipv4_checksum_is_valid(&stripped));
+        let stripped_packet = Ipv4Packet::parse(&mut stripped);
+        assert_eq!(20, stripped_packet.ipv4_header().header_length());
+        assert_eq!(32, stripped_packet.length());
+        assert!(stripped_packet.ipv4_header().options().is_empty());
+        assert_eq!([0x11, 0x22, 0x33, 0x44], stripped_packet.payload().unwrap());
+
+        // nothing to strip
+        let raw = &mut create_packet()[..];
+        assert!(router.strip_options(&Ipv4Packet::parse(raw)).is_none());
+    }
+
+    #[test]
+    fn preserve_ipv4_options() {
+        let raw = &mut create_packet_with_options()[..];
+        let ipv4_packet = Ipv4Packet::parse(raw);
+
+        let router = create_router(RelayConfigBuilder::new(0));
+        assert!(router.strip_options(&ipv4_packet).is_none());
+        assert_eq!(24, ipv4_packet.ipv4_header().header_length());
+        assert_eq!([1, 1, 1, 0], ipv4_packet.ipv4_header().options());
+        assert_eq!([0x11, 0x22, 0x33, 0x44], ipv4_packet.payload().unwrap());
+    }
+
+    fn create_dns_router(resolver: Option<SocketAddrV4>) -> Router {
+        let mut dns_override = dns::tests::create_override();
+        if let Some(resolver) = resolver {
+            dns_override.set_resolver(resolver);
+        }
+        create_router(RelayConfigBuilder::new(0).dns_override(dns_override))
+    }
+
+    #[test]
+    fn answer_overridden_dns_query() {
+        let router = create_dns_router(None);
+        let query = dns::tests::create_query("example.com", 1);
+        let raw = &mut create_packet()[..];
+        let mut raw = Ipv4Packet::parse(raw).with_payload(&query);
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        let id = Router::connection_id(&ipv4_packet);
+
+        let mut response = router.dns_response(&id, &ipv4_packet).unwrap();
+        assert!(ipv4_checksum_is_valid(&response));
+        let response_packet = Ipv4Packet::parse(&mut response);
+        let response_id = Router::connection_id(&response_packet);
+        assert_eq!(id.source(), response_id.destination());
+        assert_eq!(id.destination(), response_id.source());
+        let answer = router
+            .config
+            .dns_override()
+            .unwrap()
+            .answer(&query)
+            .unwrap();
+        assert_eq!(&answer[..], response_packet.payload().unwrap());
+    }
+
+    #[test]
+    fn address_dns_response_to_announced_client_address() {
+        let mut router = create_dns_router(None);
+        let client_address = Ipv4Addr::new(10, 0, 0, 2);
+        router.set_client_address(ClientAddress::new(client_address, 24));
+
+        let query = dns::tests::create_query("example.com", 1);
+        let raw = &mut create_packet()[..];
+        let mut raw = Ipv4Packet::parse(raw).with_payload(&query);
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        let id = Router::connection_id(&ipv4_packet);
+
+        let mut response = router.dns_response(&id, &ipv4_packet).unwrap();
+        assert!(ipv4_checksum_is_valid(&response));
+        let response_packet = Ipv4Packet::parse(&mut response);
+        let response_id = Router::connection_id(&response_packet);
+        assert_eq!(client_address, *response_id.destination().ip());
+        assert_eq!(id.source().port(), response_id.destination().port());
+    }
+
+    #[test]
+    fn answer_cached_dns_query() {
+        let mut router = create_router(RelayConfigBuilder::new(0).dns_cache_capacity(4));
+        let query = dns::tests::create_query("example.com", 1);
+        let raw = &mut create_packet()[..];
+        let mut raw = Ipv4Packet::parse(raw).with_payload(&query);
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        let id = Router::connection_id(&ipv4_packet);
+
+        assert!(router
+            .cached_dns_response(&id, &ipv4_packet, Instant::now())
+            .is_none());
+        assert_eq!(1, router.metrics.get(Counter::DnsCacheMisses));
+
+        // the response received from upstream
+        let answer = dns::tests::create_override().answer(&query).unwrap();
+        router.cache_dns_response(&id, &answer, Instant::now());
+
+        let mut response = router
+            .cached_dns_response(&id, &ipv4_packet, Instant::now())
+            .unwrap();
+        assert!(ipv4_checksum_is_valid(&response));
+        let response_packet = Ipv4Packet::parse(&mut response);
+        let response_id = Router::connection_id(&response_packet);
+        assert_eq!(id.source(), response_id.destination());
+        assert_eq!(&answer[..], response_packet.payload().unwrap());
+        assert_eq!(1, router.metrics.get(Counter::DnsCacheHits));
+    }
+
+    #[test]
+    fn forward_dns_query() {
+        let resolver = SocketAddrV4::new([192, 168, 1, 1].into(), 53);
+        let router = create_dns_router(Some(resolver));
+        let query = dns::tests::create_query("www.example.com", 1);
+        let raw = &mut create_packet()[..];
+        let mut raw = Ipv4Packet::parse(raw).with_payload(&query);
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        let id = Router::connection_id(&ipv4_packet);
+
+        assert!(router.dns_response(&id, &ipv4_packet).is_none());
+        assert_eq!(resolver, router.upstream_destination(&id));
+
+        // without resolver, the query is relayed to its destination
+        let router = create_dns_router(None);
+        assert!(router.dns_response(&id, &ipv4_packet).is_none());
+        assert_eq!(id.rewritten_destination(), router.upstream_destination(&id));
+    }
+
+    #[test]
+    fn redirect_dns_query() {
+        let resolver = SocketAddrV4::new([192, 168, 1, 1].into(), 53);
+        let router = create_router(RelayConfigBuilder::new(0).dns_redirect(resolver));
+        let query = dns::tests::create_query("www.example.com", 1);
+        let raw = &mut create_packet()[..];
+        let mut raw = Ipv4Packet::parse(raw).with_payload(&query);
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        let id = Router::connection_id(&ipv4_packet);
+        assert_eq!(resolver, router.upstream_destination(&id));
+
+        // the resolver of the DNS override takes precedence
+        let other = SocketAddrV4::new([192, 168, 1, 2].into(), 53);
+        let mut dns_override = dns::tests::create_override();
+        dns_override.set_resolver(other);
+        let config = RelayConfigBuilder::new(0)
+            .dns_override(dns_override)
+            .dns_redirect(resolver);
+        assert_eq!(other, create_router(config).upstream_destination(&id));
+    }
+
+    #[test]
+    fn inject_packet_loss() {
+        let config = RelayConfigBuilder::new(0)
+            .packet_loss(0.25, 0.0)
+            .packet_loss_seed(1234)
+            .build();
+        let metrics = Arc::new(Metrics::new());
+        let mut router = Router::new(
+            Rc::new(config),
+            metrics.clone(),
+            Default::default(),
+            Instant::now(),
+        );
+        let mut expected = LossInjector::new(0.25, 0.0, Some(1234));
+
+        let mut drops = 0;
+        for _ in 0..1000 {
+            let dropped = router.inject_loss_to_network();
+            assert_eq!(expected.drop_to_network(), dropped);
+            if dropped {
+                drops += 1;
+            }
+            assert!(!router.inject_loss_to_client());
+            // keep both generators in sync
+            expected.drop_to_client();
+        }
+        assert_eq!(drops, metrics.get(Counter::InjectedLossToNetwork));
+        assert_eq!(0, metrics.get(Counter::InjectedLossToClient));
+        assert!(drops > 200 && drops < 300, "{} drops", drops);
+    }
+
+    #[test]
+    fn drop_packets_above_rate() {
+        let config = RelayConfigBuilder::new(0).max_packet_rate(1000).build();
+        let metrics = Arc::new(Metrics::new());
+        let start = Instant::now();
+        let mut router = Router::new(Rc::new(config), metrics.clone(), Default::default(), start);
+
+        // a burst of 100 packets (a tenth of the rate) is accepted, the excess is dropped
+        let accepted = (0..150)
+            .filter(|_| !router.exceeds_packet_rate(start))
+            .count();
+        assert_eq!(100, accepted);
+        assert_eq!(50, metrics.get(Counter::RateLimitedPackets));
+
+        // 10 ms later, 10 more packets are accepted
+        let later = start + Duration::from_millis(10);
+        let accepted = (0..20)
+            .filter(|_| !router.exceeds_packet_rate(later))
+            .count();
+        assert_eq!(10, accepted);
+        assert_eq!(60, metrics.get(Counter::RateLimitedPackets));
+    }
+
+    #[test]
+    fn accept_packets_below_rate() {
+        let config = RelayConfigBuilder::new(0).max_packet_rate(1000).build();
+        let metrics = Arc::new(Metrics::new());
+        let start = Instant::now();
+        let mut router = Router::new(Rc::new(config), metrics.clone(), Default::default(), start);
+
+        // 500 packets per second during 10 seconds
+        for i in 0..5000 {
+            let now = start + Duration::from_millis(2 * i);
+            assert!(!router.exceeds_packet_rate(now));
+        }
+        assert_eq!(0, metrics.get(Counter::RateLimitedPackets));
+
+        let mut router = create_router(RelayConfigBuilder::new(0));
+        assert!((0..10_000).all(|_| !router.exceeds_packet_rate(start)));
+    }
+
+    #[test]
+    fn drop_igmp() {
+        let metrics = Arc::new(Metrics::new());
+        let router = Router::new(
+            Rc::new(RelayConfigBuilder::new(0).build()),
+            metrics.clone(),
+            Default::default(),
+            Instant::now(),
+        );
+
+        // IGMPv2 membership report for 224.0.0.251
+        let mut raw = create_packet();
+        raw[9] = PROTOCOL_IGMP;
+        raw[20..28].copy_from_slice(&[0x16, 0, 0, 0, 224, 0, 0, 251]);
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        assert!(!ipv4_packet.is_valid());
+        assert!(router.is_igmp(&ipv4_packet));
+        assert_eq!(1, metrics.get(Counter::IgmpPacketsDropped));
+
+        let raw = &mut create_packet()[..];
+        let ipv4_packet = Ipv4Packet::parse(raw);
+        assert!(!router.is_igmp(&ipv4_packet));
+        assert_eq!(1, metrics.get(Counter::IgmpPacketsDropped));
+    }
+
+    #[test]
+    fn limit_pending_echoes() {
+        let router = create_router(RelayConfigBuilder::new(0));
+        router.pending_echoes.set(MAX_PENDING_ECHOES - 1);
+        assert!(!router.exceeds_pending_echoes());
+        router.pending_echoes.set(MAX_PENDING_ECHOES);
+        assert!(router.exceeds_pending_echoes());
+    }
+
+    #[test]
+    fn count_packets_by_protocol() {
+        use crate::relay::tcp_connection::tests::create_tcp_packet;
+        use crate::relay::tcp_header::FLAG_SYN;
+
+        let metrics = Arc::new(Metrics::new());
+        let router = Router::new(
+            Rc::new(RelayConfigBuilder::new(0).build()),
+            metrics.clone(),
+            Default::default(),
+            Instant::now(),
+        );
+
+        let mut tcp = create_tcp_packet(1234, 1000, 0, FLAG_SYN, 0xffff, &[]);
+        router.count_protocol(&Ipv4Packet::parse(&mut tcp));
+        // UDP, UDP and GRE
+        for &protocol in &[17, 17, PROTOCOL_GRE] {
+            let mut raw = create_packet();
+            raw[9] = protocol;
+            router.count_protocol(&Ipv4Packet::parse(&mut raw));
+        }
+        assert_eq!(1, metrics.protocol_packets(Protocol::Tcp));
+        assert_eq!(2, metrics.protocol_packets(Protocol::Udp));
+        assert_eq!(1, metrics.protocol_packets(Protocol::Other(PROTOCOL_GRE)));
+        assert_eq!(0, metrics.protocol_packets(Protocol::Other(1)));
+    }
+
+    #[test]
+    fn drop_spoofed_source() {
+        let metrics = Arc::new(Metrics::new());
+        let config = RelayConfigBuilder::new(0).build();
+        let mut router = Router::new(
+            Rc::new(config),
+            metrics.clone(),
+            Default::default(),
+            Instant::now(),
+        );
+        let raw = &mut create_packet()[..];
+        let ipv4_packet = Ipv4Packet::parse(raw);
+        let id = Router::connection_id(&ipv4_packet);
+
+        // no address announced yet
+        assert!(!router.is_spoofed(&id));
+
+        // the source 18.52.86.120 is in range
+        router.set_client_address(ClientAddress::new(Ipv4Addr::new(18, 52, 0, 1), 16));
+        assert!(!router.is_spoofed(&id));
+        assert_eq!(0, metrics.get(Counter::SpoofedPacketsDropped));
+
+        router.set_client_address(ClientAddress::new(Ipv4Addr::new(10, 0, 0, 2), 24));
+        assert!(router.is_spoofed(&id));
+        assert_eq!(1, metrics.get(Counter::SpoofedPacketsDropped));
+    }
+
+    #[test]
+    fn log_sample_of_drops() {
+        let metrics = Arc::new(Metrics::new());
+        let config = RelayConfigBuilder::new(0).drop_log_sample_rate(10).build();
+        let router = Router::new(
+            Rc::new(config),
+            metrics.clone(),
+            Default::default(),
+            Instant::now(),
+        );
+        let raw = &mut create_packet()[..];
+        let id = Router::connection_id(&Ipv4Packet::parse(raw));
+
+        for _ in 0..100 {
+            router.notify_drop(&id, DropReason::RateLimited);
+        }
+        // all the drops are counted, one in ten is logged
+        assert_eq!(100, metrics.get(Counter::DroppedPackets));
+        assert_eq!(10, router.drop_logger.as_ref().unwrap().logged());
+    }
+
+    #[test]
+    fn accept_spoofed_source_if_validation_disabled() {
+        let mut router = create_router(RelayConfigBuilder::new(0).source_validation(false));
+        router.set_client_address(ClientAddress::new(Ipv4Addr::new(10, 0, 0, 2), 24));
+        let raw = &mut create_packet()[..];
+        let ipv4_packet = Ipv4Packet::parse(raw);
+        let id = Router::connection_id(&ipv4_packet);
+        assert!(!router.is_spoofed(&id));
+    }
+
+    #[test]
+    fn no_inspector_accepts() {
+        let router = create_router(RelayConfigBuilder::new(0));
+        let raw = &mut create_packet()[..];
+        let ipv4_packet = Ipv4Packet::parse(raw);
+        let id = Router::connection_id(&ipv4_packet);
+        assert!(matches!(
+            router.inspect(&id, &ipv4_packet),
+            Inspection::Accepted
+        ));
+    }
+
+    #[derive(Default)]
+    struct CloseRecorder {
+        reasons: RefCell<Vec<CloseReason>>,
+    }
+
+    impl Observer for CloseRecorder {
+        fn on_close(&self, _: &ConnectionId, _: &ConnectionStats, reason: CloseReason) {
+            self.reasons.borrow_mut().push(reason);
+        }
+    }
+
+    struct FakeConnection {
+        id: ConnectionId,
+        stats: ConnectionStats,
+        expired: bool,
+        close_reason: Option<CloseReason>,
+        last_activity: Option<Instant>,
+    }
+
+    impl Connection for FakeConnection {
+        fn id(&self) -> &ConnectionId {
+            &self.id
+        }
+
+        fn send_to_network(&mut self, _: &mut Selector, _: &mut ClientChannel, _: &Ipv4Packet) {}
+
+        fn close(&mut self, _: &mut Selector, reason: CloseReason) {
+            self.close_reason = Some(reason);
+        }
+
+        fn is_expired(&self, _: Instant) -> bool {
+            self.expired
+        }
+
+        fn close_reason(&self) -> Option<CloseReason> {
+            self.close_reason
+        }
+
+        fn stats(&self) -> &ConnectionStats {
+            &self.stats
+        }
+
+        fn stats_mut(&mut self) -> &mut ConnectionStats {
+            &mut self.stats
+        }
+
+        fn set_lifetime_timer(&mut self, _: TimerId) {}
+
+        fn last_activity(&self) -> Option<Instant> {
+            self.last_activity
+        }
+    }
+
+    fn add_fake_connection(router: &mut Router, expired: bool) -> Rc<RefCell<FakeConnection>> {
+        let raw = &mut create_packet()[..];
+        let ipv4_packet = Ipv4Packet::parse(raw);
+        let connection = Rc::new(RefCell::new(FakeConnection {
+            id: Router::connection_id(&ipv4_packet),
+            stats: ConnectionStats::default(),
+            expired,
+            close_reason: None,
+            last_activity: None,
+        }));
+        router.connections.push(connection.clone());
+        connection
+    }
+
+    #[test]
+    fn cancel_lifetime_timer_on_close() {
+        let mut selector = Selector::create().unwrap();
+        let config = RelayConfigBuilder::new(0).max_connection_lifetime(Duration::from_secs(60));
+        let mut router = create_router(config);
+        let raw = &mut create_packet()[..];
+        let ipv4_packet = Ipv4Packet::parse(raw);
+        let id = Router::connection_id(&ipv4_packet);
+        router
+            .connection(&mut selector, None, &id, &ipv4_packet)
+            .unwrap();
+        assert!(selector.next_timer_timeout().is_some());
+
+        router.clear(&mut selector);
+        assert!(selector.next_timer_timeout().is_none());
+    }
+
+    #[test]
+    fn report_close_reasons() {
+        let recorder = Rc::new(CloseRecorder::default());
+        let mut selector = Selector::create().unwrap();
+        let mut router = create_router(RelayConfigBuilder::new(0).observer(recorder.clone()));
+
+        let expired = add_fake_connection(&mut router, true);
+        let active = add_fake_connection(&mut router, false);
+        router.clean_expired_connections(&mut selector);
+        assert_eq!(Some(CloseReason::Timeout), expired.borrow().close_reason);
+        assert_eq!(None, active.borrow().close_reason);
+        assert_eq!(vec![CloseReason::Timeout], *recorder.reasons.borrow());
+
+        // a connection closed by its state machine removes itself with its own reason
+        active
+            .borrow_mut()
+            .close(&mut selector, CloseReason::UpstreamFin);
+        router.remove(&*active.borrow());
+        assert!(router.connections.is_empty());
+
+        let remaining = add_fake_connection(&mut router, false);
+        router.clear(&mut selector);
+        assert_eq!(
+            Some(CloseReason::ClientDisconnected),
+            remaining.borrow().close_reason
+        );
+        assert_eq!(
+            vec![
+                CloseReason::Timeout,
+                CloseReason::UpstreamFin,
+                CloseReason::ClientDisconnected
+            ],
+            *recorder.reasons.borrow()
+        );
+    }
+
+    #[test]
+    fn evict_idlest_udp_connection() {
+        let recorder = Rc::new(CloseRecorder::default());
+        let mut selector = Selector::create().unwrap();
+        let mut router = create_router(RelayConfigBuilder::new(0).observer(recorder.clone()));
+        let now = Instant::now();
+        let active = add_fake_connection(&mut router, false);
+        active.borrow_mut().last_activity = Some(now);
+        let idle = add_fake_connection(&mut router, false);
+        idle.borrow_mut().last_activity = Some(now - Duration::from_secs(10));
+
+        assert!(router.evict_idlest_udp_connection(&mut selector));
+        assert_eq!(Some(CloseReason::Evicted), idle.borrow().close_reason);
+        assert_eq!(None, active.borrow().close_reason);
+        assert_eq!(1, router.connections.len());
+        assert_eq!(vec![CloseReason::Evicted], *recorder.reasons.borrow());
+
+        assert!(router.evict_idlest_udp_connection(&mut selector));
+        assert!(!router.evict_idlest_udp_connection(&mut selector));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn report_connections_not_handed_off() {
+        let recorder = Rc::new(CloseRecorder::default());
+        let mut router = create_router(RelayConfigBuilder::new(0).observer(recorder.clone()));
+        // a UDP connection is never handed off
+        add_fake_connection(&mut router, false);
+        let (states, fds) = router.hand_off(Instant::now()).unwrap();
+        assert!(states.is_empty());
+        assert!(fds.is_empty());
+        assert_eq!(vec![CloseReason::RelayShutdown], *recorder.reasons.borrow());
+
+        router.abandon();
+        assert_eq!(
+            vec![CloseReason::RelayShutdown, CloseReason::RelayShutdown],
+            *recorder.reasons.borrow()
+        );
+    }
+
+    // the decision, the upstream address and the close reason
+    type Audit = (Decision, Option<SocketAddrV4>, Option<CloseReason>);
+
+    #[derive(Default)]
+    struct AuditRecorder {
+        records: RefCell<Vec<Audit>>,
+    }
+
+    impl Observer for AuditRecorder {
+        fn on_audit(&self, record: &AuditRecord) {
+            self.records
+                .borrow_mut()
+                .push((record.decision, record.upstream, record.close_reason));
+        }
+    }
+
+    #[test]
+    fn audit_blocked_connection() {
+        let recorder = Rc::new(AuditRecorder::default());
+        let mut router = create_router(RelayConfigBuilder::new(0).observer(recorder.clone()));
+        let raw = &mut create_packet()[..];
+        let id = Router::connection_id(&Ipv4Packet::parse(raw));
+        router.drop_inspected(&id);
+        assert_eq!(
+            vec![(Decision::Blocked, None, None)],
+            *recorder.records.borrow()
+        );
+
+        // dropping a packet of an existing connection does not block it
+        add_fake_connection(&mut router, false);
+        router.drop_inspected(&id);
+        assert_eq!(1, recorder.records.borrow().len());
+    }
+
+    #[test]
+    fn audit_closed_connection() {
+        let recorder = Rc::new(AuditRecorder::default());
+        let mut selector = Selector::create().unwrap();
+        let mut router = create_router(RelayConfigBuilder::new(0).observer(recorder.clone()));
+        let connection = add_fake_connection(&mut router, false);
+        connection
+            .borrow_mut()
+            .close(&mut selector, CloseReason::ClientFin);
+        router.remove(&*connection.borrow());
+
+        let upstream = "66.66.66.66:53".parse().unwrap();
+        assert_eq!(
+            vec![(
+                Decision::Accepted,
+                Some(upstream),
+                Some(CloseReason::ClientFin)
+            )],
+            *recorder.records.borrow()
+        );
+    }
+
+    #[cfg(unix)]
+    fn states_without_timestamps(states: &[TcpConnectionState]) -> serde_json::Value {
+        let mut states = serde_json::to_value(states).unwrap();
+        for state in states.as_array_mut().unwrap() {
+            // our timestamps keep increasing during the test
+            state.as_object_mut().unwrap().remove("timestamp");
+        }
+        states
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hand_off_tcp_connections() {
+        use crate::relay::tcp_connection::tests::create_tcp_packet;
+        use crate::relay::tcp_header;
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut selector = Selector::create().unwrap();
+        let mut router = create_router(RelayConfigBuilder::new(0));
+        for &source_port in &[41000, 41001] {
+            let mut raw = create_tcp_packet(port, 1000, 0, tcp_header::FLAG_SYN, 0xffff, &[]);
+            BigEndian::write_u16(&mut raw[20..22], source_port);
+            let ipv4_packet = Ipv4Packet::parse(&mut raw);
+            let id = Router::connection_id(&ipv4_packet);
+            router
+                .connection(&mut selector, None, &id, &ipv4_packet)
+                .unwrap();
+        }
+        // a UDP connection, which is not handed off
+        let raw = &mut create_packet()[..];
+        let ipv4_packet = Ipv4Packet::parse(raw);
+        let id = Router::connection_id(&ipv4_packet);
+        router
+            .connection(&mut selector, None, &id, &ipv4_packet)
+            .unwrap();
+
+        let (states, fds) = router.hand_off(Instant::now()).unwrap();
+        assert_eq!(2, fds.len());
+
+        let mut restored_router = create_router(RelayConfigBuilder::new(0));
+        restored_router
+            .restore(&mut selector, &states, fds)
+            .unwrap();
+
+        let ids = |router: &Router| {
+            router
+                .connection_stats()
+                .into_iter()
+                .map(|(id, _, _)| id.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(&router)[..2], ids(&restored_router)[..]);
+        let (restored_states, restored_fds) = restored_router.hand_off(Instant::now()).unwrap();
+        assert_eq!(2, restored_fds.len());
+        assert_eq!(
+            states_without_timestamps(&states),
+            states_without_timestamps(&restored_states)
+        );
+
+        assert!(restored_router
+            .restore(&mut selector, &states, Vec::new())
+            .is_err());
+
+        router.clear(&mut selector);
+        restored_router.clear(&mut selector);
+    }
+}
@@ -0,0 +1,123 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::cmp;
+use std::time::Duration;
+
+// lower bound of the timeout (RFC 6298 section 2.4)
+const MIN_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Estimate the round-trip time from the samples measured on the ACKs, as computed for the
+/// retransmission timer (RFC 6298).
+pub struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+}
+
+impl RttEstimator {
+    pub fn new() -> Self {
+        Self {
+            srtt: None,
+            rttvar: Duration::default(),
+        }
+    }
+
+    pub fn on_sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(rtt);
+                // alpha = 1/8, beta = 1/4
+                self.rttvar = self.rttvar * 3 / 4 + delta / 4;
+                self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+            }
+        }
+    }
+
+    /// The smoothed round-trip time, if at least one sample has been measured.
+    pub fn smoothed(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// The delay after which the data in flight should have been acknowledged.
+    pub fn timeout(&self) -> Duration {
+        match self.srtt {
+            Some(srtt) => cmp::min(cmp::max(srtt + 4 * self.rttvar, MIN_TIMEOUT), MAX_TIMEOUT),
+            None => MIN_TIMEOUT,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn first_sample() {
+        let mut rtt = RttEstimator::new();
+        assert!(rtt.smoothed().is_none());
+        assert_eq!(MIN_TIMEOUT, rtt.timeout());
+        rtt.on_sample(millis(100));
+        assert_eq!(Some(millis(100)), rtt.smoothed());
+        assert_eq!(millis(50), rtt.rttvar);
+    }
+
+    #[test]
+    fn converge_to_constant_delay() {
+        let mut rtt = RttEstimator::new();
+        rtt.on_sample(millis(20));
+        for _ in 0..100 {
+            rtt.on_sample(millis(300));
+        }
+        let srtt = rtt.smoothed().unwrap();
+        assert!(srtt > millis(299) && srtt <= millis(300), "{:?}", srtt);
+        assert!(rtt.rttvar < millis(1));
+        assert_eq!(MIN_TIMEOUT, rtt.timeout());
+    }
+
+    #[test]
+    fn smooth_jittered_delays() {
+        let mut rtt = RttEstimator::new();
+        // alternate between 100 ms and 300 ms
+        for i in 0..100 {
+            rtt.on_sample(millis(if i % 2 == 0 { 100 } else { 300 }));
+        }
+        let srtt = rtt.smoothed().unwrap();
+        assert!(srtt > millis(180) && srtt < millis(220), "{:?}", srtt);
+        // the variation reflects the jitter
+        assert!(rtt.rttvar > millis(80) && rtt.rttvar < millis(120));
+    }
+
+    #[test]
+    fn timeout_follows_slow_path() {
+        let mut rtt = RttEstimator::new();
+        for _ in 0..10 {
+            rtt.on_sample(millis(2000));
+        }
+        assert!(rtt.timeout() > millis(2000) && rtt.timeout() < millis(3000));
+
+        rtt.on_sample(Duration::from_secs(120));
+        assert_eq!(MAX_TIMEOUT, rtt.timeout());
+    }
+}
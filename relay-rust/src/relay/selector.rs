@@ -17,12 +17,21 @@
 use log::*;
 use mio::{Event, Evented, Events, Poll, PollOpt, Ready, Token};
 use slab::Slab;
+use std::collections::HashMap;
+use std::fmt;
 use std::io;
+#[cfg(all(feature = "tokio", unix))]
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::rc::Rc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+use super::clock::{Clock, SystemClock};
+use super::observer::Observer;
 
 const TAG: &str = "Selector";
 
+const INITIAL_CAPACITY: usize = 1024;
+
 pub trait EventHandler {
     fn on_ready(&self, selector: &mut Selector, event: Event);
 }
@@ -36,22 +45,119 @@ where
     }
 }
 
+pub trait TimerHandler {
+    fn on_timeout(&self, selector: &mut Selector);
+}
+
+impl<F> TimerHandler for F
+where
+    F: Fn(&mut Selector),
+{
+    fn on_timeout(&self, selector: &mut Selector) {
+        self(selector);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TimerId(u64);
+
+struct Timer {
+    deadline: Instant,
+    handler: Rc<dyn TimerHandler>,
+}
+
+struct Handle {
+    handler: Rc<dyn EventHandler>,
+    // false once deregistered from the poll
+    registered: bool,
+    // as last (re)registered
+    interest: Ready,
+    opts: PollOpt,
+}
+
+/// State of a handle registered in the selector, for debugging.
+#[derive(Debug, PartialEq, Eq)]
+pub struct HandleState {
+    pub token: Token,
+    pub registered: bool,
+    pub pending_removal: bool,
+}
+
+impl fmt::Display for HandleState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "handle {} registered={} pending_removal={}",
+            self.token.0, self.registered, self.pending_removal
+        )
+    }
+}
+
 pub struct Selector {
     poll: Poll,
-    handlers: Slab<Rc<dyn EventHandler>>,
+    handlers: Slab<Handle>,
     // tokens to be removed after all the current poll events are executed
     tokens_to_remove: Vec<Token>,
+    // one-shot timers, identified by a never reused id (so that a stale id cannot cancel a newer
+    // timer)
+    timers: HashMap<TimerId, Timer>,
+    next_timer_id: u64,
+    // notified when the capacity grows
+    observer: Option<Rc<dyn Observer>>,
+    // the time source of the timers and of the connection timeouts
+    clock: Rc<dyn Clock>,
 }
 
 impl Selector {
     pub fn create() -> io::Result<Self> {
+        Self::with_clock(Rc::new(SystemClock))
+    }
+
+    /// Create a selector whose timers (and the timeouts of the connections) follow `clock`.
+    pub fn with_clock(clock: Rc<dyn Clock>) -> io::Result<Self> {
         Ok(Self {
             poll: Poll::new()?,
-            handlers: Slab::with_capacity(1024),
+            handlers: Slab::with_capacity(INITIAL_CAPACITY),
             tokens_to_remove: Vec::new(),
+            timers: HashMap::new(),
+            next_timer_id: 0,
+            observer: None,
+            clock,
         })
     }
 
+    /// The current time, according to the clock of the selector.
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    pub fn set_observer(&mut self, observer: Rc<dyn Observer>) {
+        self.observer = Some(observer);
+    }
+
+    /// The number of handles which may be registered without reallocating.
+    #[allow(dead_code)]
+    pub fn capacity(&self) -> usize {
+        self.handlers.capacity()
+    }
+
+    // double the capacity when the slab is full, and report it so that the initial capacity may be
+    // tuned (the tokens are the indexes in the slab, so they remain stable)
+    fn reserve_if_full(&mut self) {
+        let capacity = self.handlers.capacity();
+        if self.handlers.len() == capacity {
+            self.handlers.reserve(capacity.max(1));
+            let new_capacity = self.handlers.capacity();
+            info!(
+                target: TAG,
+                "Handle capacity grown from {} to {}", capacity, new_capacity
+            );
+            if let Some(ref observer) = self.observer {
+                observer.on_capacity_grown(new_capacity);
+            }
+        }
+    }
+
     pub fn register<E, H>(
         &mut self,
         handle: &E,
@@ -63,7 +169,13 @@ impl Selector {
         E: Evented + ?Sized,
         H: EventHandler + 'static,
     {
-        let token = Token(self.handlers.insert(Rc::new(handler)));
+        self.reserve_if_full();
+        let token = Token(self.handlers.insert(Handle {
+            handler: Rc::new(handler),
+            registered: true,
+            interest,
+            opts,
+        }));
         if let Err(err) = self.poll.register(handle, token, interest, opts) {
             // remove the token we just added
             self.handlers.remove(token.0);
@@ -83,7 +195,32 @@ impl Selector {
     where
         E: Evented + ?Sized,
     {
-        self.poll.reregister(handle, token, interest, opts)
+        if let Some(handle) = self.handlers.get(token.0) {
+            // a oneshot registration must be rearmed even if nothing changed
+            if handle.registered
+                && handle.interest == interest
+                && handle.opts == opts
+                && !opts.is_oneshot()
+            {
+                return Ok(());
+            }
+        }
+        self.poll.reregister(handle, token, interest, opts)?;
+        if let Some(handle) = self.handlers.get_mut(token.0) {
+            handle.interest = interest;
+            handle.opts = opts;
+        }
+        Ok(())
+    }
+
+    /// Whether the handle identified by `token` is registered with a non-empty interest, so that
+    /// it may receive events.
+    #[allow(dead_code)]
+    pub fn is_registered(&self, token: Token) -> bool {
+        match self.handlers.get(token.0) {
+            Some(handle) => handle.registered && !handle.interest.is_empty(),
+            None => false,
+        }
     }
 
     pub fn deregister<E>(&mut self, handle: &E, token: Token) -> io::Result<()>
@@ -91,6 +228,9 @@ impl Selector {
         E: Evented + ?Sized,
     {
         self.poll.deregister(handle)?;
+        if let Some(handle) = self.handlers.get_mut(token.0) {
+            handle.registered = false;
+        }
         // remove them before next poll()
         self.tokens_to_remove.push(token);
         Ok(())
@@ -103,10 +243,79 @@ impl Selector {
         self.tokens_to_remove.clear();
     }
 
+    /// Call `handler` once, after `delay`.
+    ///
+    /// The timers are only fired from `run_expired_timers()`, so the caller of `poll()` must take
+    /// `next_timer_timeout()` into account.
+    pub fn set_timer<H>(&mut self, delay: Duration, handler: H) -> TimerId
+    where
+        H: TimerHandler + 'static,
+    {
+        let id = TimerId(self.next_timer_id);
+        self.next_timer_id += 1;
+        let timer = Timer {
+            deadline: self.now() + delay,
+            handler: Rc::new(handler),
+        };
+        self.timers.insert(id, timer);
+        id
+    }
+
+    /// Cancel a timer. Cancelling a timer which already fired has no effect.
+    pub fn cancel_timer(&mut self, id: TimerId) {
+        self.timers.remove(&id);
+    }
+
+    /// The delay until the next timer expires, if any.
+    pub fn next_timer_timeout(&self) -> Option<Duration> {
+        let now = self.now();
+        self.timers
+            .values()
+            .map(|timer| timer.deadline.saturating_duration_since(now))
+            .min()
+    }
+
+    /// Fire the expired timers, and return the number of handlers called.
+    pub fn run_expired_timers(&mut self) -> usize {
+        let now = self.now();
+        let mut expired: Vec<(Instant, TimerId)> = self
+            .timers
+            .iter()
+            .filter(|(_, timer)| timer.deadline <= now)
+            .map(|(&id, timer)| (timer.deadline, id))
+            .collect();
+        // fire them in order
+        expired.sort_by_key(|&(deadline, id)| (deadline, id.0));
+        for &(_, id) in &expired {
+            // a handler may cancel another expired timer
+            if let Some(timer) = self.timers.remove(&id) {
+                timer.handler.on_timeout(self);
+            }
+        }
+        // a handler may have deregistered a handle, release it even if no event follows
+        self.clean_removed_tokens();
+        expired.len()
+    }
+
     pub fn poll(&mut self, events: &mut Events, timeout: Option<Duration>) -> io::Result<usize> {
         self.poll.poll(events, timeout)
     }
 
+    /// The state of all the handles, ordered by token, to debug a stuck relay.
+    pub fn debug_dump(&self) -> Vec<HandleState> {
+        let mut states: Vec<HandleState> = self
+            .handlers
+            .iter()
+            .map(|(index, handle)| HandleState {
+                token: Token(index),
+                registered: handle.registered,
+                pending_removal: self.tokens_to_remove.contains(&Token(index)),
+            })
+            .collect();
+        states.sort_by_key(|state| state.token.0);
+        states
+    }
+
     pub fn run_handlers(&mut self, events: &Events) {
         for event in events {
             debug!(target: TAG, "event={:?}", event);
@@ -114,6 +323,7 @@ impl Selector {
                 .handlers
                 .get_mut(event.token().0)
                 .expect("Token not found")
+                .handler
                 .clone();
             handler.on_ready(self, event);
         }
@@ -122,3 +332,205 @@ impl Selector {
         self.clean_removed_tokens();
     }
 }
+
+// the selector is ready when its poll descriptor is readable, so that it can be driven by another
+// event loop
+#[cfg(all(feature = "tokio", unix))]
+impl AsRawFd for Selector {
+    fn as_raw_fd(&self) -> RawFd {
+        self.poll.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::clock::MockClock;
+    use mio::Registration;
+    use std::cell::{Cell, RefCell};
+
+    #[test]
+    fn dump_handles() {
+        let mut selector = Selector::create().unwrap();
+        let registrations: Vec<Registration> = (0..3).map(|_| Registration::new2().0).collect();
+        let tokens: Vec<Token> = registrations
+            .iter()
+            .map(|registration| {
+                let handler = |_: &mut Selector, _| {};
+                selector
+                    .register(registration, handler, Ready::readable(), PollOpt::edge())
+                    .unwrap()
+            })
+            .collect();
+        selector.deregister(&registrations[1], tokens[1]).unwrap();
+
+        let states = selector.debug_dump();
+        assert_eq!(3, states.len());
+        assert_eq!(
+            HandleState {
+                token: tokens[0],
+                registered: true,
+                pending_removal: false,
+            },
+            states[0]
+        );
+        assert_eq!(
+            HandleState {
+                token: tokens[1],
+                registered: false,
+                pending_removal: true,
+            },
+            states[1]
+        );
+        assert!(states[2].registered && !states[2].pending_removal);
+        assert_eq!(
+            "handle 1 registered=false pending_removal=true",
+            states[1].to_string()
+        );
+
+        // removed before the next poll
+        selector.run_handlers(&Events::with_capacity(1));
+        let states = selector.debug_dump();
+        assert_eq!(2, states.len());
+        assert!(states.iter().all(|state| state.token != tokens[1]));
+    }
+
+    #[derive(Default)]
+    struct CapacityObserver {
+        capacity: Cell<usize>,
+    }
+
+    impl Observer for CapacityObserver {
+        fn on_capacity_grown(&self, capacity: usize) {
+            self.capacity.set(capacity);
+        }
+    }
+
+    #[test]
+    fn grow_capacity() {
+        let mut selector = Selector::create().unwrap();
+        let observer = Rc::new(CapacityObserver::default());
+        selector.set_observer(observer.clone());
+        assert_eq!(INITIAL_CAPACITY, selector.capacity());
+
+        let registrations: Vec<Registration> = (0..INITIAL_CAPACITY + 1)
+            .map(|_| Registration::new2().0)
+            .collect();
+        let mut tokens = Vec::new();
+        for registration in &registrations {
+            let handler = |_: &mut Selector, _| {};
+            let token = selector
+                .register(registration, handler, Ready::readable(), PollOpt::edge())
+                .unwrap();
+            tokens.push(token);
+        }
+        assert!(selector.capacity() >= 2 * INITIAL_CAPACITY);
+        assert_eq!(selector.capacity(), observer.capacity.get());
+        // the tokens registered before the growth are still valid
+        for (i, state) in selector.debug_dump().iter().enumerate() {
+            assert_eq!(tokens[i], state.token);
+            assert!(state.registered);
+        }
+    }
+
+    #[test]
+    fn registered_with_interest() {
+        let mut selector = Selector::create().unwrap();
+        let (registration, _) = Registration::new2();
+        let handler = |_: &mut Selector, _| {};
+        let token = selector
+            .register(&registration, handler, Ready::empty(), PollOpt::level())
+            .unwrap();
+        assert!(!selector.is_registered(token));
+
+        selector
+            .reregister(&registration, token, Ready::readable(), PollOpt::level())
+            .unwrap();
+        assert!(selector.is_registered(token));
+        // no-op
+        selector
+            .reregister(&registration, token, Ready::readable(), PollOpt::level())
+            .unwrap();
+        assert!(selector.is_registered(token));
+
+        selector.deregister(&registration, token).unwrap();
+        assert!(!selector.is_registered(token));
+    }
+
+    #[test]
+    fn fire_expired_timers_in_order() {
+        let mut selector = Selector::create().unwrap();
+        let fired = Rc::new(RefCell::new(Vec::new()));
+        let mut ids = Vec::new();
+        for &(delay, value) in &[(0, 1), (1000, 3), (0, 2)] {
+            let fired = fired.clone();
+            let id = selector.set_timer(Duration::from_millis(delay), move |_: &mut Selector| {
+                fired.borrow_mut().push(value)
+            });
+            ids.push(id);
+        }
+        assert_eq!(
+            Some(Duration::from_millis(0)),
+            selector.next_timer_timeout()
+        );
+
+        assert_eq!(2, selector.run_expired_timers());
+        assert_eq!(vec![1, 2], *fired.borrow());
+
+        // already fired, no effect
+        selector.cancel_timer(ids[0]);
+        assert!(selector.next_timer_timeout().unwrap() > Duration::from_millis(0));
+    }
+
+    #[test]
+    fn fire_timer_when_clock_advances() {
+        let clock = Rc::new(MockClock::new());
+        let mut selector = Selector::with_clock(clock.clone()).unwrap();
+        let fired = Rc::new(Cell::new(false));
+        {
+            let fired = fired.clone();
+            selector.set_timer(Duration::from_secs(10), move |_: &mut Selector| {
+                fired.set(true)
+            });
+        }
+        assert_eq!(Some(Duration::from_secs(10)), selector.next_timer_timeout());
+
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(0, selector.run_expired_timers());
+        assert_eq!(Some(Duration::from_secs(1)), selector.next_timer_timeout());
+        assert!(!fired.get());
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(1, selector.run_expired_timers());
+        assert!(fired.get());
+        assert!(selector.next_timer_timeout().is_none());
+    }
+
+    #[test]
+    fn cancel_timer() {
+        let mut selector = Selector::create().unwrap();
+        let id = selector.set_timer(Duration::from_millis(0), |_: &mut Selector| {
+            panic!("Cancelled timer fired")
+        });
+        selector.cancel_timer(id);
+        assert!(selector.next_timer_timeout().is_none());
+        assert_eq!(0, selector.run_expired_timers());
+    }
+
+    #[test]
+    fn remove_handle_deregistered_by_timer() {
+        let mut selector = Selector::create().unwrap();
+        let registration = Rc::new(Registration::new2().0);
+        let handler = |_: &mut Selector, _| {};
+        let token = selector
+            .register(&*registration, handler, Ready::readable(), PollOpt::edge())
+            .unwrap();
+        selector.set_timer(Duration::from_millis(0), move |selector: &mut Selector| {
+            selector.deregister(&*registration, token).unwrap();
+        });
+
+        // removed without waiting for the next events
+        assert_eq!(1, selector.run_expired_timers());
+        assert!(selector.debug_dump().is_empty());
+    }
+}
@@ -0,0 +1,215 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use log::*;
+use mio::{Ready, SetReadiness};
+use std::io;
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread;
+
+use super::config::RelayConfig;
+use super::event_loop::EventLoop;
+use super::metrics::Metrics;
+use super::pause_switch::PauseSwitch;
+
+const TAG: &str = "ShardedRelay";
+
+type ConfigFactory = dyn Fn() -> RelayConfig + Send + Sync;
+
+/// A relay spreading its clients over several threads, each running its own event loop (a
+/// shard).
+///
+/// A shard owns the clients it is given and all their connections: the threads only share the
+/// metrics and the pause switch. The clients are accepted by the thread calling `run()`, and
+/// assigned to the shards according to their id, so round-robin.
+///
+/// The configuration is not `Send` (the inspector and the observer need not be thread-safe), so
+/// each shard builds its own by calling `config`. The control server is not supported.
+pub struct ShardedRelay {
+    shards: usize,
+    config: Arc<ConfigFactory>,
+    metrics: Arc<Metrics>,
+    pause_switch: Arc<PauseSwitch>,
+}
+
+// the acceptor side of a running shard
+struct Shard {
+    clients: Sender<(u32, TcpStream)>,
+    waker: SetReadiness,
+}
+
+impl ShardedRelay {
+    pub fn new<F>(shards: usize, config: F) -> Self
+    where
+        F: Fn() -> RelayConfig + Send + Sync + 'static,
+    {
+        assert!(shards > 0, "The number of shards must be positive");
+        Self {
+            shards,
+            config: Arc::new(config),
+            metrics: Arc::new(Metrics::new()),
+            pause_switch: Arc::new(PauseSwitch::new()),
+        }
+    }
+
+    /// The counters of the relay, shared by all the shards.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// The switch to pause and resume all the shards, which may be used from another thread.
+    pub fn pause_switch(&self) -> Arc<PauseSwitch> {
+        self.pause_switch.clone()
+    }
+
+    /// Start the shards, then accept the clients on the current thread until an error occurs.
+    pub fn run(&self) -> io::Result<()> {
+        let config = (self.config)();
+        if config.control_port().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The control server is not supported by a sharded relay",
+            ));
+        }
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, config.port()))?;
+        let shards = (0..self.shards)
+            .map(|index| self.start_shard(index))
+            .collect::<io::Result<Vec<_>>>()?;
+        info!(target: TAG, "Relay server started ({} shards)", shards.len());
+
+        let mut next_client_id = 0u32;
+        loop {
+            let (stream, _) = match listener.accept() {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    error!(target: TAG, "Cannot accept client: {}", err);
+                    continue;
+                }
+            };
+            let client_id = next_client_id;
+            next_client_id = next_client_id.wrapping_add(1);
+            let index = shard_index(client_id, shards.len());
+            debug!(target: TAG, "Client #{} assigned to shard {}", client_id, index);
+            shards[index].send(client_id, stream)?;
+        }
+    }
+
+    fn start_shard(&self, index: usize) -> io::Result<Shard> {
+        let (clients, receiver) = mpsc::channel();
+        let (ready_sender, ready_receiver) = mpsc::channel();
+        let config = self.config.clone();
+        let metrics = self.metrics.clone();
+        let pause_switch = self.pause_switch.clone();
+        thread::Builder::new()
+            .name(format!("shard-{}", index))
+            .spawn(move || {
+                let config = Rc::new(config());
+                match EventLoop::create_shard(config, metrics, pause_switch, receiver) {
+                    Ok((mut event_loop, waker)) => {
+                        // the acceptor is waiting for the waker
+                        let _ = ready_sender.send(Ok(waker));
+                        if let Err(err) = event_loop.run() {
+                            error!(target: TAG, "Shard {} stopped: {}", index, err);
+                        }
+                    }
+                    Err(err) => {
+                        let _ = ready_sender.send(Err(err));
+                    }
+                }
+            })?;
+        let waker = ready_receiver
+            .recv()
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Shard not started"))??;
+        Ok(Shard { clients, waker })
+    }
+}
+
+impl Shard {
+    fn send(&self, client_id: u32, stream: TcpStream) -> io::Result<()> {
+        self.clients
+            .send((client_id, stream))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Shard stopped"))?;
+        self.waker.set_readiness(Ready::readable())
+    }
+}
+
+fn shard_index(client_id: u32, shards: usize) -> usize {
+    client_id as usize % shards
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::config::RelayConfigBuilder;
+    use crate::relay::connection::ConnectionId;
+    use crate::relay::inspector::Verdict;
+    use crate::relay::tcp_connection::tests::{
+        connect_tunnel, create_tcp_packet, free_port, read_tcp_packet, CLIENT_SEQ,
+    };
+    use crate::relay::tcp_header::{FLAG_ACK, FLAG_SYN};
+    use std::io::Write;
+    use std::sync::Mutex;
+
+    #[test]
+    fn assign_round_robin() {
+        let indices = (0..6).map(|id| shard_index(id, 3)).collect::<Vec<_>>();
+        assert_eq!(vec![0, 1, 2, 0, 1, 2], indices);
+    }
+
+    #[test]
+    fn shards_handle_clients_independently() {
+        let relay_port = free_port();
+        // the threads on which the packets of the clients are inspected
+        let threads = Arc::new(Mutex::new(Vec::new()));
+        let threads2 = threads.clone();
+        thread::spawn(move || {
+            let relay = ShardedRelay::new(2, move || {
+                let threads = threads2.clone();
+                let inspector = move |_: &ConnectionId, _: &[u8]| {
+                    threads.lock().unwrap().push(thread::current().id());
+                    Verdict::Accept
+                };
+                RelayConfigBuilder::new(relay_port)
+                    .inspector(Rc::new(inspector))
+                    .build()
+            });
+            relay.run().unwrap();
+        });
+
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let mut tunnels = vec![connect_tunnel(relay_port), connect_tunnel(relay_port)];
+
+        // open a connection from both clients, the shards run concurrently
+        for tunnel in &mut tunnels {
+            let syn = create_tcp_packet(port, CLIENT_SEQ, 0, FLAG_SYN, 0xffff, &[]);
+            tunnel.write_all(&syn).unwrap();
+        }
+        for tunnel in &mut tunnels {
+            let (_, flags, _) = read_tcp_packet(tunnel);
+            assert_eq!(FLAG_SYN | FLAG_ACK, flags);
+        }
+
+        // each client has been handled by its own shard
+        let threads = threads.lock().unwrap();
+        assert_eq!(2, threads.len());
+        assert_ne!(threads[0], threads[1]);
+        assert!(!threads.contains(&thread::current().id()));
+    }
+}
@@ -73,6 +73,29 @@ impl StreamBuffer {
         }
     }
 
+    /// Same as `write_to()`, but write both parts of the circular buffer in a single call when
+    /// the data wrap around.
+    pub fn write_vectored_to<W: io::Write>(&mut self, destination: &mut W) -> io::Result<usize> {
+        if self.head == self.tail {
+            // buffer is empty, nothing to do
+            Ok(0)
+        } else {
+            let w = if self.head > self.tail {
+                destination.write(&self.buf[self.tail..self.head])?
+            } else {
+                // self.head < self.tail
+                let slices = [
+                    io::IoSlice::new(&self.buf[self.tail..]),
+                    io::IoSlice::new(&self.buf[..self.head]),
+                ];
+                destination.write_vectored(&slices)?
+            };
+            self.tail = (self.tail + w) % self.buf.len();
+            self.optimize();
+            Ok(w)
+        }
+    }
+
     pub fn read_from(&mut self, source: &[u8]) {
         assert!(
             source.len() <= self.remaining(),
@@ -175,6 +198,23 @@ mod tests {
         assert_eq!([0, 1, 2, 3, 4, 5, 0, 1, 2], &result[..]);
     }
 
+    #[test]
+    fn write_vectored_circular() {
+        let data = create_data();
+        let mut stream_buffer = StreamBuffer::new(9);
+
+        stream_buffer.read_from(&data);
+        read_some(&mut stream_buffer, 3);
+        stream_buffer.read_from(&data);
+        // consume 3 bytes (the "tail" position is 6, so the test data wrap around)
+        read_some(&mut stream_buffer, 3);
+
+        let mut cursor = io::Cursor::new(Vec::new());
+        stream_buffer.write_vectored_to(&mut cursor).unwrap();
+        assert_eq!(cursor.get_ref(), &data);
+        assert!(stream_buffer.is_empty());
+    }
+
     fn read_some(stream_buffer: &mut StreamBuffer, bytes: usize) -> Vec<u8> {
         let mut vec = vec![0u8; bytes];
         {
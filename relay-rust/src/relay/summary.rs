@@ -0,0 +1,164 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use log::*;
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::config::RelayConfig;
+use super::connection::ConnectionId;
+use super::metrics::{Counter, Metrics};
+use super::selector::Selector;
+use super::tunnel_server::TunnelServer;
+
+const TAG: &str = "Summary";
+
+// the number of connections listed as top talkers
+const TOP_TALKERS: usize = 5;
+
+/// Periodic overview of the connections of a relay, for dashboards.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionSummary {
+    pub active_connections: usize,
+    /// The connections which relayed the most bytes (in both directions), the busiest first.
+    pub top_talkers: Vec<(ConnectionId, u64)>,
+    /// The packets dropped by the routers since the relay started.
+    pub dropped_packets: u64,
+}
+
+impl ConnectionSummary {
+    fn collect(tunnel_server: &TunnelServer, metrics: &Metrics) -> Self {
+        let connections = tunnel_server.connection_stats();
+        let active_connections = connections.len();
+        let mut talkers = connections
+            .into_iter()
+            .map(|(id, stats, _)| (id, stats.bytes_to_network + stats.bytes_to_client))
+            .collect::<Vec<_>>();
+        talkers.sort_by(|(_, a), (_, b)| b.cmp(a));
+        talkers.truncate(TOP_TALKERS);
+        Self {
+            active_connections,
+            top_talkers: talkers,
+            dropped_packets: metrics.get(Counter::DroppedPackets),
+        }
+    }
+}
+
+impl fmt::Display for ConnectionSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} active connections, {} packets dropped",
+            self.active_connections, self.dropped_packets
+        )?;
+        for (id, bytes) in &self.top_talkers {
+            write!(f, "\n  {} bytes: {}", bytes, id)?;
+        }
+        Ok(())
+    }
+}
+
+/// Emit a `ConnectionSummary` of the connections of `tunnel_server` every `interval` (to the
+/// log and to the `Observer`, if any), as long as the selector runs.
+pub fn schedule(
+    selector: &mut Selector,
+    interval: Duration,
+    tunnel_server: Rc<RefCell<TunnelServer>>,
+    config: Rc<RelayConfig>,
+    metrics: Arc<Metrics>,
+) {
+    let handler = move |selector: &mut Selector| {
+        let summary = ConnectionSummary::collect(&tunnel_server.borrow(), &metrics);
+        info!(target: TAG, "{}", summary);
+        if let Some(observer) = config.observer() {
+            observer.on_summary(&summary);
+        }
+        schedule(
+            selector,
+            interval,
+            tunnel_server.clone(),
+            config.clone(),
+            metrics.clone(),
+        );
+    };
+    selector.set_timer(interval, handler);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::observer::Observer;
+    use crate::relay::tcp_connection::tests::{connect_tunnel, handshake, start_relay_with};
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::sync::mpsc;
+
+    struct SummarySender(mpsc::Sender<ConnectionSummary>);
+
+    impl Observer for SummarySender {
+        fn on_summary(&self, summary: &ConnectionSummary) {
+            self.0.send(summary.clone()).unwrap();
+        }
+    }
+
+    #[test]
+    fn emit_summary_periodically() {
+        let (sender, receiver) = mpsc::channel();
+        let relay_port = start_relay_with(move |builder| {
+            builder
+                .observer(Rc::new(SummarySender(sender)))
+                .summary_interval(Duration::from_millis(50))
+        });
+        let next_summary = || receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        let summary = next_summary();
+        assert_eq!(0, summary.active_connections);
+        assert!(summary.top_talkers.is_empty());
+
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let mut tunnel = connect_tunnel(relay_port);
+        handshake(&mut tunnel, port, 0xffff);
+        let _upstream = server.accept().unwrap();
+
+        // the summaries emitted before the connection may still be queued
+        let summary = loop {
+            let summary = next_summary();
+            if summary.active_connections > 0 {
+                break summary;
+            }
+        };
+        assert_eq!(1, summary.active_connections);
+        assert_eq!(1, summary.top_talkers.len());
+        assert_eq!(port, summary.top_talkers[0].0.destination().port());
+        assert_eq!(0, summary.dropped_packets);
+    }
+
+    #[test]
+    fn display_summary() {
+        let summary = ConnectionSummary {
+            active_connections: 3,
+            top_talkers: Vec::new(),
+            dropped_packets: 7,
+        };
+        assert_eq!(
+            "3 active connections, 7 packets dropped",
+            summary.to_string()
+        );
+    }
+}
@@ -14,34 +14,62 @@
  * limitations under the License.
  */
 
+#[cfg(unix)]
+use byteorder::{BigEndian, WriteBytesExt};
 use log::*;
 use mio::net::TcpStream;
 use mio::{Event, PollOpt, Ready, Token};
 use rand::random;
-use std::cell::RefCell;
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
 use std::cmp;
 use std::io;
+#[cfg(unix)]
+use std::net::SocketAddrV4;
+use std::net::{Ipv4Addr, Shutdown};
 use std::num::Wrapping;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::rc::{Rc, Weak};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use super::binary;
 use super::client::{Client, ClientChannel};
-use super::connection::{Connection, ConnectionId};
+use super::config::RelayConfig;
+use super::congestion_window::CongestionWindow;
+use super::connection::{Connection, ConnectionId, ConnectionStats};
 use super::ipv4_header::Ipv4Header;
 use super::ipv4_packet::{Ipv4Packet, MAX_PACKET_LENGTH};
+use super::metrics::{Counter, Metrics};
+use super::nat;
+use super::net;
+use super::observer::CloseReason;
 use super::packet_source::PacketSource;
 use super::packetizer::Packetizer;
-use super::selector::Selector;
+use super::retry_backoff::RetryBackoff;
+use super::rtt_estimator::RttEstimator;
+use super::selector::{Selector, TimerId};
 use super::stream_buffer::StreamBuffer;
 use super::tcp_header::{self, TcpHeader, TcpHeaderMut};
+use super::token_bucket::TokenBucket;
 use super::transport_header::{TransportHeader, TransportHeaderMut};
+use super::unacked_queue::{seq_le, UnackedQueue};
 
 const TAG: &str = "TcpConnection";
 
 // same value as GnirehtetService.MTU in the client
 const MTU: u16 = 0x4000;
-// 20 bytes for IP headers, 20 bytes for TCP headers
-const MAX_PAYLOAD_LENGTH: u16 = MTU - 20 - 20 as u16;
+// 20 bytes for IP headers, 20 bytes for TCP headers (without options)
+pub const MAX_PAYLOAD_LENGTH: u16 = MTU - 20 - 20 as u16;
+
+// the buffer of the data received from the client, announced as the receive window of the relay
+const CLIENT_TO_NETWORK_BUFFER_SIZE: usize = 4 * MAX_PACKET_LENGTH;
+// the window scale announced by the relay, so that its window may cover the whole buffer
+const RELAY_WINDOW_SCALE: u8 = 3;
+
+// by default, a connection not established after this delay is reset
+pub const HANDSHAKE_TIMEOUT_SECONDS: u64 = 30;
 
 pub struct TcpConnection {
     self_weak: Weak<RefCell<TcpConnection>>,
@@ -53,24 +81,97 @@ pub struct TcpConnection {
     client_to_network: StreamBuffer,
     network_to_client: Packetizer,
     packet_for_client_length: Option<u16>,
-    closed: bool,
+    close_reason: Option<CloseReason>,
     tcb: Tcb,
+    stats: ConnectionStats,
+    throttle: Option<TokenBucket>,
+    throttle_timer: Option<TimerId>,
+    ack_timer: Option<TimerId>,
+    // closes the connection at its maximum lifetime, if limited
+    lifetime_timer: Option<TimerId>,
+    // resets the connection if it is not established in time
+    handshake_timer: Option<TimerId>,
+    // the number of half-open connections of the client (shared with the router), and whether
+    // this connection is counted in it
+    half_open: Rc<Cell<usize>>,
+    counted_half_open: bool,
+    connect_retry: Option<ConnectRetry>,
+    // how long to linger in TimeWait after a graceful close, if enabled
+    time_wait: Option<Duration>,
+    // set while lingering in TimeWait, along with the reason to report once it expires
+    time_wait_timer: Option<(TimerId, CloseReason)>,
+    // the MSS announced in the SYN-ACK
+    mss: u16,
+    // the last time a packet has been relayed in either direction
+    last_activity: Instant,
+}
+
+// the state needed to retry a failed upstream connection
+struct ConnectRetry {
+    backoff: RetryBackoff,
+    config: Rc<RelayConfig>,
+    metrics: Arc<Metrics>,
+    // set while waiting before the next attempt (the stream is deregistered meanwhile)
+    timer: Option<TimerId>,
 }
 
 // Transport Control Block
 struct Tcb {
     state: TcpState,
     syn_sequence_number: u32,
+    // the sequence number of our SYN-ACK, to retransmit it on a duplicate SYN
+    syn_ack_sequence_number: u32,
     sequence_number: Wrapping<u32>,
     acknowledgement_number: Wrapping<u32>,
     their_acknowledgement_number: u32,
     fin_sequence_number: Option<u32>,
     fin_received: bool,
-    client_window: u16,
+    // in bytes, already scaled
+    client_window: u32,
+    // negotiated on the SYN if the client sent the window scale option (RFC 7323)
+    window_scale: Option<WindowScale>,
+    unacked: UnackedQueue,
+    congestion_window: CongestionWindow,
+    rtt: RttEstimator,
+    // the latest TSval received from the client, if the timestamps are enabled (RFC 7323)
+    ts_recent: Option<u32>,
+    // the origin of our own timestamps
+    ts_origin: Instant,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct WindowScale {
+    client: u8,
+    relay: u8,
+}
+
+/// The bookkeeping of a TCP connection, to hand it off (along with its socket) to another relay
+/// process.
+///
+/// The data in flight and the buffered data are not part of it: the connection should be handed
+/// off once its buffers are drained.
+#[cfg(unix)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TcpConnectionState {
+    source: SocketAddrV4,
+    destination: SocketAddrV4,
+    state: TcpState,
+    syn_sequence_number: u32,
+    syn_ack_sequence_number: u32,
+    sequence_number: u32,
+    acknowledgement_number: u32,
+    their_acknowledgement_number: u32,
+    fin_sequence_number: Option<u32>,
+    fin_received: bool,
+    client_window: u32,
+    window_scale: Option<WindowScale>,
+    ts_recent: Option<u32>,
+    // the current value of our own timestamps, so that they keep increasing
+    timestamp: u32,
 }
 
 // See RFC793: <https://tools.ietf.org/html/rfc793#page-23>
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 enum TcpState {
     Init,
     SynSent,
@@ -81,6 +182,7 @@ enum TcpState {
     Closing,
     FinWait1,
     FinWait2,
+    TimeWait,
 }
 
 impl TcpState {
@@ -93,35 +195,84 @@ impl TcpState {
             || self == &TcpState::FinWait2
             || self == &TcpState::Closing
             || self == &TcpState::LastAck
+            || self == &TcpState::TimeWait
     }
 }
 
 impl Tcb {
-    fn new() -> Self {
+    fn new(now: Instant) -> Self {
         Self {
             state: TcpState::Init,
             syn_sequence_number: 0,
+            syn_ack_sequence_number: 0,
             sequence_number: Wrapping(0),
             acknowledgement_number: Wrapping(0),
             their_acknowledgement_number: 0,
             fin_sequence_number: None,
             fin_received: false,
             client_window: 0,
+            window_scale: None,
+            unacked: UnackedQueue::new(),
+            congestion_window: CongestionWindow::new(u32::from(MAX_PAYLOAD_LENGTH)),
+            rtt: RttEstimator::new(),
+            ts_recent: None,
+            ts_origin: now,
         }
     }
 
-    fn remaining_client_window(&self) -> u16 {
+    // the TSval of the packets sent to the client, in milliseconds
+    fn timestamp(&self, now: Instant) -> u32 {
+        now.saturating_duration_since(self.ts_origin).as_millis() as u32
+    }
+
+    fn max_payload_length(&self) -> u16 {
+        if self.ts_recent.is_some() {
+            MAX_PAYLOAD_LENGTH - u16::from(tcp_header::TIMESTAMP_OPTIONS_LENGTH)
+        } else {
+            MAX_PAYLOAD_LENGTH
+        }
+    }
+
+    // the window advertised by the client in a segment other than a SYN, in bytes
+    fn scaled_client_window(&self, window: u16) -> u32 {
+        let shift = self
+            .window_scale
+            .map_or(0, |window_scale| window_scale.client);
+        u32::from(window) << shift
+    }
+
+    // the window field of the segments sent to the client, if the window scaling is enabled
+    fn relay_window(&self, syn: bool) -> Option<u16> {
+        self.window_scale.map(|window_scale| {
+            // the window of a SYN-ACK is never scaled
+            let shift = if syn { 0 } else { window_scale.relay };
+            let window = CLIENT_TO_NETWORK_BUFFER_SIZE >> shift;
+            cmp::min(window, usize::from(u16::MAX)) as u16
+        })
+    }
+
+    fn remaining_client_window(&self) -> u32 {
         let wrapped_remaining = Wrapping(self.their_acknowledgement_number)
-            + Wrapping(u32::from(self.client_window))
+            + Wrapping(self.client_window)
             - self.sequence_number;
         let remaining = wrapped_remaining.0;
-        if remaining <= u32::from(self.client_window) {
-            remaining as u16
+        if remaining <= self.client_window {
+            remaining
         } else {
             0
         }
     }
 
+    // the number of bytes which may be sent to the client, limited both by its receive window and
+    // by the congestion window
+    fn remaining_window(&self) -> u16 {
+        let congestion_window = self.congestion_window.size();
+        let bytes_in_flight = self.unacked.bytes_in_flight();
+        let remaining_congestion_window = congestion_window.saturating_sub(bytes_in_flight);
+        let remaining = cmp::min(self.remaining_client_window(), remaining_congestion_window);
+        cmp::min(remaining, u32::from(u16::MAX)) as u16
+    }
+
     fn numbers(&self) -> String {
         format!(
             "(seq={}, ack={})",
@@ -132,38 +283,102 @@ impl Tcb {
 
 impl TcpConnection {
     #[allow(clippy::needless_pass_by_value)] // semantically, headers are consumed
+    #[allow(clippy::too_many_arguments)]
     pub fn create(
         selector: &mut Selector,
         id: ConnectionId,
         client: Weak<RefCell<Client>>,
+        client_address: Option<Ipv4Addr>,
         ipv4_header: Ipv4Header,
         transport_header: TransportHeader,
+        config: &Rc<RelayConfig>,
+        metrics: Arc<Metrics>,
+        half_open: Rc<Cell<usize>>,
     ) -> io::Result<Rc<RefCell<Self>>> {
         cx_info!(target: TAG, id, "Open");
-        let stream = Self::create_stream(&id)?;
+        let source = nat::upstream_source(&id, config);
+        let stream = net::connect_upstream_tcp(source, id.rewritten_destination(), config)?;
+        // interests will be set on the first packet received
+        // set the initial value now so that they won't need to be updated
+        let interests = Ready::writable();
+        let rc = Self::build(
+            selector,
+            id,
+            client,
+            client_address,
+            ipv4_header,
+            transport_header,
+            config,
+            stream,
+            Tcb::new(selector.now()),
+            interests,
+            half_open,
+        )?;
+        if let Some((retries, initial_backoff)) = config.connect_retries() {
+            rc.borrow_mut().connect_retry = Some(ConnectRetry {
+                backoff: RetryBackoff::new(retries, initial_backoff),
+                config: config.clone(),
+                metrics,
+                timer: None,
+            });
+        }
+        Ok(rc)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn build(
+        selector: &mut Selector,
+        id: ConnectionId,
+        client: Weak<RefCell<Client>>,
+        client_address: Option<Ipv4Addr>,
+        ipv4_header: Ipv4Header,
+        transport_header: TransportHeader,
+        config: &RelayConfig,
+        stream: TcpStream,
+        tcb: Tcb,
+        interests: Ready,
+        half_open: Rc<Cell<usize>>,
+    ) -> io::Result<Rc<RefCell<Self>>> {
+        let throttle = config
+            .rate_limit(*id.destination().ip())
+            .map(|rate| TokenBucket::new(rate, selector.now()));
 
         let tcp_header = Self::tcp_header_of_transport(transport_header);
 
-        // shrink the TCP options to pass a minimal refrence header to the packetizer
-        let mut shrinked_tcp_header_raw = [0u8; 20];
-        shrinked_tcp_header_raw.copy_from_slice(&tcp_header.raw()[..20]);
+        // shrink the TCP options to pass a minimal refrence header to the packetizer, only keeping
+        // room for the timestamps if the client uses them
+        let timestamp = tcp_header.timestamp();
+        let header_length = if timestamp.is_some() {
+            20 + tcp_header::TIMESTAMP_OPTIONS_LENGTH as usize
+        } else {
+            20
+        };
+        let mut shrinked_tcp_header_raw = [0u8; 20 + tcp_header::TIMESTAMP_OPTIONS_LENGTH as usize];
+        let shrinked_tcp_header_raw = &mut shrinked_tcp_header_raw[..header_length];
+        shrinked_tcp_header_raw[..20].copy_from_slice(&tcp_header.raw()[..20]);
         let mut shrinked_tcp_header_data = tcp_header.data().clone();
         {
             let mut shrinked_tcp_header =
-                shrinked_tcp_header_data.bind_mut(&mut shrinked_tcp_header_raw);
+                shrinked_tcp_header_data.bind_mut(shrinked_tcp_header_raw);
             shrinked_tcp_header.shrink_options();
-            assert_eq!(20, shrinked_tcp_header.header_length());
+            if let Some((tsval, _)) = timestamp {
+                shrinked_tcp_header.set_timestamp_options(0, tsval);
+            }
+            assert_eq!(header_length, shrinked_tcp_header.header_length() as usize);
         }
 
         let shrinked_transport_header = shrinked_tcp_header_data
-            .bind(&shrinked_tcp_header_raw)
+            .bind(shrinked_tcp_header_raw)
             .into();
 
-        let packetizer = Packetizer::new(&ipv4_header, &shrinked_transport_header);
+        let mut packetizer = Packetizer::new(&ipv4_header, &shrinked_transport_header);
+        if let Some(client_address) = client_address {
+            // address the packets to the client as it announced itself
+            packetizer
+                .ipv4_header_mut()
+                .set_destination(u32::from(client_address));
+        }
 
-        // interests will be set on the first packet received
-        // set the initial value now so that they won't need to be updated
-        let interests = Ready::writable();
         let rc = Rc::new(RefCell::new(Self {
             self_weak: Weak::new(),
             id,
@@ -171,11 +386,24 @@ impl TcpConnection {
             stream,
             interests,
             token: Token(0), // default value, will be set afterwards
-            client_to_network: StreamBuffer::new(4 * MAX_PACKET_LENGTH),
+            client_to_network: StreamBuffer::new(CLIENT_TO_NETWORK_BUFFER_SIZE),
             network_to_client: packetizer,
             packet_for_client_length: None,
-            closed: false,
-            tcb: Tcb::new(),
+            close_reason: None,
+            tcb,
+            stats: ConnectionStats::default(),
+            throttle,
+            throttle_timer: None,
+            ack_timer: None,
+            lifetime_timer: None,
+            handshake_timer: None,
+            half_open,
+            counted_half_open: false,
+            connect_retry: None,
+            time_wait: config.tcp_time_wait(),
+            time_wait_timer: None,
+            mss: config.tcp_mss(),
+            last_activity: selector.now(),
         }));
 
         {
@@ -191,14 +419,15 @@ impl TcpConnection {
             let token =
                 selector.register(&self_ref.stream, handler, interests, PollOpt::level())?;
             self_ref.token = token;
+
+            if !self_ref.tcb.state.is_connected() {
+                self_ref.start_handshake_timer(selector, config.tcp_handshake_timeout());
+            }
+            self_ref.update_half_open();
         }
         Ok(rc)
     }
 
-    fn create_stream(id: &ConnectionId) -> io::Result<TcpStream> {
-        TcpStream::connect(&id.rewritten_destination().into())
-    }
-
     fn remove_from_router(&self) {
         // route is embedded in router which is embedded in client: the client necessarily exists
         let client_rc = self.client.upgrade().expect("Expected client not found");
@@ -218,29 +447,50 @@ impl TcpConnection {
     }
     // return Err(err) with err.kind() == io::ErrorKind::WouldBlock on spurious event
     fn process(&mut self, selector: &mut Selector, event: Event) -> io::Result<()> {
-        if !self.closed {
+        if self.close_reason.is_none() {
             let ready = event.readiness();
-            if ready.is_readable() || ready.is_writable() {
+            if self.tcb.state == TcpState::SynSent {
+                // the non-blocking connection completed (writable on success, error or hup on
+                // failure)
+                self.process_connect(selector);
+                if self.close_reason.is_none() {
+                    self.update_interests(selector);
+                }
+            } else if ready.is_readable() || ready.is_writable() {
                 if ready.is_writable() {
-                    if self.tcb.state == TcpState::SynSent {
-                        // writable is first triggered when the stream is connected
-                        self.process_connect(selector);
-                    } else {
-                        self.process_send(selector)?;
-                    }
+                    self.process_send(selector)?;
                 }
-                if !self.closed && ready.is_readable() {
+                if self.close_reason.is_none() && ready.is_readable() {
                     self.process_receive(selector)?;
                 }
-                if !self.closed {
+                if self.close_reason.is_none() {
                     self.update_interests(selector);
                 }
+            } else if self.tcb.state == TcpState::CloseWait {
+                cx_debug!(target: TAG, self.id, "received ready = {:?}", ready);
+                // both sides are shut down, but the data received from the network are still
+                // readable: drain them once the client window allows it, without being woken up
+                // again until the interests change
+                selector
+                    .reregister(
+                        &self.stream,
+                        self.token,
+                        self.interests,
+                        PollOpt::level() | PollOpt::oneshot(),
+                    )
+                    .expect("Cannot register on poll");
             } else {
                 cx_debug!(target: TAG, self.id, "received ready = {:?}", ready);
                 // error or hup
-                self.close(selector);
+                let reason = match self.tcb.state {
+                    // both sides are shut down, only the last ACK from the client is missing
+                    TcpState::LastAck => CloseReason::ClientFin,
+                    TcpState::Closing => CloseReason::UpstreamFin,
+                    _ => CloseReason::UpstreamRst,
+                };
+                self.close(selector, reason);
             }
-            if self.closed {
+            if self.close_reason.is_some() {
                 // on_ready is not called from the router, so the connection must remove itself
                 self.remove_from_router();
             }
@@ -274,7 +524,7 @@ impl TcpConnection {
                         self.send_empty_packet_to_client(selector, tcp_header::FLAG_ACK);
                     }
                 } else {
-                    self.close(selector);
+                    self.close(selector, CloseReason::UpstreamRst);
                 }
             }
             Err(err) => {
@@ -290,7 +540,7 @@ impl TcpConnection {
                     err
                 );
                 self.send_empty_packet_to_client(selector, tcp_header::FLAG_RST);
-                self.close(selector);
+                self.close(selector, CloseReason::UpstreamRst);
             }
         }
         Ok(())
@@ -302,26 +552,39 @@ impl TcpConnection {
             self.packet_for_client_length.is_none(),
             "A pending packet was not sent"
         );
-        let remaining_client_window = self.tcb.remaining_client_window();
+        let remaining_window = self.tcb.remaining_window();
         assert!(
-            remaining_client_window > 0,
+            remaining_window > 0,
             "process_received() must not be called when window == 0"
         );
-        let max_payload_length =
-            Some(cmp::min(remaining_client_window, MAX_PAYLOAD_LENGTH) as usize);
+        let mut max_payload_length =
+            cmp::min(remaining_window, self.tcb.max_payload_length()) as usize;
+        let now = selector.now();
+        if let Some(ref mut throttle) = self.throttle {
+            max_payload_length = cmp::min(max_payload_length, throttle.available(now));
+        }
+        if max_payload_length == 0 {
+            // the rate limit is reached, reading 0 byte would be interpreted as EOF
+            self.start_throttle_timer(selector);
+            return Ok(());
+        }
         Self::update_headers(
             &mut self.network_to_client,
             &self.tcb,
             tcp_header::FLAG_ACK | tcp_header::FLAG_PSH,
+            now,
         );
         match self
             .network_to_client
-            .packetize_read(&mut self.stream, max_payload_length)
+            .packetize_read(&mut self.stream, Some(max_payload_length))
         {
             Ok(Some(ipv4_packet)) => {
+                let len = ipv4_packet.payload().unwrap().len();
+                if let Some(ref mut throttle) = self.throttle {
+                    throttle.consume(len, now);
+                }
                 match Self::send_to_client(&self.client, selector, &ipv4_packet) {
                     Ok(_) => {
-                        let len = ipv4_packet.payload().unwrap().len();
                         cx_debug!(
                             target: TAG,
                             self.id,
@@ -329,7 +592,13 @@ impl TcpConnection {
                             len,
                             self.tcb.numbers()
                         );
+                        self.tcb
+                            .unacked
+                            .push(self.tcb.sequence_number.0, len as u32, now);
+                        self.stats.count_to_client(len);
+                        self.last_activity = now;
                         self.tcb.sequence_number += Wrapping(len as u32);
+                        self.start_ack_timer(selector);
                     }
                     Err(_) => {
                         // ask to the client to pull when its buffer is not full
@@ -340,6 +609,7 @@ impl TcpConnection {
                         self.packet_for_client_length = Some(ipv4_packet.length());
                     }
                 };
+                self.start_throttle_timer(selector);
             }
             Ok(None) => {
                 self.eof(selector);
@@ -357,20 +627,267 @@ impl TcpConnection {
                     err
                 );
                 self.send_empty_packet_to_client(selector, tcp_header::FLAG_RST);
-                self.close(selector);
+                self.close(selector, CloseReason::UpstreamRst);
             }
         }
         Ok(())
     }
 
+    fn start_throttle_timer(&mut self, selector: &mut Selector) {
+        if self.throttle_timer.is_some() {
+            return;
+        }
+        let delay = match self.throttle {
+            Some(ref mut throttle) => throttle.delay(selector.now()),
+            None => None,
+        };
+        if let Some(delay) = delay {
+            cx_debug!(target: TAG, self.id, "Throttled for {:?}", delay);
+            let weak = self.self_weak.clone();
+            let handler = move |selector: &mut Selector| {
+                if let Some(rc) = weak.upgrade() {
+                    rc.borrow_mut().on_throttle_timeout(selector);
+                }
+            };
+            self.throttle_timer = Some(selector.set_timer(delay, handler));
+        }
+    }
+
+    // the relay does not retransmit, but data not acknowledged in time means that the client is
+    // overwhelmed
+    fn start_ack_timer(&mut self, selector: &mut Selector) {
+        if self.ack_timer.is_some() || self.tcb.unacked.is_empty() {
+            return;
+        }
+        let weak = self.self_weak.clone();
+        let handler = move |selector: &mut Selector| {
+            if let Some(rc) = weak.upgrade() {
+                rc.borrow_mut().on_ack_timeout(selector);
+            }
+        };
+        self.ack_timer = Some(selector.set_timer(self.tcb.rtt.timeout(), handler));
+    }
+
+    fn restart_ack_timer(&mut self, selector: &mut Selector) {
+        if let Some(timer) = self.ack_timer.take() {
+            selector.cancel_timer(timer);
+        }
+        self.start_ack_timer(selector);
+    }
+
+    fn on_ack_timeout(&mut self, selector: &mut Selector) {
+        self.ack_timer = None;
+        if self.close_reason.is_some() || self.tcb.unacked.is_empty() {
+            return;
+        }
+        self.tcb.congestion_window.on_timeout();
+        self.tcb.unacked.time_out();
+        cx_debug!(
+            target: TAG,
+            self.id,
+            "ACK timeout, congestion window shrinked to {} bytes",
+            self.tcb.congestion_window.size()
+        );
+        self.start_ack_timer(selector);
+    }
+
+    // close once the last ACK has been exchanged, possibly lingering in TimeWait to absorb the late
+    // segments of the client (which would otherwise open a new connection)
+    fn close_gracefully(&mut self, selector: &mut Selector, reason: CloseReason) {
+        let delay = match self.time_wait {
+            Some(delay) => delay,
+            None => {
+                self.close(selector, reason);
+                return;
+            }
+        };
+        if let Some(timer) = self.throttle_timer.take() {
+            selector.cancel_timer(timer);
+        }
+        if let Some(timer) = self.ack_timer.take() {
+            selector.cancel_timer(timer);
+        }
+        if let Err(err) = selector.deregister(&self.stream, self.token) {
+            cx_warn!(
+                target: TAG,
+                self.id,
+                "Fail to deregister TCP stream: {:?}",
+                err
+            );
+        }
+        self.set_state(TcpState::TimeWait);
+        cx_debug!(target: TAG, self.id, "State = {:?}", self.tcb.state);
+        let weak = self.self_weak.clone();
+        let handler = move |selector: &mut Selector| {
+            if let Some(rc) = weak.upgrade() {
+                rc.borrow_mut().on_time_wait_timeout(selector);
+            }
+        };
+        self.time_wait_timer = Some((selector.set_timer(delay, handler), reason));
+    }
+
+    fn start_handshake_timer(&mut self, selector: &mut Selector, timeout: Duration) {
+        let weak = self.self_weak.clone();
+        let handler = move |selector: &mut Selector| {
+            if let Some(rc) = weak.upgrade() {
+                rc.borrow_mut().on_handshake_timeout(selector);
+            }
+        };
+        self.handshake_timer = Some(selector.set_timer(timeout, handler));
+    }
+
+    fn cancel_handshake_timer(&mut self, selector: &mut Selector) {
+        if let Some(timer) = self.handshake_timer.take() {
+            selector.cancel_timer(timer);
+        }
+    }
+
+    fn on_handshake_timeout(&mut self, selector: &mut Selector) {
+        self.handshake_timer = None;
+        if self.close_reason.is_some() || self.tcb.state.is_connected() {
+            return;
+        }
+        cx_debug!(target: TAG, self.id, "Handshake timeout in {:?}", self.tcb.state);
+        self.send_empty_packet_to_client(selector, tcp_header::FLAG_RST | tcp_header::FLAG_ACK);
+        self.close(selector, CloseReason::Timeout);
+        // called by the selector, so the connection must remove itself
+        self.remove_from_router();
+    }
+
+    fn set_state(&mut self, state: TcpState) {
+        self.tcb.state = state;
+        self.update_half_open();
+    }
+
+    // keep the count of half-open connections shared with the router up to date
+    fn update_half_open(&mut self) {
+        let half_open = self.close_reason.is_none() && self.is_half_open();
+        if half_open != self.counted_half_open {
+            let count = self.half_open.get();
+            self.half_open
+                .set(if half_open { count + 1 } else { count - 1 });
+            self.counted_half_open = half_open;
+        }
+    }
+
+    fn on_time_wait_timeout(&mut self, selector: &mut Selector) {
+        if let Some((_, reason)) = self.time_wait_timer.take() {
+            self.close(selector, reason);
+            // called by the selector, so the connection must remove itself
+            self.remove_from_router();
+        }
+    }
+
+    fn on_throttle_timeout(&mut self, selector: &mut Selector) {
+        self.throttle_timer = None;
+        if self.close_reason.is_none() {
+            self.update_interests(selector);
+        }
+    }
+
     fn process_connect(&mut self, selector: &mut Selector) {
         assert_eq!(self.tcb.state, TcpState::SynSent);
-        self.tcb.state = TcpState::SynReceived;
+        let error = match self.stream.take_error() {
+            Ok(error) => error,
+            Err(err) => Some(err),
+        };
+        if let Some(err) = error {
+            self.on_connect_error(selector, &err);
+            return;
+        }
+        // the connection is established, the retry state is not needed anymore
+        self.connect_retry = None;
+        self.set_state(TcpState::SynReceived);
         cx_debug!(target: TAG, self.id, "State = {:?}", self.tcb.state);
-        self.send_empty_packet_to_client(selector, tcp_header::FLAG_SYN | tcp_header::FLAG_ACK);
+        self.send_syn_ack_to_client(selector);
         self.tcb.sequence_number += Wrapping(1); // SYN counts for 1 byte
     }
 
+    fn on_connect_error(&mut self, selector: &mut Selector, err: &io::Error) {
+        let delay = self
+            .connect_retry
+            .as_mut()
+            .and_then(|connect_retry| connect_retry.backoff.next_delay());
+        if let Some(delay) = delay {
+            cx_warn!(
+                target: TAG,
+                self.id,
+                "Cannot connect: [{:?}] {}, retrying in {:?}",
+                err.kind(),
+                err,
+                delay
+            );
+            // the failed stream must not wake up the selector anymore
+            if let Err(err) = selector.deregister(&self.stream, self.token) {
+                cx_warn!(
+                    target: TAG,
+                    self.id,
+                    "Fail to deregister TCP stream: {:?}",
+                    err
+                );
+            }
+            let weak = self.self_weak.clone();
+            let handler = move |selector: &mut Selector| {
+                if let Some(rc) = weak.upgrade() {
+                    rc.borrow_mut().on_connect_retry(selector);
+                }
+            };
+            let connect_retry = self.connect_retry.as_mut().unwrap();
+            connect_retry
+                .metrics
+                .increment(Counter::UpstreamConnectRetries);
+            connect_retry.timer = Some(selector.set_timer(delay, handler));
+            return;
+        }
+        cx_error!(
+            target: TAG,
+            self.id,
+            "Cannot connect: [{:?}] {}",
+            err.kind(),
+            err
+        );
+        // the RST must acknowledge the SYN to be accepted by the client
+        self.send_empty_packet_to_client(selector, tcp_header::FLAG_RST | tcp_header::FLAG_ACK);
+        self.close(selector, CloseReason::UpstreamRst);
+    }
+
+    fn on_connect_retry(&mut self, selector: &mut Selector) {
+        let config = {
+            let connect_retry = self.connect_retry.as_mut().expect("No connect retry state");
+            connect_retry.timer = None;
+            connect_retry.config.clone()
+        };
+        if self.close_reason.is_some() {
+            return;
+        }
+        cx_debug!(target: TAG, self.id, "Retry connecting");
+        let source = nat::upstream_source(&self.id, &config);
+        let destination = self.id.rewritten_destination();
+        let result = net::connect_upstream_tcp(source, destination, &config).and_then(|stream| {
+            let rc = self
+                .self_weak
+                .upgrade()
+                .expect("Expected connection not found");
+            let handler =
+                move |selector: &mut Selector, event| rc.borrow_mut().on_ready(selector, event);
+            let token = selector.register(&stream, handler, self.interests, PollOpt::level())?;
+            Ok((stream, token))
+        });
+        match result {
+            Ok((stream, token)) => {
+                // the previous stream was already deregistered, it is closed by RAII
+                self.stream = stream;
+                self.token = token;
+            }
+            Err(err) => {
+                self.on_connect_error(selector, &err);
+                if self.close_reason.is_some() {
+                    self.remove_from_router();
+                }
+            }
+        }
+    }
+
     fn send_to_client(
         client: &Weak<RefCell<Client>>,
         selector: &mut Selector,
@@ -381,6 +898,47 @@ impl TcpConnection {
         client.send_to_client(selector, &ipv4_packet)
     }
 
+    fn send_syn_ack_to_client(&mut self, selector: &mut Selector) {
+        let client_rc = self.client.upgrade().expect("Expected client not found");
+        let mut client = client_rc.borrow_mut();
+        self.reply_syn_ack_to_client(selector, &mut client.channel())
+    }
+
+    fn reply_syn_ack_to_client(
+        &mut self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+    ) {
+        let flags = tcp_header::FLAG_SYN | tcp_header::FLAG_ACK;
+        // the packetizer has no room for the MSS and window scale options, which are only sent in
+        // the SYN-ACK
+        let mut raw = {
+            let ipv4_packet = Self::create_empty_response_packet(
+                &self.id,
+                &mut self.network_to_client,
+                &self.tcb,
+                flags,
+                selector.now(),
+            );
+            tcp_header::with_mss_option(&ipv4_packet, self.mss)
+        };
+        if let Some(window_scale) = self.tcb.window_scale {
+            raw = tcp_header::with_window_scale_option(
+                &Ipv4Packet::parse(&mut raw),
+                window_scale.relay,
+            );
+        }
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        if let Err(err) = client_channel.send_to_client(selector, &ipv4_packet) {
+            cx_warn!(
+                target: TAG,
+                self.id,
+                "Cannot send packet to client: {}",
+                err
+            );
+        }
+    }
+
     /// Borrow self.client and send empty packet to it
     ///
     /// To be used if called by on_ready() (so the client is not borrowed yet).
@@ -405,6 +963,7 @@ impl TcpConnection {
             &mut self.network_to_client,
             &self.tcb,
             flags,
+            selector.now(),
         );
         if let Err(err) = client_channel.send_to_client(selector, &ipv4_packet) {
             // losing such an empty packet will not break the TCP connection
@@ -421,11 +980,11 @@ impl TcpConnection {
         self.send_empty_packet_to_client(selector, tcp_header::FLAG_FIN | tcp_header::FLAG_ACK);
         self.tcb.fin_sequence_number = Some(self.tcb.sequence_number.0);
         self.tcb.sequence_number += Wrapping(1); // FIN counts for 1 byte
-        self.tcb.state = if self.tcb.state == TcpState::CloseWait {
+        self.set_state(if self.tcb.state == TcpState::CloseWait {
             TcpState::LastAck
         } else {
             TcpState::FinWait1
-        };
+        });
         cx_debug!(target: TAG, self.id, "State = {:?}", self.tcb.state);
     }
 
@@ -456,11 +1015,17 @@ impl TcpConnection {
         }
     }
 
-    fn update_headers(packetizer: &mut Packetizer, tcb: &Tcb, flags: u16) {
+    fn update_headers(packetizer: &mut Packetizer, tcb: &Tcb, flags: u16, now: Instant) {
         let mut tcp_header = Self::tcp_header_of_transport_mut(packetizer.transport_header_mut());
         tcp_header.set_sequence_number(tcb.sequence_number.0);
         tcp_header.set_acknowledgement_number(tcb.acknowledgement_number.0);
         tcp_header.set_flags(flags);
+        if let Some(window) = tcb.relay_window(flags & tcp_header::FLAG_SYN != 0) {
+            tcp_header.set_window(window);
+        }
+        if let Some(ts_recent) = tcb.ts_recent {
+            tcp_header.set_timestamp_options(tcb.timestamp(now), ts_recent);
+        }
     }
 
     fn handle_packet(
@@ -475,6 +1040,11 @@ impl TcpConnection {
             return;
         }
 
+        if self.tcb.state == TcpState::TimeWait {
+            self.handle_late_segment(selector, client_channel, ipv4_packet);
+            return;
+        }
+
         if tcp_header.is_syn() {
             self.handle_duplicate_syn(selector, client_channel, ipv4_packet);
             return;
@@ -483,8 +1053,20 @@ impl TcpConnection {
         let expected_packet =
             (self.tcb.acknowledgement_number + Wrapping(self.client_to_network.size() as u32)).0;
         if tcp_header.sequence_number() != expected_packet {
-            // ignore packet already received or out-of-order, retransmission is already
-            // managed by both sides
+            if Self::is_retransmission(ipv4_packet, expected_packet) {
+                // the client did not receive our ACK, send it again (but do not forward the data
+                // twice)
+                cx_debug!(
+                    target: TAG,
+                    self.id,
+                    "Re-acking retransmitted packet {}; expecting {}",
+                    tcp_header.sequence_number(),
+                    expected_packet
+                );
+                self.reply_empty_packet_to_client(selector, client_channel, tcp_header::FLAG_ACK);
+                return;
+            }
+            // ignore packet out-of-order, retransmission is already managed by both sides
             cx_warn!(
                 target: TAG,
                 self.id,
@@ -497,8 +1079,14 @@ impl TcpConnection {
             return;
         }
 
-        self.tcb.client_window = tcp_header.window();
+        self.tcb.client_window = self.tcb.scaled_client_window(tcp_header.window());
         self.tcb.their_acknowledgement_number = tcp_header.acknowledgement_number();
+        if self.tcb.ts_recent.is_some() {
+            if let Some((tsval, _)) = tcp_header.timestamp() {
+                // echo the latest timestamp of the client
+                self.tcb.ts_recent = Some(tsval);
+            }
+        }
 
         cx_debug!(
             target: TAG,
@@ -509,7 +1097,7 @@ impl TcpConnection {
         );
 
         if tcp_header.is_rst() {
-            self.close(selector);
+            self.close(selector, CloseReason::ClientRst);
             return;
         }
 
@@ -521,6 +1109,28 @@ impl TcpConnection {
                 tcp_header.acknowledgement_number()
             );
 
+            let bytes_in_flight = self.tcb.unacked.bytes_in_flight();
+            if let Some(sent) = self.tcb.unacked.ack(tcp_header.acknowledgement_number()) {
+                self.tcb
+                    .rtt
+                    .on_sample(selector.now().saturating_duration_since(sent));
+            }
+            for (left_edge, right_edge) in tcp_header.sack_blocks() {
+                self.tcb.unacked.sack(left_edge, right_edge);
+            }
+            let acked = bytes_in_flight - self.tcb.unacked.bytes_in_flight();
+            if acked > 0 {
+                self.tcb.congestion_window.on_ack(acked);
+                self.restart_ack_timer(selector);
+            }
+            cx_debug!(
+                target: TAG,
+                self.id,
+                "{} bytes in flight (congestion window: {} bytes)",
+                self.tcb.unacked.bytes_in_flight(),
+                self.tcb.congestion_window.size()
+            );
+
             self.handle_ack(selector, client_channel, ipv4_packet);
         }
 
@@ -536,6 +1146,17 @@ impl TcpConnection {
         }
     }
 
+    // indicate whether a segment from the client only contains data (or a FIN) already received
+    fn is_retransmission(ipv4_packet: &Ipv4Packet, expected_packet: u32) -> bool {
+        let tcp_header = Self::tcp_header_of_packet(ipv4_packet);
+        let mut length = ipv4_packet.payload().expect("No payload").len() as u32;
+        if tcp_header.is_fin() {
+            length += 1; // FIN counts for 1 byte
+        }
+        let end = tcp_header.sequence_number().wrapping_add(length);
+        length > 0 && seq_le(end, expected_packet)
+    }
+
     fn handle_first_packet(
         &mut self,
         selector: &mut Selector,
@@ -549,7 +1170,8 @@ impl TcpConnection {
             self.tcb.acknowledgement_number = Wrapping(their_sequence_number) + Wrapping(1);
             self.tcb.syn_sequence_number = their_sequence_number;
 
-            self.tcb.sequence_number = Wrapping(random::<u32>());
+            self.tcb.syn_ack_sequence_number = random::<u32>();
+            self.tcb.sequence_number = Wrapping(self.tcb.syn_ack_sequence_number);
             cx_debug!(
                 target: TAG,
                 self.id,
@@ -557,8 +1179,16 @@ impl TcpConnection {
                 self.tcb.sequence_number,
                 self.tcb.acknowledgement_number
             );
-            self.tcb.client_window = tcp_header.window();
-            self.tcb.state = TcpState::SynSent;
+            // the window of a SYN is never scaled
+            self.tcb.client_window = u32::from(tcp_header.window());
+            // the window scaling is enabled if the client sent the option in its SYN
+            self.tcb.window_scale = tcp_header.window_scale().map(|client| WindowScale {
+                client,
+                relay: RELAY_WINDOW_SCALE,
+            });
+            // the timestamps are enabled if the client sent them in its SYN
+            self.tcb.ts_recent = tcp_header.timestamp().map(|(tsval, _)| tsval);
+            self.set_state(TcpState::SynSent);
             cx_debug!(target: TAG, self.id, "State = {:?}", self.tcb.state);
         } else {
             cx_warn!(
@@ -572,7 +1202,7 @@ impl TcpConnection {
             // make a RST in the window client
             self.tcb.sequence_number = Wrapping(tcp_header.acknowledgement_number());
             self.reply_empty_packet_to_client(selector, client_channel, tcp_header::FLAG_RST);
-            self.close(selector);
+            self.close(selector, CloseReason::ClientRst);
         }
     }
 
@@ -592,7 +1222,75 @@ impl TcpConnection {
         } else if their_sequence_number != self.tcb.syn_sequence_number {
             // duplicate SYN with different sequence number
             self.reply_empty_packet_to_client(selector, client_channel, tcp_header::FLAG_RST);
-            self.close(selector);
+            self.close(selector, CloseReason::ClientRst);
+        } else if self.tcb.state == TcpState::SynReceived {
+            if tcp_header.is_ack()
+                && Wrapping(tcp_header.acknowledgement_number()) == self.tcb.sequence_number
+            {
+                // simultaneous open: the client answered our SYN-ACK by its own SYN-ACK (RFC 793
+                // section 3.4), which acknowledges our SYN
+                self.tcb.client_window = u32::from(tcp_header.window());
+                self.tcb.their_acknowledgement_number = tcp_header.acknowledgement_number();
+                self.set_state(TcpState::Established);
+                self.cancel_handshake_timer(selector);
+                cx_debug!(target: TAG, self.id, "State = {:?} (simultaneous open)", self.tcb.state);
+                self.reply_empty_packet_to_client(selector, client_channel, tcp_header::FLAG_ACK);
+            } else {
+                // the SYN-ACK has been lost, send it again
+                self.retransmit_syn_ack(selector, client_channel);
+            }
+        } else if self.tcb.state == TcpState::Established {
+            // a late copy of the SYN, which must not open another connection: the client
+            // acknowledges the SYN-ACK again if it is already established
+            self.retransmit_syn_ack(selector, client_channel);
+        }
+    }
+
+    fn retransmit_syn_ack(&mut self, selector: &mut Selector, client_channel: &mut ClientChannel) {
+        cx_debug!(target: TAG, self.id, "Retransmitting SYN-ACK");
+        let sequence_number = self.tcb.sequence_number;
+        let acknowledgement_number = self.tcb.acknowledgement_number;
+        self.tcb.sequence_number = Wrapping(self.tcb.syn_ack_sequence_number);
+        self.tcb.acknowledgement_number = Wrapping(self.tcb.syn_sequence_number) + Wrapping(1);
+        self.reply_syn_ack_to_client(selector, client_channel);
+        self.tcb.sequence_number = sequence_number;
+        self.tcb.acknowledgement_number = acknowledgement_number;
+    }
+
+    fn handle_late_segment(
+        &mut self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        ipv4_packet: &Ipv4Packet,
+    ) {
+        let tcp_header = Self::tcp_header_of_packet(ipv4_packet);
+        cx_debug!(
+            target: TAG,
+            self.id,
+            "Late segment {} in TimeWait; flags={}",
+            tcp_header.sequence_number(),
+            tcp_header.flags()
+        );
+        if tcp_header.is_rst() {
+            // ignore it, to protect the TimeWait state (RFC 1337)
+            return;
+        }
+        if tcp_header.is_syn() {
+            // the tuple is not reusable yet, refuse the new connection
+            let acknowledgement_number = self.tcb.acknowledgement_number;
+            self.tcb.acknowledgement_number = Wrapping(tcp_header.sequence_number()) + Wrapping(1);
+            self.reply_empty_packet_to_client(
+                selector,
+                client_channel,
+                tcp_header::FLAG_RST | tcp_header::FLAG_ACK,
+            );
+            self.tcb.acknowledgement_number = acknowledgement_number;
+            return;
+        }
+        let payload = ipv4_packet.payload().expect("No payload");
+        if tcp_header.is_fin() || !payload.is_empty() {
+            // our last ACK has been lost, send it again
+            self.reply_empty_packet_to_client(selector, client_channel, tcp_header::FLAG_ACK);
         }
     }
 
@@ -620,24 +1318,21 @@ impl TcpConnection {
         self.tcb.acknowledgement_number += Wrapping(1); // received FIN counts for 1 byte
 
         if self.tcb.state == TcpState::Established {
-            self.reply_empty_packet_to_client(
-                selector,
-                client_channel,
-                tcp_header::FLAG_FIN | tcp_header::FLAG_ACK,
-            );
-            self.tcb.fin_sequence_number = Some(self.tcb.sequence_number.0);
-            self.tcb.sequence_number += Wrapping(1); // FIN counts for 1 byte
-                                                     // the connection will be closed by RAII, so switch immediately to LastAck
-                                                     // (bypass CloseWait)
-            self.tcb.state = TcpState::LastAck;
+            self.reply_empty_packet_to_client(selector, client_channel, tcp_header::FLAG_ACK);
+            // the client will not send data anymore, but the data from the network must still be
+            // delivered to the client: the FIN will be sent on EOF, once they are drained
+            if let Err(err) = self.stream.shutdown(Shutdown::Write) {
+                cx_warn!(target: TAG, self.id, "Cannot shutdown: {}", err);
+            }
+            self.set_state(TcpState::CloseWait);
             cx_debug!(target: TAG, self.id, "State = {:?}", self.tcb.state);
         } else if self.tcb.state == TcpState::FinWait1 {
             self.reply_empty_packet_to_client(selector, client_channel, tcp_header::FLAG_ACK);
-            self.tcb.state = TcpState::Closing;
+            self.set_state(TcpState::Closing);
             cx_debug!(target: TAG, self.id, "State = {:?}", self.tcb.state);
         } else if self.tcb.state == TcpState::FinWait2 {
             self.reply_empty_packet_to_client(selector, client_channel, tcp_header::FLAG_ACK);
-            self.close(selector);
+            self.close_gracefully(selector, CloseReason::UpstreamFin);
         } else {
             cx_warn!(
                 target: TAG,
@@ -649,10 +1344,14 @@ impl TcpConnection {
     }
 
     fn handle_fin_ack(&mut self, selector: &mut Selector) {
-        if self.tcb.state == TcpState::LastAck || self.tcb.state == TcpState::Closing {
-            self.close(selector);
+        if self.tcb.state == TcpState::LastAck {
+            // the client sent its FIN first (in CloseWait)
+            self.close_gracefully(selector, CloseReason::ClientFin);
+        } else if self.tcb.state == TcpState::Closing {
+            // simultaneous close, but the upstream FIN was sent first (in FinWait1)
+            self.close_gracefully(selector, CloseReason::UpstreamFin);
         } else if self.tcb.state == TcpState::FinWait1 {
-            self.tcb.state = TcpState::FinWait2;
+            self.set_state(TcpState::FinWait2);
             cx_debug!(target: TAG, self.id, "State = {:?}", self.tcb.state);
         } else if self.tcb.state != TcpState::FinWait2 {
             cx_warn!(
@@ -666,13 +1365,14 @@ impl TcpConnection {
 
     fn handle_ack(
         &mut self,
-        _selector: &mut Selector,
+        selector: &mut Selector,
         _client_channel: &mut ClientChannel,
         ipv4_packet: &Ipv4Packet,
     ) {
         cx_debug!(target: TAG, self.id, "handle_ack()");
         if self.tcb.state == TcpState::SynReceived {
-            self.tcb.state = TcpState::Established;
+            self.set_state(TcpState::Established);
+            self.cancel_handshake_timer(selector);
             cx_debug!(target: TAG, self.id, "State = {:?}", self.tcb.state);
             return;
         }
@@ -706,8 +1406,9 @@ impl TcpConnection {
         packetizer: &'a mut Packetizer,
         tcb: &Tcb,
         flags: u16,
+        now: Instant,
     ) -> Ipv4Packet<'a> {
-        Self::update_headers(packetizer, tcb, flags);
+        Self::update_headers(packetizer, tcb, flags, now);
         cx_debug!(
             target: TAG,
             id,
@@ -731,7 +1432,11 @@ impl TcpConnection {
     }
 
     fn update_interests(&mut self, selector: &mut Selector) {
-        assert!(!self.closed);
+        assert!(self.close_reason.is_none());
+        if self.tcb.state == TcpState::TimeWait {
+            // the stream has already been deregistered
+            return;
+        }
         let mut ready = Ready::empty();
         if self.tcb.state == TcpState::SynSent {
             // waiting for connectable
@@ -762,7 +1467,11 @@ impl TcpConnection {
             // a packet is already pending
             return false;
         }
-        self.tcb.remaining_client_window() > 0
+        if self.throttle_timer.is_some() {
+            // the rate limit is reached
+            return false;
+        }
+        self.tcb.remaining_window() > 0
     }
 
     fn may_write(&self) -> bool {
@@ -770,6 +1479,154 @@ impl TcpConnection {
     }
 }
 
+// hand off the connections for live upgrades
+#[cfg(unix)]
+impl TcpConnection {
+    /// Rebuild a connection handed off by another relay process from its state and its socket.
+    pub fn restore(
+        selector: &mut Selector,
+        state: &TcpConnectionState,
+        stream: TcpStream,
+        client: Weak<RefCell<Client>>,
+        client_address: Option<Ipv4Addr>,
+        config: &RelayConfig,
+        half_open: Rc<Cell<usize>>,
+    ) -> io::Result<Rc<RefCell<Self>>> {
+        let mut raw =
+            Self::reference_packet(state.source, state.destination, state.ts_recent.is_some());
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        let id = ConnectionId::from_headers(
+            ipv4_packet.ipv4_header_data(),
+            ipv4_packet.transport_header_data().unwrap(),
+        );
+        cx_info!(target: TAG, id, "Restore {:?}", state.state);
+        let (ipv4_header, transport_header) = ipv4_packet.headers();
+
+        let mut tcb = Tcb::new(selector.now());
+        tcb.state = state.state;
+        tcb.syn_sequence_number = state.syn_sequence_number;
+        tcb.syn_ack_sequence_number = state.syn_ack_sequence_number;
+        tcb.sequence_number = Wrapping(state.sequence_number);
+        tcb.acknowledgement_number = Wrapping(state.acknowledgement_number);
+        tcb.their_acknowledgement_number = state.their_acknowledgement_number;
+        tcb.fin_sequence_number = state.fin_sequence_number;
+        tcb.fin_received = state.fin_received;
+        tcb.client_window = state.client_window;
+        tcb.window_scale = state.window_scale;
+        tcb.ts_recent = state.ts_recent;
+        let elapsed = Duration::from_millis(u64::from(state.timestamp));
+        if let Some(ts_origin) = selector.now().checked_sub(elapsed) {
+            tcb.ts_origin = ts_origin;
+        }
+
+        let rc = Self::build(
+            selector,
+            id,
+            client,
+            client_address,
+            ipv4_header,
+            transport_header.expect("No transport"),
+            config,
+            stream,
+            tcb,
+            Ready::writable(),
+            half_open,
+        )?;
+        {
+            let mut self_ref = rc.borrow_mut();
+            if self_ref.tcb.state.is_connected() {
+                self_ref.update_interests(selector);
+            }
+        }
+        Ok(rc)
+    }
+
+    /// The state of the connection at `now`, to hand it off to another relay process.
+    pub fn state(&self, now: Instant) -> TcpConnectionState {
+        TcpConnectionState {
+            source: self.id.source(),
+            destination: self.id.destination(),
+            state: self.tcb.state,
+            syn_sequence_number: self.tcb.syn_sequence_number,
+            syn_ack_sequence_number: self.tcb.syn_ack_sequence_number,
+            sequence_number: self.tcb.sequence_number.0,
+            acknowledgement_number: self.tcb.acknowledgement_number.0,
+            their_acknowledgement_number: self.tcb.their_acknowledgement_number,
+            fin_sequence_number: self.tcb.fin_sequence_number,
+            fin_received: self.tcb.fin_received,
+            client_window: self.tcb.client_window,
+            window_scale: self.tcb.window_scale,
+            ts_recent: self.tcb.ts_recent,
+            timestamp: self.tcb.timestamp(now),
+        }
+    }
+
+    // a packet from the client with the addresses of the connection, to initialize the headers of
+    // the packetizer of a restored connection
+    fn reference_packet(
+        source: SocketAddrV4,
+        destination: SocketAddrV4,
+        timestamps: bool,
+    ) -> Vec<u8> {
+        let options_length = if timestamps {
+            tcp_header::TIMESTAMP_OPTIONS_LENGTH
+        } else {
+            0
+        };
+        let tcp_header_length = 20 + options_length;
+        let mut raw = Vec::new();
+        raw.write_u8(4u8 << 4 | 5).unwrap();
+        raw.write_u8(0).unwrap(); // ToS
+        raw.write_u16::<BigEndian>(20 + u16::from(tcp_header_length))
+            .unwrap(); // total length
+        raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+        raw.write_u8(64).unwrap(); // TTL
+        raw.write_u8(6).unwrap(); // protocol (TCP)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(u32::from(*source.ip())).unwrap();
+        raw.write_u32::<BigEndian>(u32::from(*destination.ip()))
+            .unwrap();
+
+        raw.write_u16::<BigEndian>(source.port()).unwrap();
+        raw.write_u16::<BigEndian>(destination.port()).unwrap();
+        raw.write_u32::<BigEndian>(0).unwrap(); // sequence number
+        raw.write_u32::<BigEndian>(0).unwrap(); // acknowledgement number
+        raw.write_u8((tcp_header_length / 4) << 4).unwrap(); // data offset
+        raw.write_u8(0).unwrap(); // flags
+        raw.write_u16::<BigEndian>(0).unwrap(); // window
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u16::<BigEndian>(0).unwrap(); // urgent pointer
+        if timestamps {
+            // NOP, NOP, timestamps (kind, length, TSval, TSecr)
+            raw.extend_from_slice(&[1, 1, 8, 10, 0, 0, 0, 0, 0, 0, 0, 0]);
+        }
+        raw
+    }
+}
+
+// force the state of a connection, to test the transitions from any state without replaying the
+// whole exchange leading to it
+#[cfg(test)]
+impl TcpConnection {
+    /// Force the connection into `state`, with `sequence_number` as the next sequence number of
+    /// the relay (all the data sent before being acknowledged) and `acknowledgement_number` as the
+    /// next sequence number expected from the client.
+    ///
+    /// The interests are updated on the next packet received from the client.
+    fn debug_set_state(
+        &mut self,
+        state: TcpState,
+        sequence_number: u32,
+        acknowledgement_number: u32,
+    ) {
+        cx_debug!(target: TAG, self.id, "Force state = {:?}", state);
+        self.set_state(state);
+        self.tcb.sequence_number = Wrapping(sequence_number);
+        self.tcb.their_acknowledgement_number = sequence_number;
+        self.tcb.acknowledgement_number = Wrapping(acknowledgement_number);
+    }
+}
+
 impl Connection for TcpConnection {
     fn id(&self) -> &ConnectionId {
         &self.id
@@ -781,15 +1638,41 @@ impl Connection for TcpConnection {
         client_channel: &mut ClientChannel,
         ipv4_packet: &Ipv4Packet,
     ) {
+        self.last_activity = selector.now();
         self.handle_packet(selector, client_channel, ipv4_packet);
-        if !self.closed {
+        if self.close_reason.is_none() {
             self.update_interests(selector);
         }
     }
 
-    fn close(&mut self, selector: &mut Selector) {
+    fn close(&mut self, selector: &mut Selector, reason: CloseReason) {
         cx_info!(target: TAG, self.id, "Close");
-        self.closed = true;
+        self.close_reason = Some(reason);
+        self.update_half_open();
+        if let Some(timer) = self.lifetime_timer.take() {
+            selector.cancel_timer(timer);
+        }
+        self.cancel_handshake_timer(selector);
+        if let Some(timer) = self.throttle_timer.take() {
+            selector.cancel_timer(timer);
+        }
+        if let Some(timer) = self.ack_timer.take() {
+            selector.cancel_timer(timer);
+        }
+        if let Some((timer, _)) = self.time_wait_timer.take() {
+            // the stream has already been deregistered when entering TimeWait
+            selector.cancel_timer(timer);
+            return;
+        }
+        if let Some(timer) = self
+            .connect_retry
+            .as_mut()
+            .and_then(|connect_retry| connect_retry.timer.take())
+        {
+            // the stream has already been deregistered after the connection failed
+            selector.cancel_timer(timer);
+            return;
+        }
         if let Err(err) = selector.deregister(&self.stream, self.token) {
             // do not panic, this can happen in mio
             // see <https://github.com/Genymobile/gnirehtet/issues/136>
@@ -803,13 +1686,46 @@ impl Connection for TcpConnection {
         // socket will be closed by RAII
     }
 
-    fn is_expired(&self) -> bool {
+    fn is_expired(&self, _: Instant) -> bool {
         // no external timeout expiration
         false
     }
 
-    fn is_closed(&self) -> bool {
-        self.closed
+    fn close_reason(&self) -> Option<CloseReason> {
+        self.close_reason
+    }
+
+    fn stats(&self) -> &ConnectionStats {
+        &self.stats
+    }
+
+    fn stats_mut(&mut self) -> &mut ConnectionStats {
+        &mut self.stats
+    }
+
+    fn set_lifetime_timer(&mut self, timer: TimerId) {
+        self.lifetime_timer = Some(timer);
+    }
+
+    fn is_half_open(&self) -> bool {
+        self.tcb.state == TcpState::SynSent || self.tcb.state == TcpState::SynReceived
+    }
+
+    fn rtt_estimate(&self) -> Option<Duration> {
+        self.tcb.rtt.smoothed()
+    }
+
+    fn last_activity(&self) -> Option<Instant> {
+        Some(self.last_activity)
+    }
+
+    #[cfg(unix)]
+    fn handoff(&self, now: Instant) -> Option<(TcpConnectionState, RawFd)> {
+        if self.tcb.state == TcpState::TimeWait {
+            // nothing left to relay
+            return None;
+        }
+        Some((self.state(now), self.stream.as_raw_fd()))
     }
 }
 
@@ -826,6 +1742,18 @@ impl PacketSource for TcpConnection {
         let len = self
             .packet_for_client_length
             .expect("next() called on empty packet source");
+        let payload_length = self
+            .network_to_client
+            .inflate(len)
+            .payload()
+            .expect("No payload")
+            .len();
+        let now = selector.now();
+        self.tcb
+            .unacked
+            .push(self.tcb.sequence_number.0, payload_length as u32, now);
+        self.stats.count_to_client(payload_length);
+        self.last_activity = now;
         cx_debug!(
             target: TAG,
             self.id,
@@ -835,6 +1763,1148 @@ impl PacketSource for TcpConnection {
         );
         self.tcb.sequence_number += Wrapping(u32::from(len));
         self.packet_for_client_length = None;
+        self.start_ack_timer(selector);
         self.update_interests(selector);
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::{
+        Tcb, TcpConnection, TcpConnectionState, TcpState, WindowScale,
+        CLIENT_TO_NETWORK_BUFFER_SIZE, MTU, RELAY_WINDOW_SCALE,
+    };
+    use crate::relay::client::Client;
+    use crate::relay::connection::{Connection, ConnectionId, ConnectionStats};
+    use crate::relay::ipv4_packet::Ipv4Packet;
+    use crate::relay::metrics::{Counter, Metrics};
+    use crate::relay::observer::{CloseReason, Observer};
+    use crate::relay::selector::Selector;
+    use crate::relay::tcp_header::{
+        TcpOptionsIter, FLAG_ACK, FLAG_FIN, FLAG_PSH, FLAG_RST, FLAG_SYN, OPTION_MSS,
+    };
+    use crate::relay::transport_header::TransportHeader;
+    use crate::relay::{Relay, RelayConfigBuilder};
+    use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+    use mio::Events;
+    use std::cell::RefCell;
+    use std::io::{self, Read, Write};
+    use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream};
+    use std::num::Wrapping;
+    use std::rc::{Rc, Weak};
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    const CLIENT_PORT: u16 = 41000;
+    pub const CLIENT_SEQ: u32 = 1000;
+
+    pub fn free_port() -> u16 {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    /// Create a TCP packet sent by the client to `port` on localhost.
+    pub fn create_tcp_packet(
+        port: u16,
+        seq: u32,
+        ack: u32,
+        flags: u16,
+        window: u16,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        create_tcp_packet_with_options(port, seq, ack, flags, window, &[], payload)
+    }
+
+    /// Create a TCP packet with `options` (whose length must be a multiple of 4).
+    pub fn create_tcp_packet_with_options(
+        port: u16,
+        seq: u32,
+        ack: u32,
+        flags: u16,
+        window: u16,
+        options: &[u8],
+        payload: &[u8],
+    ) -> Vec<u8> {
+        assert_eq!(0, options.len() % 4);
+        let header_length = 20 + options.len() as u16;
+        let total_length = 20 + header_length + payload.len() as u16;
+        let mut raw = Vec::new();
+        raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
+        raw.write_u8(0).unwrap(); // ToS
+        raw.write_u16::<BigEndian>(total_length).unwrap();
+        raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+        raw.write_u8(64).unwrap(); // TTL
+        raw.write_u8(6).unwrap(); // protocol (TCP)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x0a000002).unwrap(); // source address
+        raw.write_u32::<BigEndian>(0x7f000001).unwrap(); // destination address
+
+        raw.write_u16::<BigEndian>(CLIENT_PORT).unwrap(); // source port
+        raw.write_u16::<BigEndian>(port).unwrap(); // destination port
+        raw.write_u32::<BigEndian>(seq).unwrap(); // sequence number
+        raw.write_u32::<BigEndian>(ack).unwrap(); // acknowledgement number
+        let data_offset = header_length / 4;
+        raw.write_u16::<BigEndian>(data_offset << 12 | flags)
+            .unwrap(); // data offset + flags
+        raw.write_u16::<BigEndian>(window).unwrap(); // window
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u16::<BigEndian>(0).unwrap(); // urgent pointer
+        raw.extend_from_slice(options);
+
+        raw.extend_from_slice(payload);
+        raw
+    }
+
+    /// Read the next packet from the tunnel.
+    pub fn read_packet(tunnel: &mut TcpStream) -> Vec<u8> {
+        let mut raw = vec![0; 4];
+        tunnel.read_exact(&mut raw).unwrap();
+        let total_length = BigEndian::read_u16(&raw[2..4]) as usize;
+        raw.resize(total_length, 0);
+        tunnel.read_exact(&mut raw[4..]).unwrap();
+        raw
+    }
+
+    /// Read the next packet from the tunnel, and return its sequence number, flags and payload.
+    pub fn read_tcp_packet(tunnel: &mut TcpStream) -> (u32, u16, Vec<u8>) {
+        let raw = read_packet(tunnel);
+        let tcp = &raw[(raw[0] & 0xf) as usize * 4..];
+        let seq = BigEndian::read_u32(&tcp[4..8]);
+        let flags = BigEndian::read_u16(&tcp[12..14]) & 0x1ff;
+        let payload = tcp[(tcp[12] >> 4) as usize * 4..].to_vec();
+        (seq, flags, payload)
+    }
+
+    /// Start a relay with the default configuration, and return its port.
+    pub fn start_relay() -> u16 {
+        start_relay_with(|builder| builder)
+    }
+
+    /// Start a relay configured by `configure` on a port chosen by the system, and return this
+    /// port once bound.
+    ///
+    /// The configuration is built on the relay thread (it is not `Send`).
+    pub fn start_relay_with<F>(configure: F) -> u16
+    where
+        F: FnOnce(RelayConfigBuilder) -> RelayConfigBuilder + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let relay = Relay::with_config(configure(RelayConfigBuilder::new(0)).build());
+            sender.send(relay.local_addr().unwrap().port()).unwrap();
+            relay.run().unwrap();
+        });
+        receiver.recv().unwrap()
+    }
+
+    /// Connect to the tunnel of the relay listening on `port`, and read the client id.
+    pub fn connect_tunnel(port: u16) -> TcpStream {
+        for _ in 0..100 {
+            if let Ok(mut tunnel) = TcpStream::connect((Ipv4Addr::LOCALHOST, port)) {
+                tunnel
+                    .set_read_timeout(Some(Duration::from_secs(5)))
+                    .unwrap();
+                let mut client_id = [0; 4];
+                tunnel.read_exact(&mut client_id).unwrap();
+                return tunnel;
+            }
+            // the relay is not listening yet
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("Cannot connect to the relay");
+    }
+
+    /// Open a TCP connection to `port` on localhost through the tunnel, and return the next
+    /// sequence number of the relay.
+    ///
+    /// The next sequence number of the client is `CLIENT_SEQ + 1`.
+    pub fn handshake(tunnel: &mut TcpStream, port: u16, window: u16) -> u32 {
+        let syn = create_tcp_packet(port, CLIENT_SEQ, 0, FLAG_SYN, window, &[]);
+        tunnel.write_all(&syn).unwrap();
+        let (relay_seq, flags, _) = read_tcp_packet(tunnel);
+        assert_eq!(FLAG_SYN | FLAG_ACK, flags);
+
+        let relay_seq = relay_seq + 1;
+        let ack = create_tcp_packet(port, CLIENT_SEQ + 1, relay_seq, FLAG_ACK, window, &[]);
+        tunnel.write_all(&ack).unwrap();
+        relay_seq
+    }
+
+    // the (TSval, TSecr) values of a TCP packet
+    fn read_timestamp(tunnel: &mut TcpStream) -> Option<(u32, u32)> {
+        let mut raw = read_packet(tunnel);
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        match ipv4_packet.transport_header() {
+            Some(TransportHeader::Tcp(tcp_header)) => tcp_header.timestamp(),
+            _ => panic!("Not a TCP packet"),
+        }
+    }
+
+    fn timestamp_options(tsval: u32, tsecr: u32) -> Vec<u8> {
+        let mut options = vec![1, 1, 8, 10]; // NOP, NOP, timestamp, length
+        options.write_u32::<BigEndian>(tsval).unwrap();
+        options.write_u32::<BigEndian>(tsecr).unwrap();
+        options
+    }
+
+    #[test]
+    fn echo_timestamps() {
+        let relay_port = start_relay();
+
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let mut tunnel = connect_tunnel(relay_port);
+
+        let options = timestamp_options(100, 0);
+        let syn =
+            create_tcp_packet_with_options(port, CLIENT_SEQ, 0, FLAG_SYN, 0xffff, &options, &[]);
+        tunnel.write_all(&syn).unwrap();
+        let (relay_tsval, tsecr) = read_timestamp(&mut tunnel).unwrap();
+        assert_eq!(100, tsecr);
+        let (mut upstream, _) = server.accept().unwrap();
+
+        // the relay sequence number does not matter for this test
+        let seq = CLIENT_SEQ + 1;
+        let options = timestamp_options(200, relay_tsval);
+        let ack = create_tcp_packet_with_options(port, seq, 0, FLAG_ACK, 0xffff, &options, &[]);
+        tunnel.write_all(&ack).unwrap();
+
+        let options = timestamp_options(300, relay_tsval);
+        let flags = FLAG_ACK | FLAG_PSH;
+        let data = create_tcp_packet_with_options(port, seq, 0, flags, 0xffff, &options, b"hello");
+        tunnel.write_all(&data).unwrap();
+        let mut buf = [0; 5];
+        upstream.read_exact(&mut buf).unwrap();
+
+        // the ACK of the data echoes the latest client timestamp
+        let (tsval, tsecr) = read_timestamp(&mut tunnel).unwrap();
+        assert_eq!(300, tsecr);
+        assert!(tsval >= relay_tsval);
+    }
+
+    #[test]
+    fn no_timestamps() {
+        let relay_port = start_relay();
+
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let mut tunnel = connect_tunnel(relay_port);
+        let syn = create_tcp_packet(port, CLIENT_SEQ, 0, FLAG_SYN, 0xffff, &[]);
+        tunnel.write_all(&syn).unwrap();
+        assert!(read_timestamp(&mut tunnel).is_none());
+    }
+
+    // the (window, window scale) values of a TCP packet
+    fn read_window(tunnel: &mut TcpStream) -> (u16, Option<u8>) {
+        let mut raw = read_packet(tunnel);
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        match ipv4_packet.transport_header() {
+            Some(TransportHeader::Tcp(tcp_header)) => {
+                (tcp_header.window(), tcp_header.window_scale())
+            }
+            _ => panic!("Not a TCP packet"),
+        }
+    }
+
+    #[test]
+    fn scale_client_window() {
+        let mut tcb = Tcb::new(Instant::now());
+        assert_eq!(1000, tcb.scaled_client_window(1000));
+        assert!(tcb.relay_window(false).is_none());
+
+        tcb.window_scale = Some(WindowScale {
+            client: 7,
+            relay: RELAY_WINDOW_SCALE,
+        });
+        assert_eq!(1000 << 7, tcb.scaled_client_window(1000));
+        assert_eq!(0xffff << 7, tcb.scaled_client_window(0xffff));
+        assert_eq!(Some(0xffff), tcb.relay_window(true));
+        assert_eq!(
+            Some((CLIENT_TO_NETWORK_BUFFER_SIZE >> RELAY_WINDOW_SCALE) as u16),
+            tcb.relay_window(false)
+        );
+
+        // the remaining window is no longer capped by the 16-bit window field
+        tcb.client_window = tcb.scaled_client_window(0xffff);
+        assert_eq!(0xffff << 7, tcb.remaining_client_window());
+        tcb.sequence_number = Wrapping(100_000);
+        tcb.their_acknowledgement_number = 100_000;
+        assert_eq!(0xffff << 7, tcb.remaining_client_window());
+    }
+
+    #[test]
+    fn negotiate_window_scale() {
+        let relay_port = start_relay();
+
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let mut tunnel = connect_tunnel(relay_port);
+
+        let options = [1, 3, 3, 7]; // NOP, window scale, length, shift
+        let syn =
+            create_tcp_packet_with_options(port, CLIENT_SEQ, 0, FLAG_SYN, 0xffff, &options, &[]);
+        tunnel.write_all(&syn).unwrap();
+        // the window of the SYN-ACK is not scaled
+        assert_eq!((0xffff, Some(RELAY_WINDOW_SCALE)), read_window(&mut tunnel));
+        let (mut upstream, _) = server.accept().unwrap();
+
+        let seq = CLIENT_SEQ + 1;
+        let ack = create_tcp_packet(port, seq, 0, FLAG_ACK, 0xffff, &[]);
+        tunnel.write_all(&ack).unwrap();
+        let data = create_tcp_packet(port, seq, 0, FLAG_ACK | FLAG_PSH, 0xffff, b"hello");
+        tunnel.write_all(&data).unwrap();
+        let mut buf = [0; 5];
+        upstream.read_exact(&mut buf).unwrap();
+
+        // the ACK of the data announces the scaled window of the relay, without the option
+        let window = (CLIENT_TO_NETWORK_BUFFER_SIZE >> RELAY_WINDOW_SCALE) as u16;
+        assert_eq!((window, None), read_window(&mut tunnel));
+    }
+
+    // read the SYN-ACK from the tunnel, and return its options
+    fn read_syn_ack_options(tunnel: &mut TcpStream) -> Vec<(u8, Vec<u8>)> {
+        let raw = read_packet(tunnel);
+        let tcp = &raw[(raw[0] & 0xf) as usize * 4..];
+        assert_eq!(
+            FLAG_SYN | FLAG_ACK,
+            BigEndian::read_u16(&tcp[12..14]) & 0x1ff
+        );
+        let options = &tcp[20..(tcp[12] >> 4) as usize * 4];
+        TcpOptionsIter::new(options)
+            .map(|(kind, value)| (kind, value.to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn announce_mss() {
+        let (relay_port, _) = start_observed_relay_with(|builder| builder.tcp_mss(1360));
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let mut tunnel = connect_tunnel(relay_port);
+
+        let syn = create_tcp_packet(port, CLIENT_SEQ, 0, FLAG_SYN, 0xffff, &[]);
+        tunnel.write_all(&syn).unwrap();
+        let mss = 1360u16.to_be_bytes().to_vec();
+        assert_eq!(vec![(OPTION_MSS, mss)], read_syn_ack_options(&mut tunnel));
+
+        // along with the window scale, if negotiated
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let options = [1, 3, 3, 7]; // NOP, window scale, length, shift
+        let syn =
+            create_tcp_packet_with_options(port, CLIENT_SEQ, 0, FLAG_SYN, 0xffff, &options, &[]);
+        tunnel.write_all(&syn).unwrap();
+        let mss = 1360u16.to_be_bytes().to_vec();
+        assert_eq!(
+            vec![(OPTION_MSS, mss), (3, vec![RELAY_WINDOW_SCALE])],
+            read_syn_ack_options(&mut tunnel)
+        );
+    }
+
+    #[test]
+    fn announce_default_mss() {
+        let (relay_port, _) = start_observed_relay_with(|builder| builder);
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let mut tunnel = connect_tunnel(relay_port);
+
+        let syn = create_tcp_packet(port, CLIENT_SEQ, 0, FLAG_SYN, 0xffff, &[]);
+        tunnel.write_all(&syn).unwrap();
+        // the MTU minus the IPv4 and TCP headers
+        let mss = (MTU - 40).to_be_bytes().to_vec();
+        assert_eq!(vec![(OPTION_MSS, mss)], read_syn_ack_options(&mut tunnel));
+    }
+
+    #[test]
+    fn no_window_scale() {
+        let relay_port = start_relay();
+
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let mut tunnel = connect_tunnel(relay_port);
+        let syn = create_tcp_packet(port, CLIENT_SEQ, 0, FLAG_SYN, 1234, &[]);
+        tunnel.write_all(&syn).unwrap();
+        // the window of the client is echoed as before
+        assert_eq!((1234, None), read_window(&mut tunnel));
+    }
+
+    #[test]
+    fn drain_before_fin() {
+        const WINDOW: u16 = 1000;
+        const LENGTH: usize = 5000;
+
+        let relay_port = start_relay();
+
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let server_port = server.local_addr().unwrap().port();
+        let mut tunnel = connect_tunnel(relay_port);
+        let relay_seq = handshake(&mut tunnel, server_port, WINDOW);
+        let (mut upstream, _) = server.accept().unwrap();
+
+        let data: Vec<u8> = (0..LENGTH).map(|i| i as u8).collect();
+        let expected = data.clone();
+        let server_thread = thread::spawn(move || {
+            upstream.write_all(&data).unwrap();
+            // close once the client closed its side
+            let mut buf = Vec::new();
+            upstream.read_to_end(&mut buf).unwrap();
+        });
+
+        // receive a first packet, limited by the window
+        let mut received = Vec::new();
+        while received.is_empty() {
+            let (_, _, payload) = read_tcp_packet(&mut tunnel);
+            received.extend_from_slice(&payload);
+        }
+        assert!(received.len() < LENGTH);
+
+        // the client closes its side while data is still pending for it
+        let ack = relay_seq + received.len() as u32;
+        let flags = FLAG_FIN | FLAG_ACK;
+        let fin = create_tcp_packet(server_port, CLIENT_SEQ + 1, ack, flags, WINDOW, &[]);
+        tunnel.write_all(&fin).unwrap();
+
+        let client_seq = CLIENT_SEQ + 2;
+        loop {
+            let (seq, flags, payload) = read_tcp_packet(&mut tunnel);
+            if !payload.is_empty() {
+                assert_eq!(relay_seq + received.len() as u32, seq);
+                received.extend_from_slice(&payload);
+                let ack = relay_seq + received.len() as u32;
+                let ack = create_tcp_packet(server_port, client_seq, ack, FLAG_ACK, WINDOW, &[]);
+                tunnel.write_all(&ack).unwrap();
+            }
+            if flags & FLAG_FIN != 0 {
+                // all the data must have been delivered before the FIN
+                assert_eq!(relay_seq + LENGTH as u32, seq);
+                break;
+            }
+        }
+        assert_eq!(expected, received);
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn connect_upstream() {
+        let relay_port = start_relay();
+
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let mut tunnel = connect_tunnel(relay_port);
+        let relay_seq = handshake(&mut tunnel, port, 0xffff);
+        let (mut upstream, _) = server.accept().unwrap();
+
+        // the connection is established: data are relayed in both directions
+        let flags = FLAG_ACK | FLAG_PSH;
+        let data = create_tcp_packet(port, CLIENT_SEQ + 1, relay_seq, flags, 0xffff, b"ping");
+        tunnel.write_all(&data).unwrap();
+        let mut buf = [0; 4];
+        upstream.read_exact(&mut buf).unwrap();
+        assert_eq!(b"ping", &buf);
+
+        upstream.write_all(b"pong").unwrap();
+        loop {
+            let (seq, _, payload) = read_tcp_packet(&mut tunnel);
+            if !payload.is_empty() {
+                assert_eq!(relay_seq, seq);
+                assert_eq!(b"pong", &payload[..]);
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn simultaneous_open() {
+        let relay_port = start_relay();
+
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let mut tunnel = connect_tunnel(relay_port);
+        let syn = create_tcp_packet(port, CLIENT_SEQ, 0, FLAG_SYN, 0xffff, &[]);
+        tunnel.write_all(&syn).unwrap();
+        let (relay_seq, flags, _) = read_tcp_packet(&mut tunnel);
+        assert_eq!(FLAG_SYN | FLAG_ACK, flags);
+        let relay_seq = relay_seq + 1;
+
+        // the client answers by a SYN-ACK (with the sequence number of its SYN) instead of an ACK
+        let flags = FLAG_SYN | FLAG_ACK;
+        let syn_ack = create_tcp_packet(port, CLIENT_SEQ, relay_seq, flags, 0xffff, &[]);
+        tunnel.write_all(&syn_ack).unwrap();
+        let raw = read_packet(&mut tunnel);
+        let tcp = &raw[20..];
+        assert_eq!(relay_seq, BigEndian::read_u32(&tcp[4..8]));
+        assert_eq!(CLIENT_SEQ + 1, BigEndian::read_u32(&tcp[8..12]));
+        assert_eq!(FLAG_ACK, BigEndian::read_u16(&tcp[12..14]) & 0x1ff);
+
+        // the connection is established
+        let (mut upstream, _) = server.accept().unwrap();
+        let flags = FLAG_ACK | FLAG_PSH;
+        let data = create_tcp_packet(port, CLIENT_SEQ + 1, relay_seq, flags, 0xffff, b"ping");
+        tunnel.write_all(&data).unwrap();
+        let mut buf = [0; 4];
+        upstream.read_exact(&mut buf).unwrap();
+        assert_eq!(b"ping", &buf);
+    }
+
+    #[test]
+    fn retransmit_syn_ack() {
+        let relay_port = start_relay();
+
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let mut tunnel = connect_tunnel(relay_port);
+        let syn = create_tcp_packet(port, CLIENT_SEQ, 0, FLAG_SYN, 0xffff, &[]);
+        tunnel.write_all(&syn).unwrap();
+        let (relay_seq, flags, _) = read_tcp_packet(&mut tunnel);
+        assert_eq!(FLAG_SYN | FLAG_ACK, flags);
+
+        // the SYN-ACK is lost, the client retransmits its SYN
+        tunnel.write_all(&syn).unwrap();
+        let (seq, flags, _) = read_tcp_packet(&mut tunnel);
+        assert_eq!(FLAG_SYN | FLAG_ACK, flags);
+        assert_eq!(relay_seq, seq);
+    }
+
+    #[test]
+    fn connect_refused() {
+        let relay_port = start_relay();
+
+        // nothing listens on this port
+        let port = free_port();
+        let mut tunnel = connect_tunnel(relay_port);
+        let syn = create_tcp_packet(port, CLIENT_SEQ, 0, FLAG_SYN, 0xffff, &[]);
+        tunnel.write_all(&syn).unwrap();
+
+        let raw = read_packet(&mut tunnel);
+        let tcp = &raw[20..];
+        let flags = BigEndian::read_u16(&tcp[12..14]) & 0x1ff;
+        assert_eq!(FLAG_RST | FLAG_ACK, flags);
+        // the SYN is acknowledged
+        assert_eq!(CLIENT_SEQ + 1, BigEndian::read_u32(&tcp[8..12]));
+    }
+
+    #[test]
+    fn restore_state() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let destination = listener.local_addr().unwrap();
+        let state = TcpConnectionState {
+            source: SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), CLIENT_PORT),
+            destination: match destination {
+                SocketAddr::V4(destination) => destination,
+                SocketAddr::V6(_) => unreachable!(),
+            },
+            state: TcpState::Established,
+            syn_sequence_number: 4242,
+            syn_ack_sequence_number: 4000,
+            sequence_number: 5000,
+            acknowledgement_number: CLIENT_SEQ + 100,
+            their_acknowledgement_number: 4900,
+            fin_sequence_number: None,
+            fin_received: false,
+            client_window: 0xffff << 2,
+            window_scale: Some(WindowScale {
+                client: 2,
+                relay: RELAY_WINDOW_SCALE,
+            }),
+            ts_recent: Some(123_456),
+            timestamp: 10_000,
+        };
+        let data = serde_json::to_vec(&state).unwrap();
+        let decoded: TcpConnectionState = serde_json::from_slice(&data).unwrap();
+        assert_eq!(state, decoded);
+
+        let mut selector = Selector::create().unwrap();
+        let stream = mio::net::TcpStream::connect(&destination).unwrap();
+        let config = RelayConfigBuilder::new(0).build();
+        let connection = TcpConnection::restore(
+            &mut selector,
+            &decoded,
+            stream,
+            Weak::new(),
+            None,
+            &config,
+            Default::default(),
+        )
+        .unwrap();
+        let connection = connection.borrow();
+        assert_eq!(state.source, connection.id().source());
+        assert_eq!(state.destination, connection.id().destination());
+
+        let mut restored = connection.state(selector.now());
+        // our timestamps keep increasing from their value before the handoff
+        assert!((10_000..11_000).contains(&restored.timestamp));
+        restored.timestamp = state.timestamp;
+        assert_eq!(state, restored);
+    }
+
+    struct CloseSender(mpsc::Sender<CloseReason>);
+
+    impl Observer for CloseSender {
+        fn on_close(&self, _: &ConnectionId, _: &ConnectionStats, reason: CloseReason) {
+            self.0.send(reason).unwrap();
+        }
+    }
+
+    /// Start a relay, and return its port and the reasons of the connections it closes.
+    fn start_observed_relay() -> (u16, mpsc::Receiver<CloseReason>) {
+        start_observed_relay_with(|builder| builder)
+    }
+
+    /// Same as `start_observed_relay()`, with additional options set by `configure`.
+    fn start_observed_relay_with(
+        configure: fn(RelayConfigBuilder) -> RelayConfigBuilder,
+    ) -> (u16, mpsc::Receiver<CloseReason>) {
+        let (sender, receiver) = mpsc::channel();
+        let relay_port = start_relay_with(move |builder| {
+            configure(builder.observer(Rc::new(CloseSender(sender))))
+        });
+        (relay_port, receiver)
+    }
+
+    fn next_close_reason(receiver: &mpsc::Receiver<CloseReason>) -> CloseReason {
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap()
+    }
+
+    // read packets until the relay sends a FIN, and return its sequence number
+    fn read_fin(tunnel: &mut TcpStream) -> u32 {
+        loop {
+            let (seq, flags, _) = read_tcp_packet(tunnel);
+            if flags & FLAG_FIN != 0 {
+                return seq;
+            }
+        }
+    }
+
+    #[test]
+    fn close_reason_client_fin() {
+        let (relay_port, close_reasons) = start_observed_relay();
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let mut tunnel = connect_tunnel(relay_port);
+        let relay_seq = handshake(&mut tunnel, port, 0xffff);
+        let (mut upstream, _) = server.accept().unwrap();
+
+        let flags = FLAG_FIN | FLAG_ACK;
+        let fin = create_tcp_packet(port, CLIENT_SEQ + 1, relay_seq, flags, 0xffff, &[]);
+        tunnel.write_all(&fin).unwrap();
+        // the server closes once the client closed its side
+        let mut buf = Vec::new();
+        upstream.read_to_end(&mut buf).unwrap();
+        drop(upstream);
+
+        let fin_seq = read_fin(&mut tunnel);
+        let ack = create_tcp_packet(port, CLIENT_SEQ + 2, fin_seq + 1, FLAG_ACK, 0xffff, &[]);
+        tunnel.write_all(&ack).unwrap();
+        assert_eq!(CloseReason::ClientFin, next_close_reason(&close_reasons));
+    }
+
+    #[test]
+    fn close_reason_upstream_fin() {
+        let (relay_port, close_reasons) = start_observed_relay();
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let mut tunnel = connect_tunnel(relay_port);
+        handshake(&mut tunnel, port, 0xffff);
+        let (upstream, _) = server.accept().unwrap();
+        drop(upstream);
+
+        let ack = read_fin(&mut tunnel) + 1;
+        let ack_packet = create_tcp_packet(port, CLIENT_SEQ + 1, ack, FLAG_ACK, 0xffff, &[]);
+        tunnel.write_all(&ack_packet).unwrap();
+        let flags = FLAG_FIN | FLAG_ACK;
+        let fin = create_tcp_packet(port, CLIENT_SEQ + 1, ack, flags, 0xffff, &[]);
+        tunnel.write_all(&fin).unwrap();
+        assert_eq!(CloseReason::UpstreamFin, next_close_reason(&close_reasons));
+    }
+
+    #[test]
+    fn close_reason_client_rst() {
+        let (relay_port, close_reasons) = start_observed_relay();
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let mut tunnel = connect_tunnel(relay_port);
+        let relay_seq = handshake(&mut tunnel, port, 0xffff);
+        let _upstream = server.accept().unwrap();
+
+        let rst = create_tcp_packet(port, CLIENT_SEQ + 1, relay_seq, FLAG_RST, 0xffff, &[]);
+        tunnel.write_all(&rst).unwrap();
+        assert_eq!(CloseReason::ClientRst, next_close_reason(&close_reasons));
+    }
+
+    #[test]
+    fn close_reason_upstream_rst() {
+        let (relay_port, close_reasons) = start_observed_relay();
+        // nothing listens on this port
+        let port = free_port();
+        let mut tunnel = connect_tunnel(relay_port);
+        let syn = create_tcp_packet(port, CLIENT_SEQ, 0, FLAG_SYN, 0xffff, &[]);
+        tunnel.write_all(&syn).unwrap();
+        assert_eq!(CloseReason::UpstreamRst, next_close_reason(&close_reasons));
+    }
+
+    #[test]
+    fn close_reason_client_disconnected() {
+        let (relay_port, close_reasons) = start_observed_relay();
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let mut tunnel = connect_tunnel(relay_port);
+        handshake(&mut tunnel, port, 0xffff);
+        let _upstream = server.accept().unwrap();
+
+        // the client disconnects without closing its connections
+        drop(tunnel);
+        assert_eq!(
+            CloseReason::ClientDisconnected,
+            next_close_reason(&close_reasons)
+        );
+    }
+
+    fn start_retrying_relay(retries: u32, initial_backoff: Duration) -> (u16, Arc<Metrics>) {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let relay = Relay::with_config(
+                RelayConfigBuilder::new(0)
+                    .connect_retries(retries, initial_backoff)
+                    .build(),
+            );
+            let relay_port = relay.local_addr().unwrap().port();
+            sender.send((relay_port, relay.metrics())).unwrap();
+            relay.run().unwrap();
+        });
+        receiver.recv().unwrap()
+    }
+
+    #[test]
+    fn connect_after_retries() {
+        let (relay_port, metrics) = start_retrying_relay(3, Duration::from_millis(100));
+        // nothing listens on this port yet
+        let port = free_port();
+        let mut tunnel = connect_tunnel(relay_port);
+        let syn = create_tcp_packet(port, CLIENT_SEQ, 0, FLAG_SYN, 0xffff, &[]);
+        tunnel.write_all(&syn).unwrap();
+
+        // listen once the first attempt and the first retry failed
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while metrics.get(Counter::UpstreamConnectRetries) < 2 {
+            assert!(Instant::now() < deadline, "The connection is not retried");
+            thread::sleep(Duration::from_millis(5));
+        }
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).unwrap();
+
+        let (relay_seq, flags, _) = read_tcp_packet(&mut tunnel);
+        assert_eq!(FLAG_SYN | FLAG_ACK, flags);
+        let relay_seq = relay_seq + 1;
+        let ack = create_tcp_packet(port, CLIENT_SEQ + 1, relay_seq, FLAG_ACK, 0xffff, &[]);
+        tunnel.write_all(&ack).unwrap();
+        let (mut upstream, _) = server.accept().unwrap();
+        assert_eq!(2, metrics.get(Counter::UpstreamConnectRetries));
+
+        // the connection is established
+        upstream.write_all(b"hello").unwrap();
+        let (seq, _, payload) = read_tcp_packet(&mut tunnel);
+        assert_eq!(relay_seq, seq);
+        assert_eq!(b"hello", &payload[..]);
+    }
+
+    #[test]
+    fn give_up_after_retries() {
+        let (relay_port, metrics) = start_retrying_relay(2, Duration::from_millis(10));
+        // nothing listens on this port
+        let port = free_port();
+        let mut tunnel = connect_tunnel(relay_port);
+        let syn = create_tcp_packet(port, CLIENT_SEQ, 0, FLAG_SYN, 0xffff, &[]);
+        tunnel.write_all(&syn).unwrap();
+
+        let (_, flags, _) = read_tcp_packet(&mut tunnel);
+        assert_eq!(FLAG_RST | FLAG_ACK, flags);
+        assert_eq!(2, metrics.get(Counter::UpstreamConnectRetries));
+    }
+
+    #[test]
+    fn absorb_late_segments_in_time_wait() {
+        let (relay_port, close_reasons) =
+            start_observed_relay_with(|builder| builder.tcp_time_wait(Duration::from_millis(500)));
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let mut tunnel = connect_tunnel(relay_port);
+        handshake(&mut tunnel, port, 0xffff);
+        let (upstream, _) = server.accept().unwrap();
+        drop(upstream);
+
+        let ack = read_fin(&mut tunnel) + 1;
+        let ack_packet = create_tcp_packet(port, CLIENT_SEQ + 1, ack, FLAG_ACK, 0xffff, &[]);
+        tunnel.write_all(&ack_packet).unwrap();
+        let flags = FLAG_FIN | FLAG_ACK;
+        let fin = create_tcp_packet(port, CLIENT_SEQ + 1, ack, flags, 0xffff, &[]);
+        tunnel.write_all(&fin).unwrap();
+        assert_eq!((ack, FLAG_ACK), read_ack(&mut tunnel, CLIENT_SEQ + 2));
+        // the connection lingers, it is not closed yet
+        assert!(close_reasons
+            .recv_timeout(Duration::from_millis(100))
+            .is_err());
+
+        // our ACK was lost, the client retransmits its FIN
+        tunnel.write_all(&fin).unwrap();
+        assert_eq!((ack, FLAG_ACK), read_ack(&mut tunnel, CLIENT_SEQ + 2));
+
+        // a new SYN on the same tuple is refused, without connecting upstream
+        let syn = create_tcp_packet(port, CLIENT_SEQ + 100, 0, FLAG_SYN, 0xffff, &[]);
+        tunnel.write_all(&syn).unwrap();
+        assert_eq!(
+            (ack, FLAG_RST | FLAG_ACK),
+            read_ack(&mut tunnel, CLIENT_SEQ + 101)
+        );
+        server.set_nonblocking(true).unwrap();
+        assert!(server.accept().is_err());
+
+        assert_eq!(CloseReason::UpstreamFin, next_close_reason(&close_reasons));
+    }
+
+    // read a packet which must acknowledge `expected_ack`, and return its sequence number and flags
+    fn read_ack(tunnel: &mut TcpStream, expected_ack: u32) -> (u32, u16) {
+        let raw = read_packet(tunnel);
+        let tcp = &raw[20..];
+        assert_eq!(expected_ack, BigEndian::read_u32(&tcp[8..12]));
+        let flags = BigEndian::read_u16(&tcp[12..14]) & 0x1ff;
+        (BigEndian::read_u32(&tcp[4..8]), flags)
+    }
+
+    #[test]
+    fn reack_retransmitted_segment() {
+        let (relay_port, _) = start_observed_relay();
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let mut tunnel = connect_tunnel(relay_port);
+        let relay_seq = handshake(&mut tunnel, port, 0xffff);
+        let (mut upstream, _) = server.accept().unwrap();
+
+        let flags = FLAG_ACK | FLAG_PSH;
+        let data = create_tcp_packet(port, CLIENT_SEQ + 1, relay_seq, flags, 0xffff, b"hello");
+        tunnel.write_all(&data).unwrap();
+        read_ack(&mut tunnel, CLIENT_SEQ + 6);
+
+        // the client did not receive the ACK, and retransmits the segment
+        tunnel.write_all(&data).unwrap();
+        read_ack(&mut tunnel, CLIENT_SEQ + 6);
+
+        let data = create_tcp_packet(port, CLIENT_SEQ + 6, relay_seq, flags, 0xffff, b"world");
+        tunnel.write_all(&data).unwrap();
+        read_ack(&mut tunnel, CLIENT_SEQ + 11);
+
+        // the retransmitted data have not been forwarded twice
+        let mut buf = [0; 10];
+        upstream.read_exact(&mut buf).unwrap();
+        assert_eq!(b"helloworld", &buf);
+        upstream.set_nonblocking(true).unwrap();
+        assert_eq!(
+            io::ErrorKind::WouldBlock,
+            upstream.read(&mut buf).unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn retransmit_syn_ack_on_duplicate_syn() {
+        let (relay_port, close_reasons) = start_observed_relay();
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let mut tunnel = connect_tunnel(relay_port);
+        let relay_seq = handshake(&mut tunnel, port, 0xffff);
+        let (mut upstream, _) = server.accept().unwrap();
+
+        let flags = FLAG_ACK | FLAG_PSH;
+        let data = create_tcp_packet(port, CLIENT_SEQ + 1, relay_seq, flags, 0xffff, b"hello");
+        tunnel.write_all(&data).unwrap();
+        read_ack(&mut tunnel, CLIENT_SEQ + 6);
+
+        // a late copy of the SYN of the established connection
+        let syn = create_tcp_packet(port, CLIENT_SEQ, 0, FLAG_SYN, 0xffff, &[]);
+        tunnel.write_all(&syn).unwrap();
+        assert_eq!(
+            (relay_seq - 1, FLAG_SYN | FLAG_ACK),
+            read_ack(&mut tunnel, CLIENT_SEQ + 1)
+        );
+
+        // the connection is still the same
+        let data = create_tcp_packet(port, CLIENT_SEQ + 6, relay_seq, flags, 0xffff, b"world");
+        tunnel.write_all(&data).unwrap();
+        read_ack(&mut tunnel, CLIENT_SEQ + 11);
+        let mut buf = [0; 10];
+        upstream.read_exact(&mut buf).unwrap();
+        assert_eq!(b"helloworld", &buf);
+        server.set_nonblocking(true).unwrap();
+        assert!(server.accept().is_err());
+        assert!(close_reasons
+            .recv_timeout(Duration::from_millis(100))
+            .is_err());
+    }
+
+    #[test]
+    fn reset_segment_for_unknown_connection() {
+        let (relay_port, close_reasons) = start_observed_relay_with(|builder| builder);
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        server.set_nonblocking(true).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let mut tunnel = connect_tunnel(relay_port);
+
+        // data acknowledging something, without any prior SYN
+        let flags = FLAG_ACK | FLAG_PSH;
+        let packet = create_tcp_packet(port, CLIENT_SEQ, 0x1234, flags, 0xffff, b"data");
+        tunnel.write_all(&packet).unwrap();
+        assert_eq!((0x1234, FLAG_RST, vec![]), read_tcp_packet(&mut tunnel));
+
+        // no connection has been created: none is closed, and nothing connected upstream
+        assert!(close_reasons
+            .recv_timeout(Duration::from_millis(100))
+            .is_err());
+        assert_eq!(
+            io::ErrorKind::WouldBlock,
+            server.accept().unwrap_err().kind()
+        );
+
+        // a SYN still opens the connection
+        server.set_nonblocking(false).unwrap();
+        handshake(&mut tunnel, port, 0xffff);
+        server.accept().unwrap();
+    }
+
+    #[test]
+    fn reset_syn_if_tcp_disabled() {
+        let (relay_port, close_reasons) =
+            start_observed_relay_with(|builder| builder.allow_tcp(false));
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        server.set_nonblocking(true).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let mut tunnel = connect_tunnel(relay_port);
+
+        let syn = create_tcp_packet(port, CLIENT_SEQ, 0, FLAG_SYN, 0xffff, &[]);
+        tunnel.write_all(&syn).unwrap();
+        assert_eq!(
+            (0, FLAG_RST | FLAG_ACK),
+            read_ack(&mut tunnel, CLIENT_SEQ + 1)
+        );
+        // no connection has been created
+        assert!(close_reasons
+            .recv_timeout(Duration::from_millis(100))
+            .is_err());
+        assert!(server.accept().is_err());
+    }
+
+    #[test]
+    fn refuse_syn_above_half_open_limit() {
+        let (relay_port, _) =
+            start_observed_relay_with(|builder| builder.max_half_open_connections(1));
+        let servers: Vec<TcpListener> = (0..3)
+            .map(|_| TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap())
+            .collect();
+        let ports: Vec<u16> = servers
+            .iter()
+            .map(|server| server.local_addr().unwrap().port())
+            .collect();
+        let mut tunnel = connect_tunnel(relay_port);
+
+        // the first connection stays half-open: its SYN-ACK is not acknowledged yet
+        let syn = create_tcp_packet(ports[0], CLIENT_SEQ, 0, FLAG_SYN, 0xffff, &[]);
+        tunnel.write_all(&syn).unwrap();
+        let (relay_seq, flags, _) = read_tcp_packet(&mut tunnel);
+        assert_eq!(FLAG_SYN | FLAG_ACK, flags);
+
+        // another SYN is refused
+        let syn = create_tcp_packet(ports[1], CLIENT_SEQ, 0, FLAG_SYN, 0xffff, &[]);
+        tunnel.write_all(&syn).unwrap();
+        assert_eq!(
+            (0, FLAG_RST | FLAG_ACK),
+            read_ack(&mut tunnel, CLIENT_SEQ + 1)
+        );
+
+        // once the first connection is established, a new one may be opened
+        let relay_seq = relay_seq + 1;
+        let ack = create_tcp_packet(ports[0], CLIENT_SEQ + 1, relay_seq, FLAG_ACK, 0xffff, &[]);
+        tunnel.write_all(&ack).unwrap();
+        handshake(&mut tunnel, ports[2], 0xffff);
+        servers[2].accept().unwrap();
+
+        // the established connection is unaffected
+        let (mut upstream, _) = servers[0].accept().unwrap();
+        let flags = FLAG_ACK | FLAG_PSH;
+        let data = create_tcp_packet(ports[0], CLIENT_SEQ + 1, relay_seq, flags, 0xffff, b"hello");
+        tunnel.write_all(&data).unwrap();
+        let mut buf = [0; 5];
+        upstream.read_exact(&mut buf).unwrap();
+        assert_eq!(b"hello", &buf);
+    }
+
+    #[test]
+    fn reset_half_open_after_handshake_timeout() {
+        let (relay_port, close_reasons) = start_observed_relay_with(|builder| {
+            builder
+                .max_half_open_connections(1)
+                .tcp_handshake_timeout(Duration::from_millis(300))
+        });
+        let servers: Vec<TcpListener> = (0..2)
+            .map(|_| TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap())
+            .collect();
+        let ports: Vec<u16> = servers
+            .iter()
+            .map(|server| server.local_addr().unwrap().port())
+            .collect();
+        let mut tunnel = connect_tunnel(relay_port);
+
+        // the SYN-ACK is never acknowledged
+        let syn = create_tcp_packet(ports[0], CLIENT_SEQ, 0, FLAG_SYN, 0xffff, &[]);
+        tunnel.write_all(&syn).unwrap();
+        let (_, flags, _) = read_tcp_packet(&mut tunnel);
+        assert_eq!(FLAG_SYN | FLAG_ACK, flags);
+
+        let (_, flags, _) = read_tcp_packet(&mut tunnel);
+        assert_eq!(FLAG_RST | FLAG_ACK, flags);
+        assert_eq!(
+            CloseReason::Timeout,
+            close_reasons.recv_timeout(Duration::from_secs(5)).unwrap()
+        );
+
+        // the connection does not count as half-open anymore
+        handshake(&mut tunnel, ports[1], 0xffff);
+        servers[1].accept().unwrap();
+    }
+
+    #[test]
+    fn close_at_max_lifetime() {
+        let (relay_port, close_reasons) = start_observed_relay_with(|builder| {
+            builder.max_connection_lifetime(Duration::from_millis(300))
+        });
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let mut tunnel = connect_tunnel(relay_port);
+        let start = Instant::now();
+        handshake(&mut tunnel, port, 0xffff);
+        let (mut upstream, _) = server.accept().unwrap();
+
+        // keep the connection active
+        let mut seq = CLIENT_SEQ + 1;
+        while close_reasons
+            .recv_timeout(Duration::from_millis(50))
+            .map(|reason| assert_eq!(CloseReason::MaxLifetime, reason))
+            .is_err()
+        {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            let flags = FLAG_ACK | FLAG_PSH;
+            let data = create_tcp_packet(port, seq, 0, flags, 0xffff, b"ping");
+            tunnel.write_all(&data).unwrap();
+            seq += 4;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(300));
+
+        // the upstream socket has been closed
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut buf = Vec::new();
+        upstream.read_to_end(&mut buf).unwrap();
+        assert_eq!(0, buf.len() % 4);
+    }
+
+    // a client connected to a local peer (playing the role of the device), and a connection of
+    // this client to `port` on localhost, before its first packet
+    fn create_connection(
+        selector: &mut Selector,
+        port: u16,
+    ) -> (Rc<RefCell<Client>>, TcpStream, Rc<RefCell<TcpConnection>>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let stream = mio::net::TcpStream::from_stream(stream).unwrap();
+        let config = Rc::new(RelayConfigBuilder::new(0).build());
+        let metrics = Arc::new(Metrics::new());
+        let on_closed = |_: &Client| {};
+        let client = Client::create(
+            0,
+            selector,
+            stream,
+            Box::new(on_closed),
+            config.clone(),
+            metrics.clone(),
+            Default::default(),
+        )
+        .unwrap();
+
+        let mut raw = create_tcp_packet(port, CLIENT_SEQ, 0, FLAG_SYN, 0xffff, &[]);
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        let (ipv4_header, transport_header) = ipv4_packet.headers();
+        let transport_header = transport_header.unwrap();
+        let id = ConnectionId::from_headers(ipv4_header.data(), &transport_header.data_clone());
+        let connection = TcpConnection::create(
+            selector,
+            id,
+            Rc::downgrade(&client),
+            None,
+            ipv4_header,
+            transport_header,
+            &config,
+            metrics,
+            Default::default(),
+        )
+        .unwrap();
+        (client, peer, connection)
+    }
+
+    fn send_from_client(
+        selector: &mut Selector,
+        client: &Rc<RefCell<Client>>,
+        connection: &Rc<RefCell<TcpConnection>>,
+        mut raw: Vec<u8>,
+    ) {
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        let mut client = client.borrow_mut();
+        connection
+            .borrow_mut()
+            .send_to_network(selector, &mut client.channel(), &ipv4_packet);
+    }
+
+    #[test]
+    fn fin_from_close_wait() {
+        let mut selector = Selector::create().unwrap();
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = server.local_addr().unwrap().port();
+        let (client, mut peer, connection) = create_connection(&mut selector, port);
+        let (upstream, _) = server.accept().unwrap();
+
+        // as if the client had sent its FIN (which counts for 1 byte) after the handshake
+        let relay_seq = 5000;
+        let client_seq = CLIENT_SEQ + 2;
+        connection
+            .borrow_mut()
+            .debug_set_state(TcpState::CloseWait, relay_seq, client_seq);
+        // the client announces its window
+        let ack = create_tcp_packet(port, client_seq, relay_seq, FLAG_ACK, 0xffff, &[]);
+        send_from_client(&mut selector, &client, &connection, ack);
+        assert!(connection.borrow().close_reason().is_none());
+
+        // on EOF from upstream, the relay sends its FIN
+        drop(upstream);
+        let mut events = Events::with_capacity(16);
+        let start = Instant::now();
+        while connection.borrow().tcb.state != TcpState::LastAck {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            selector
+                .poll(&mut events, Some(Duration::from_millis(50)))
+                .unwrap();
+            selector.run_handlers(&events);
+        }
+        assert!(client.borrow_mut().flush_blocking(Duration::from_secs(1)));
+        let mut client_id = [0; 4];
+        peer.read_exact(&mut client_id).unwrap();
+        let (seq, flags, _) = read_tcp_packet(&mut peer);
+        assert_eq!(relay_seq, seq);
+        assert_eq!(FLAG_FIN | FLAG_ACK, flags);
+
+        // the ACK of the FIN closes the connection
+        let ack = create_tcp_packet(port, client_seq, relay_seq + 1, FLAG_ACK, 0xffff, &[]);
+        send_from_client(&mut selector, &client, &connection, ack);
+        assert_eq!(
+            Some(CloseReason::ClientFin),
+            connection.borrow().close_reason()
+        );
+    }
+}
@@ -0,0 +1,155 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::time::{Duration, Instant};
+
+// the bucket may hold the tokens for this duration, to absorb small bursts
+const BURST_MILLIS: u32 = 100;
+
+/// Limit the rate of the data forwarded by a connection, in bytes per second.
+///
+/// Each forwarded byte consumes a token; the tokens are refilled continuously at the configured
+/// rate. The router also uses it to limit the packets of a client (a token per packet).
+pub struct TokenBucket {
+    rate: u32,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32, now: Instant) -> Self {
+        assert!(rate > 0, "The rate must be positive");
+        let capacity = (f64::from(rate) * f64::from(BURST_MILLIS) / 1000.0).max(1.0);
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now > self.last_refill {
+            let elapsed = now - self.last_refill;
+            let tokens = self.tokens + elapsed.as_secs_f64() * f64::from(self.rate);
+            self.tokens = tokens.min(self.capacity);
+            self.last_refill = now;
+        }
+    }
+
+    /// The number of bytes which may be forwarded immediately.
+    pub fn available(&mut self, now: Instant) -> usize {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens as usize
+        } else {
+            0
+        }
+    }
+
+    /// Consume the tokens for `bytes` forwarded bytes.
+    ///
+    /// A datagram cannot be split, so more tokens than available may be consumed: the debt is paid
+    /// before any other byte is allowed.
+    pub fn consume(&mut self, bytes: usize, now: Instant) {
+        self.refill(now);
+        self.tokens -= bytes as f64;
+    }
+
+    /// The delay before the next byte may be forwarded, if it may not be forwarded immediately.
+    pub fn delay(&mut self, now: Instant) -> Option<Duration> {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            None
+        } else {
+            let missing = 1.0 - self.tokens;
+            // round up, so that the byte is available once the delay expired
+            let micros = (missing * 1_000_000.0 / f64::from(self.rate)).ceil();
+            Some(Duration::from_micros(micros as u64))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn start_full() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(10_000, now);
+        assert_eq!(1000, bucket.available(now));
+        assert!(bucket.delay(now).is_none());
+    }
+
+    #[test]
+    fn consume_and_refill() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(10_000, now);
+        bucket.consume(1000, now);
+        assert_eq!(0, bucket.available(now));
+        assert_eq!(Some(Duration::from_micros(100)), bucket.delay(now));
+
+        let later = now + Duration::from_millis(50);
+        assert_eq!(500, bucket.available(later));
+
+        // never more than the capacity
+        let much_later = now + Duration::from_secs(10);
+        assert_eq!(1000, bucket.available(much_later));
+    }
+
+    #[test]
+    fn pay_debt() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(10_000, now);
+        // a datagram larger than the capacity
+        bucket.consume(3000, now);
+        assert_eq!(Some(Duration::from_micros(200_100)), bucket.delay(now));
+        assert_eq!(0, bucket.available(now + Duration::from_millis(200)));
+        assert!(bucket.delay(now + Duration::from_micros(200_100)).is_none());
+    }
+
+    #[test]
+    fn throttled_rate() {
+        const RATE: u32 = 50_000;
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(RATE, start);
+        let mut now = start;
+        let mut forwarded = 0;
+        // forward chunks of at most 1400 bytes as fast as allowed, during 10 seconds
+        while now < start + Duration::from_secs(10) {
+            match bucket.delay(now) {
+                Some(delay) => now += delay,
+                None => {
+                    let len = bucket.available(now).min(1400);
+                    bucket.consume(len, now);
+                    forwarded += len;
+                }
+            }
+        }
+        let expected = 10 * RATE as usize;
+        // the initial burst may exceed the rate
+        let burst = RATE as usize / 10;
+        assert!(forwarded >= expected - 1400, "{} < {}", forwarded, expected);
+        assert!(
+            forwarded <= expected + burst,
+            "{} > {}",
+            forwarded,
+            expected
+        );
+    }
+}
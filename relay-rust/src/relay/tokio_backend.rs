@@ -0,0 +1,116 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+
+use super::event_loop::EventLoop;
+
+// the poll descriptor of the selector, owned by the event loop
+struct SelectorFd(RawFd);
+
+impl AsRawFd for SelectorFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+/// Drive `event_loop` from the tokio runtime: wait (asynchronously) for its selector to be ready
+/// or for its timeout to expire, then poll it without blocking.
+pub async fn run(mut event_loop: EventLoop) -> io::Result<()> {
+    let fd = AsyncFd::with_interest(
+        SelectorFd(event_loop.selector().as_raw_fd()),
+        Interest::READABLE,
+    )?;
+    loop {
+        let timeout = event_loop.timeout();
+        let mut guard = tokio::select! {
+            guard = fd.readable() => Some(guard?),
+            _ = tokio::time::sleep(timeout) => None,
+        };
+        let count = event_loop.poll(Duration::from_secs(0))?;
+        if count == 0 {
+            // only clear the readiness once all the pending events have been handled, since the
+            // selector may contain level-triggered registrations
+            if let Some(guard) = guard.as_mut() {
+                guard.clear_ready();
+            }
+        }
+        event_loop.dispatch();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::relay::tcp_connection::tests::{
+        connect_tunnel, create_tcp_packet, handshake, read_tcp_packet,
+    };
+    use crate::relay::tcp_header::{FLAG_ACK, FLAG_PSH};
+    use crate::relay::{Relay, RelayConfigBuilder};
+    use std::io::{Read, Write};
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::thread;
+
+    fn run_client(relay_port: u16) {
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let server_port = server.local_addr().unwrap().port();
+
+        let mut tunnel = connect_tunnel(relay_port);
+        let relay_seq = handshake(&mut tunnel, server_port, 0xffff);
+        let (mut upstream, _) = server.accept().unwrap();
+
+        let flags = FLAG_ACK | FLAG_PSH;
+        let data = create_tcp_packet(server_port, 1001, relay_seq, flags, 0xffff, b"hello");
+        tunnel.write_all(&data).unwrap();
+        let mut buf = [0; 5];
+        upstream.read_exact(&mut buf).unwrap();
+        assert_eq!(b"hello", &buf);
+
+        upstream.write_all(b"world").unwrap();
+        loop {
+            // skip the pure ACK packets
+            let (_, _, payload) = read_tcp_packet(&mut tunnel);
+            if !payload.is_empty() {
+                assert_eq!(b"world", &payload[..]);
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn relay_tcp_through_tokio() {
+        let relay = Relay::with_config(RelayConfigBuilder::new(0).build());
+        let relay_port = relay.local_addr().unwrap().port();
+        let client = thread::spawn(move || run_client(relay_port));
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            tokio::select! {
+                result = relay.run_async() => panic!("Relay stopped: {:?}", result),
+                result = tokio::task::spawn_blocking(move || client.join()) => {
+                    // propagate the client assertion failures
+                    result.unwrap().unwrap();
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,186 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::VecDeque;
+use std::fmt::{self, Write};
+use std::time::Instant;
+
+use super::ipv4_packet::Ipv4Packet;
+
+// the headers are always kept, the payload is truncated to this length
+const MAX_TRACED_PAYLOAD_LENGTH: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    ToNetwork,
+    ToClient,
+}
+
+#[derive(Clone, Debug)]
+pub struct TraceEntry {
+    time: Instant,
+    direction: Direction,
+    // the length of the whole packet, the data may be truncated
+    length: usize,
+    data: Vec<u8>,
+}
+
+/// Keep the last packets relayed for a client in memory, to dump them on demand when something
+/// goes wrong.
+///
+/// Only the headers and the beginning of the payload of each packet are kept, or its first
+/// `snaplen` bytes if set. Once the capacity is reached, recording a packet evicts the oldest one
+/// (and reuses its buffer).
+pub struct TraceRing {
+    capacity: usize,
+    snaplen: Option<usize>,
+    entries: VecDeque<TraceEntry>,
+}
+
+impl TraceEntry {
+    pub fn time(&self) -> Instant {
+        self.time
+    }
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let direction = match self.direction {
+            Direction::ToNetwork => "to_network",
+            Direction::ToClient => "to_client",
+        };
+        let mut hex = String::with_capacity(2 * self.data.len());
+        for &byte in &self.data {
+            write!(hex, "{:02X}", byte)?;
+        }
+        write!(f, "{} length={} {}", direction, self.length, hex)?;
+        if self.data.len() < self.length {
+            write!(f, " ...")?;
+        }
+        Ok(())
+    }
+}
+
+impl TraceRing {
+    pub fn new(capacity: usize, snaplen: Option<usize>) -> Self {
+        assert!(capacity > 0, "The trace capacity must be positive");
+        Self {
+            capacity,
+            snaplen,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn record(&mut self, direction: Direction, ipv4_packet: &Ipv4Packet, now: Instant) {
+        let raw = ipv4_packet.raw();
+        let traced_length = match self.snaplen {
+            Some(snaplen) => raw.len().min(snaplen),
+            None => {
+                let headers_length = match ipv4_packet.payload() {
+                    Some(payload) => raw.len() - payload.len(),
+                    None => ipv4_packet.ipv4_header().header_length() as usize,
+                };
+                raw.len().min(headers_length + MAX_TRACED_PAYLOAD_LENGTH)
+            }
+        };
+
+        let mut data = if self.entries.len() == self.capacity {
+            let mut oldest = self.entries.pop_front().unwrap();
+            oldest.data.clear();
+            oldest.data
+        } else {
+            Vec::new()
+        };
+        data.extend_from_slice(&raw[..traced_length]);
+        self.entries.push_back(TraceEntry {
+            time: now,
+            direction,
+            length: raw.len(),
+            data,
+        });
+    }
+
+    /// The recorded entries, from the oldest to the most recent.
+    pub fn entries(&self) -> impl Iterator<Item = &TraceEntry> {
+        self.entries.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::tcp_connection::tests::create_tcp_packet;
+
+    fn record_tcp(trace_ring: &mut TraceRing, seq: u32, payload: &[u8]) {
+        let mut raw = create_tcp_packet(1234, seq, 0, 0, 0, payload);
+        let packet = Ipv4Packet::parse(&mut raw);
+        trace_ring.record(Direction::ToNetwork, &packet, Instant::now());
+    }
+
+    fn traced_seq(entry: &TraceEntry) -> u32 {
+        // 20 bytes of IPv4 header, then the sequence number at offset 4 in the TCP header
+        let data = &entry.data;
+        u32::from_be_bytes([data[24], data[25], data[26], data[27]])
+    }
+
+    #[test]
+    fn keep_most_recent_entries() {
+        let mut trace_ring = TraceRing::new(4, None);
+        for seq in 0..10 {
+            record_tcp(&mut trace_ring, seq, &[]);
+        }
+        let seqs: Vec<u32> = trace_ring.entries().map(traced_seq).collect();
+        assert_eq!(vec![6, 7, 8, 9], seqs);
+    }
+
+    #[test]
+    fn truncate_payload() {
+        let mut trace_ring = TraceRing::new(4, None);
+        record_tcp(&mut trace_ring, 0, &[0x42; 100]);
+        record_tcp(&mut trace_ring, 1, &[0x42; 10]);
+
+        let entries: Vec<&TraceEntry> = trace_ring.entries().collect();
+        assert_eq!(140, entries[0].length);
+        assert_eq!(40 + MAX_TRACED_PAYLOAD_LENGTH, entries[0].data.len());
+        assert!(entries[0].to_string().ends_with(" ..."));
+
+        assert_eq!(50, entries[1].length);
+        assert_eq!(50, entries[1].data.len());
+        assert!(entries[1]
+            .to_string()
+            .starts_with("to_network length=50 4500"));
+    }
+
+    #[test]
+    fn truncate_to_snaplen() {
+        let mut trace_ring = TraceRing::new(4, Some(64));
+        record_tcp(&mut trace_ring, 0, &[0x42; 1960]);
+        record_tcp(&mut trace_ring, 1, &[0x42; 10]);
+
+        let entries: Vec<&TraceEntry> = trace_ring.entries().collect();
+        // the original length is preserved
+        assert_eq!(2000, entries[0].length);
+        assert_eq!(64, entries[0].data.len());
+        assert_eq!(0, traced_seq(entries[0]));
+        assert!(entries[0]
+            .to_string()
+            .starts_with("to_network length=2000 4500"));
+        assert!(entries[0].to_string().ends_with(" ..."));
+
+        assert_eq!(50, entries[1].length);
+        assert_eq!(50, entries[1].data.len());
+    }
+}
@@ -15,50 +15,187 @@
  */
 
 use log::*;
-use mio::net::TcpListener;
+use mio::net::{TcpListener, TcpStream};
 use mio::{Event, PollOpt, Ready};
+#[cfg(unix)]
+use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
 use std::io;
 use std::net::{Ipv4Addr, SocketAddr};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, BorrowedFd};
 use std::ptr;
 use std::rc::{Rc, Weak};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use super::client::Client;
+#[cfg(unix)]
+use super::client::ClientState;
+use super::close_listener::CloseListener;
+use super::config::RelayConfig;
+use super::connection::{ConnectionId, ConnectionInfo, ConnectionStats};
+#[cfg(unix)]
+use super::handoff::Handoff;
+use super::metrics::Metrics;
+use super::nat::NatPorts;
 use super::selector::Selector;
+use super::trace_ring::TraceEntry;
 
 const TAG: &str = "TunnelServer";
 
 pub struct TunnelServer {
     self_weak: Weak<RefCell<TunnelServer>>,
     clients: Vec<Rc<RefCell<Client>>>,
-    tcp_listener: TcpListener,
+    // the clients of a shard are accepted by another thread
+    tcp_listener: Option<TcpListener>,
     next_client_id: u32,
+    config: Rc<RelayConfig>,
+    metrics: Arc<Metrics>,
+    // the ports of the translated sources, shared by the clients
+    nat_ports: Rc<RefCell<NatPorts>>,
+    paused: bool,
+}
+
+// the data of a hand-off, its sockets are transferred separately
+#[cfg(unix)]
+#[derive(Serialize, Deserialize)]
+struct RelayState {
+    next_client_id: u32,
+    clients: Vec<ClientState>,
 }
 
 impl TunnelServer {
-    pub fn create(port: u16, selector: &mut Selector) -> io::Result<Rc<RefCell<Self>>> {
-        let tcp_listener = Self::start_socket(port)?;
+    pub fn create(
+        selector: &mut Selector,
+        config: Rc<RelayConfig>,
+        metrics: Arc<Metrics>,
+    ) -> io::Result<Rc<RefCell<Self>>> {
+        let tcp_listener = Self::start_socket(config.port())?;
+        let rc = Self::create_shard(config, metrics);
+        Self::listen(&rc, selector, tcp_listener)?;
+        Ok(rc)
+    }
+
+    /// Create a server resuming the listening socket and the clients handed off by another relay
+    /// process.
+    #[cfg(unix)]
+    pub fn restore(
+        selector: &mut Selector,
+        config: Rc<RelayConfig>,
+        metrics: Arc<Metrics>,
+        handoff: Handoff,
+    ) -> io::Result<Rc<RefCell<Self>>> {
+        let (data, fds) = handoff.into_parts();
+        let state: RelayState = serde_json::from_slice(&data)?;
+        let expected = 1 + state
+            .clients
+            .iter()
+            .map(ClientState::socket_count)
+            .sum::<usize>();
+        if fds.len() != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Expected {} sockets, got {}", expected, fds.len()),
+            ));
+        }
+        let mut fds = fds.into_iter();
+        let listener_fd = fds.next().expect("No listening socket");
+        let tcp_listener = TcpListener::from_std(std::net::TcpListener::from(listener_fd))?;
+        let rc = Self::create_shard(config, metrics);
+        Self::listen(&rc, selector, tcp_listener)?;
+        {
+            let mut self_ref = rc.borrow_mut();
+            self_ref.next_client_id = state.next_client_id;
+            for client_state in &state.clients {
+                let client_fds = fds.by_ref().take(client_state.socket_count()).collect();
+                let client = Client::restore(
+                    selector,
+                    client_state,
+                    client_fds,
+                    self_ref.close_listener(),
+                    self_ref.config.clone(),
+                    self_ref.metrics.clone(),
+                    self_ref.nat_ports.clone(),
+                )?;
+                self_ref.clients.push(client);
+            }
+        }
+        info!(target: TAG, "{} clients restored", state.clients.len());
+        Ok(rc)
+    }
+
+    fn listen(
+        rc: &Rc<RefCell<Self>>,
+        selector: &mut Selector,
+        tcp_listener: TcpListener,
+    ) -> io::Result<()> {
+        let rc2 = rc.clone();
+        // must anotate selector type: https://stackoverflow.com/a/44004103/1987178
+        let handler =
+            move |selector: &mut Selector, event| rc2.borrow_mut().on_ready(selector, event);
+        selector.register(&tcp_listener, handler, Ready::readable(), PollOpt::edge())?;
+        rc.borrow_mut().tcp_listener = Some(tcp_listener);
+        Ok(())
+    }
+
+    /// Capture the listening socket and the clients, so that another relay process may resume
+    /// them.
+    ///
+    /// The relay must not run anymore afterwards. The clients which cannot be flushed in time are
+    /// not handed off (they are disconnected once this relay is dropped).
+    #[cfg(unix)]
+    pub fn hand_off(&mut self, now: Instant) -> io::Result<Handoff> {
+        let tcp_listener = self.tcp_listener.as_ref().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Unsupported, "A shard cannot be handed off")
+        })?;
+        // the socket remains owned by this relay until it is dropped
+        let listener_fd = unsafe { BorrowedFd::borrow_raw(tcp_listener.as_raw_fd()) };
+        let mut fds = vec![listener_fd.try_clone_to_owned()?];
+        let mut clients = Vec::new();
+        for client in &self.clients {
+            if let Some((client_state, client_fds)) = client.borrow_mut().hand_off(now)? {
+                clients.push(client_state);
+                fds.extend(client_fds);
+            }
+        }
+        info!(target: TAG, "{} clients handed off", clients.len());
+        let state = RelayState {
+            next_client_id: self.next_client_id,
+            clients,
+        };
+        let data = serde_json::to_vec(&state)?;
+        Ok(Handoff::new(data, fds))
+    }
+
+    /// Create a server without listening: its clients are accepted by another thread and given
+    /// by `add_client()`.
+    pub fn create_shard(config: Rc<RelayConfig>, metrics: Arc<Metrics>) -> Rc<RefCell<Self>> {
         let rc = Rc::new(RefCell::new(Self {
             self_weak: Weak::new(),
             clients: Vec::new(),
-            tcp_listener,
+            tcp_listener: None,
             next_client_id: 0,
+            config,
+            metrics,
+            nat_ports: Rc::new(RefCell::new(NatPorts::new())),
+            paused: false,
         }));
 
         // keep a shared reference to this
         rc.borrow_mut().self_weak = Rc::downgrade(&rc);
+        rc
+    }
 
-        let rc2 = rc.clone();
-        // must anotate selector type: https://stackoverflow.com/a/44004103/1987178
-        let handler =
-            move |selector: &mut Selector, event| rc2.borrow_mut().on_ready(selector, event);
-        selector.register(
-            &rc.borrow().tcp_listener,
-            handler,
-            Ready::readable(),
-            PollOpt::edge(),
-        )?;
-        Ok(rc)
+    /// The address the clients connect to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self.tcp_listener {
+            Some(ref tcp_listener) => tcp_listener.local_addr(),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "The shard does not listen",
+            )),
+        }
     }
 
     fn start_socket(port: u16) -> io::Result<TcpListener> {
@@ -79,11 +216,43 @@ impl TunnelServer {
     }
 
     fn accept_client(&mut self, selector: &mut Selector) -> io::Result<()> {
-        let (stream, _) = self.tcp_listener.accept()?;
+        let (stream, _) = self
+            .tcp_listener
+            .as_ref()
+            .expect("Accepting without listener")
+            .accept()?;
         let client_id = self.next_client_id;
         self.next_client_id += 1;
+        self.add_client(selector, client_id, stream)
+    }
+
+    /// Handle a client accepted (and identified by `client_id`) by another thread.
+    pub fn add_client(
+        &mut self,
+        selector: &mut Selector,
+        client_id: u32,
+        stream: TcpStream,
+    ) -> io::Result<()> {
+        let client = Client::create(
+            client_id,
+            selector,
+            stream,
+            self.close_listener(),
+            self.config.clone(),
+            self.metrics.clone(),
+            self.nat_ports.clone(),
+        )?;
+        if self.paused {
+            client.borrow_mut().set_paused(selector, true);
+        }
+        self.clients.push(client);
+        info!(target: TAG, "Client #{} connected", client_id);
+        Ok(())
+    }
+
+    fn close_listener(&self) -> Box<dyn CloseListener<Client>> {
         let weak = self.self_weak.clone();
-        let on_client_closed = Box::new(move |client: &Client| {
+        Box::new(move |client: &Client| {
             if let Some(rc) = weak.upgrade() {
                 let mut tunnel_server = rc.borrow_mut();
                 tunnel_server.remove_client(client);
@@ -93,15 +262,19 @@ impl TunnelServer {
                     "on_client_closed called but no tunnel_server available"
                 );
             }
-        });
-        let client = Client::create(client_id, selector, stream, on_client_closed)?;
-        self.clients.push(client);
-        info!(target: TAG, "Client #{} connected", client_id);
-        Ok(())
+        })
     }
 
     fn remove_client(&mut self, client: &Client) {
-        info!(target: TAG, "Client #{} disconnected", client.id());
+        match client.address() {
+            Some(address) => info!(
+                target: TAG,
+                "Client #{} ({}) disconnected",
+                client.id(),
+                address
+            ),
+            None => info!(target: TAG, "Client #{} disconnected", client.id()),
+        }
         let index = self
             .clients
             .iter()
@@ -113,6 +286,67 @@ impl TunnelServer {
         self.clients.swap_remove(index);
     }
 
+    /// Stop (or restart) reading the packets sent by all the clients, including the clients
+    /// connected afterwards.
+    pub fn set_paused(&mut self, selector: &mut Selector, paused: bool) {
+        if paused == self.paused {
+            return;
+        }
+        info!(target: TAG, "{}", if paused { "Paused" } else { "Resumed" });
+        self.paused = paused;
+        for client in &self.clients {
+            client.borrow_mut().set_paused(selector, paused);
+        }
+    }
+
+    pub fn connection_stats(&self) -> Vec<(ConnectionId, ConnectionStats, Option<Duration>)> {
+        self.clients
+            .iter()
+            .flat_map(|client| client.borrow_mut().router().connection_stats())
+            .collect()
+    }
+
+    /// A snapshot of the connections of all the clients for which `predicate` returns true.
+    pub fn connections_where<P>(&self, now: Instant, mut predicate: P) -> Vec<ConnectionInfo>
+    where
+        P: FnMut(&ConnectionInfo) -> bool,
+    {
+        self.clients
+            .iter()
+            .flat_map(|client| {
+                client
+                    .borrow_mut()
+                    .router()
+                    .connections_where(now, &mut predicate)
+            })
+            .collect()
+    }
+
+    /// The packets traced for every client (with its id), if tracing is enabled.
+    pub fn trace_entries(&self) -> Vec<(u32, TraceEntry)> {
+        self.clients
+            .iter()
+            .flat_map(|client| {
+                let mut client = client.borrow_mut();
+                let id = client.id();
+                match client.router().trace_ring() {
+                    Some(trace_ring) => trace_ring
+                        .borrow()
+                        .entries()
+                        .map(|entry| (id, entry.clone()))
+                        .collect(),
+                    None => Vec::new(),
+                }
+            })
+            .collect()
+    }
+
+    pub fn reset_connection_stats(&mut self) {
+        for client in &self.clients {
+            client.borrow_mut().router().reset_connection_stats();
+        }
+    }
+
     pub fn clean_up(&mut self, selector: &mut Selector) {
         for client in &self.clients {
             client.borrow_mut().clean_expired_connections(selector);
@@ -18,26 +18,88 @@ use log::*;
 use mio::net::UdpSocket;
 use mio::{Event, PollOpt, Ready, Token};
 use std::cell::RefCell;
+use std::cmp;
 use std::io;
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::{Ipv4Addr, SocketAddrV4};
 use std::rc::{Rc, Weak};
-use std::time::Instant;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use super::binary;
 use super::client::{Client, ClientChannel};
-use super::connection::{Connection, ConnectionId};
+use super::config::RelayConfig;
+use super::connection::{Connection, ConnectionId, ConnectionStats};
 use super::datagram_buffer::DatagramBuffer;
+use super::icmp;
 use super::ipv4_header::Ipv4Header;
 use super::ipv4_packet::{Ipv4Packet, MAX_PACKET_LENGTH};
+use super::metrics::{Counter, Metrics};
+use super::nat;
+use super::net;
+use super::observer::CloseReason;
+use super::overflow::{Overflow, OverflowSlot};
+use super::packet_source::PacketSource;
 use super::packetizer::Packetizer;
-use super::selector::Selector;
+use super::selector::{Selector, TimerId};
+use super::token_bucket::TokenBucket;
 use super::transport_header::TransportHeader;
 
 const TAG: &str = "UdpConnection";
 
 pub const IDLE_TIMEOUT_SECONDS: u64 = 2 * 60;
 
+// QUIC runs over UDP, but its flows are long-lived like TCP connections: they must survive much
+// longer pauses than the other UDP flows
+pub const QUIC_PORT: u16 = 443;
+pub const QUIC_IDLE_TIMEOUT_SECONDS: u64 = 30 * 60;
+
+// delay before retrying to send when the kernel buffers are exhausted
+const SEND_BACKOFF_MILLIS: u64 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Idleness {
+    Active,
+    // idle for longer than the soft timeout, but the socket is kept
+    Grace,
+    Expired,
+}
+
+/// Two-phase idle timeout: a connection idle for longer than the soft timeout is only closed once
+/// the hard timeout (the soft timeout plus the grace period) expires. Any traffic in between makes
+/// it active again.
+struct IdleTimeout {
+    soft: Duration,
+    hard: Duration,
+    idle_since: Instant,
+}
+
+impl IdleTimeout {
+    fn new(soft: Duration, grace: Duration, now: Instant) -> Self {
+        Self {
+            soft,
+            hard: soft + grace,
+            idle_since: now,
+        }
+    }
+
+    fn touch(&mut self, now: Instant) {
+        self.idle_since = now;
+    }
+
+    fn idleness(&self, now: Instant) -> Idleness {
+        let idle = now.saturating_duration_since(self.idle_since);
+        if idle > self.hard {
+            Idleness::Expired
+        } else if idle > self.soft {
+            Idleness::Grace
+        } else {
+            Idleness::Active
+        }
+    }
+}
+
 pub struct UdpConnection {
+    self_weak: Weak<RefCell<UdpConnection>>,
     id: ConnectionId,
     client: Weak<RefCell<Client>>,
     socket: UdpSocket,
@@ -45,24 +107,59 @@ pub struct UdpConnection {
     token: Token,
     client_to_network: DatagramBuffer,
     network_to_client: Packetizer,
-    closed: bool,
-    idle_since: Instant,
+    // datagram from the network waiting for some space in the client queue
+    overflow: OverflowSlot,
+    close_reason: Option<CloseReason>,
+    idle_timeout: IdleTimeout,
+    metrics: Arc<Metrics>,
+    send_backoff_timer: Option<TimerId>,
+    stats: ConnectionStats,
+    throttle: Option<TokenBucket>,
+    throttle_timer: Option<TimerId>,
+    // closes the connection at its maximum lifetime, if limited
+    lifetime_timer: Option<TimerId>,
+    // the headers of the last datagram sent by the client, quoted if the port is unreachable
+    last_datagram_headers: Vec<u8>,
 }
 
 impl UdpConnection {
     #[allow(clippy::needless_pass_by_value)] // semantically, headers are consumed
+    #[allow(clippy::too_many_arguments)]
     pub fn create(
         selector: &mut Selector,
         id: ConnectionId,
+        destination: SocketAddrV4,
         client: Weak<RefCell<Client>>,
+        client_address: Option<Ipv4Addr>,
         ipv4_header: Ipv4Header,
         transport_header: TransportHeader,
+        config: &RelayConfig,
+        metrics: Arc<Metrics>,
     ) -> io::Result<Rc<RefCell<Self>>> {
         cx_info!(target: TAG, id, "Open");
-        let socket = Self::create_socket(&id)?;
-        let packetizer = Packetizer::new(&ipv4_header, &transport_header);
+        if destination != id.rewritten_destination() {
+            cx_info!(target: TAG, id, "Redirected to {}", destination);
+        }
+        let source = nat::upstream_source(&id, config);
+        let socket = net::connect_upstream_udp(source, destination, config)?;
+        let throttle = config
+            .rate_limit(*id.destination().ip())
+            .map(|rate| TokenBucket::new(rate, selector.now()));
+        let mut packetizer = Packetizer::new(&ipv4_header, &transport_header);
+        if let Some(client_address) = client_address {
+            // address the packets to the client as it announced itself
+            packetizer
+                .ipv4_header_mut()
+                .set_destination(u32::from(client_address));
+        }
+        let idle_timeout = IdleTimeout::new(
+            config.udp_idle_timeout(id.destination().port()),
+            config.udp_grace_period().unwrap_or_default(),
+            selector.now(),
+        );
         let interests = Ready::readable();
         let rc = Rc::new(RefCell::new(Self {
+            self_weak: Weak::new(),
             id,
             client,
             socket,
@@ -70,12 +167,22 @@ impl UdpConnection {
             token: Token(0), // default value, will be set afterwards
             client_to_network: DatagramBuffer::new(4 * MAX_PACKET_LENGTH),
             network_to_client: packetizer,
-            closed: false,
-            idle_since: Instant::now(),
+            overflow: OverflowSlot::new(config.overflow_policy()),
+            close_reason: None,
+            idle_timeout,
+            metrics,
+            send_backoff_timer: None,
+            stats: ConnectionStats::default(),
+            throttle,
+            throttle_timer: None,
+            lifetime_timer: None,
+            last_datagram_headers: Vec::new(),
         }));
 
         {
             let mut self_ref = rc.borrow_mut();
+            // keep a shared reference to this
+            self_ref.self_weak = Rc::downgrade(&rc);
 
             let rc2 = rc.clone();
             // must anotate selector type: https://stackoverflow.com/a/44004103/1987178
@@ -88,13 +195,6 @@ impl UdpConnection {
         Ok(rc)
     }
 
-    fn create_socket(id: &ConnectionId) -> io::Result<UdpSocket> {
-        let autobind_addr = SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), 0);
-        let udp_socket = UdpSocket::bind(&autobind_addr)?;
-        udp_socket.connect(id.rewritten_destination().into())?;
-        Ok(udp_socket)
-    }
-
     fn remove_from_router(&self) {
         // route is embedded in router which is embedded in client: the client necessarily exists
         let client_rc = self.client.upgrade().expect("Expected client not found");
@@ -115,24 +215,29 @@ impl UdpConnection {
 
     // return Err(err) with err.kind() == io::ErrorKind::WouldBlock on spurious event
     fn process(&mut self, selector: &mut Selector, event: Event) -> io::Result<()> {
-        if !self.closed {
-            self.touch();
+        if self.close_reason.is_none() {
+            self.touch(selector.now());
             let ready = event.readiness();
             if ready.is_readable() || ready.is_writable() {
                 if ready.is_writable() {
                     self.process_send(selector)?;
                 }
-                if !self.closed && ready.is_readable() {
+                if self.close_reason.is_none() && ready.is_readable() {
                     self.process_receive(selector)?;
                 }
-                if !self.closed {
+                if self.close_reason.is_none() {
                     self.update_interests(selector);
                 }
             } else {
                 // error or hup
-                self.close(selector);
+                match self.socket.take_error() {
+                    Ok(Some(ref err)) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                        self.on_port_unreachable(selector);
+                    }
+                    _ => self.close(selector, CloseReason::UpstreamRst),
+                }
             }
-            if self.closed {
+            if self.close_reason.is_some() {
                 // on_ready is not called from the router, so the connection must remove itself
                 self.remove_from_router();
             }
@@ -145,13 +250,25 @@ impl UdpConnection {
         match self.write() {
             Ok(_) => (),
             Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
-                cx_debug!(target: TAG, self.id, "Spurious event, ignoring")
+                // the datagram is kept, it will be sent on the next writable event
+                cx_debug!(target: TAG, self.id, "Send would block, datagram queued");
+                self.metrics.increment(Counter::UdpSendsBlocked);
+            }
+            Err(ref err) if net::is_no_buffer_space(err) => {
+                // the socket is still writable, wait a bit to avoid a busy loop
+                cx_debug!(
+                    target: TAG,
+                    self.id,
+                    "No buffer space available, retry in {} ms",
+                    SEND_BACKOFF_MILLIS
+                );
+                self.metrics.increment(Counter::UdpSendsBlocked);
+                self.start_send_backoff(selector);
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                self.on_port_unreachable(selector);
             }
             Err(err) => {
-                if err.kind() == io::ErrorKind::WouldBlock {
-                    // rethrow
-                    return Err(err);
-                }
                 cx_error!(
                     target: TAG,
                     self.id,
@@ -159,7 +276,7 @@ impl UdpConnection {
                     err.kind(),
                     err
                 );
-                self.close(selector);
+                self.close(selector, CloseReason::UpstreamRst);
             }
         }
         Ok(())
@@ -174,6 +291,10 @@ impl UdpConnection {
                     // rethrow
                     return Err(err);
                 }
+                if err.kind() == io::ErrorKind::ConnectionRefused {
+                    self.on_port_unreachable(selector);
+                    return Ok(());
+                }
                 cx_error!(
                     target: TAG,
                     self.id,
@@ -181,7 +302,7 @@ impl UdpConnection {
                     err.kind(),
                     err
                 );
-                self.close(selector);
+                self.close(selector, CloseReason::UpstreamRst);
             }
         }
         Ok(())
@@ -189,11 +310,32 @@ impl UdpConnection {
 
     fn read(&mut self, selector: &mut Selector) -> io::Result<()> {
         let ipv4_packet = self.network_to_client.packetize(&mut self.socket)?;
+        if self.overflow.held().is_some() {
+            // the packetizer buffer has been overwritten, the new datagram replaces the held one
+            let overflow = self.overflow.overflow(ipv4_packet.length());
+            debug_assert_eq!(Overflow::Replaced, overflow);
+            cx_debug!(target: TAG, self.id, "Client queue full, drop oldest packet");
+            self.metrics.increment(Counter::ClientQueueOverflows);
+            self.start_throttle_timer(selector);
+            return Ok(());
+        }
+        let payload_length = ipv4_packet.payload().expect("No payload").len();
+        if let Some(ref mut throttle) = self.throttle {
+            throttle.consume(payload_length, selector.now());
+        }
         let client_rc = self.client.upgrade().expect("Expected client not found");
-        match client_rc
-            .borrow_mut()
-            .send_to_client(selector, &ipv4_packet)
-        {
+        let mut client = client_rc.borrow_mut();
+        if client.router().inject_loss_to_client() {
+            cx_debug!(target: TAG, self.id, "Packet dropped to simulate loss");
+            self.start_throttle_timer(selector);
+            return Ok(());
+        }
+        client.router().cache_dns_response(
+            &self.id,
+            ipv4_packet.payload().expect("No payload"),
+            selector.now(),
+        );
+        match client.send_to_client(selector, &ipv4_packet) {
             Ok(_) => {
                 cx_debug!(
                     target: TAG,
@@ -201,6 +343,7 @@ impl UdpConnection {
                     "Packet ({} bytes) sent to client",
                     ipv4_packet.length()
                 );
+                self.stats.count_to_client(payload_length);
                 if log_enabled!(target: TAG, Level::Trace) {
                     cx_trace!(
                         target: TAG,
@@ -210,22 +353,102 @@ impl UdpConnection {
                     );
                 }
             }
-            Err(_) => cx_warn!(target: TAG, self.id, "Cannot send to client, drop packet"),
+            Err(_) => match self.overflow.overflow(ipv4_packet.length()) {
+                Overflow::Held => {
+                    cx_debug!(target: TAG, self.id, "Client queue full, packet held");
+                    let self_rc = self.self_weak.upgrade().unwrap();
+                    client.register_pending_packet_source(self_rc);
+                }
+                Overflow::Replaced => unreachable!("A packet is already held"),
+                Overflow::Dropped => {
+                    cx_warn!(target: TAG, self.id, "Cannot send to client, drop packet");
+                    self.metrics.increment(Counter::ClientQueueOverflows);
+                }
+            },
         }
+        self.start_throttle_timer(selector);
         Ok(())
     }
 
+    // the upstream host rejected a datagram (ICMP port unreachable received on the socket): reply
+    // the same way to the client, so that its socket fails immediately rather than timing out
+    fn on_port_unreachable(&mut self, selector: &mut Selector) {
+        cx_info!(target: TAG, self.id, "Destination port unreachable");
+        if !self.last_datagram_headers.is_empty() {
+            let mut raw = icmp::build_port_unreachable(&self.last_datagram_headers);
+            let mut ipv4_packet = Ipv4Packet::parse(&mut raw);
+            // address the reply to the client as the other packets of this connection
+            let client_address = self.network_to_client.ipv4_header_mut().destination();
+            ipv4_packet.rewrite_destination(client_address);
+            let client_rc = self.client.upgrade().expect("Expected client not found");
+            let mut client = client_rc.borrow_mut();
+            if client.send_to_client(selector, &ipv4_packet).is_err() {
+                cx_warn!(target: TAG, self.id, "Cannot send ICMP error to client");
+            }
+        }
+        self.close(selector, CloseReason::UpstreamRst);
+    }
+
     fn write(&mut self) -> io::Result<()> {
         self.client_to_network.write_to(&mut self.socket)?;
         Ok(())
     }
 
-    fn update_interests(&mut self, selector: &mut Selector) {
-        let ready = if self.client_to_network.is_empty() {
-            Ready::readable()
-        } else {
-            Ready::readable() | Ready::writable()
+    fn start_send_backoff(&mut self, selector: &mut Selector) {
+        if self.send_backoff_timer.is_none() {
+            let weak = self.self_weak.clone();
+            let handler = move |selector: &mut Selector| {
+                if let Some(rc) = weak.upgrade() {
+                    rc.borrow_mut().on_send_backoff_timeout(selector);
+                }
+            };
+            let delay = Duration::from_millis(SEND_BACKOFF_MILLIS);
+            self.send_backoff_timer = Some(selector.set_timer(delay, handler));
+        }
+    }
+
+    fn on_send_backoff_timeout(&mut self, selector: &mut Selector) {
+        self.send_backoff_timer = None;
+        if self.close_reason.is_none() {
+            self.update_interests(selector);
+        }
+    }
+
+    fn start_throttle_timer(&mut self, selector: &mut Selector) {
+        if self.throttle_timer.is_some() {
+            return;
+        }
+        let delay = match self.throttle {
+            Some(ref mut throttle) => throttle.delay(selector.now()),
+            None => None,
         };
+        if let Some(delay) = delay {
+            cx_debug!(target: TAG, self.id, "Throttled for {:?}", delay);
+            let weak = self.self_weak.clone();
+            let handler = move |selector: &mut Selector| {
+                if let Some(rc) = weak.upgrade() {
+                    rc.borrow_mut().on_throttle_timeout(selector);
+                }
+            };
+            self.throttle_timer = Some(selector.set_timer(delay, handler));
+        }
+    }
+
+    fn on_throttle_timeout(&mut self, selector: &mut Selector) {
+        self.throttle_timer = None;
+        if self.close_reason.is_none() {
+            self.update_interests(selector);
+        }
+    }
+
+    fn update_interests(&mut self, selector: &mut Selector) {
+        let mut ready = Ready::empty();
+        if self.throttle_timer.is_none() && self.overflow.may_read() {
+            ready |= Ready::readable();
+        }
+        if !self.client_to_network.is_empty() && self.send_backoff_timer.is_none() {
+            ready |= Ready::writable();
+        }
         cx_debug!(target: TAG, self.id, "interests: {:?}", ready);
         if self.interests != ready {
             // interests must be changed
@@ -236,8 +459,11 @@ impl UdpConnection {
         }
     }
 
-    fn touch(&mut self) {
-        self.idle_since = Instant::now();
+    fn touch(&mut self, now: Instant) {
+        if self.idle_timeout.idleness(now) == Idleness::Grace {
+            cx_debug!(target: TAG, self.id, "Resumed during the grace period");
+        }
+        self.idle_timeout.touch(now);
     }
 }
 
@@ -252,6 +478,11 @@ impl Connection for UdpConnection {
         _: &mut ClientChannel,
         ipv4_packet: &Ipv4Packet,
     ) {
+        let header_length = ipv4_packet.ipv4_header_data().header_length() as usize;
+        let quoted_length = cmp::min(ipv4_packet.raw().len(), header_length + 8);
+        self.last_datagram_headers.clear();
+        self.last_datagram_headers
+            .extend_from_slice(&ipv4_packet.raw()[..quoted_length]);
         match self
             .client_to_network
             .read_from(ipv4_packet.payload().expect("No payload"))
@@ -259,18 +490,30 @@ impl Connection for UdpConnection {
             Ok(_) => {
                 self.update_interests(selector);
             }
-            Err(err) => cx_warn!(
-                target: TAG,
-                self.id,
-                "Cannot send to network, drop packet: {}",
-                err
-            ),
+            Err(err) => {
+                cx_warn!(
+                    target: TAG,
+                    self.id,
+                    "Cannot send to network, drop packet: {}",
+                    err
+                );
+                self.metrics.increment(Counter::UdpDatagramsDropped);
+            }
         }
     }
 
-    fn close(&mut self, selector: &mut Selector) {
+    fn close(&mut self, selector: &mut Selector, reason: CloseReason) {
         cx_info!(target: TAG, self.id, "Close");
-        self.closed = true;
+        self.close_reason = Some(reason);
+        if let Some(timer) = self.lifetime_timer.take() {
+            selector.cancel_timer(timer);
+        }
+        if let Some(timer) = self.send_backoff_timer.take() {
+            selector.cancel_timer(timer);
+        }
+        if let Some(timer) = self.throttle_timer.take() {
+            selector.cancel_timer(timer);
+        }
         if let Err(err) = selector.deregister(&self.socket, self.token) {
             // do not panic, this can happen in mio
             // see <https://github.com/Genymobile/gnirehtet/issues/136>
@@ -284,11 +527,225 @@ impl Connection for UdpConnection {
         // socket will be closed by RAII
     }
 
-    fn is_expired(&self) -> bool {
-        self.idle_since.elapsed().as_secs() > IDLE_TIMEOUT_SECONDS
+    fn is_expired(&self, now: Instant) -> bool {
+        self.idle_timeout.idleness(now) == Idleness::Expired
+    }
+
+    fn close_reason(&self) -> Option<CloseReason> {
+        self.close_reason
+    }
+
+    fn stats(&self) -> &ConnectionStats {
+        &self.stats
+    }
+
+    fn stats_mut(&mut self) -> &mut ConnectionStats {
+        &mut self.stats
     }
 
-    fn is_closed(&self) -> bool {
-        self.closed
+    fn set_lifetime_timer(&mut self, timer: TimerId) {
+        self.lifetime_timer = Some(timer);
+    }
+
+    fn last_activity(&self) -> Option<Instant> {
+        Some(self.idle_timeout.idle_since)
+    }
+}
+
+impl PacketSource for UdpConnection {
+    fn get(&mut self) -> Option<Ipv4Packet> {
+        if let Some(len) = self.overflow.held() {
+            Some(self.network_to_client.inflate(len))
+        } else {
+            None
+        }
+    }
+
+    fn next(&mut self, selector: &mut Selector) {
+        let len = self
+            .overflow
+            .release()
+            .expect("next() called on empty packet source");
+        let payload_length = self
+            .network_to_client
+            .inflate(len)
+            .payload()
+            .expect("No payload")
+            .len();
+        self.stats.count_to_client(payload_length);
+        cx_debug!(
+            target: TAG,
+            self.id,
+            "Deferred packet ({} bytes) sent to client",
+            len
+        );
+        if self.close_reason.is_none() {
+            self.update_interests(selector);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::icmp::{CODE_PORT_UNREACHABLE, TYPE_DESTINATION_UNREACHABLE};
+    use crate::relay::ipv4_header::{Ipv4HeaderData, Protocol, PROTOCOL_ICMP};
+    use crate::relay::tcp_connection::tests::{
+        connect_tunnel, read_packet, start_relay, start_relay_with,
+    };
+    use crate::relay::{DscpRemap, RelayConfigBuilder};
+    use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+    use std::io::Write;
+    use std::net::UdpSocket as StdUdpSocket;
+
+    const CLIENT_PORT: u16 = 42000;
+
+    /// Create a UDP packet sent by the client to `port` on localhost.
+    fn create_udp_packet(port: u16, dscp: u8, payload: &[u8]) -> Vec<u8> {
+        let udp_length = 8 + payload.len() as u16;
+        let mut raw = vec![0; 20];
+        Ipv4HeaderData::init(&mut raw, Protocol::Udp, 0x0a000002, 0x7f000001, udp_length);
+        raw[1] = dscp << 2;
+        raw.write_u16::<BigEndian>(CLIENT_PORT).unwrap(); // source port
+        raw.write_u16::<BigEndian>(port).unwrap(); // destination port
+        raw.write_u16::<BigEndian>(udp_length).unwrap(); // length
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.extend_from_slice(payload);
+        raw
+    }
+
+    // the idle timeout of a new UDP connection to `port` on localhost
+    fn idle_timeout_to_port(port: u16) -> Duration {
+        let mut selector = Selector::create().unwrap();
+        let mut raw = create_udp_packet(port, 0, &[]);
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        let (ipv4_header_data, transport_header_data) = ipv4_packet.headers_data();
+        let id = ConnectionId::from_headers(ipv4_header_data, transport_header_data.unwrap());
+        let destination = id.rewritten_destination();
+        let (ipv4_header, transport_header) = ipv4_packet.headers();
+        let connection = UdpConnection::create(
+            &mut selector,
+            id,
+            destination,
+            Weak::new(),
+            None,
+            ipv4_header,
+            transport_header.unwrap(),
+            &RelayConfigBuilder::new(0).build(),
+            Arc::new(Metrics::new()),
+        )
+        .unwrap();
+        let soft = connection.borrow().idle_timeout.soft;
+        soft
+    }
+
+    #[test]
+    fn long_idle_timeout_for_quic() {
+        assert_eq!(
+            Duration::from_secs(QUIC_IDLE_TIMEOUT_SECONDS),
+            idle_timeout_to_port(QUIC_PORT)
+        );
+        assert_eq!(
+            Duration::from_secs(IDLE_TIMEOUT_SECONDS),
+            idle_timeout_to_port(12345)
+        );
+    }
+
+    const SOFT: Duration = Duration::from_secs(120);
+    const GRACE: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn traffic_during_grace_period_keeps_connection() {
+        let start = Instant::now();
+        let mut idle_timeout = IdleTimeout::new(SOFT, GRACE, start);
+        assert_eq!(Idleness::Active, idle_timeout.idleness(start + SOFT));
+
+        let resumed = start + SOFT + GRACE / 2;
+        assert_eq!(Idleness::Grace, idle_timeout.idleness(resumed));
+        idle_timeout.touch(resumed);
+        assert_eq!(Idleness::Active, idle_timeout.idleness(resumed));
+        // the timeouts restart from the last traffic
+        let later = start + SOFT + GRACE + Duration::from_secs(1);
+        assert_eq!(Idleness::Active, idle_timeout.idleness(later));
+    }
+
+    #[test]
+    fn hard_timeout_expires() {
+        let start = Instant::now();
+        let idle_timeout = IdleTimeout::new(SOFT, GRACE, start);
+        assert_eq!(Idleness::Grace, idle_timeout.idleness(start + SOFT + GRACE));
+        let expired = start + SOFT + GRACE + Duration::from_secs(1);
+        assert_eq!(Idleness::Expired, idle_timeout.idleness(expired));
+    }
+
+    #[test]
+    fn no_grace_period() {
+        let start = Instant::now();
+        let idle_timeout = IdleTimeout::new(SOFT, Duration::default(), start);
+        assert_eq!(Idleness::Active, idle_timeout.idleness(start + SOFT));
+        let expired = start + SOFT + Duration::from_secs(1);
+        assert_eq!(Idleness::Expired, idle_timeout.idleness(expired));
+    }
+
+    #[test]
+    fn reply_port_unreachable() {
+        let relay_port = start_relay();
+
+        // nothing listens on this port once the socket is closed
+        let port = StdUdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut tunnel = connect_tunnel(relay_port);
+
+        let raw = create_udp_packet(port, 0, &[1, 2, 3, 4]);
+        tunnel.write_all(&raw).unwrap();
+
+        let reply = read_packet(&mut tunnel);
+        let ipv4_header = Ipv4HeaderData::parse(&reply);
+        assert_eq!(Protocol::Other(PROTOCOL_ICMP), ipv4_header.protocol());
+        assert_eq!(0x7f000001, ipv4_header.source());
+        assert_eq!(0x0a000002, ipv4_header.destination());
+        let icmp = &reply[20..];
+        assert_eq!(TYPE_DESTINATION_UNREACHABLE, icmp[0]);
+        assert_eq!(CODE_PORT_UNREACHABLE, icmp[1]);
+        // the quoted datagram identifies the client socket
+        let quoted_udp = &icmp[8 + 20..];
+        assert_eq!(CLIENT_PORT, BigEndian::read_u16(&quoted_udp[0..2]));
+        assert_eq!(port, BigEndian::read_u16(&quoted_udp[2..4]));
+    }
+
+    #[test]
+    fn remap_dscp_in_both_directions() {
+        let relay_port = start_relay_with(|builder| {
+            let dscp_remap = DscpRemap::new().map(46, 10).map(10, 12);
+            builder.dscp_remap(dscp_remap)
+        });
+
+        let server = StdUdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let port = server.local_addr().unwrap().port();
+        let mut tunnel = connect_tunnel(relay_port);
+
+        tunnel
+            .write_all(&create_udp_packet(port, 46, &[1, 2, 3, 4]))
+            .unwrap();
+        let mut buf = [0; 16];
+        let (len, peer) = server.recv_from(&mut buf).unwrap();
+        assert_eq!([1, 2, 3, 4], buf[..len]);
+        server.send_to(&[5, 6], peer).unwrap();
+
+        let mut reply = read_packet(&mut tunnel);
+        // the header of the replies is built from the one of the packets received from the client,
+        // remapped (46 -> 10), then remapped again when sent to the client (10 -> 12)
+        let mut ipv4_packet = Ipv4Packet::parse(&mut reply);
+        assert_eq!(12, ipv4_packet.ipv4_header().dscp());
+        assert_eq!([5, 6], ipv4_packet.payload().unwrap());
+        let checksum = BigEndian::read_u16(&ipv4_packet.raw()[10..12]);
+        ipv4_packet.ipv4_header_mut().update_checksum();
+        assert_eq!(checksum, BigEndian::read_u16(&ipv4_packet.raw()[10..12]));
     }
 }
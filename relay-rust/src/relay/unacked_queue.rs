@@ -0,0 +1,215 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::VecDeque;
+use std::time::Instant;
+
+/// Segments sent to the client but not acknowledged yet.
+///
+/// Segments are removed once they are covered by a cumulative ACK or by a SACK block (RFC 2018),
+/// so that only the segments which are really missing on the client side are kept.
+///
+/// Their send time is kept to measure the round-trip time. The relay does not retransmit, but the
+/// segments still in flight when the ACK timer expires are handled as if they were retransmitted
+/// (Karn's algorithm): their ACK does not provide a reliable sample.
+pub struct UnackedQueue {
+    segments: VecDeque<Segment>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Segment {
+    sequence_number: u32,
+    length: u32,
+    sent: Instant,
+    timed_out: bool,
+}
+
+impl Segment {
+    fn end(&self) -> u32 {
+        self.sequence_number.wrapping_add(self.length)
+    }
+}
+
+// compare sequence numbers, taking into account that they wrap around (RFC 1982)
+pub fn seq_le(lhs: u32, rhs: u32) -> bool {
+    rhs.wrapping_sub(lhs) as i32 >= 0
+}
+
+#[allow(dead_code)]
+impl UnackedQueue {
+    pub fn new() -> Self {
+        Self {
+            segments: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, sequence_number: u32, length: u32, sent: Instant) {
+        if length > 0 {
+            self.segments.push_back(Segment {
+                sequence_number,
+                length,
+                sent,
+                timed_out: false,
+            });
+        }
+    }
+
+    /// Remove the segments entirely acknowledged by `acknowledgement_number`.
+    ///
+    /// Return the send time of the most recent segment acknowledged which did not time out, to
+    /// measure a round-trip time sample.
+    pub fn ack(&mut self, acknowledgement_number: u32) -> Option<Instant> {
+        let mut sample = None;
+        while let Some(segment) = self.segments.front() {
+            if !seq_le(segment.end(), acknowledgement_number) {
+                break;
+            }
+            if !segment.timed_out {
+                sample = Some(segment.sent);
+            }
+            self.segments.pop_front();
+        }
+        sample
+    }
+
+    /// Remove the segments entirely covered by the SACK block `[left_edge, right_edge)`.
+    pub fn sack(&mut self, left_edge: u32, right_edge: u32) {
+        if seq_le(right_edge, left_edge) {
+            // empty or invalid block
+            return;
+        }
+        self.segments.retain(|segment| {
+            !seq_le(left_edge, segment.sequence_number) || !seq_le(segment.end(), right_edge)
+        });
+    }
+
+    /// Mark all the segments in flight as timed out, so that they provide no round-trip time
+    /// sample.
+    pub fn time_out(&mut self) {
+        for segment in &mut self.segments {
+            segment.timed_out = true;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.segments.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    pub fn bytes_in_flight(&self) -> u32 {
+        self.segments.iter().map(|segment| segment.length).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn create_queue(first_sequence_number: u32) -> UnackedQueue {
+        let mut queue = UnackedQueue::new();
+        let now = Instant::now();
+        queue.push(first_sequence_number, 100, now);
+        queue.push(first_sequence_number.wrapping_add(100), 100, now);
+        queue.push(first_sequence_number.wrapping_add(200), 100, now);
+        queue
+    }
+
+    fn sequence_numbers(queue: &UnackedQueue) -> Vec<u32> {
+        queue
+            .segments
+            .iter()
+            .map(|segment| segment.sequence_number)
+            .collect()
+    }
+
+    #[test]
+    fn sack_middle_segment() {
+        let mut queue = create_queue(1000);
+        queue.sack(1100, 1200);
+        assert_eq!(vec![1000, 1200], sequence_numbers(&queue));
+        assert_eq!(200, queue.bytes_in_flight());
+    }
+
+    #[test]
+    fn sack_partial_segment() {
+        let mut queue = create_queue(1000);
+        // the block does not cover any segment entirely
+        queue.sack(1050, 1150);
+        assert_eq!(3, queue.len());
+    }
+
+    #[test]
+    fn sack_invalid_block() {
+        let mut queue = create_queue(1000);
+        queue.sack(1200, 1100);
+        assert_eq!(3, queue.len());
+    }
+
+    #[test]
+    fn cumulative_ack() {
+        let mut queue = create_queue(1000);
+        queue.ack(1150);
+        assert_eq!(vec![1100, 1200], sequence_numbers(&queue));
+        queue.ack(1300);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn ack_after_sack() {
+        let mut queue = create_queue(1000);
+        queue.sack(1200, 1300);
+        queue.ack(1100);
+        assert_eq!(vec![1100], sequence_numbers(&queue));
+    }
+
+    #[test]
+    fn sequence_number_wrapping() {
+        let mut queue = create_queue(0xFFFF_FF9C); // -100
+        queue.sack(0, 100);
+        assert_eq!(vec![0xFFFF_FF9C, 100], sequence_numbers(&queue));
+        queue.ack(0);
+        assert_eq!(vec![100], sequence_numbers(&queue));
+    }
+
+    #[test]
+    fn rtt_sample_of_latest_segment() {
+        let mut queue = UnackedQueue::new();
+        let start = Instant::now();
+        let later = start + Duration::from_millis(10);
+        queue.push(1000, 100, start);
+        queue.push(1100, 100, later);
+        queue.push(1200, 100, later + Duration::from_millis(10));
+        assert_eq!(Some(later), queue.ack(1200));
+        // no segment entirely acknowledged
+        assert_eq!(None, queue.ack(1250));
+    }
+
+    #[test]
+    fn no_rtt_sample_after_timeout() {
+        let mut queue = UnackedQueue::new();
+        let start = Instant::now();
+        queue.push(1000, 100, start);
+        queue.time_out();
+        let later = start + Duration::from_millis(10);
+        queue.push(1100, 100, later);
+        assert_eq!(None, queue.ack(1100));
+        assert_eq!(Some(later), queue.ack(1200));
+    }
+}
@@ -0,0 +1,207 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::io;
+use std::net::{SocketAddrV4, TcpStream, UdpSocket};
+
+/// Factory of the upstream sockets, replacing the ones created by the relay (e.g. to route some
+/// destinations differently, or to intercept the traffic).
+///
+/// The sockets are made non-blocking by the relay. They are not configured by the relay (buffer
+/// sizes, mark or external address): the factory is responsible for their whole setup.
+pub trait UpstreamFactory {
+    /// Create a TCP stream connected (or connecting) to `destination`.
+    ///
+    /// It is called from the event loop, so a blocking connection blocks the whole relay.
+    fn connect_tcp(&self, destination: SocketAddrV4) -> io::Result<TcpStream>;
+
+    /// Create a bound UDP socket to send datagrams to `destination` (the relay connects it).
+    fn bind_udp(&self, destination: SocketAddrV4) -> io::Result<UdpSocket>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::config::RelayConfigBuilder;
+    use crate::relay::metrics::Counter;
+    use crate::relay::net;
+    use crate::relay::relay::Relay;
+    use crate::relay::tcp_connection::tests::{
+        connect_tunnel, create_tcp_packet, free_port, handshake, start_relay_with, CLIENT_SEQ,
+    };
+    use crate::relay::tcp_header::{FLAG_RST, FLAG_SYN};
+    use std::io::Write;
+    use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+    use std::rc::Rc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    // connect every TCP stream to `tcp_target` (whatever the destination), and count the sockets
+    #[derive(Default)]
+    struct LoopbackFactory {
+        tcp_target: Option<SocketAddrV4>,
+        tcp_count: Arc<AtomicUsize>,
+        udp_count: Arc<AtomicUsize>,
+    }
+
+    impl UpstreamFactory for LoopbackFactory {
+        fn connect_tcp(&self, _: SocketAddrV4) -> io::Result<TcpStream> {
+            self.tcp_count.fetch_add(1, Ordering::SeqCst);
+            TcpStream::connect(self.tcp_target.unwrap())
+        }
+
+        fn bind_udp(&self, _: SocketAddrV4) -> io::Result<UdpSocket> {
+            self.udp_count.fetch_add(1, Ordering::SeqCst);
+            UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        }
+    }
+
+    fn local_addr_v4(address: SocketAddr) -> SocketAddrV4 {
+        match address {
+            SocketAddr::V4(address) => address,
+            _ => panic!("Not an IPv4 address"),
+        }
+    }
+
+    #[test]
+    fn create_tcp_stream_per_connection() {
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let tcp_target = local_addr_v4(server.local_addr().unwrap());
+        let tcp_count = Arc::new(AtomicUsize::new(0));
+        let relay_port = {
+            let tcp_count = tcp_count.clone();
+            start_relay_with(move |builder| {
+                let factory = LoopbackFactory {
+                    tcp_target: Some(tcp_target),
+                    tcp_count,
+                    ..Default::default()
+                };
+                builder.upstream_factory(Rc::new(factory))
+            })
+        };
+
+        // nothing listens on this port, the connection succeeds only through the factory
+        let port = free_port();
+        let mut tunnel = connect_tunnel(relay_port);
+        let relay_seq = handshake(&mut tunnel, port, 0xffff);
+        let _upstream1 = server.accept().unwrap();
+        assert_eq!(1, tcp_count.load(Ordering::SeqCst));
+
+        let rst = create_tcp_packet(port, CLIENT_SEQ + 1, relay_seq, FLAG_RST, 0xffff, &[]);
+        tunnel.write_all(&rst).unwrap();
+        handshake(&mut tunnel, port, 0xffff);
+        let _upstream2 = server.accept().unwrap();
+        assert_eq!(2, tcp_count.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn connect_udp_socket_from_factory() {
+        let udp_count = Arc::new(AtomicUsize::new(0));
+        let factory = LoopbackFactory {
+            udp_count: udp_count.clone(),
+            ..Default::default()
+        };
+        let config = RelayConfigBuilder::new(0)
+            .upstream_factory(Rc::new(factory))
+            .build();
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let destination = local_addr_v4(server.local_addr().unwrap());
+
+        let socket = net::connect_upstream_udp(None, destination, &config).unwrap();
+        assert_eq!(1, udp_count.load(Ordering::SeqCst));
+        // connected by the relay, so send() is allowed
+        socket.send(b"test").unwrap();
+        let mut buf = [0; 4];
+        let (_, source) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(socket.local_addr().unwrap(), source);
+
+        net::connect_upstream_udp(None, destination, &config).unwrap();
+        assert_eq!(2, udp_count.load(Ordering::SeqCst));
+    }
+
+    // fail the first TCP connection as if the process had no file descriptor left
+    struct ExhaustedFactory {
+        tcp_target: SocketAddrV4,
+        tcp_count: Arc<AtomicUsize>,
+    }
+
+    impl UpstreamFactory for ExhaustedFactory {
+        fn connect_tcp(&self, _: SocketAddrV4) -> io::Result<TcpStream> {
+            if self.tcp_count.fetch_add(1, Ordering::SeqCst) == 0 {
+                return Err(io::Error::from_raw_os_error(libc::EMFILE));
+            }
+            TcpStream::connect(self.tcp_target)
+        }
+
+        fn bind_udp(&self, _: SocketAddrV4) -> io::Result<UdpSocket> {
+            UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        }
+    }
+
+    #[test]
+    fn back_off_when_descriptors_exhausted() {
+        let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let tcp_target = local_addr_v4(server.local_addr().unwrap());
+        let tcp_count = Arc::new(AtomicUsize::new(0));
+        let (sender, receiver) = mpsc::channel();
+        {
+            let tcp_count = tcp_count.clone();
+            thread::spawn(move || {
+                let factory = ExhaustedFactory {
+                    tcp_target,
+                    tcp_count,
+                };
+                let relay = Relay::with_config(
+                    RelayConfigBuilder::new(0)
+                        .upstream_factory(Rc::new(factory))
+                        .build(),
+                );
+                let relay_port = relay.local_addr().unwrap().port();
+                sender.send((relay_port, relay.metrics())).unwrap();
+                relay.run().unwrap();
+            });
+        }
+        let (relay_port, metrics) = receiver.recv().unwrap();
+        let mut tunnel = connect_tunnel(relay_port);
+
+        let wait_for = |counter, value| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while metrics.get(counter) < value {
+                assert!(Instant::now() < deadline, "{} not reached", counter.name());
+                thread::sleep(Duration::from_millis(5));
+            }
+        };
+
+        let syn = create_tcp_packet(free_port(), CLIENT_SEQ, 0, FLAG_SYN, 0xffff, &[]);
+        tunnel.write_all(&syn).unwrap();
+        wait_for(Counter::DescriptorsExhausted, 1);
+
+        // no socket creation is attempted for the new connections in the meantime
+        let syn = create_tcp_packet(free_port(), CLIENT_SEQ, 0, FLAG_SYN, 0xffff, &[]);
+        tunnel.write_all(&syn).unwrap();
+        wait_for(Counter::DroppedPackets, 2);
+        assert_eq!(1, tcp_count.load(Ordering::SeqCst));
+        assert_eq!(1, metrics.get(Counter::DescriptorsExhausted));
+
+        // the connections are created again once the delay has expired
+        thread::sleep(Duration::from_millis(1100));
+        handshake(&mut tunnel, free_port(), 0xffff);
+        let _upstream = server.accept().unwrap();
+        assert_eq!(2, tcp_count.load(Ordering::SeqCst));
+    }
+}
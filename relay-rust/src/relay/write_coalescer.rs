@@ -0,0 +1,154 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::time::Duration;
+
+use super::ipv4_packet::MAX_PACKET_LENGTH;
+
+/// Decide when the packets buffered for a client must be written to the client socket.
+///
+/// When a window is set, small packets are held for at most this delay, so that they are written
+/// to the client socket as a batch. A packet with the PSH flag forces an immediate flush.
+pub struct WriteCoalescer {
+    window: Option<Duration>,
+    holding: bool,
+    pending_bytes: usize,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Write the buffered packets now.
+    Flush,
+    /// Hold the buffered packets, and flush them after the given delay.
+    StartTimer(Duration),
+    /// Hold the buffered packets (a timer is already pending).
+    Wait,
+}
+
+impl WriteCoalescer {
+    pub fn new(window: Option<Duration>) -> Self {
+        Self {
+            window,
+            holding: false,
+            pending_bytes: 0,
+        }
+    }
+
+    /// Called when a packet of `length` bytes has been added to the client buffer.
+    ///
+    /// If the buffer already contained data being written, there is no need to wait: the new
+    /// packet will be written along with them.
+    pub fn on_packet(&mut self, length: usize, push: bool, was_empty: bool) -> Action {
+        let window = match self.window {
+            Some(window) => window,
+            None => return Action::Flush,
+        };
+        self.pending_bytes += length;
+        if push || self.pending_bytes >= MAX_PACKET_LENGTH || (!self.holding && !was_empty) {
+            self.reset();
+            Action::Flush
+        } else if self.holding {
+            Action::Wait
+        } else {
+            self.holding = true;
+            Action::StartTimer(window)
+        }
+    }
+
+    /// Called when the timer started on `Action::StartTimer` expired.
+    pub fn on_timeout(&mut self) {
+        self.reset();
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.window.is_some()
+    }
+
+    /// Indicate whether the buffered packets must not be written yet.
+    pub fn is_holding(&self) -> bool {
+        self.holding
+    }
+
+    fn reset(&mut self) {
+        self.holding = false;
+        self.pending_bytes = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_millis(5);
+
+    #[test]
+    fn disabled() {
+        let mut coalescer = WriteCoalescer::new(None);
+        assert_eq!(Action::Flush, coalescer.on_packet(40, false, true));
+        assert!(!coalescer.is_holding());
+    }
+
+    #[test]
+    fn batch_small_packets() {
+        let mut coalescer = WriteCoalescer::new(Some(WINDOW));
+        assert_eq!(
+            Action::StartTimer(WINDOW),
+            coalescer.on_packet(40, false, true)
+        );
+        assert_eq!(Action::Wait, coalescer.on_packet(40, false, false));
+        assert_eq!(Action::Wait, coalescer.on_packet(40, false, false));
+        assert!(coalescer.is_holding());
+
+        coalescer.on_timeout();
+        assert!(!coalescer.is_holding());
+        assert_eq!(
+            Action::StartTimer(WINDOW),
+            coalescer.on_packet(40, false, true)
+        );
+    }
+
+    #[test]
+    fn push_flushes_immediately() {
+        let mut coalescer = WriteCoalescer::new(Some(WINDOW));
+        assert_eq!(
+            Action::StartTimer(WINDOW),
+            coalescer.on_packet(40, false, true)
+        );
+        assert_eq!(Action::Flush, coalescer.on_packet(100, true, false));
+        assert!(!coalescer.is_holding());
+    }
+
+    #[test]
+    fn flush_when_enough_bytes() {
+        let mut coalescer = WriteCoalescer::new(Some(WINDOW));
+        assert_eq!(
+            Action::StartTimer(WINDOW),
+            coalescer.on_packet(40, false, true)
+        );
+        assert_eq!(
+            Action::Flush,
+            coalescer.on_packet(MAX_PACKET_LENGTH, false, false)
+        );
+        assert!(!coalescer.is_holding());
+    }
+
+    #[test]
+    fn do_not_hold_data_being_written() {
+        let mut coalescer = WriteCoalescer::new(Some(WINDOW));
+        // the buffer is not empty, and is not held
+        assert_eq!(Action::Flush, coalescer.on_packet(40, false, false));
+    }
+}
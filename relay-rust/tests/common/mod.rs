@@ -0,0 +1,366 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Harness to run the whole relay in-process and drive it from a fake client over loopback.
+//!
+//! The relay runs on its own thread. The fake client speaks the tunnel protocol: it reads the
+//! client id, then writes and reads raw IPv4 packets.
+
+#![allow(dead_code)]
+
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use relaylib::packet::ipv4_packet::Ipv4Packet;
+use relaylib::packet::tcp_header::{FLAG_ACK, FLAG_FIN, FLAG_PSH, FLAG_SYN};
+use relaylib::packet::transport_header::TransportHeader;
+use relaylib::{Relay, RelayConfigBuilder};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, SocketAddrV4, TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// The address of the fake client on its tunnel interface.
+pub const CLIENT_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+// every blocking read fails after this delay instead of hanging the test
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+const WINDOW: u16 = 0xffff;
+
+/// Start a relay with the default configuration, and return its port.
+pub fn start_relay() -> u16 {
+    start_relay_with(|builder| builder)
+}
+
+/// Start a relay configured by `configure`, and return its port.
+pub fn start_relay_with<F>(configure: F) -> u16
+where
+    F: FnOnce(RelayConfigBuilder) -> RelayConfigBuilder + Send + 'static,
+{
+    spawn_relay(configure).0
+}
+
+/// Start a relay configured by `configure` with a control server, and return its port and the
+/// port of the control server.
+pub fn start_controlled_relay_with<F>(configure: F) -> (u16, u16)
+where
+    F: FnOnce(RelayConfigBuilder) -> RelayConfigBuilder + Send + 'static,
+{
+    let (port, control_port) = spawn_relay(move |builder| configure(builder).control_port(0));
+    (port, control_port.unwrap())
+}
+
+// start a relay on ports chosen by the system, and return them once bound
+//
+// the configuration is built on the relay thread (it is not `Send`)
+fn spawn_relay<F>(configure: F) -> (u16, Option<u16>)
+where
+    F: FnOnce(RelayConfigBuilder) -> RelayConfigBuilder + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let config = configure(RelayConfigBuilder::new(0)).build();
+        let relay = Relay::with_config(config);
+        let port = relay.local_addr().unwrap().port();
+        let control_port = relay.control_addr().unwrap().map(|addr| addr.port());
+        sender.send((port, control_port)).unwrap();
+        relay.run().unwrap();
+    });
+    receiver.recv().unwrap()
+}
+
+/// Send `command` to the control server of the relay listening on `control_port`, and return the
+/// lines of its response (up to the final `OK` or `ERROR` line).
+pub fn control(control_port: u16, command: &str) -> Vec<String> {
+    let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, control_port)).unwrap();
+    stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+    writeln!(stream, "{}", command).unwrap();
+    let mut lines = Vec::new();
+    for line in BufReader::new(stream).lines() {
+        let line = line.unwrap();
+        let last = line == "OK" || line.starts_with("ERROR");
+        lines.push(line);
+        if last {
+            break;
+        }
+    }
+    lines
+}
+
+/// Start a TCP server on localhost echoing everything it receives, and return its port.
+pub fn start_echo_server() -> u16 {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            thread::spawn(move || {
+                let mut buf = [0; 4096];
+                loop {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(r) => {
+                            if stream.write_all(&buf[..r]).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+            });
+        }
+    });
+    port
+}
+
+/// A TCP packet received from the relay.
+#[derive(Debug)]
+pub struct TcpSegment {
+    pub source: SocketAddrV4,
+    pub destination: SocketAddrV4,
+    pub sequence_number: u32,
+    pub acknowledgement_number: u32,
+    pub flags: u16,
+    pub payload: Vec<u8>,
+}
+
+/// A client connected to the tunnel of a relay.
+pub struct FakeClient {
+    tunnel: TcpStream,
+    id: u32,
+}
+
+impl FakeClient {
+    /// Connect to the relay listening on `relay_port`, waiting for it to be started.
+    pub fn connect(relay_port: u16) -> Self {
+        for _ in 0..100 {
+            if let Ok(mut tunnel) = TcpStream::connect((Ipv4Addr::LOCALHOST, relay_port)) {
+                tunnel.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+                let mut raw_id = [0; 4];
+                tunnel.read_exact(&mut raw_id).unwrap();
+                let id = BigEndian::read_u32(&raw_id);
+                return Self { tunnel, id };
+            }
+            // the relay is not listening yet
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("Cannot connect to the relay");
+    }
+
+    /// The id assigned by the relay.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn send_packet(&mut self, raw: &[u8]) {
+        self.tunnel.write_all(raw).unwrap();
+    }
+
+    pub fn read_packet(&mut self) -> Vec<u8> {
+        let mut raw = vec![0; 4];
+        self.tunnel.read_exact(&mut raw).unwrap();
+        let length = BigEndian::read_u16(&raw[2..4]) as usize;
+        raw.resize(length, 0);
+        self.tunnel.read_exact(&mut raw[4..]).unwrap();
+        raw
+    }
+
+    /// Read the next packet, which must be a TCP packet.
+    pub fn read_tcp_segment(&mut self) -> TcpSegment {
+        let mut raw = self.read_packet();
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        let ipv4_header = ipv4_packet.ipv4_header();
+        let source = Ipv4Addr::from(ipv4_header.source());
+        let destination = Ipv4Addr::from(ipv4_header.destination());
+        match ipv4_packet.transport_header() {
+            Some(TransportHeader::Tcp(tcp_header)) => TcpSegment {
+                source: SocketAddrV4::new(source, tcp_header.source_port()),
+                destination: SocketAddrV4::new(destination, tcp_header.destination_port()),
+                sequence_number: tcp_header.sequence_number(),
+                acknowledgement_number: tcp_header.acknowledgement_number(),
+                flags: tcp_header.flags(),
+                payload: ipv4_packet.payload().unwrap().to_vec(),
+            },
+            _ => panic!("Not a TCP packet"),
+        }
+    }
+}
+
+/// A TCP connection opened by a fake client through the relay.
+pub struct TcpFlow {
+    source: SocketAddrV4,
+    destination: SocketAddrV4,
+    // the next sequence numbers of each side
+    client_seq: u32,
+    relay_seq: u32,
+}
+
+impl TcpFlow {
+    /// Open a connection from `source_port` to `destination` with the 3-way handshake.
+    ///
+    /// The SYN-ACK from the relay must acknowledge the SYN.
+    pub fn open(client: &mut FakeClient, source_port: u16, destination: SocketAddrV4) -> Self {
+        let mut flow = Self {
+            source: SocketAddrV4::new(CLIENT_ADDRESS, source_port),
+            destination,
+            client_seq: 1000,
+            relay_seq: 0,
+        };
+        flow.send(client, FLAG_SYN, &[]);
+        flow.client_seq += 1; // the SYN counts for 1 byte
+
+        let syn_ack = client.read_tcp_segment();
+        assert_eq!(FLAG_SYN | FLAG_ACK, syn_ack.flags);
+        assert_eq!(flow.destination, syn_ack.source);
+        assert_eq!(flow.source, syn_ack.destination);
+        assert_eq!(flow.client_seq, syn_ack.acknowledgement_number);
+        flow.relay_seq = syn_ack.sequence_number.wrapping_add(1);
+
+        flow.send(client, FLAG_ACK, &[]);
+        flow
+    }
+
+    /// Send `data` in a single segment.
+    pub fn write(&mut self, client: &mut FakeClient, data: &[u8]) {
+        self.send(client, FLAG_ACK | FLAG_PSH, data);
+        self.client_seq = self.client_seq.wrapping_add(data.len() as u32);
+    }
+
+    /// Read `length` bytes of data from the relay, acknowledging them.
+    ///
+    /// The segments without payload (e.g. the acknowledgements of our data) are ignored.
+    pub fn read(&mut self, client: &mut FakeClient, length: usize) -> Vec<u8> {
+        let mut data = Vec::with_capacity(length);
+        while data.len() < length {
+            let segment = client.read_tcp_segment();
+            assert_eq!(self.destination, segment.source);
+            assert_eq!(0, segment.flags & FLAG_FIN, "Unexpected FIN");
+            if segment.payload.is_empty() {
+                continue;
+            }
+            assert_eq!(self.relay_seq, segment.sequence_number);
+            self.relay_seq = self.relay_seq.wrapping_add(segment.payload.len() as u32);
+            data.extend_from_slice(&segment.payload);
+            self.send(client, FLAG_ACK, &[]);
+        }
+        data
+    }
+
+    fn send(&self, client: &mut FakeClient, flags: u16, payload: &[u8]) {
+        let mut raw = create_tcp_packet(
+            self.source,
+            self.destination,
+            self.client_seq,
+            self.relay_seq,
+            flags,
+            payload,
+        );
+        Ipv4Packet::parse(&mut raw).compute_checksums();
+        client.send_packet(&raw);
+    }
+}
+
+/// Create a TCP packet (without options), with its checksums unset.
+pub fn create_tcp_packet(
+    source: SocketAddrV4,
+    destination: SocketAddrV4,
+    seq: u32,
+    ack: u32,
+    flags: u16,
+    payload: &[u8],
+) -> Vec<u8> {
+    let total_length = 40 + payload.len() as u16;
+    let mut raw = Vec::new();
+    raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
+    raw.write_u8(0).unwrap(); // ToS
+    raw.write_u16::<BigEndian>(total_length).unwrap();
+    raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+    raw.write_u8(64).unwrap(); // TTL
+    raw.write_u8(6).unwrap(); // protocol (TCP)
+    raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+    raw.write_u32::<BigEndian>(u32::from(*source.ip())).unwrap();
+    raw.write_u32::<BigEndian>(u32::from(*destination.ip()))
+        .unwrap();
+
+    raw.write_u16::<BigEndian>(source.port()).unwrap();
+    raw.write_u16::<BigEndian>(destination.port()).unwrap();
+    raw.write_u32::<BigEndian>(seq).unwrap(); // sequence number
+    raw.write_u32::<BigEndian>(ack).unwrap(); // acknowledgement number
+    raw.write_u16::<BigEndian>(5 << 12 | flags).unwrap(); // data offset + flags
+    raw.write_u16::<BigEndian>(WINDOW).unwrap(); // window
+    raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+    raw.write_u16::<BigEndian>(0).unwrap(); // urgent pointer
+
+    raw.extend_from_slice(payload);
+    raw
+}
+
+/// Create a UDP packet, with its checksums unset.
+pub fn create_udp_packet(
+    source: SocketAddrV4,
+    destination: SocketAddrV4,
+    payload: &[u8],
+) -> Vec<u8> {
+    let udp_length = 8 + payload.len() as u16;
+    let mut raw = Vec::new();
+    raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
+    raw.write_u8(0).unwrap(); // ToS
+    raw.write_u16::<BigEndian>(20 + udp_length).unwrap(); // total length
+    raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+    raw.write_u8(64).unwrap(); // TTL
+    raw.write_u8(17).unwrap(); // protocol (UDP)
+    raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+    raw.write_u32::<BigEndian>(u32::from(*source.ip())).unwrap();
+    raw.write_u32::<BigEndian>(u32::from(*destination.ip()))
+        .unwrap();
+
+    raw.write_u16::<BigEndian>(source.port()).unwrap();
+    raw.write_u16::<BigEndian>(destination.port()).unwrap();
+    raw.write_u16::<BigEndian>(udp_length).unwrap(); // length
+    raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+
+    raw.extend_from_slice(payload);
+    raw
+}
+
+/// Create an ICMP echo request, with its checksums unset.
+pub fn create_icmp_echo_request(
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    identifier: u16,
+    sequence_number: u16,
+    data: &[u8],
+) -> Vec<u8> {
+    let icmp_length = 8 + data.len() as u16;
+    let mut raw = Vec::new();
+    raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
+    raw.write_u8(0).unwrap(); // ToS
+    raw.write_u16::<BigEndian>(20 + icmp_length).unwrap(); // total length
+    raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+    raw.write_u8(64).unwrap(); // TTL
+    raw.write_u8(1).unwrap(); // protocol (ICMP)
+    raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+    raw.write_u32::<BigEndian>(u32::from(source)).unwrap();
+    raw.write_u32::<BigEndian>(u32::from(destination)).unwrap();
+
+    raw.write_u8(8).unwrap(); // type (echo request)
+    raw.write_u8(0).unwrap(); // code
+    raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+    raw.write_u16::<BigEndian>(identifier).unwrap();
+    raw.write_u16::<BigEndian>(sequence_number).unwrap();
+
+    raw.extend_from_slice(data);
+    raw
+}
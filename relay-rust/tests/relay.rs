@@ -0,0 +1,667 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+mod common;
+
+use byteorder::{BigEndian, ByteOrder};
+use common::{
+    control, create_icmp_echo_request, create_tcp_packet, create_udp_packet,
+    start_controlled_relay_with, start_echo_server, start_relay, start_relay_with, FakeClient,
+    TcpFlow, CLIENT_ADDRESS,
+};
+use relaylib::packet::ipv4_packet::Ipv4Packet;
+use relaylib::packet::tcp_header::FLAG_SYN;
+use relaylib::packet::transport_header::TransportHeader;
+use relaylib::{ChecksumValidation, IcmpPolicy};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn tcp_echo() {
+    let relay_port = start_relay();
+    let echo_port = start_echo_server();
+    let mut client = FakeClient::connect(relay_port);
+
+    let destination = SocketAddrV4::new(Ipv4Addr::LOCALHOST, echo_port);
+    let mut flow = TcpFlow::open(&mut client, 41000, destination);
+    flow.write(&mut client, b"hello");
+    assert_eq!(b"hello", &flow.read(&mut client, 5)[..]);
+}
+
+#[test]
+fn tcp_echo_several_segments() {
+    let relay_port = start_relay();
+    let echo_port = start_echo_server();
+    let mut client = FakeClient::connect(relay_port);
+
+    let destination = SocketAddrV4::new(Ipv4Addr::LOCALHOST, echo_port);
+    let mut flow = TcpFlow::open(&mut client, 41000, destination);
+    let data: Vec<u8> = (0..20_000).map(|i| i as u8).collect();
+    for chunk in data.chunks(1000) {
+        flow.write(&mut client, chunk);
+    }
+    assert_eq!(data, flow.read(&mut client, data.len()));
+}
+
+#[test]
+fn tcp_echo_several_connections() {
+    let relay_port = start_relay();
+    let echo_port = start_echo_server();
+    let mut client = FakeClient::connect(relay_port);
+
+    let destination = SocketAddrV4::new(Ipv4Addr::LOCALHOST, echo_port);
+    let mut flow1 = TcpFlow::open(&mut client, 41000, destination);
+    let mut flow2 = TcpFlow::open(&mut client, 41001, destination);
+    flow2.write(&mut client, b"second");
+    assert_eq!(b"second", &flow2.read(&mut client, 6)[..]);
+    flow1.write(&mut client, b"first");
+    assert_eq!(b"first", &flow1.read(&mut client, 5)[..]);
+}
+
+#[test]
+fn monitor_only() {
+    let (relay_port, control_port) =
+        start_controlled_relay_with(|builder| builder.monitor_only(true).trace_capacity(16));
+    let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    server.set_nonblocking(true).unwrap();
+    let port = server.local_addr().unwrap().port();
+    let mut client = FakeClient::connect(relay_port);
+
+    let source = SocketAddrV4::new(CLIENT_ADDRESS, 41000);
+    let destination = SocketAddrV4::new(Ipv4Addr::LOCALHOST, port);
+    let mut syn = create_tcp_packet(source, destination, 1000, 0, FLAG_SYN, &[]);
+    Ipv4Packet::parse(&mut syn).compute_checksums();
+    client.send_packet(&syn);
+
+    // the packet is accounted, even though it is not relayed
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let stats = control(control_port, "stats");
+        if stats.iter().any(|line| line == "monitored_packets 1") {
+            assert!(stats.iter().any(|line| line == "packets_tcp 1"));
+            // no connection has been created
+            assert!(!stats.iter().any(|line| line.starts_with("connection ")));
+            break;
+        }
+        assert!(Instant::now() < deadline, "Packet not monitored");
+        thread::sleep(Duration::from_millis(10));
+    }
+    let trace = control(control_port, "trace");
+    assert_eq!(2, trace.len());
+    assert!(trace[0].starts_with(&format!("client #{} ", client.id())));
+
+    // nothing connected upstream
+    match server.accept() {
+        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => (),
+        other => panic!("Unexpected upstream connection: {:?}", other),
+    }
+}
+
+#[test]
+fn verify_checksums() {
+    let (relay_port, control_port) =
+        start_controlled_relay_with(|builder| builder.verify_checksums(true));
+    let echo_port = start_echo_server();
+    let mut client = FakeClient::connect(relay_port);
+
+    let source = SocketAddrV4::new(CLIENT_ADDRESS, 41000);
+    let destination = SocketAddrV4::new(Ipv4Addr::LOCALHOST, echo_port);
+    let mut syn = create_tcp_packet(source, destination, 1000, 0, FLAG_SYN, &[]);
+    Ipv4Packet::parse(&mut syn).compute_checksums();
+    // corrupt the sequence number
+    syn[27] ^= 0x01;
+    client.send_packet(&syn);
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let stats = control(control_port, "stats");
+        if stats.iter().any(|line| line == "invalid_checksums 1") {
+            // the corrupt packet did not create any connection
+            assert!(!stats.iter().any(|line| line.starts_with("connection ")));
+            break;
+        }
+        assert!(Instant::now() < deadline, "Corrupt packet not dropped");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // the packets with valid checksums are relayed
+    let mut flow = TcpFlow::open(&mut client, 41000, destination);
+    flow.write(&mut client, b"hello");
+    assert_eq!(b"hello", &flow.read(&mut client, 5)[..]);
+}
+
+// send a SYN with a corrupt checksum, and return whether it has been relayed, along with the
+// number of invalid checksums counted
+fn relay_corrupt_syn(validation: ChecksumValidation) -> (bool, String) {
+    let (relay_port, control_port) =
+        start_controlled_relay_with(move |builder| builder.checksum_validation(validation));
+    let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    server.set_nonblocking(true).unwrap();
+    let port = server.local_addr().unwrap().port();
+    let mut client = FakeClient::connect(relay_port);
+
+    let source = SocketAddrV4::new(CLIENT_ADDRESS, 41000);
+    let destination = SocketAddrV4::new(Ipv4Addr::LOCALHOST, port);
+    let mut syn = create_tcp_packet(source, destination, 1000, 0, FLAG_SYN, &[]);
+    Ipv4Packet::parse(&mut syn).compute_checksums();
+    // corrupt the sequence number
+    syn[27] ^= 0x01;
+    client.send_packet(&syn);
+
+    // the SYN has been handled once it is counted (a packet dropped for its checksum is not
+    // counted per protocol)
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let stats = loop {
+        let stats = control(control_port, "stats");
+        if stats
+            .iter()
+            .any(|line| line == "packets_tcp 1" || line == "invalid_checksums 1")
+        {
+            break stats;
+        }
+        assert!(Instant::now() < deadline, "SYN not handled");
+        thread::sleep(Duration::from_millis(10));
+    };
+    let invalid_checksums = stats
+        .into_iter()
+        .find(|line| line.starts_with("invalid_checksums "))
+        .unwrap();
+    // give the relay the time to connect, if it relays the SYN
+    thread::sleep(Duration::from_millis(100));
+    (server.accept().is_ok(), invalid_checksums)
+}
+
+#[test]
+fn lenient_checksum_validation() {
+    let (relayed, invalid_checksums) = relay_corrupt_syn(ChecksumValidation::Lenient);
+    assert!(relayed);
+    assert_eq!("invalid_checksums 1", invalid_checksums);
+}
+
+#[test]
+fn strict_checksum_validation() {
+    let (relayed, invalid_checksums) = relay_corrupt_syn(ChecksumValidation::Strict);
+    assert!(!relayed);
+    assert_eq!("invalid_checksums 1", invalid_checksums);
+}
+
+#[test]
+fn checksum_validation_off() {
+    let (relayed, invalid_checksums) = relay_corrupt_syn(ChecksumValidation::Off);
+    assert!(relayed);
+    assert_eq!("invalid_checksums 0", invalid_checksums);
+}
+
+#[test]
+fn dns_redirect() {
+    let resolver = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    resolver
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let resolver_address = match resolver.local_addr().unwrap() {
+        SocketAddr::V4(address) => address,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    let relay_port = start_relay_with(move |builder| builder.dns_redirect(resolver_address));
+    let mut client = FakeClient::connect(relay_port);
+
+    // a query to another DNS server reaches the configured resolver
+    let source = SocketAddrV4::new(CLIENT_ADDRESS, 41000);
+    let server = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 53), 53);
+    client.send_packet(&create_udp_packet(source, server, b"query"));
+    let mut buf = [0; 16];
+    let (length, relay_address) = resolver.recv_from(&mut buf).unwrap();
+    assert_eq!(b"query", &buf[..length]);
+
+    // the response is sent back from the server the query was addressed to
+    resolver.send_to(b"response", relay_address).unwrap();
+    let mut raw = client.read_packet();
+    let ipv4_packet = Ipv4Packet::parse(&mut raw);
+    let (ipv4_header, transport_header) = ipv4_packet.headers();
+    assert_eq!(u32::from(*server.ip()), ipv4_header.source());
+    assert_eq!(u32::from(CLIENT_ADDRESS), ipv4_header.destination());
+    match transport_header {
+        Some(TransportHeader::Udp(udp_header)) => {
+            assert_eq!(server.port(), udp_header.source_port());
+            assert_eq!(source.port(), udp_header.destination_port());
+        }
+        _ => panic!("Not a UDP packet"),
+    }
+    assert_eq!(b"response", ipv4_packet.payload().unwrap());
+}
+
+#[test]
+fn external_address_pool() {
+    let pool = vec![Ipv4Addr::new(127, 0, 0, 1), Ipv4Addr::new(127, 0, 0, 2)];
+    let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    server
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let server_address = match server.local_addr().unwrap() {
+        SocketAddr::V4(address) => address,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    let relay_port = {
+        let pool = pool.clone();
+        start_relay_with(move |builder| builder.external_address_pool(pool))
+    };
+    let mut clients = [
+        FakeClient::connect(relay_port),
+        FakeClient::connect(relay_port),
+    ];
+
+    // both clients use the same source, but are seen from distinct addresses
+    let source = SocketAddrV4::new(CLIENT_ADDRESS, 41000);
+    let mut relay_addresses = Vec::new();
+    for client in &mut clients {
+        let payload = client.id().to_be_bytes();
+        client.send_packet(&create_udp_packet(source, server_address, &payload));
+        let mut buf = [0; 4];
+        let (length, relay_address) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(&payload, &buf[..length]);
+        let expected = pool[client.id() as usize % pool.len()];
+        assert_eq!(IpAddr::V4(expected), relay_address.ip());
+        relay_addresses.push(relay_address);
+    }
+    assert_ne!(relay_addresses[0].ip(), relay_addresses[1].ip());
+
+    // each reply is relayed to the client owning the address it is sent to
+    for (client, relay_address) in clients.iter_mut().zip(&relay_addresses).rev() {
+        let payload = client.id().to_be_bytes();
+        server.send_to(&payload, relay_address).unwrap();
+        let mut raw = client.read_packet();
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        assert_eq!(&payload, ipv4_packet.payload().unwrap());
+    }
+}
+
+#[test]
+fn source_nat_udp() {
+    let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    server
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let server_address = match server.local_addr().unwrap() {
+        SocketAddr::V4(address) => address,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    let relay_port = start_relay_with(|builder| {
+        builder
+            .external_address(Ipv4Addr::LOCALHOST)
+            .source_nat(true)
+    });
+    let mut client = FakeClient::connect(relay_port);
+
+    let source = SocketAddrV4::new(CLIENT_ADDRESS, 41000);
+    client.send_packet(&create_udp_packet(source, server_address, b"query"));
+    let mut buf = [0; 5];
+    let (length, translated) = server.recv_from(&mut buf).unwrap();
+    assert_eq!(b"query", &buf[..length]);
+    // the source has been translated to the external address and a port allocated by the relay
+    assert_eq!(IpAddr::V4(Ipv4Addr::LOCALHOST), translated.ip());
+    assert!(translated.port() >= 49152, "port {}", translated.port());
+
+    // the reply to the translated source is restored to the client source
+    server.send_to(b"reply", translated).unwrap();
+    let mut raw = client.read_packet();
+    let ipv4_packet = Ipv4Packet::parse(&mut raw);
+    let (ipv4_header, transport_header) = ipv4_packet.headers();
+    assert_eq!(u32::from(*server_address.ip()), ipv4_header.source());
+    assert_eq!(u32::from(CLIENT_ADDRESS), ipv4_header.destination());
+    match transport_header {
+        Some(TransportHeader::Udp(udp_header)) => {
+            assert_eq!(server_address.port(), udp_header.source_port());
+            assert_eq!(source.port(), udp_header.destination_port());
+        }
+        _ => panic!("Not a UDP packet"),
+    }
+    assert_eq!(b"reply", ipv4_packet.payload().unwrap());
+}
+
+#[test]
+fn source_nat_tcp() {
+    let relay_port = start_relay_with(|builder| {
+        builder
+            .external_address(Ipv4Addr::LOCALHOST)
+            .source_nat(true)
+    });
+    let echo_port = start_echo_server();
+    let mut client = FakeClient::connect(relay_port);
+
+    // the segments from the relay are addressed to the client source, not the translated one
+    let destination = SocketAddrV4::new(Ipv4Addr::LOCALHOST, echo_port);
+    let mut flow = TcpFlow::open(&mut client, 41000, destination);
+    flow.write(&mut client, b"hello");
+    assert_eq!(b"hello", &flow.read(&mut client, 5)[..]);
+}
+
+#[test]
+fn source_nat_clients_on_same_address() {
+    let relay_port = start_relay_with(|builder| {
+        builder
+            .external_address(Ipv4Addr::LOCALHOST)
+            .source_nat(true)
+    });
+    let echo_port = start_echo_server();
+    let mut clients = [
+        FakeClient::connect(relay_port),
+        FakeClient::connect(relay_port),
+    ];
+
+    // both clients open the same flow, translated on the same address to distinct ports
+    let destination = SocketAddrV4::new(Ipv4Addr::LOCALHOST, echo_port);
+    let mut flows: Vec<_> = clients
+        .iter_mut()
+        .map(|client| TcpFlow::open(client, 41000, destination))
+        .collect();
+    for (client, flow) in clients.iter_mut().zip(&mut flows) {
+        let payload = client.id().to_be_bytes();
+        flow.write(client, &payload);
+        assert_eq!(&payload, &flow.read(client, 4)[..]);
+    }
+}
+
+#[test]
+fn external_address_pool_with_source_nat() {
+    let pool = vec![Ipv4Addr::new(127, 0, 0, 1), Ipv4Addr::new(127, 0, 0, 2)];
+    let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    server
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let server_address = match server.local_addr().unwrap() {
+        SocketAddr::V4(address) => address,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    let relay_port = {
+        let pool = pool.clone();
+        start_relay_with(move |builder| builder.external_address_pool(pool).source_nat(true))
+    };
+    let mut clients = [
+        FakeClient::connect(relay_port),
+        FakeClient::connect(relay_port),
+    ];
+
+    // both clients use the same source, translated on their own address
+    let source = SocketAddrV4::new(CLIENT_ADDRESS, 41000);
+    let mut translated_sources = Vec::new();
+    for client in &mut clients {
+        let payload = client.id().to_be_bytes();
+        client.send_packet(&create_udp_packet(source, server_address, &payload));
+        let mut buf = [0; 4];
+        let (length, translated) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(&payload, &buf[..length]);
+        let expected = pool[client.id() as usize % pool.len()];
+        assert_eq!(IpAddr::V4(expected), translated.ip());
+        assert!(translated.port() >= 49152, "port {}", translated.port());
+        translated_sources.push(translated);
+    }
+    assert_ne!(translated_sources[0].ip(), translated_sources[1].ip());
+
+    // each reply is restored to the source of the client owning the translated source
+    for (client, translated) in clients.iter_mut().zip(&translated_sources).rev() {
+        let payload = client.id().to_be_bytes();
+        server.send_to(&payload, translated).unwrap();
+        let mut raw = client.read_packet();
+        let ipv4_packet = Ipv4Packet::parse(&mut raw);
+        let (ipv4_header, transport_header) = ipv4_packet.headers();
+        assert_eq!(u32::from(CLIENT_ADDRESS), ipv4_header.destination());
+        match transport_header {
+            Some(TransportHeader::Udp(udp_header)) => {
+                assert_eq!(source.port(), udp_header.destination_port());
+            }
+            _ => panic!("Not a UDP packet"),
+        }
+        assert_eq!(&payload, ipv4_packet.payload().unwrap());
+    }
+}
+
+#[test]
+fn icmp_echo_synthesized() {
+    let relay_port = start_relay_with(|builder| builder.icmp_policy(IcmpPolicy::Synthesize));
+    let mut client = FakeClient::connect(relay_port);
+
+    let destination = Ipv4Addr::new(192, 0, 2, 1);
+    let request = create_icmp_echo_request(CLIENT_ADDRESS, destination, 0x1234, 1, b"ping");
+    client.send_packet(&request);
+    let mut raw = client.read_packet();
+    let ipv4_packet = Ipv4Packet::parse(&mut raw);
+    let ipv4_header = ipv4_packet.ipv4_header();
+    assert_eq!(u32::from(destination), ipv4_header.source());
+    assert_eq!(u32::from(CLIENT_ADDRESS), ipv4_header.destination());
+    let icmp = &ipv4_packet.raw()[20..];
+    assert_eq!(0, icmp[0]); // echo reply
+                            // the identifier, the sequence number and the data are echoed
+    assert_eq!(&request[24..], &icmp[4..]);
+}
+
+#[test]
+fn icmp_echo_dropped() {
+    let (relay_port, control_port) = start_controlled_relay_with(|builder| builder);
+    let mut client = FakeClient::connect(relay_port);
+
+    let destination = Ipv4Addr::new(192, 0, 2, 1);
+    let request = create_icmp_echo_request(CLIENT_ADDRESS, destination, 0x1234, 1, b"ping");
+    client.send_packet(&request);
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let stats = control(control_port, "stats");
+        if stats.iter().any(|line| line == "icmp_echo_dropped 1") {
+            assert!(stats.iter().any(|line| line == "icmp_echo_synthesized 0"));
+            break;
+        }
+        assert!(Instant::now() < deadline, "ICMP echo request not dropped");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn icmp_echo_forwarded() {
+    let (relay_port, control_port) =
+        start_controlled_relay_with(|builder| builder.icmp_policy(IcmpPolicy::Forward));
+    let mut client = FakeClient::connect(relay_port);
+
+    let destination = Ipv4Addr::LOCALHOST;
+    let request = create_icmp_echo_request(CLIENT_ADDRESS, destination, 0x1234, 1, b"ping");
+    client.send_packet(&request);
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let stats = control(control_port, "stats");
+        if stats.iter().any(|line| line == "icmp_echo_dropped 1") {
+            // unprivileged ICMP sockets are not allowed (net.ipv4.ping_group_range)
+            return;
+        }
+        if stats.iter().any(|line| line == "icmp_echo_forwarded 1") {
+            break;
+        }
+        assert!(Instant::now() < deadline, "ICMP echo request not forwarded");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let mut raw = client.read_packet();
+    let ipv4_packet = Ipv4Packet::parse(&mut raw);
+    let ipv4_header = ipv4_packet.ipv4_header();
+    assert_eq!(u32::from(destination), ipv4_header.source());
+    assert_eq!(u32::from(CLIENT_ADDRESS), ipv4_header.destination());
+    let icmp = &ipv4_packet.raw()[20..];
+    assert_eq!(0, icmp[0]); // echo reply
+                            // the identifier replaced by the kernel is restored
+    assert_eq!(&request[24..], &icmp[4..]);
+}
+
+#[test]
+fn udp_disabled() {
+    let (relay_port, control_port) =
+        start_controlled_relay_with(|builder| builder.allow_udp(false));
+    let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    server.set_nonblocking(true).unwrap();
+    let server_address = match server.local_addr().unwrap() {
+        SocketAddr::V4(address) => address,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    let echo_port = start_echo_server();
+    let mut client = FakeClient::connect(relay_port);
+
+    let source = SocketAddrV4::new(CLIENT_ADDRESS, 41000);
+    client.send_packet(&create_udp_packet(source, server_address, b"hello"));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let stats = control(control_port, "stats");
+        if stats.iter().any(|line| line == "dropped_packets 1") {
+            // no connection has been created
+            assert!(!stats.iter().any(|line| line.starts_with("connection ")));
+            break;
+        }
+        assert!(Instant::now() < deadline, "UDP datagram not dropped");
+        thread::sleep(Duration::from_millis(10));
+    }
+    let mut buf = [0; 16];
+    assert_eq!(
+        io::ErrorKind::WouldBlock,
+        server.recv_from(&mut buf).unwrap_err().kind()
+    );
+
+    // TCP is still relayed
+    let destination = SocketAddrV4::new(Ipv4Addr::LOCALHOST, echo_port);
+    let mut flow = TcpFlow::open(&mut client, 41000, destination);
+    flow.write(&mut client, b"hello");
+    assert_eq!(b"hello", &flow.read(&mut client, 5)[..]);
+}
+
+#[test]
+fn fragmentation_needed_above_tunnel_mtu() {
+    let relay_port = start_relay_with(|builder| builder.tunnel_mtu(1280));
+    let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    server
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let server_address = match server.local_addr().unwrap() {
+        SocketAddr::V4(address) => address,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    let mut client = FakeClient::connect(relay_port);
+    let source = SocketAddrV4::new(CLIENT_ADDRESS, 42000);
+
+    let mut oversized = create_udp_packet(source, server_address, &[0x42; 1400]);
+    oversized[6] |= 0x40; // don't fragment
+    client.send_packet(&oversized);
+    let mut raw = client.read_packet();
+    let ipv4_packet = Ipv4Packet::parse(&mut raw);
+    let ipv4_header = ipv4_packet.ipv4_header();
+    assert_eq!(u32::from(*server_address.ip()), ipv4_header.source());
+    assert_eq!(u32::from(CLIENT_ADDRESS), ipv4_header.destination());
+    let icmp = &ipv4_packet.raw()[20..];
+    assert_eq!(3, icmp[0]); // destination unreachable
+    assert_eq!(4, icmp[1]); // fragmentation needed
+    assert_eq!(1280, BigEndian::read_u16(&icmp[6..8]));
+    // the headers of the oversized packet are quoted
+    assert_eq!(&oversized[..28], &icmp[8..]);
+
+    // without the flag, the packet is relayed
+    let mut buf = [0; 2048];
+    let fragmentable = create_udp_packet(source, server_address, &[0x42; 1400]);
+    client.send_packet(&fragmentable);
+    let (length, _) = server.recv_from(&mut buf).unwrap();
+    assert_eq!(1400, length);
+
+    // as is a packet fitting in the tunnel MTU
+    let mut small = create_udp_packet(source, server_address, b"hello");
+    small[6] |= 0x40;
+    client.send_packet(&small);
+    let (length, _) = server.recv_from(&mut buf).unwrap();
+    assert_eq!(b"hello", &buf[..length]);
+}
+
+#[test]
+fn fragment_packets_above_tunnel_mtu() {
+    let relay_port = start_relay_with(|builder| builder.tunnel_mtu(1280));
+    let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    server
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let server_address = match server.local_addr().unwrap() {
+        SocketAddr::V4(address) => address,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    let mut client = FakeClient::connect(relay_port);
+    let source = SocketAddrV4::new(CLIENT_ADDRESS, 42000);
+
+    client.send_packet(&create_udp_packet(source, server_address, b"hello"));
+    let mut buf = [0; 16];
+    let (_, peer) = server.recv_from(&mut buf).unwrap();
+    let data: Vec<u8> = (0..2000).map(|i| i as u8).collect();
+    server.send_to(&data, peer).unwrap();
+
+    // the 2028-byte response is split into 1276 + 772 bytes
+    let first = client.read_packet();
+    let second = client.read_packet();
+    assert_eq!(1276, first.len());
+    assert_eq!(772, second.len());
+    assert_eq!(0x20, first[6] & 0x20); // more fragments
+    assert_eq!(0, second[6] & 0x20);
+    assert_eq!(1256 / 8, BigEndian::read_u16(&second[6..8]) & 0x1fff);
+    assert_ne!(0, BigEndian::read_u16(&first[4..6]));
+    assert_eq!(first[4..6], second[4..6]); // same identification
+
+    let mut datagram = first[20..].to_vec();
+    datagram.extend_from_slice(&second[20..]);
+    assert_eq!(2008, BigEndian::read_u16(&datagram[4..6])); // UDP length
+    assert_eq!(data, &datagram[8..]);
+}
+
+#[test]
+fn query_connections() {
+    let (relay_port, control_port) = start_controlled_relay_with(|builder| builder);
+    let echo_port = start_echo_server();
+    let server = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let port = server.local_addr().unwrap().port();
+    let mut client = FakeClient::connect(relay_port);
+
+    let destination = SocketAddrV4::new(Ipv4Addr::LOCALHOST, echo_port);
+    let mut flow = TcpFlow::open(&mut client, 41000, destination);
+    flow.write(&mut client, b"hello");
+    assert_eq!(b"hello", &flow.read(&mut client, 5)[..]);
+
+    // the SYN-ACK of this connection is never acknowledged
+    let source = SocketAddrV4::new(CLIENT_ADDRESS, 41001);
+    let destination = SocketAddrV4::new(Ipv4Addr::LOCALHOST, port);
+    let mut syn = create_tcp_packet(source, destination, 1000, 0, FLAG_SYN, &[]);
+    Ipv4Packet::parse(&mut syn).compute_checksums();
+    client.send_packet(&syn);
+    client.read_packet();
+
+    let connections = control(control_port, "connections");
+    assert_eq!(3, connections.len());
+    assert!(connections[..2]
+        .iter()
+        .all(|line| line.starts_with("connection ") && line.contains(" idle_ms=")));
+
+    let half_open = control(control_port, "connections half-open");
+    assert_eq!(2, half_open.len());
+    assert!(half_open[0].contains(&format!(":{} ", port)));
+    assert!(half_open[0].ends_with(" half_open"));
+
+    let to_echo = control(control_port, &format!("connections port {}", echo_port));
+    assert_eq!(2, to_echo.len());
+    assert!(to_echo[0].contains(&format!(":{} ", echo_port)));
+    assert!(!to_echo[0].contains("half_open"));
+
+    let idle = control(control_port, "connections idle 3600");
+    assert_eq!(vec!["OK"], idle);
+}